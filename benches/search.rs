@@ -0,0 +1,84 @@
+//! Compares the node search strategies, both on bare sorted slices and inside trees.
+//!
+//! Run with `cargo bench --bench search`.
+
+use btree2::search::{Binary, Linear, SearchStrategy, Simd};
+use btree2::std_btree::BTree;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const LOOKUPS: usize = 1 << 20;
+
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn time(mut f: impl FnMut()) -> Duration {
+    // Warm up the caches and the branch predictor first.
+    f();
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    println!(
+        "{name:<28} {:>8.2} ns/lookup",
+        elapsed.as_nanos() as f64 / LOOKUPS as f64
+    );
+}
+
+fn bench_slice<S: SearchStrategy<u32, u32>>(
+    name: &str,
+    strategy: S,
+    keys: &[u32],
+    needles: &[u32],
+) {
+    let elapsed = time(|| {
+        for needle in needles {
+            black_box(strategy.search(black_box(keys), needle)).ok();
+        }
+    });
+    report(name, elapsed);
+}
+
+fn bench_tree<S: SearchStrategy<u32, u32>>(name: &str, strategy: S, len: u32, needles: &[u32]) {
+    let mut tree = BTree::<u32, u32, false, (), S>::with_search(strategy);
+    for key in 0..len {
+        tree.insert(2 * key, key);
+    }
+    let elapsed = time(|| {
+        for needle in needles {
+            black_box(tree.get(needle));
+        }
+    });
+    report(name, elapsed);
+}
+
+fn main() {
+    let mut state = 0x2545_f491_4f6c_dd1d;
+
+    for len in [8, 19, 64, 256] {
+        let keys: Vec<u32> = (0..len).map(|i| 2 * i).collect();
+        let needles: Vec<u32> = (0..LOOKUPS)
+            .map(|_| (xorshift(&mut state) % (2 * len as u64 + 1)) as u32)
+            .collect();
+        println!("slice of {len} keys:");
+        bench_slice("  linear", Linear, &keys, &needles);
+        bench_slice("  binary", Binary, &keys, &needles);
+        bench_slice("  simd", Simd, &keys, &needles);
+    }
+
+    for len in [1_000, 100_000, 1_000_000] {
+        let needles: Vec<u32> = (0..LOOKUPS)
+            .map(|_| (xorshift(&mut state) % (2 * len as u64)) as u32)
+            .collect();
+        println!("tree of {len} keys:");
+        bench_tree("  linear", Linear, len, &needles);
+        bench_tree("  binary", Binary, len, &needles);
+        bench_tree("  simd", Simd, len, &needles);
+    }
+}
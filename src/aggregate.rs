@@ -0,0 +1,208 @@
+use core::marker::PhantomData;
+use core::ops::Add;
+
+/// A monoid over the entries of a tree. Aggregated trees cache the aggregate of every node's
+/// subtree, which lets them fold it over any key range in logarithmic time.
+///
+/// `combine` has to be associative, with `identity()` as its identity element. It doesn't have to
+/// be commutative, entries are always combined in key order.
+pub trait Aggregate<K, V> {
+    type Value: Copy;
+
+    /// Whether the trees have to maintain the aggregate at all. Only `()` turns this off.
+    const ENABLED: bool = true;
+
+    fn identity() -> Self::Value;
+
+    fn lift(key: &K, value: &V) -> Self::Value;
+
+    fn combine(left: &Self::Value, right: &Self::Value) -> Self::Value;
+}
+
+/// The empty aggregate, used by trees that don't aggregate anything.
+impl<K, V> Aggregate<K, V> for () {
+    type Value = ();
+
+    const ENABLED: bool = false;
+
+    #[inline]
+    fn identity() {}
+
+    #[inline]
+    fn lift(_key: &K, _value: &V) {}
+
+    #[inline]
+    fn combine(_left: &(), _right: &()) {}
+}
+
+/// Counts the entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Count;
+
+impl<K, V> Aggregate<K, V> for Count {
+    type Value = usize;
+
+    #[inline]
+    fn identity() -> usize {
+        0
+    }
+
+    #[inline]
+    fn lift(_key: &K, _value: &V) -> usize {
+        1
+    }
+
+    #[inline]
+    fn combine(left: &usize, right: &usize) -> usize {
+        left + right
+    }
+}
+
+/// Sums the values, starting from `V::default()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Sum;
+
+impl<K, V: Copy + Default + Add<Output = V>> Aggregate<K, V> for Sum {
+    type Value = V;
+
+    #[inline]
+    fn identity() -> V {
+        V::default()
+    }
+
+    #[inline]
+    fn lift(_key: &K, value: &V) -> V {
+        *value
+    }
+
+    #[inline]
+    fn combine(left: &V, right: &V) -> V {
+        *left + *right
+    }
+}
+
+/// The smallest value, or `None` if there are no entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Min;
+
+impl<K, V: Copy + Ord> Aggregate<K, V> for Min {
+    type Value = Option<V>;
+
+    #[inline]
+    fn identity() -> Option<V> {
+        None
+    }
+
+    #[inline]
+    fn lift(_key: &K, value: &V) -> Option<V> {
+        Some(*value)
+    }
+
+    #[inline]
+    fn combine(left: &Option<V>, right: &Option<V>) -> Option<V> {
+        match (left, right) {
+            (Some(left), Some(right)) => Some(*left.min(right)),
+            _ => left.or(*right),
+        }
+    }
+}
+
+/// The largest value, or `None` if there are no entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Max;
+
+impl<K, V: Copy + Ord> Aggregate<K, V> for Max {
+    type Value = Option<V>;
+
+    #[inline]
+    fn identity() -> Option<V> {
+        None
+    }
+
+    #[inline]
+    fn lift(_key: &K, value: &V) -> Option<V> {
+        Some(*value)
+    }
+
+    #[inline]
+    fn combine(left: &Option<V>, right: &Option<V>) -> Option<V> {
+        match (left, right) {
+            (Some(left), Some(right)) => Some(*left.max(right)),
+            _ => left.or(*right),
+        }
+    }
+}
+
+/// Combines two aggregates into a pair.
+pub struct Pair<A, B>(PhantomData<(A, B)>);
+
+impl<K, V, A: Aggregate<K, V>, B: Aggregate<K, V>> Aggregate<K, V> for Pair<A, B> {
+    type Value = (A::Value, B::Value);
+
+    const ENABLED: bool = A::ENABLED || B::ENABLED;
+
+    #[inline]
+    fn identity() -> Self::Value {
+        (A::identity(), B::identity())
+    }
+
+    #[inline]
+    fn lift(key: &K, value: &V) -> Self::Value {
+        (A::lift(key, value), B::lift(key, value))
+    }
+
+    #[inline]
+    fn combine(left: &Self::Value, right: &Self::Value) -> Self::Value {
+        (A::combine(&left.0, &right.0), B::combine(&left.1, &right.1))
+    }
+}
+
+/// Picks what the nodes of a tree keep of the size of their subtree from the tree's `COUNTED`
+/// flag: a `usize` in counted trees, and `()` in the others, so that their nodes have no room for
+/// it at all. Code that is generic over `COUNTED` needs the bound `Counted<COUNTED>: SubtreeSize`.
+pub struct Counted<const COUNTED: bool>;
+
+pub trait SubtreeSize {
+    type Size: Size;
+}
+
+impl SubtreeSize for Counted<true> {
+    type Size = usize;
+}
+
+impl SubtreeSize for Counted<false> {
+    type Size = ();
+}
+
+/// The size of a subtree as a node stores it. `()` stores nothing, which only uncounted trees
+/// use: it reads as 0 and ignores writes.
+pub trait Size: Copy + Default {
+    fn get(self) -> usize;
+
+    fn set(&mut self, size: usize);
+}
+
+impl Size for usize {
+    #[inline]
+    fn get(self) -> usize {
+        self
+    }
+
+    #[inline]
+    fn set(&mut self, size: usize) {
+        *self = size;
+    }
+}
+
+impl Size for () {
+    #[inline]
+    fn get(self) -> usize {
+        0
+    }
+
+    #[inline]
+    fn set(&mut self, _size: usize) {}
+}
+
+/// The size type of the nodes of a tree with the given `COUNTED` flag.
+pub(crate) type SizeOf<const COUNTED: bool> = <Counted<COUNTED> as SubtreeSize>::Size;
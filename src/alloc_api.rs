@@ -0,0 +1,293 @@
+//! Adapters that let other collections allocate from the slab allocators.
+//!
+//! [`SlabHandle`] serves the layouts that fit the `T` of a shared slab allocator, so that a `Box`
+//! of `T` can live in a slab. [`SegregatedAlloc`] serves small layouts of any kind from a
+//! [`SlabCache`] with a size class for each power of two from 16 to 512 bytes, leaves the rest to a
+//! fallback allocator, [`System`] by default, and can be the `#[global_allocator]`, so that `Vec`,
+//! `Box` and the `std_btree` tree all draw from the slabs.
+//!
+//! Both have `allocate` and `deallocate` methods, and implement the `Allocator` trait of the
+//! allocator-api2 crate with the `allocator-api2` feature, and the nightly `Allocator` trait with
+//! the `nightly` feature.
+
+use crate::slab::{LockedSlabAllocator, SharedSlabAlloc, SlabCache};
+use core::alloc::{GlobalAlloc, Layout};
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ptr::{self, NonNull};
+use std::alloc::System;
+
+/// A handle to a shared slab allocator of `T`, which allocates a slab for every layout that fits
+/// `T`, and rejects the others.
+pub struct SlabHandle<'a, T, A: SharedSlabAlloc<T> = LockedSlabAllocator<T>> {
+    alloc: &'a A,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<'a, T, A: SharedSlabAlloc<T>> SlabHandle<'a, T, A> {
+    #[inline]
+    pub fn new(alloc: &'a A) -> Self {
+        Self {
+            alloc,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Whether a slab of `T` can hold `layout`.
+    #[inline]
+    pub fn fits(layout: Layout) -> bool {
+        layout.size() <= size_of::<T>() && layout.align() <= align_of::<T>()
+    }
+
+    /// Allocates a slab for `layout`, or returns `None` if it doesn't fit `T` or the allocator
+    /// needs a new chunk.
+    #[inline]
+    pub fn allocate(&self, layout: Layout) -> Option<NonNull<[u8]>> {
+        if !Self::fits(layout) {
+            return None;
+        }
+        let ptr = self.alloc.malloc()?;
+        Some(NonNull::slice_from_raw_parts(ptr.cast(), size_of::<T>()))
+    }
+
+    /// Frees a slab allocated for `layout`.
+    ///
+    /// # Safety
+    /// `ptr` must have been allocated by this handle, or a copy of it, for `layout`.
+    #[inline]
+    pub unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        debug_assert!(Self::fits(layout));
+        self.alloc.free(ptr.cast());
+    }
+}
+
+impl<T, A: SharedSlabAlloc<T>> Clone for SlabHandle<'_, T, A> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, A: SharedSlabAlloc<T>> Copy for SlabHandle<'_, T, A> {}
+
+/// The sizes of the classes of a [`SegregatedAlloc`], which are aligned to their size.
+pub const SEGREGATED_SIZES: [usize; 6] = [16, 32, 64, 128, 256, 512];
+
+/// A segregated-fit allocator of small layouts, with a size class for each of
+/// [`SEGREGATED_SIZES`]. A layout is served by the smallest class that is at least as large as
+/// both its size and its alignment. Larger layouts, and small ones while the classes have no
+/// memory, go to the fallback allocator `F`.
+///
+/// It starts without any memory, so that it can be created in a `static`, and serves everything
+/// from the fallback until it is given a chunk. Frees are routed by whether the pointer is in one
+/// of the chunks, which takes time linear in their number, so the chunks should be large.
+pub struct SegregatedAlloc<F = System> {
+    cache: spin::Mutex<Option<SlabCache<{ SEGREGATED_SIZES.len() }>>>,
+    fallback: F,
+}
+
+impl<F: Default> Default for SegregatedAlloc<F> {
+    #[inline]
+    fn default() -> Self {
+        Self::with_fallback(F::default())
+    }
+}
+
+impl SegregatedAlloc {
+    #[inline]
+    pub const fn new() -> Self {
+        Self::with_fallback(System)
+    }
+
+    /// The class that serves `layout`, if any does.
+    #[inline]
+    pub fn class(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+        SEGREGATED_SIZES.iter().position(|&class| size <= class)
+    }
+}
+
+impl<F> SegregatedAlloc<F> {
+    #[inline]
+    pub const fn with_fallback(fallback: F) -> Self {
+        Self {
+            cache: spin::Mutex::new(None),
+            fallback,
+        }
+    }
+
+    #[inline]
+    pub fn fallback(&self) -> &F {
+        &self.fallback
+    }
+
+    /// Adds the pages of `chunk` to the classes' shared pool.
+    pub fn add_chunk(&self, chunk: &'static mut [u8]) {
+        let mut cache = self.cache.lock();
+        match &mut *cache {
+            Some(cache) => cache.add_chunk(chunk),
+            None => {
+                let classes = SEGREGATED_SIZES
+                    .map(|size| Layout::from_size_align(size, size).expect("power of two"));
+                *cache = Some(SlabCache::new(classes, chunk));
+            }
+        }
+    }
+
+    /// Returns true if any class can allocate fewer than 64 slabs without a new chunk.
+    pub fn needs_new_chunk(&self) -> bool {
+        self.cache
+            .lock()
+            .as_ref()
+            .is_none_or(SlabCache::needs_new_chunk)
+    }
+
+    /// Whether `ptr` is a slab of one of the classes rather than memory of the fallback.
+    #[inline]
+    pub fn is_slab(&self, ptr: NonNull<u8>) -> bool {
+        self.cache
+            .lock()
+            .as_ref()
+            .is_some_and(|cache| cache.contains(ptr))
+    }
+
+    /// Allocates a slab for `layout`, or returns `None` if it is too large or the allocator needs
+    /// a new chunk. Never uses the fallback.
+    #[inline]
+    pub fn allocate_slab(&self, layout: Layout) -> Option<NonNull<[u8]>> {
+        let class = SegregatedAlloc::class(layout)?;
+        let ptr = self.cache.lock().as_mut()?.malloc(class)?;
+        Some(NonNull::slice_from_raw_parts(ptr, SEGREGATED_SIZES[class]))
+    }
+}
+
+impl<F: GlobalAlloc> SegregatedAlloc<F> {
+    /// Allocates a slab for `layout`, or memory from the fallback if it is too large or the
+    /// allocator needs a new chunk. Returns `None` if the fallback fails too.
+    #[inline]
+    pub fn allocate(&self, layout: Layout) -> Option<NonNull<[u8]>> {
+        if let Some(slab) = self.allocate_slab(layout) {
+            return Some(slab);
+        }
+        if layout.size() == 0 {
+            // `GlobalAlloc` can't allocate zero bytes, and nothing needs to be freed for them.
+            let dangling = ptr::without_provenance_mut::<u8>(layout.align());
+            return NonNull::new(dangling).map(|ptr| NonNull::slice_from_raw_parts(ptr, 0));
+        }
+        let ptr = NonNull::new(unsafe { self.fallback.alloc(layout) })?;
+        Some(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    /// Frees memory allocated for `layout`, by a class or by the fallback.
+    ///
+    /// # Safety
+    /// `ptr` must have been allocated by this allocator for `layout`.
+    #[inline]
+    pub unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if let Some(class) = SegregatedAlloc::class(layout) {
+            let mut cache = self.cache.lock();
+            if let Some(cache) = cache.as_mut().filter(|cache| cache.contains(ptr)) {
+                cache.free(ptr, class);
+                return;
+            }
+        }
+        if layout.size() != 0 {
+            self.fallback.dealloc(ptr.as_ptr(), layout);
+        }
+    }
+}
+
+unsafe impl<F: GlobalAlloc> GlobalAlloc for SegregatedAlloc<F> {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout)
+            .map_or(ptr::null_mut(), |ptr| ptr.as_ptr().cast())
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.deallocate(NonNull::new_unchecked(ptr), layout);
+    }
+
+    /// Keeps the slab if the new size is served by the same class, and leaves memory that stays
+    /// too large for the classes to the fallback's `realloc`.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (
+            SegregatedAlloc::class(layout),
+            SegregatedAlloc::class(new_layout),
+        ) {
+            (None, None) => return self.fallback.realloc(ptr, layout, new_size),
+            (Some(old), Some(new)) if old == new && self.is_slab(NonNull::new_unchecked(ptr)) => {
+                return ptr
+            }
+            _ => {}
+        }
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(feature = "allocator-api2")]
+mod api2 {
+    use super::*;
+    use allocator_api2::alloc::{AllocError, Allocator};
+
+    unsafe impl<T, A: SharedSlabAlloc<T>> Allocator for SlabHandle<'_, T, A> {
+        #[inline]
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            SlabHandle::allocate(self, layout).ok_or(AllocError)
+        }
+
+        #[inline]
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            SlabHandle::deallocate(self, ptr, layout)
+        }
+    }
+
+    unsafe impl<F: GlobalAlloc> Allocator for SegregatedAlloc<F> {
+        #[inline]
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            SegregatedAlloc::allocate(self, layout).ok_or(AllocError)
+        }
+
+        #[inline]
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            SegregatedAlloc::deallocate(self, ptr, layout)
+        }
+    }
+}
+
+#[cfg(feature = "nightly")]
+mod nightly {
+    use super::*;
+    use core::alloc::{AllocError, Allocator};
+
+    unsafe impl<T, A: SharedSlabAlloc<T>> Allocator for SlabHandle<'_, T, A> {
+        #[inline]
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            SlabHandle::allocate(self, layout).ok_or(AllocError)
+        }
+
+        #[inline]
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            SlabHandle::deallocate(self, ptr, layout)
+        }
+    }
+
+    unsafe impl<F: GlobalAlloc> Allocator for SegregatedAlloc<F> {
+        #[inline]
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            SegregatedAlloc::allocate(self, layout).ok_or(AllocError)
+        }
+
+        #[inline]
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            SegregatedAlloc::deallocate(self, ptr, layout)
+        }
+    }
+}
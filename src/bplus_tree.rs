@@ -0,0 +1,1895 @@
+//! A B+tree: a sorted map like [`BTree`](crate::btree::BTree), but the internal nodes only hold
+//! separator keys, and all the entries are in the leaves, which are linked to their neighbours.
+//! Without values, the internal nodes fit more keys per slab, so the tree is shallower, and
+//! iterating walks the chain of leaves instead of going up and down the tree.
+//!
+//! The separators are clones of keys, so inserting and removing need `K: Clone`. Like `BTree`, the
+//! tree can be counted, for [`nth`](BPlusTree::nth) and [`rank`](BPlusTree::rank), and cache an
+//! [`Aggregate`] for [`fold_range`](BPlusTree::fold_range). The internal nodes then keep the size
+//! and the aggregate of their subtrees, which the separators don't count towards.
+//!
+//! There is no `write_relocatable`: the [relocatable](crate::relocatable) format stores an entry
+//! with every key of the internal nodes, which the separators don't have. Build a
+//! [`BTree`](crate::btree::BTree) from the entries to get an image.
+
+use crate::aggregate::{Aggregate, Counted, Size, SizeOf, SubtreeSize};
+use crate::btree::{
+    copy_within, Buffers, KVPair, B as LEAF_B, LEAF_CLASS, MAX_NUM_ELEMENTS as LEAF_CAPACITY,
+    MIN_NUM_ELEMENTS as LEAF_MIN, NODE_CLASS,
+};
+use crate::budget::{Account, BudgetExceeded, MemoryBudget};
+use crate::compare::By;
+use crate::dump::{DotEscape, JsonEscape};
+use crate::search::{Linear, SearchStrategy};
+use crate::slab::SlabCache;
+use crate::stats::{max_depth, BTreeStats, TreeStats};
+#[cfg(feature = "check-invariants")]
+use crate::validate::Corruption;
+use crate::validate::InvariantViolation;
+use alloc::sync::Arc;
+use core::alloc::Layout;
+#[cfg(feature = "check-invariants")]
+use core::any::type_name;
+use core::fmt::{self, Write as _};
+use core::iter::FusedIterator;
+use core::marker::PhantomData;
+use core::mem::{self, size_of, MaybeUninit};
+use core::ops::{Bound, RangeBounds};
+use core::ptr::{self, NonNull};
+use core::slice;
+
+/// Internal nodes hold no values, so they get a larger fan-out than the leaves.
+const NODE_B: usize = 16;
+
+const NODE_MIN: usize = NODE_B - 1;
+const NODE_CAPACITY: usize = 2 * NODE_B - 1;
+
+/// The most levels the tree can have, which sizes the paths kept by insertions and removals.
+/// Internal nodes have at least `NODE_B` children and leaves at least `LEAF_B - 1` entries, so the
+/// bound for `LEAF_B` children per node holds.
+const MAX_DEPTH: usize = max_depth(LEAF_B);
+
+/// Inserts `item` at index `i` into the full array of `len` elements at `left`, then moves the
+/// elements from index `at` on into the empty array at `right`.
+#[inline]
+unsafe fn insert_split<T>(left: *mut T, right: *mut T, len: usize, i: usize, item: T, at: usize) {
+    if i < at {
+        ptr::copy_nonoverlapping(left.add(at - 1), right, len + 1 - at);
+        copy_within(left, i, i + 1, at - 1 - i);
+        left.add(i).write(item);
+    } else {
+        ptr::copy_nonoverlapping(left.add(at), right, i - at);
+        right.add(i - at).write(item);
+        ptr::copy_nonoverlapping(left.add(i), right.add(i - at + 1), len - i);
+    }
+}
+
+#[repr(align(8))]
+struct Leaf<K, V> {
+    len: u8,
+    prev: Option<NonNull<Leaf<K, V>>>,
+    next: Option<NonNull<Leaf<K, V>>>,
+    keys: [MaybeUninit<K>; LEAF_CAPACITY],
+    values: [MaybeUninit<V>; LEAF_CAPACITY],
+}
+
+impl<K, V> Leaf<K, V> {
+    #[inline]
+    fn len(&self) -> usize {
+        self.len as _
+    }
+
+    #[inline]
+    fn keys(&self) -> &[K] {
+        unsafe { slice::from_raw_parts(self.keys.as_ptr() as _, self.len()) }
+    }
+    #[inline]
+    fn values(&self) -> &[V] {
+        unsafe { slice::from_raw_parts(self.values.as_ptr() as _, self.len()) }
+    }
+
+    #[inline]
+    unsafe fn key_mut(&mut self, i: usize) -> &mut K {
+        self.keys.get_unchecked_mut(i).assume_init_mut()
+    }
+    #[inline]
+    unsafe fn value_mut(&mut self, i: usize) -> &mut V {
+        self.values.get_unchecked_mut(i).assume_init_mut()
+    }
+
+    /// Inserts an entry at `i`. The leaf must not be full.
+    #[inline]
+    unsafe fn insert(&mut self, i: usize, key: K, value: V) {
+        let len = self.len();
+        copy_within(self.keys.as_mut_ptr(), i, i + 1, len - i);
+        copy_within(self.values.as_mut_ptr(), i, i + 1, len - i);
+        self.keys.get_unchecked_mut(i).write(key);
+        self.values.get_unchecked_mut(i).write(value);
+        self.len += 1;
+    }
+
+    #[inline]
+    unsafe fn remove(&mut self, i: usize) -> (K, V) {
+        let len = self.len();
+        let key = self.keys.get_unchecked(i).assume_init_read();
+        let value = self.values.get_unchecked(i).assume_init_read();
+        copy_within(self.keys.as_mut_ptr(), i + 1, i, len - i - 1);
+        copy_within(self.values.as_mut_ptr(), i + 1, i, len - i - 1);
+        self.len -= 1;
+        (key, value)
+    }
+}
+
+#[repr(align(8))]
+struct Node<K, V, N, G> {
+    len: u8,
+    /// The number of entries in this node's subtree. Only counted trees have room for it.
+    size: N,
+    /// The aggregate of this node's subtree. Only maintained by aggregated trees.
+    agg: G,
+    keys: [MaybeUninit<K>; NODE_CAPACITY],
+    children: [MaybeUninit<Child<K, V, N, G>>; NODE_CAPACITY + 1],
+}
+
+impl<K, V, N, G> Node<K, V, N, G> {
+    #[inline]
+    fn len(&self) -> usize {
+        self.len as _
+    }
+
+    #[inline]
+    fn keys(&self) -> &[K] {
+        unsafe { slice::from_raw_parts(self.keys.as_ptr() as _, self.len()) }
+    }
+    #[inline]
+    fn children(&self) -> &[Child<K, V, N, G>] {
+        unsafe { slice::from_raw_parts(self.children.as_ptr() as _, self.len() + 1) }
+    }
+
+    #[inline]
+    unsafe fn key_mut(&mut self, i: usize) -> &mut K {
+        self.keys.get_unchecked_mut(i).assume_init_mut()
+    }
+    #[inline]
+    unsafe fn child(&self, i: usize) -> Child<K, V, N, G> {
+        self.children.get_unchecked(i).assume_init()
+    }
+
+    /// Inserts `key` at `i`, and `child` after it. The node must not be full.
+    #[inline]
+    unsafe fn insert(&mut self, i: usize, key: K, child: Child<K, V, N, G>) {
+        let len = self.len();
+        copy_within(self.keys.as_mut_ptr(), i, i + 1, len - i);
+        copy_within(self.children.as_mut_ptr(), i + 1, i + 2, len - i);
+        self.keys.get_unchecked_mut(i).write(key);
+        self.children.get_unchecked_mut(i + 1).write(child);
+        self.len += 1;
+    }
+
+    /// Removes the key at `i`, and the child after it.
+    #[inline]
+    unsafe fn remove(&mut self, i: usize) -> (K, Child<K, V, N, G>) {
+        let len = self.len();
+        let key = self.keys.get_unchecked(i).assume_init_read();
+        let child = self.child(i + 1);
+        copy_within(self.keys.as_mut_ptr(), i + 1, i, len - i - 1);
+        copy_within(self.children.as_mut_ptr(), i + 2, i + 1, len - i - 1);
+        self.len -= 1;
+        (key, child)
+    }
+
+    /// Inserts `key` and `child` before the first key and child.
+    #[inline]
+    unsafe fn push_front(&mut self, key: K, child: Child<K, V, N, G>) {
+        let len = self.len();
+        copy_within(self.keys.as_mut_ptr(), 0, 1, len);
+        copy_within(self.children.as_mut_ptr(), 0, 1, len + 1);
+        self.keys.get_unchecked_mut(0).write(key);
+        self.children.get_unchecked_mut(0).write(child);
+        self.len += 1;
+    }
+
+    /// Removes the first key and the first child.
+    #[inline]
+    unsafe fn pop_front(&mut self) -> (K, Child<K, V, N, G>) {
+        let len = self.len();
+        let key = self.keys.get_unchecked(0).assume_init_read();
+        let child = self.child(0);
+        copy_within(self.keys.as_mut_ptr(), 1, 0, len - 1);
+        copy_within(self.children.as_mut_ptr(), 1, 0, len);
+        self.len -= 1;
+        (key, child)
+    }
+}
+
+/// A child of a node: a leaf if the node is on the level above the leaves, a node otherwise.
+union Child<K, V, N, G> {
+    node: NonNull<Node<K, V, N, G>>,
+    leaf: NonNull<Leaf<K, V>>,
+}
+
+impl<K, V, N, G> Clone for Child<K, V, N, G> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, V, N, G> Copy for Child<K, V, N, G> {}
+
+/// A node on the way down to a leaf, with the index of the child taken in it.
+type Step<K, V, N, G> = (NonNull<Node<K, V, N, G>>, usize);
+
+/// The nodes on the way down to a leaf.
+type Path<K, V, N, G> = [Step<K, V, N, G>; MAX_DEPTH];
+
+/// The leaves and internal nodes of a tree, allocated from the shared pages of one cache, and
+/// charged to the tree's budget, as in [`BTree`](crate::btree::BTree).
+struct NodeSlabs<K, V, N, G> {
+    cache: SlabCache<2>,
+    account: Account,
+    _phantom: PhantomData<Child<K, V, N, G>>,
+}
+
+impl<K, V, N, G> NodeSlabs<K, V, N, G> {
+    fn new(chunk: &'static mut [u8]) -> Self {
+        let classes = [
+            Layout::new::<Leaf<K, V>>(),
+            Layout::new::<Node<K, V, N, G>>(),
+        ];
+        Self {
+            cache: SlabCache::new(classes, chunk),
+            account: Account::default(),
+            _phantom: PhantomData,
+        }
+    }
+
+    #[inline]
+    fn malloc_leaf(&mut self) -> NonNull<Leaf<K, V>> {
+        self.malloc_charged(LEAF_CLASS, size_of::<Leaf<K, V>>())
+            .cast()
+    }
+
+    #[inline]
+    fn malloc_node(&mut self) -> NonNull<Node<K, V, N, G>> {
+        self.malloc_charged(NODE_CLASS, size_of::<Node<K, V, N, G>>())
+            .cast()
+    }
+
+    /// Allocates a slab of `class`, charging its `bytes` to the budget. Panics if the cache is
+    /// out of pages.
+    #[inline]
+    fn malloc_charged(&mut self, class: usize, bytes: usize) -> NonNull<u8> {
+        self.account.charge(bytes);
+        let Some(slab) = self.cache.malloc(class) else {
+            self.account.refund(bytes);
+            panic!("Failed to allocate");
+        };
+        slab
+    }
+
+    #[inline]
+    unsafe fn free_leaf(&mut self, leaf: NonNull<Leaf<K, V>>) {
+        self.cache.free(leaf.cast(), LEAF_CLASS);
+        self.account.refund(size_of::<Leaf<K, V>>());
+    }
+
+    #[inline]
+    unsafe fn free_node(&mut self, node: NonNull<Node<K, V, N, G>>) {
+        self.cache.free(node.cast(), NODE_CLASS);
+        self.account.refund(size_of::<Node<K, V, N, G>>());
+    }
+}
+
+/// The nodes and children of a tree with the given `COUNTED` flag and aggregate `A`.
+type TreeNode<K, V, const COUNTED: bool, A> =
+    Node<K, V, SizeOf<COUNTED>, <A as Aggregate<K, V>>::Value>;
+type TreeChild<K, V, const COUNTED: bool, A> =
+    Child<K, V, SizeOf<COUNTED>, <A as Aggregate<K, V>>::Value>;
+
+/// A sorted map with its entries in a chain of leaves, under internal nodes of separator keys.
+/// Nodes and leaves are allocated from caller supplied chunks, or from buffers the tree owns, as
+/// in [`BTree`](crate::btree::BTree).
+///
+/// `S` is the [`SearchStrategy`] used to search the keys of each node, see [`crate::search`]. It
+/// can also be a [`By`] comparator that orders the keys instead of `Ord`, see [`BPlusTreeBy`].
+pub struct BPlusTree<K, V, const COUNTED: bool = false, A: Aggregate<K, V> = (), S = Linear>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    root: MaybeUninit<TreeChild<K, V, COUNTED, A>>,
+    depth: u8,
+    size: usize,
+
+    slabs: NodeSlabs<K, V, SizeOf<COUNTED>, A::Value>,
+    search: S,
+    _aggregate: PhantomData<A>,
+    /// Dropped last, once no node is left in them.
+    buffers: Buffers,
+}
+
+unsafe impl<K: Send, V: Send, const COUNTED: bool, A: Aggregate<K, V>, S: Send> Send
+    for BPlusTree<K, V, COUNTED, A, S>
+where
+    A::Value: Send,
+    Counted<COUNTED>: SubtreeSize,
+{
+}
+unsafe impl<K: Sync, V: Sync, const COUNTED: bool, A: Aggregate<K, V>, S: Sync> Sync
+    for BPlusTree<K, V, COUNTED, A, S>
+where
+    A::Value: Sync,
+    Counted<COUNTED>: SubtreeSize,
+{
+}
+
+impl<K, V, const COUNTED: bool, A: Aggregate<K, V>, S> BPlusTree<K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    #[inline]
+    pub fn new(chunk: &'static mut [u8]) -> Self
+    where
+        S: Default,
+    {
+        Self::with_search(chunk, S::default())
+    }
+
+    /// Creates a tree that searches its nodes with `search`.
+    pub fn with_search(chunk: &'static mut [u8], search: S) -> Self {
+        let slabs = NodeSlabs::new(chunk);
+        assert!(
+            2 <= slabs.cache.free_pages(),
+            "a chunk needs room for two pages of {} bytes",
+            slabs.cache.page_size(),
+        );
+
+        Self {
+            root: MaybeUninit::uninit(),
+            depth: 0,
+            size: 0,
+            slabs,
+            search,
+            _aggregate: PhantomData,
+            buffers: Buffers::default(),
+        }
+    }
+
+    /// Creates a tree whose nodes live in `buffer`, like a `Vec<u8>` or a `Box<[u8]>`, which the
+    /// tree owns and frees when it is dropped, so no chunk has to be leaked for it.
+    pub fn with_buffer(buffer: impl Into<Box<[u8]>>) -> Self
+    where
+        S: Default,
+    {
+        let mut buffers = Buffers::default();
+        let mut tree = Self::new(buffers.adopt(buffer.into()));
+        tree.buffers = buffers;
+        tree
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    #[inline]
+    pub fn needs_new_chunk(&self) -> bool {
+        self.slabs.cache.needs_new_chunk()
+    }
+
+    #[inline]
+    pub fn add_chunk(&mut self, chunk: &'static mut [u8]) {
+        self.slabs.cache.add_chunk(chunk);
+    }
+
+    /// Adds a buffer that the tree owns and frees when it is dropped, like the one of
+    /// [`with_buffer`](Self::with_buffer).
+    pub fn add_buffer(&mut self, buffer: impl Into<Box<[u8]>>) {
+        let chunk = self.buffers.adopt(buffer.into());
+        self.slabs.cache.add_chunk(chunk);
+    }
+
+    /// Charges every leaf and node the tree allocates from now on to `budget`, like
+    /// [`BTree::set_budget`](crate::btree::BTree::set_budget). Panics if the tree has any nodes.
+    pub fn set_budget(&mut self, budget: Arc<dyn MemoryBudget>) {
+        assert_eq!(self.depth, 0, "a budget can only be set on an empty tree");
+        self.slabs.account.set_budget(budget);
+    }
+
+    /// The bytes of the leaves and nodes charged to the tree's budget.
+    #[inline]
+    pub fn charged(&self) -> usize {
+        self.slabs.account.charged()
+    }
+
+    /// Removes every entry. If neither keys nor values need to be dropped, the nodes aren't
+    /// visited at all, and the slab cache is reset to its chunks. Otherwise the entries and
+    /// separators are dropped in a post-order walk.
+    pub fn clear(&mut self) {
+        if self.depth == 0 {
+            return;
+        }
+        unsafe {
+            if mem::needs_drop::<K>() || mem::needs_drop::<V>() {
+                self.drop_child(self.root.assume_init(), self.depth as usize - 1);
+            } else {
+                self.slabs.cache.reset();
+                self.slabs.account.refund_all();
+            }
+        }
+        self.depth = 0;
+        self.size = 0;
+    }
+
+    /// Gathers the shape of the tree and the state of its slab cache. Takes linear time in the
+    /// number of nodes and partly used pages.
+    pub fn stats(&self) -> BTreeStats {
+        BTreeStats {
+            tree: self.tree_stats(),
+            node_alloc: self.slabs.cache.stats(NODE_CLASS),
+            leaf_alloc: self.slabs.cache.stats(LEAF_CLASS),
+            free_pages: self.slabs.cache.pool_size(),
+        }
+    }
+
+    /// Gathers the shape of the tree. The keys of an internal level are its separators. Takes
+    /// linear time in the number of nodes.
+    pub fn tree_stats(&self) -> TreeStats {
+        let mut stats = TreeStats {
+            len: self.size,
+            depth: self.depth as usize,
+            ..TreeStats::default()
+        };
+        if self.depth != 0 {
+            self.stats_child(unsafe { self.root.assume_init() }, 0, &mut stats);
+        }
+        stats
+    }
+
+    fn stats_child(&self, child: TreeChild<K, V, COUNTED, A>, level: usize, stats: &mut TreeStats) {
+        unsafe {
+            if level + 1 == self.depth as usize {
+                Self::record_node(stats, level, (*child.leaf.as_ptr()).len(), true);
+            } else {
+                let node = &*child.node.as_ptr();
+                Self::record_node(stats, level, node.len(), false);
+                for &child in node.children() {
+                    self.stats_child(child, level + 1, stats);
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn record_node(stats: &mut TreeStats, level: usize, len: usize, is_leaf: bool) {
+        if is_leaf {
+            stats.leaves += 1;
+        } else {
+            stats.nodes += 1;
+        }
+        let level = &mut stats.levels[level];
+        level.nodes += 1;
+        level.keys += len;
+        level.capacity += if is_leaf {
+            LEAF_CAPACITY
+        } else {
+            NODE_CAPACITY
+        };
+    }
+
+    /// Gets an iterator over the entries of the tree, sorted by key.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        if self.depth == 0 {
+            return Iter::empty();
+        }
+        unsafe {
+            let last = self.last_leaf();
+            Iter {
+                front: Some((self.first_leaf(), 0)),
+                back: Some((last, (*last.as_ptr()).len() - 1)),
+                remaining: self.size,
+                _marker: PhantomData,
+            }
+        }
+    }
+
+    /// Gets an iterator over the entries with keys in `range`, sorted by key. Finds both ends in
+    /// logarithmic time, then walks the leaves between them. Counted trees also count the entries
+    /// in between in logarithmic time, while the others count them along the chain of leaves,
+    /// which takes time linear in the number of leaves of the range.
+    pub fn range<Q, R>(&self, range: R) -> Iter<'_, K, V>
+    where
+        Q: ?Sized,
+        R: RangeBounds<Q>,
+        S: SearchStrategy<K, Q>,
+    {
+        if self.depth == 0 {
+            return Iter::empty();
+        }
+        unsafe {
+            let front = match range.start_bound() {
+                Bound::Included(key) => self.lower_bound(key, false),
+                Bound::Excluded(key) => self.lower_bound(key, true),
+                Bound::Unbounded => Some((self.first_leaf(), 0)),
+            };
+            let back = match range.end_bound() {
+                Bound::Included(key) => self.upper_bound(key, true),
+                Bound::Excluded(key) => self.upper_bound(key, false),
+                Bound::Unbounded => {
+                    let last = self.last_leaf();
+                    Some((last, (*last.as_ptr()).len() - 1))
+                }
+            };
+            // The range is empty if its first entry is already past the end.
+            let (Some((leaf, i)), Some(_)) = (front, back) else {
+                return Iter::empty();
+            };
+            let first = slice::from_ref((*leaf.as_ptr()).keys().get_unchecked(i));
+            let within = match range.end_bound() {
+                Bound::Included(key) => matches!(self.search.search(first, key), Ok(_) | Err(1)),
+                Bound::Excluded(key) => self.search.search(first, key) == Err(1),
+                Bound::Unbounded => true,
+            };
+            if !within {
+                return Iter::empty();
+            }
+            let remaining = if COUNTED {
+                let start = match range.start_bound() {
+                    Bound::Included(key) => self.position(key).unwrap_or_else(|i| i),
+                    Bound::Excluded(key) => self.position(key).map_or_else(|i| i, |i| i + 1),
+                    Bound::Unbounded => 0,
+                };
+                let end = match range.end_bound() {
+                    Bound::Included(key) => self.position(key).map_or_else(|i| i, |i| i + 1),
+                    Bound::Excluded(key) => self.position(key).unwrap_or_else(|i| i),
+                    Bound::Unbounded => self.size,
+                };
+                end - start
+            } else {
+                Self::count_between((leaf, i), back.unwrap_unchecked())
+            };
+            Iter {
+                front,
+                back,
+                remaining,
+                _marker: PhantomData,
+            }
+        }
+    }
+
+    pub fn get_entry<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        if self.depth == 0 {
+            return None;
+        }
+        unsafe {
+            let leaf = &*self.descend(key, |_, _, _| ()).as_ptr();
+            let i = self.search.search(leaf.keys(), key).ok()?;
+            Some((leaf.keys().get_unchecked(i), leaf.values().get_unchecked(i)))
+        }
+    }
+
+    #[inline]
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        Some(self.get_entry(key)?.1)
+    }
+
+    /// Gets the aggregate of all the entries in the tree.
+    #[inline]
+    pub fn fold(&self) -> A::Value {
+        match self.depth {
+            0 => A::identity(),
+            depth => unsafe { Self::aggregate(self.root.assume_init(), depth == 1) },
+        }
+    }
+
+    /// Gets the aggregate of the entries with keys in `range`. Takes logarithmic time, since
+    /// subtrees that fall entirely inside the range use their cached aggregates.
+    pub fn fold_range<Q, R>(&self, range: R) -> A::Value
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+        R: RangeBounds<Q>,
+    {
+        match self.depth {
+            0 => A::identity(),
+            depth => unsafe {
+                self.fold_child(
+                    self.root.assume_init(),
+                    depth as usize - 1,
+                    range.start_bound(),
+                    range.end_bound(),
+                )
+            },
+        }
+    }
+
+    /// Folds the entries of `child` between `start` and `end`. `height` is the number of levels
+    /// below `child`.
+    unsafe fn fold_child<Q>(
+        &self,
+        child: TreeChild<K, V, COUNTED, A>,
+        height: usize,
+        start: Bound<&Q>,
+        end: Bound<&Q>,
+    ) -> A::Value
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        if let (Bound::Unbounded, Bound::Unbounded) = (start, end) {
+            return Self::aggregate(child, height == 0);
+        }
+        if height == 0 {
+            let leaf = &*child.leaf.as_ptr();
+            let lo = match start {
+                Bound::Included(key) => self.search.search(leaf.keys(), key).unwrap_or_else(|i| i),
+                Bound::Excluded(key) => {
+                    let found = self.search.search(leaf.keys(), key);
+                    found.map_or_else(|i| i, |i| i + 1)
+                }
+                Bound::Unbounded => 0,
+            };
+            let hi = match end {
+                Bound::Included(key) => {
+                    let found = self.search.search(leaf.keys(), key);
+                    found.map_or_else(|i| i, |i| i + 1)
+                }
+                Bound::Excluded(key) => self.search.search(leaf.keys(), key).unwrap_or_else(|i| i),
+                Bound::Unbounded => leaf.len(),
+            };
+            let hi = hi.max(lo);
+            return Self::fold_entries(&leaf.keys()[lo..hi], &leaf.values()[lo..hi]);
+        }
+
+        // The children whose ranges hold the bounds. Keys equal to a separator are in the child
+        // after it.
+        let node = &*child.node.as_ptr();
+        let child_of = |key: &Q| match self.search.search(node.keys(), key) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+        let lo = match start {
+            Bound::Included(key) | Bound::Excluded(key) => child_of(key),
+            Bound::Unbounded => 0,
+        };
+        let hi = match end {
+            Bound::Included(key) | Bound::Excluded(key) => child_of(key),
+            Bound::Unbounded => node.len(),
+        };
+        if hi <= lo {
+            return self.fold_child(node.child(lo), height - 1, start, end);
+        }
+        // Only the children at the edges of the range can stick out of it.
+        let mut agg = self.fold_child(node.child(lo), height - 1, start, Bound::Unbounded);
+        for i in lo + 1..hi {
+            agg = A::combine(&agg, &Self::aggregate(node.child(i), height == 1));
+        }
+        let last = self.fold_child(node.child(hi), height - 1, Bound::Unbounded, end);
+        A::combine(&agg, &last)
+    }
+
+    /// Goes down from the root to the leaf whose range holds `key`, and calls `visit` with the
+    /// level of every node on the way, the node and the index of the child taken. Keys equal to
+    /// a separator are in the subtree after it. The tree must not be empty.
+    #[inline]
+    unsafe fn descend<Q>(
+        &self,
+        key: &Q,
+        mut visit: impl FnMut(usize, NonNull<TreeNode<K, V, COUNTED, A>>, usize),
+    ) -> NonNull<Leaf<K, V>>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        let mut child = self.root.assume_init();
+        for level in 0..self.depth as usize - 1 {
+            let node = &*child.node.as_ptr();
+            let i = match self.search.search(node.keys(), key) {
+                Ok(i) => i + 1,
+                Err(i) => i,
+            };
+            visit(level, child.node, i);
+            child = node.child(i);
+        }
+        child.leaf
+    }
+
+    /// Searches for `key` like [`rank`](Self::rank), adding up the sizes of the subtrees before
+    /// it. Only counted trees keep the sizes, and the tree must not be empty.
+    unsafe fn position<Q>(&self, key: &Q) -> Result<usize, usize>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        let mut rank = 0;
+        let leaf = self.descend(key, |level, node, i| {
+            let leaf_children = level + 2 == self.depth as usize;
+            rank += (*node.as_ptr()).children()[..i]
+                .iter()
+                .map(|&child| Self::count(child, leaf_children))
+                .sum::<usize>();
+        });
+        self.search
+            .search((*leaf.as_ptr()).keys(), key)
+            .map(|i| rank + i)
+            .map_err(|i| rank + i)
+    }
+
+    /// The number of entries from `front` to `back`, both included, which are in the same leaf or
+    /// in `back`'s leaf further down the chain.
+    unsafe fn count_between((mut leaf, i): Position<K, V>, (last, j): Position<K, V>) -> usize {
+        let mut count = j + 1;
+        while leaf != last {
+            count += (*leaf.as_ptr()).len();
+            leaf = (*leaf.as_ptr()).next.unwrap_unchecked();
+        }
+        count - i
+    }
+
+    /// The leaf of the smallest keys. The tree must not be empty.
+    unsafe fn first_leaf(&self) -> NonNull<Leaf<K, V>> {
+        let mut child = self.root.assume_init();
+        for _ in 1..self.depth {
+            child = (*child.node.as_ptr()).child(0);
+        }
+        child.leaf
+    }
+
+    /// The leaf of the largest keys. The tree must not be empty.
+    unsafe fn last_leaf(&self) -> NonNull<Leaf<K, V>> {
+        let mut child = self.root.assume_init();
+        for _ in 1..self.depth {
+            let node = &*child.node.as_ptr();
+            child = node.child(node.len());
+        }
+        child.leaf
+    }
+
+    /// The position of the first entry with a key greater than or equal to `key`, or only
+    /// greater if `excluded`.
+    unsafe fn lower_bound<Q>(&self, key: &Q, excluded: bool) -> Option<Position<K, V>>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        let leaf = self.descend(key, |_, _, _| ());
+        let i = match self.search.search((*leaf.as_ptr()).keys(), key) {
+            Ok(i) if excluded => i + 1,
+            Ok(i) | Err(i) => i,
+        };
+        if i < (*leaf.as_ptr()).len() {
+            Some((leaf, i))
+        } else {
+            (*leaf.as_ptr()).next.map(|next| (next, 0))
+        }
+    }
+
+    /// The position of the last entry with a key less than or equal to `key`, or only less if
+    /// not `included`.
+    unsafe fn upper_bound<Q>(&self, key: &Q, included: bool) -> Option<Position<K, V>>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        let leaf = self.descend(key, |_, _, _| ());
+        let i = match self.search.search((*leaf.as_ptr()).keys(), key) {
+            Ok(i) if included => i + 1,
+            Ok(i) | Err(i) => i,
+        };
+        if 0 < i {
+            Some((leaf, i - 1))
+        } else {
+            (*leaf.as_ptr())
+                .prev
+                .map(|prev| (prev, (*prev.as_ptr()).len() - 1))
+        }
+    }
+
+    fn new_leaf(&mut self) -> NonNull<Leaf<K, V>> {
+        let leaf = self.slabs.malloc_leaf();
+        unsafe {
+            let ptr = leaf.as_ptr();
+            ptr::addr_of_mut!((*ptr).len).write(0);
+            ptr::addr_of_mut!((*ptr).prev).write(None);
+            ptr::addr_of_mut!((*ptr).next).write(None);
+        }
+        leaf
+    }
+
+    fn new_node(&mut self) -> NonNull<TreeNode<K, V, COUNTED, A>> {
+        let node = self.slabs.malloc_node();
+        unsafe {
+            let ptr = node.as_ptr();
+            ptr::addr_of_mut!((*ptr).len).write(0);
+            ptr::addr_of_mut!((*ptr).size).write(Default::default());
+            ptr::addr_of_mut!((*ptr).agg).write(A::identity());
+        }
+        node
+    }
+
+    /// Drops the entries or separators in the subtree of `child`, which is `height` levels above
+    /// the leaves, and frees its nodes.
+    unsafe fn drop_child(&mut self, child: TreeChild<K, V, COUNTED, A>, height: usize) {
+        if height == 0 {
+            let leaf = &mut *child.leaf.as_ptr();
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(
+                leaf.keys.as_mut_ptr().cast::<K>(),
+                leaf.len(),
+            ));
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(
+                leaf.values.as_mut_ptr().cast::<V>(),
+                leaf.len(),
+            ));
+            self.slabs.free_leaf(child.leaf);
+        } else {
+            let node = &mut *child.node.as_ptr();
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(
+                node.keys.as_mut_ptr().cast::<K>(),
+                node.len(),
+            ));
+            for i in 0..=node.len() {
+                self.drop_child(node.child(i), height - 1);
+            }
+            self.slabs.free_node(child.node);
+        }
+    }
+
+    /// The number of entries under `child`, which is a leaf if `is_leaf` is set.
+    #[inline]
+    unsafe fn count(child: TreeChild<K, V, COUNTED, A>, is_leaf: bool) -> usize {
+        if is_leaf {
+            (*child.leaf.as_ptr()).len()
+        } else {
+            (*child.node.as_ptr()).size.get()
+        }
+    }
+
+    /// The aggregate of the entries under `child`, which is a leaf if `is_leaf` is set.
+    #[inline]
+    unsafe fn aggregate(child: TreeChild<K, V, COUNTED, A>, is_leaf: bool) -> A::Value {
+        if is_leaf {
+            let leaf = &*child.leaf.as_ptr();
+            Self::fold_entries(leaf.keys(), leaf.values())
+        } else {
+            (*child.node.as_ptr()).agg
+        }
+    }
+
+    #[inline]
+    fn fold_entries(keys: &[K], values: &[V]) -> A::Value {
+        keys.iter()
+            .zip(values)
+            .fold(A::identity(), |agg, (key, value)| {
+                A::combine(&agg, &A::lift(key, value))
+            })
+    }
+
+    /// Recomputes the cached size and aggregate of a node whose children changed. The separators
+    /// aren't entries, so only the children count.
+    #[inline]
+    unsafe fn refresh(node: NonNull<TreeNode<K, V, COUNTED, A>>, leaf_children: bool) {
+        let node = &mut *node.as_ptr();
+        if COUNTED {
+            let size = node
+                .children()
+                .iter()
+                .map(|&child| Self::count(child, leaf_children))
+                .sum();
+            node.size.set(size);
+        }
+        if A::ENABLED {
+            node.agg = node.children().iter().fold(A::identity(), |agg, &child| {
+                A::combine(&agg, &Self::aggregate(child, leaf_children))
+            });
+        }
+    }
+
+    /// Refreshes the nodes of `path`, which starts at the root, from the bottom up.
+    #[inline]
+    unsafe fn refresh_path(&self, path: &[Step<K, V, SizeOf<COUNTED>, A::Value>]) {
+        if COUNTED || A::ENABLED {
+            for (level, &(node, _)) in path.iter().enumerate().rev() {
+                Self::refresh(node, level + 2 == self.depth as usize);
+            }
+        }
+    }
+}
+
+impl<K: Clone, V, const COUNTED: bool, A: Aggregate<K, V>, S> BPlusTree<K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    /// Panics, before it changes anything, if the key is new and the tree's budget can't afford
+    /// the worst case of the insertion, like [`try_insert`](Self::try_insert) refuses it.
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)>
+    where
+        S: SearchStrategy<K, K>,
+    {
+        assert!(self.prepay_insert(&key), "memory budget exceeded");
+        let replaced = self.insert_inner(key, value);
+        self.slabs.account.settle();
+        self.check_invariants();
+        replaced
+    }
+
+    /// Inserts like [`insert`](Self::insert), or hands the entry back if the key is new and the
+    /// tree's budget can't afford the worst case of the insertion, a leaf and a node for every
+    /// level. What the insertion doesn't use of it is given back afterwards.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<(K, V)>, BudgetExceeded<K, V>>
+    where
+        S: SearchStrategy<K, K>,
+    {
+        if !self.prepay_insert(&key) {
+            return Err(BudgetExceeded { key, value });
+        }
+        let replaced = self.insert_inner(key, value);
+        self.slabs.account.settle();
+        self.check_invariants();
+        Ok(replaced)
+    }
+
+    /// Reserves the worst case of inserting `key` unless the key is already there, so that the
+    /// insertion can't run out of budget halfway through a split. Returns false if the budget
+    /// can't afford it.
+    fn prepay_insert(&mut self, key: &K) -> bool
+    where
+        S: SearchStrategy<K, K>,
+    {
+        if !self.slabs.account.has_budget() || self.get_entry(key).is_some() {
+            return true;
+        }
+        let worst =
+            size_of::<Leaf<K, V>>() + self.depth as usize * size_of::<TreeNode<K, V, COUNTED, A>>();
+        self.slabs.account.prepay(worst)
+    }
+
+    fn insert_inner(&mut self, key: K, value: V) -> Option<(K, V)>
+    where
+        S: SearchStrategy<K, K>,
+    {
+        if self.depth == 0 {
+            let leaf = self.new_leaf();
+            unsafe { (*leaf.as_ptr()).insert(0, key, value) };
+            self.root.write(Child { leaf });
+            self.depth = 1;
+            self.size = 1;
+            return None;
+        }
+
+        let mut path: Path<K, V, SizeOf<COUNTED>, A::Value> = [(NonNull::dangling(), 0); MAX_DEPTH];
+        unsafe {
+            let leaf = self.descend(&key, |level, node, i| path[level] = (node, i));
+            let i = match self.search.search((*leaf.as_ptr()).keys(), &key) {
+                Ok(i) => {
+                    let leaf = &mut *leaf.as_ptr();
+                    let old = (
+                        mem::replace(leaf.key_mut(i), key),
+                        mem::replace(leaf.value_mut(i), value),
+                    );
+                    if A::ENABLED {
+                        self.refresh_path(&path[..self.depth as usize - 1]);
+                    }
+                    return Some(old);
+                }
+                Err(i) => i,
+            };
+            self.size += 1;
+            if (*leaf.as_ptr()).len() < LEAF_CAPACITY {
+                (*leaf.as_ptr()).insert(i, key, value);
+                self.refresh_path(&path[..self.depth as usize - 1]);
+                return None;
+            }
+
+            // Split the full leaf in two halves, and link the new right half in after it.
+            let right = self.new_leaf();
+            let (l, r) = (&mut *leaf.as_ptr(), &mut *right.as_ptr());
+            insert_split(
+                l.keys.as_mut_ptr(),
+                r.keys.as_mut_ptr(),
+                LEAF_CAPACITY,
+                i,
+                MaybeUninit::new(key),
+                LEAF_B,
+            );
+            insert_split(
+                l.values.as_mut_ptr(),
+                r.values.as_mut_ptr(),
+                LEAF_CAPACITY,
+                i,
+                MaybeUninit::new(value),
+                LEAF_B,
+            );
+            l.len = LEAF_B as u8;
+            r.len = (LEAF_CAPACITY + 1 - LEAF_B) as u8;
+            r.prev = Some(leaf);
+            r.next = l.next;
+            if let Some(next) = l.next {
+                (*next.as_ptr()).prev = Some(right);
+            }
+            l.next = Some(right);
+
+            let separator = r.keys().get_unchecked(0).clone();
+            let path = &path[..self.depth as usize - 1];
+            self.insert_separator(path, separator, Child { leaf: right });
+        }
+        None
+    }
+
+    /// Appends an entry whose key is greater than all the others, or hands it back. There is no
+    /// bulk build, so the entry is inserted as usual, and deserialization only uses this to find
+    /// out of order keys.
+    #[cfg_attr(not(feature = "serde"), allow(dead_code))]
+    pub(crate) fn push_sorted(&mut self, key: K, value: V) -> Result<(), (K, V)>
+    where
+        S: SearchStrategy<K, K>,
+    {
+        if self.depth != 0 {
+            let last = unsafe { &*self.last_leaf().as_ptr() };
+            let last = unsafe { last.keys().get_unchecked(last.len() - 1) };
+            if self.search.search(slice::from_ref(last), &key) != Err(1) {
+                return Err((key, value));
+            }
+        }
+        self.insert(key, value);
+        Ok(())
+    }
+
+    /// Whether the cache is sure to have room for one more insertion, which allocates at most a
+    /// leaf and a node per level.
+    #[cfg_attr(not(feature = "serde"), allow(dead_code))]
+    #[inline]
+    pub(crate) fn has_room(&self) -> bool {
+        self.slabs.cache.has_room([1, self.depth as usize])
+    }
+
+    /// Inserts `key` and the new `child` after it into the last node of `path`, splitting the
+    /// nodes that are full on the way up, and the root if it comes to that. `path` starts at the
+    /// root.
+    unsafe fn insert_separator(
+        &mut self,
+        path: &[Step<K, V, SizeOf<COUNTED>, A::Value>],
+        mut key: K,
+        mut child: TreeChild<K, V, COUNTED, A>,
+    ) {
+        for (level, &(node, i)) in path.iter().enumerate().rev() {
+            let n = &mut *node.as_ptr();
+            if n.len() < NODE_CAPACITY {
+                n.insert(i, key, child);
+                self.refresh_path(&path[..=level]);
+                return;
+            }
+
+            // The left half keeps one more key, which moves up as the separator of the halves.
+            let right = self.new_node();
+            let r = &mut *right.as_ptr();
+            insert_split(
+                n.keys.as_mut_ptr(),
+                r.keys.as_mut_ptr(),
+                NODE_CAPACITY,
+                i,
+                MaybeUninit::new(key),
+                NODE_B + 1,
+            );
+            insert_split(
+                n.children.as_mut_ptr(),
+                r.children.as_mut_ptr(),
+                NODE_CAPACITY + 1,
+                i + 1,
+                MaybeUninit::new(child),
+                NODE_B + 1,
+            );
+            n.len = NODE_B as u8;
+            r.len = (NODE_B - 1) as u8;
+            key = n.keys.get_unchecked(NODE_B).assume_init_read();
+            child = Child { node: right };
+            let leaf_children = level + 2 == self.depth as usize;
+            Self::refresh(node, leaf_children);
+            Self::refresh(right, leaf_children);
+        }
+
+        let root = self.new_node();
+        let r = &mut *root.as_ptr();
+        r.keys[0].write(key);
+        r.children[0].write(self.root.assume_init());
+        r.children[1].write(child);
+        r.len = 1;
+        self.root.write(Child { node: root });
+        self.depth += 1;
+        Self::refresh(root, self.depth == 2);
+    }
+
+    #[cfg(not(feature = "check-invariants"))]
+    #[inline]
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        self.remove_inner(key)
+    }
+
+    /// With the `check-invariants` feature, `S` also has to compare keys with each other, to
+    /// check their order after the removal.
+    #[cfg(feature = "check-invariants")]
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q> + SearchStrategy<K, K>,
+    {
+        let removed = self.remove_inner(key);
+        self.check_invariants();
+        removed
+    }
+
+    fn remove_inner<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        if self.depth == 0 {
+            return None;
+        }
+
+        let mut path: Path<K, V, SizeOf<COUNTED>, A::Value> = [(NonNull::dangling(), 0); MAX_DEPTH];
+        unsafe {
+            let leaf = self.descend(key, |level, node, i| path[level] = (node, i));
+            let i = self.search.search((*leaf.as_ptr()).keys(), key).ok()?;
+            let removed = (*leaf.as_ptr()).remove(i);
+            self.size -= 1;
+
+            let len = (*leaf.as_ptr()).len();
+            let path = &path[..self.depth as usize - 1];
+            if self.depth == 1 {
+                if len == 0 {
+                    self.slabs.free_leaf(leaf);
+                    self.depth = 0;
+                }
+            } else if len < LEAF_MIN {
+                self.rebalance_leaf(path, leaf);
+            } else {
+                self.refresh_path(path);
+            }
+            Some(removed)
+        }
+    }
+
+    /// Refills `leaf`, which is one entry short, from a sibling, or merges it with one. The last
+    /// node of `path` is its parent. A separator that moves is replaced by a clone of the first
+    /// key of the leaf after it.
+    unsafe fn rebalance_leaf(
+        &mut self,
+        path: &[Step<K, V, SizeOf<COUNTED>, A::Value>],
+        leaf: NonNull<Leaf<K, V>>,
+    ) {
+        let (parent, i) = path[path.len() - 1];
+        let p = &mut *parent.as_ptr();
+        if 0 < i {
+            let left = &mut *p.child(i - 1).leaf.as_ptr();
+            if LEAF_MIN < left.len() {
+                let (key, value) = left.remove(left.len() - 1);
+                let l = &mut *leaf.as_ptr();
+                l.insert(0, key, value);
+                *p.key_mut(i - 1) = l.keys().get_unchecked(0).clone();
+                self.refresh_path(path);
+                return;
+            }
+        }
+        if i < p.len() {
+            let right = &mut *p.child(i + 1).leaf.as_ptr();
+            if LEAF_MIN < right.len() {
+                let (key, value) = right.remove(0);
+                let l = &mut *leaf.as_ptr();
+                l.insert(l.len(), key, value);
+                *p.key_mut(i) = right.keys().get_unchecked(0).clone();
+                self.refresh_path(path);
+                return;
+            }
+        }
+
+        let (left, right, separator) = if 0 < i {
+            (p.child(i - 1).leaf, leaf, i - 1)
+        } else {
+            (leaf, p.child(1).leaf, 0)
+        };
+        let (l, r) = (&mut *left.as_ptr(), &mut *right.as_ptr());
+        ptr::copy_nonoverlapping(r.keys.as_ptr(), l.keys.as_mut_ptr().add(l.len()), r.len());
+        ptr::copy_nonoverlapping(
+            r.values.as_ptr(),
+            l.values.as_mut_ptr().add(l.len()),
+            r.len(),
+        );
+        l.len += r.len;
+        l.next = r.next;
+        if let Some(next) = r.next {
+            (*next.as_ptr()).prev = Some(left);
+        }
+        self.slabs.free_leaf(right);
+        drop(p.remove(separator));
+        self.rebalance_node(&path[..path.len() - 1], parent);
+    }
+
+    /// Refills `node`, which lost a key, by rotating one through the parent from a sibling, or
+    /// merges it with one, on the way up. `path` leads to `node` from the root, and the root is
+    /// collapsed when its last key goes. The nodes that change, and those above them, are
+    /// refreshed.
+    unsafe fn rebalance_node(
+        &mut self,
+        path: &[Step<K, V, SizeOf<COUNTED>, A::Value>],
+        mut node: NonNull<TreeNode<K, V, COUNTED, A>>,
+    ) {
+        for level in (0..=path.len()).rev() {
+            let n = &mut *node.as_ptr();
+            let leaf_children = level + 2 == self.depth as usize;
+            if level == 0 {
+                if n.len() == 0 {
+                    self.root.write(n.child(0));
+                    self.slabs.free_node(node);
+                    self.depth -= 1;
+                } else {
+                    Self::refresh(node, leaf_children);
+                }
+                return;
+            }
+            if NODE_MIN <= n.len() {
+                Self::refresh(node, leaf_children);
+                self.refresh_path(&path[..level]);
+                return;
+            }
+
+            let (parent, i) = path[level - 1];
+            let p = &mut *parent.as_ptr();
+            if 0 < i {
+                let left = &mut *p.child(i - 1).node.as_ptr();
+                if NODE_MIN < left.len() {
+                    let (key, child) = left.remove(left.len() - 1);
+                    let separator = mem::replace(p.key_mut(i - 1), key);
+                    n.push_front(separator, child);
+                    Self::refresh(p.child(i - 1).node, leaf_children);
+                    Self::refresh(node, leaf_children);
+                    self.refresh_path(&path[..level]);
+                    return;
+                }
+            }
+            if i < p.len() {
+                let right = &mut *p.child(i + 1).node.as_ptr();
+                if NODE_MIN < right.len() {
+                    let (key, child) = right.pop_front();
+                    let separator = mem::replace(p.key_mut(i), key);
+                    n.insert(n.len(), separator, child);
+                    Self::refresh(p.child(i + 1).node, leaf_children);
+                    Self::refresh(node, leaf_children);
+                    self.refresh_path(&path[..level]);
+                    return;
+                }
+            }
+
+            let (left, right, separator) = if 0 < i {
+                (p.child(i - 1).node, node, i - 1)
+            } else {
+                (node, p.child(1).node, 0)
+            };
+            let (separator, _) = p.remove(separator);
+            let (l, r) = (&mut *left.as_ptr(), &mut *right.as_ptr());
+            let len = l.len();
+            l.keys.get_unchecked_mut(len).write(separator);
+            ptr::copy_nonoverlapping(
+                r.keys.as_ptr(),
+                l.keys.as_mut_ptr().add(l.len() + 1),
+                r.len(),
+            );
+            ptr::copy_nonoverlapping(
+                r.children.as_ptr(),
+                l.children.as_mut_ptr().add(l.len() + 1),
+                r.len() + 1,
+            );
+            l.len += 1 + r.len;
+            self.slabs.free_node(right);
+            Self::refresh(left, leaf_children);
+            node = parent;
+        }
+    }
+}
+
+impl<K, V, const COUNTED: bool, A: Aggregate<K, V>, S: SearchStrategy<K, K>>
+    BPlusTree<K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    /// Checks the structural invariants of the tree: the keys are strictly ordered, every
+    /// separator is greater than the keys before it and not greater than the keys after it,
+    /// every node and leaf but the root is at least half full, and the leaves are linked to
+    /// their neighbours in order. Returns the first violation found, or the shape of the tree.
+    ///
+    /// The order is checked with the tree's own search strategy. Takes linear time.
+    pub fn validate(&self) -> Result<TreeStats, InvariantViolation> {
+        if MAX_DEPTH < self.depth as usize {
+            return Err(InvariantViolation::TooDeep {
+                depth: self.depth as usize,
+            });
+        }
+        let mut stats = TreeStats {
+            depth: self.depth as usize,
+            ..TreeStats::default()
+        };
+        if self.depth != 0 {
+            let (mut last, mut prev) = (None, None);
+            let root = unsafe { self.root.assume_init() };
+            stats.len =
+                self.validate_child(root, 0, None, None, &mut last, &mut prev, &mut stats)?;
+            if prev.is_some_and(|leaf| unsafe { (*leaf.as_ptr()).next.is_some() }) {
+                return Err(InvariantViolation::BrokenLink {
+                    index: stats.leaves - 1,
+                });
+            }
+        }
+        if stats.len != self.size {
+            return Err(InvariantViolation::SizeMismatch {
+                expected: self.size,
+                actual: stats.len,
+            });
+        }
+        Ok(stats)
+    }
+
+    /// Validates the subtree of `child` at `level`, whose keys have to be within `lower` and
+    /// `upper`, and returns its number of entries. `last` is the last key and `prev` the last
+    /// leaf visited in order.
+    #[allow(clippy::too_many_arguments)]
+    fn validate_child<'a>(
+        &'a self,
+        child: TreeChild<K, V, COUNTED, A>,
+        level: usize,
+        lower: Option<&'a K>,
+        upper: Option<&'a K>,
+        last: &mut Option<&'a K>,
+        prev: &mut Option<NonNull<Leaf<K, V>>>,
+        stats: &mut TreeStats,
+    ) -> Result<usize, InvariantViolation> {
+        let is_leaf = level + 1 == self.depth as usize;
+        let (len, min, max) = unsafe {
+            if is_leaf {
+                ((*child.leaf.as_ptr()).len(), LEAF_MIN, LEAF_CAPACITY)
+            } else {
+                ((*child.node.as_ptr()).len(), NODE_MIN, NODE_CAPACITY)
+            }
+        };
+        let min = if level == 0 { 1 } else { min };
+        if !(min..=max).contains(&len) {
+            return Err(InvariantViolation::BadLength { level, len });
+        }
+
+        if is_leaf {
+            let leaf = unsafe { &*child.leaf.as_ptr() };
+            let linked = leaf.prev == *prev
+                && prev.is_none_or(|prev| unsafe { (*prev.as_ptr()).next == Some(child.leaf) });
+            if !linked {
+                return Err(InvariantViolation::BrokenLink {
+                    index: stats.leaves,
+                });
+            }
+            Self::record_node(stats, level, len, true);
+            *prev = Some(unsafe { child.leaf });
+            for (index, key) in leaf.keys().iter().enumerate() {
+                self.validate_order(last, key, level, index)?;
+                self.validate_bounds(key, lower, upper, level, index)?;
+            }
+            Ok(len)
+        } else {
+            let node = unsafe { &*child.node.as_ptr() };
+            Self::record_node(stats, level, len, false);
+            let mut previous = None;
+            for (index, key) in node.keys().iter().enumerate() {
+                self.validate_order(&mut previous, key, level, index)?;
+                self.validate_bounds(key, lower, upper, level, index)?;
+            }
+            let mut count = 0;
+            for (index, &child) in node.children().iter().enumerate() {
+                let lower = index.checked_sub(1).map_or(lower, |i| node.keys().get(i));
+                let upper = node.keys().get(index).or(upper);
+                count += self.validate_child(child, level + 1, lower, upper, last, prev, stats)?;
+            }
+            Ok(count)
+        }
+    }
+
+    #[inline]
+    fn validate_order<'a>(
+        &self,
+        last: &mut Option<&'a K>,
+        key: &'a K,
+        level: usize,
+        index: usize,
+    ) -> Result<(), InvariantViolation> {
+        if let Some(last) = last {
+            if self.search.search(slice::from_ref(*last), key) != Err(1) {
+                return Err(InvariantViolation::OutOfOrder { level, index });
+            }
+        }
+        *last = Some(key);
+        Ok(())
+    }
+
+    /// Checks that `lower <= key < upper`, the range the separators above give the key.
+    #[inline]
+    fn validate_bounds(
+        &self,
+        key: &K,
+        lower: Option<&K>,
+        upper: Option<&K>,
+        level: usize,
+        index: usize,
+    ) -> Result<(), InvariantViolation> {
+        let above_lower =
+            lower.is_none_or(|lower| self.search.search(slice::from_ref(lower), key) != Err(0));
+        let below_upper =
+            upper.is_none_or(|upper| self.search.search(slice::from_ref(upper), key) == Err(0));
+        if above_lower && below_upper {
+            Ok(())
+        } else {
+            Err(InvariantViolation::OutOfOrder { level, index })
+        }
+    }
+
+    /// Panics if the tree is invalid, when the `check-invariants` feature is enabled. Called after
+    /// every mutation, so a corruption is caught by the operation that caused it.
+    #[inline]
+    fn check_invariants(&self) {
+        #[cfg(feature = "check-invariants")]
+        if let Err(violation) = self.validate() {
+            panic!("{}: {violation}", type_name::<Self>());
+        }
+    }
+
+    /// Breaks the tree with `corruption`, for the tests of [`validate`](Self::validate), and
+    /// returns the corruption that undoes it.
+    ///
+    /// # Safety
+    /// Until it is undone, the tree may only be validated and corrupted. The leaf corruptions
+    /// need a leaf with two keys.
+    #[cfg(feature = "check-invariants")]
+    #[doc(hidden)]
+    pub unsafe fn corrupt(&mut self, corruption: Corruption) -> Corruption {
+        let first_leaf = |tree: &Self| {
+            let mut child = tree.root.assume_init();
+            for _ in 1..tree.depth {
+                child = (*child.node.as_ptr()).children()[0];
+            }
+            child.leaf.as_ptr()
+        };
+        match corruption {
+            Corruption::Size(size) => Corruption::Size(mem::replace(&mut self.size, size)),
+            Corruption::Depth(depth) => {
+                Corruption::Depth(mem::replace(&mut self.depth, depth as u8) as usize)
+            }
+            Corruption::LeafLen(len) => {
+                let leaf = &mut *first_leaf(self);
+                Corruption::LeafLen(mem::replace(&mut leaf.len, len as u8) as usize)
+            }
+            Corruption::SwapKeys => {
+                (*first_leaf(self)).keys.swap(0, 1);
+                Corruption::SwapKeys
+            }
+            Corruption::RootCount(size) => {
+                let root = &mut *self.root.assume_init().node.as_ptr();
+                let cached = root.size.get();
+                root.size.set(size);
+                Corruption::RootCount(cached)
+            }
+            Corruption::SwapLinks => {
+                let leaf = &mut *first_leaf(self);
+                mem::swap(&mut leaf.prev, &mut leaf.next);
+                Corruption::SwapLinks
+            }
+        }
+    }
+}
+
+impl<K, V, A: Aggregate<K, V>, S> BPlusTree<K, V, true, A, S> {
+    /// Returns the entry at position `index` in key order.
+    pub fn nth(&self, mut index: usize) -> Option<(&K, &V)> {
+        if self.size <= index {
+            return None;
+        }
+        unsafe {
+            let mut child = self.root.assume_init();
+            for level in 0..self.depth as usize - 1 {
+                let node = &*child.node.as_ptr();
+                let leaf_children = level + 2 == self.depth as usize;
+                let mut i = 0;
+                loop {
+                    let count = Self::count(node.child(i), leaf_children);
+                    if index < count {
+                        break;
+                    }
+                    index -= count;
+                    i += 1;
+                }
+                child = node.child(i);
+            }
+            let leaf = &*child.leaf.as_ptr();
+            Some((
+                leaf.keys().get_unchecked(index),
+                leaf.values().get_unchecked(index),
+            ))
+        }
+    }
+
+    /// Searches for `key`. Returns its position in key order if it's present, or the position it
+    /// would be inserted at otherwise.
+    pub fn rank<Q>(&self, key: &Q) -> Result<usize, usize>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        if self.depth == 0 {
+            return Err(0);
+        }
+        unsafe { self.position(key) }
+    }
+}
+
+/// Values can only be borrowed mutably when nothing is aggregated over them, since the cached
+/// aggregates would go stale.
+impl<K, V, const COUNTED: bool, S> BPlusTree<K, V, COUNTED, (), S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    pub fn get_entry_mut<Q>(&mut self, key: &Q) -> Option<(&K, &mut V)>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        if self.depth == 0 {
+            return None;
+        }
+        unsafe {
+            let leaf = &mut *self.descend(key, |_, _, _| ()).as_ptr();
+            let i = self.search.search(leaf.keys(), key).ok()?;
+            Some((
+                leaf.keys.get_unchecked(i).assume_init_ref(),
+                leaf.values.get_unchecked_mut(i).assume_init_mut(),
+            ))
+        }
+    }
+
+    #[inline]
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        Some(self.get_entry_mut(key)?.1)
+    }
+}
+
+/// A [`BPlusTree`] ordered by the comparator `C` instead of `Ord`.
+pub type BPlusTreeBy<K, V, C> = BPlusTree<K, V, false, (), By<C>>;
+
+impl<K, V, const COUNTED: bool, A: Aggregate<K, V>, C> BPlusTree<K, V, COUNTED, A, By<C>>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    /// Creates a tree ordered by `compare`.
+    #[inline]
+    pub fn with_comparator(chunk: &'static mut [u8], compare: C) -> Self {
+        Self::with_search(chunk, By(compare))
+    }
+
+    /// Gets the comparator that orders the tree.
+    #[inline]
+    pub fn comparator(&self) -> &C {
+        &self.search.0
+    }
+}
+
+/// An entry of a leaf.
+type Position<K, V> = (NonNull<Leaf<K, V>>, usize);
+
+/// An iterator over the entries of a [`BPlusTree`], or of a range of them, sorted by key. It
+/// walks the chain of leaves from both ends, until they meet.
+pub struct Iter<'a, K, V> {
+    /// The next entry from the front, and the next one from the back. Both are `None` once the
+    /// iterator is exhausted.
+    front: Option<Position<K, V>>,
+    back: Option<Position<K, V>>,
+    /// The number of entries left.
+    remaining: usize,
+    _marker: PhantomData<&'a Leaf<K, V>>,
+}
+
+unsafe impl<'a, K: Sync, V: Sync> Send for Iter<'a, K, V> {}
+unsafe impl<'a, K: Sync, V: Sync> Sync for Iter<'a, K, V> {}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    #[inline]
+    fn empty() -> Self {
+        Self {
+            front: None,
+            back: None,
+            remaining: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the entry at `position`, and ends the iteration if it was the last one.
+    #[inline]
+    unsafe fn take(&mut self, (leaf, i): Position<K, V>) -> (&'a K, &'a V) {
+        if self.front == self.back {
+            self.front = None;
+            self.back = None;
+        }
+        self.remaining -= 1;
+        let leaf = &*leaf.as_ptr();
+        (leaf.keys().get_unchecked(i), leaf.values().get_unchecked(i))
+    }
+}
+
+impl<'a, K, V> Clone for Iter<'a, K, V> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            front: self.front,
+            back: self.back,
+            remaining: self.remaining,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (leaf, i) = self.front?;
+        unsafe {
+            let item = self.take((leaf, i));
+            if self.front.is_some() {
+                self.front = if i + 1 < (*leaf.as_ptr()).len() {
+                    Some((leaf, i + 1))
+                } else {
+                    (*leaf.as_ptr()).next.map(|next| (next, 0))
+                };
+            }
+            Some(item)
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Iter<'a, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (leaf, i) = self.back?;
+        unsafe {
+            let item = self.take((leaf, i));
+            if self.back.is_some() {
+                self.back = if 0 < i {
+                    Some((leaf, i - 1))
+                } else {
+                    (*leaf.as_ptr())
+                        .prev
+                        .map(|prev| (prev, (*prev.as_ptr()).len() - 1))
+                };
+            }
+            Some(item)
+        }
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {}
+
+impl<'a, K, V> FusedIterator for Iter<'a, K, V> {}
+
+impl<'a, K, V, const COUNTED: bool, A: Aggregate<K, V>, S> IntoIterator
+    for &'a BPlusTree<K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K: fmt::Debug, V, const COUNTED: bool, A: Aggregate<K, V>, S> BPlusTree<K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    /// Writes the structure of the tree as a Graphviz digraph, like
+    /// [`BTree::dump_dot`](crate::btree::BTree::dump_dot). The keys of the internal nodes are
+    /// their separators, and the fill of a record is out of the capacity of its kind of node.
+    pub fn dump_dot(&self, w: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(w, "digraph BTree {{")?;
+        writeln!(w, "    node [shape=record];")?;
+        if self.depth != 0 {
+            let mut next_id = 0;
+            unsafe {
+                Self::dump_dot_child(
+                    self.root.assume_init(),
+                    self.depth as usize - 1,
+                    &mut next_id,
+                    w,
+                )?;
+            }
+        }
+        writeln!(w, "}}")
+    }
+
+    /// Writes the node of `child`, `height` levels above the leaves, and its subtree. Returns the
+    /// id of the node.
+    unsafe fn dump_dot_child(
+        child: TreeChild<K, V, COUNTED, A>,
+        height: usize,
+        next_id: &mut usize,
+        w: &mut impl fmt::Write,
+    ) -> Result<usize, fmt::Error> {
+        let id = *next_id;
+        *next_id += 1;
+        let (keys, capacity) = if height == 0 {
+            ((*child.leaf.as_ptr()).keys(), LEAF_CAPACITY)
+        } else {
+            ((*child.node.as_ptr()).keys(), NODE_CAPACITY)
+        };
+
+        write!(w, "    n{id} [label=\"{{{}/{capacity}|{{", keys.len())?;
+        for (i, key) in keys.iter().enumerate() {
+            if height != 0 {
+                write!(w, "<c{i}>|")?;
+            }
+            write!(DotEscape(w), "{key:?}")?;
+            if i + 1 < keys.len() {
+                write!(w, "|")?;
+            }
+        }
+        if height != 0 {
+            write!(w, "|<c{}>", keys.len())?;
+        }
+        writeln!(w, "}}}}\"];")?;
+
+        if height != 0 {
+            for (i, &child) in (*child.node.as_ptr()).children().iter().enumerate() {
+                let child_id = Self::dump_dot_child(child, height - 1, next_id, w)?;
+                writeln!(w, "    n{id}:c{i} -> n{child_id};")?;
+            }
+        }
+        Ok(id)
+    }
+
+    /// Writes the structure of the tree as JSON, in the format of
+    /// [`BTree::dump_json`](crate::btree::BTree::dump_json). The keys of the internal nodes are
+    /// their separators, so only the leaves list the entries.
+    pub fn dump_json(&self, w: &mut impl fmt::Write) -> fmt::Result {
+        write!(
+            w,
+            "{{\"len\": {}, \"depth\": {}, \"root\": ",
+            self.size, self.depth
+        )?;
+        if self.depth == 0 {
+            write!(w, "null")?;
+        } else {
+            unsafe { Self::dump_json_child(self.root.assume_init(), self.depth as usize - 1, w)? };
+        }
+        write!(w, "}}")
+    }
+
+    unsafe fn dump_json_child(
+        child: TreeChild<K, V, COUNTED, A>,
+        height: usize,
+        w: &mut impl fmt::Write,
+    ) -> fmt::Result {
+        let (keys, kind) = if height == 0 {
+            ((*child.leaf.as_ptr()).keys(), "leaf")
+        } else {
+            ((*child.node.as_ptr()).keys(), "node")
+        };
+
+        write!(w, "{{\"kind\": \"{kind}\", \"keys\": [")?;
+        for (i, key) in keys.iter().enumerate() {
+            if i != 0 {
+                write!(w, ", ")?;
+            }
+            write!(w, "\"")?;
+            write!(JsonEscape(w), "{key:?}")?;
+            write!(w, "\"")?;
+        }
+        write!(w, "]")?;
+
+        if height != 0 {
+            write!(w, ", \"children\": [")?;
+            for (i, &child) in (*child.node.as_ptr()).children().iter().enumerate() {
+                if i != 0 {
+                    write!(w, ", ")?;
+                }
+                Self::dump_json_child(child, height - 1, w)?;
+            }
+            write!(w, "]")?;
+        }
+        write!(w, "}}")
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, const COUNTED: bool, A: Aggregate<K, V>, S> fmt::Debug
+    for BPlusTree<K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct DebugChild<K, V, N, G> {
+            child: Child<K, V, N, G>,
+            height: u8,
+        }
+
+        impl<K: fmt::Debug, V: fmt::Debug, N, G> fmt::Debug for DebugChild<K, V, N, G> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                if self.height == 0 {
+                    let leaf = unsafe { &*self.child.leaf.as_ptr() };
+                    write!(f, "Leaf(len={}, ", leaf.len())?;
+                    let mut dbg_list = f.debug_list();
+                    for (key, value) in leaf.keys().iter().zip(leaf.values()) {
+                        dbg_list.entry(&KVPair::new(key, value));
+                    }
+                    dbg_list.finish()?;
+                } else {
+                    let node = unsafe { &*self.child.node.as_ptr() };
+                    let child = |i: usize| DebugChild {
+                        child: node.children()[i],
+                        height: self.height - 1,
+                    };
+                    write!(f, "Node(len={}, ", node.len())?;
+                    let mut dbg_list = f.debug_list();
+                    dbg_list.entry(&child(0));
+                    for (i, key) in node.keys().iter().enumerate() {
+                        dbg_list.entry(key);
+                        dbg_list.entry(&child(i + 1));
+                    }
+                    dbg_list.finish()?;
+                }
+                write!(f, ")")
+            }
+        }
+
+        let mut dbg_struct = f.debug_struct("BPlusTree");
+        dbg_struct.field("size", &self.size);
+        dbg_struct.field("depth", &self.depth);
+        if self.depth == 0 {
+            dbg_struct.field("root", &None::<()>);
+        } else {
+            dbg_struct.field(
+                "root",
+                &Some(DebugChild {
+                    child: unsafe { self.root.assume_init() },
+                    height: self.depth - 1,
+                }),
+            );
+        }
+        dbg_struct.finish()
+    }
+}
+
+impl<K, V, const COUNTED: bool, A: Aggregate<K, V>, S> Drop for BPlusTree<K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
@@ -0,0 +1,166 @@
+//! Memory budgets, which cap the bytes of nodes that trees may allocate.
+//!
+//! A [`btree::BTree`](crate::btree::BTree) or [`std_btree::BTree`](crate::std_btree::BTree) given
+//! a budget with `set_budget` charges it for every leaf and node it allocates, and gives the bytes
+//! back when it frees them. `try_insert` refuses an entry the budget can't afford with a
+//! [`BudgetExceeded`] error, which hands it back. [`SharedBudget`] keeps its count in an atomic,
+//! so trees in different threads can share one through an `Arc`.
+
+use alloc::sync::Arc;
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A budget of bytes that trees take their nodes out of.
+pub trait MemoryBudget: Send + Sync {
+    /// Takes `bytes` out of the budget, or returns false, and leaves it as it was, if it doesn't
+    /// have that many left.
+    fn reserve(&self, bytes: usize) -> bool;
+
+    /// Gives back `bytes` that were taken out before.
+    fn release(&self, bytes: usize);
+}
+
+/// A budget of `limit` bytes, counted in an atomic.
+#[derive(Debug)]
+pub struct SharedBudget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl SharedBudget {
+    #[inline]
+    pub const fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// The bytes taken out of the budget.
+    #[inline]
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn remaining(&self) -> usize {
+        self.limit - self.used()
+    }
+}
+
+impl MemoryBudget for SharedBudget {
+    #[inline]
+    fn reserve(&self, bytes: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|&used| used <= self.limit)
+            })
+            .is_ok()
+    }
+
+    #[inline]
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// An insertion that the tree's budget couldn't afford, with the entry it was given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BudgetExceeded<K, V> {
+    pub key: K,
+    pub value: V,
+}
+
+impl<K, V> fmt::Display for BudgetExceeded<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the memory budget can't afford the insertion")
+    }
+}
+
+/// The bytes of nodes a tree has taken out of its budget, if it has one.
+#[derive(Default)]
+pub(crate) struct Account {
+    budget: Option<Arc<dyn MemoryBudget>>,
+    /// Bytes reserved ahead by `prepay`, which allocations use up first.
+    credit: usize,
+    /// Bytes of the nodes allocated, which are given back as they are freed.
+    charged: usize,
+}
+
+impl Account {
+    /// Starts charging `budget`. The tree must have no nodes, which would be refunded without
+    /// having been charged.
+    #[inline]
+    pub(crate) fn set_budget(&mut self, budget: Arc<dyn MemoryBudget>) {
+        self.budget = Some(budget);
+    }
+
+    #[inline]
+    pub(crate) fn has_budget(&self) -> bool {
+        self.budget.is_some()
+    }
+
+    #[inline]
+    pub(crate) fn charged(&self) -> usize {
+        self.charged
+    }
+
+    /// Reserves `bytes` ahead of an operation that allocates at most that many, so that it can't
+    /// run out halfway. Returns false if the budget can't afford them.
+    #[inline]
+    pub(crate) fn prepay(&mut self, bytes: usize) -> bool {
+        let Some(budget) = &self.budget else {
+            return true;
+        };
+        let reserved = budget.reserve(bytes);
+        if reserved {
+            self.credit += bytes;
+        }
+        reserved
+    }
+
+    /// Gives back the credit that the operation after `prepay` didn't use.
+    #[inline]
+    pub(crate) fn settle(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.release(mem::take(&mut self.credit));
+        }
+    }
+
+    /// Charges an allocation of `bytes`. Panics if the budget can't afford it, which operations
+    /// that can't unwind halfway avoid by calling `prepay` first.
+    #[inline]
+    pub(crate) fn charge(&mut self, bytes: usize) {
+        let Some(budget) = &self.budget else {
+            return;
+        };
+        if bytes <= self.credit {
+            self.credit -= bytes;
+        } else {
+            assert!(budget.reserve(bytes), "memory budget exceeded");
+        }
+        self.charged += bytes;
+    }
+
+    /// Gives back the bytes of a freed allocation.
+    #[inline]
+    pub(crate) fn refund(&mut self, bytes: usize) {
+        if let Some(budget) = &self.budget {
+            self.charged -= bytes;
+            budget.release(bytes);
+        }
+    }
+
+    /// Gives back the bytes of every allocation at once, when they were all freed together.
+    pub(crate) fn refund_all(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.release(mem::take(&mut self.charged));
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use crate::search::SearchStrategy;
+use core::borrow::Borrow;
+use core::cmp::Ordering;
+
+/// A comparator, an ordering that doesn't have to come from the keys' `Ord` implementation and can
+/// depend on runtime state, like a collation or a case folding.
+///
+/// `Compare<L, R>` compares `L`s to `R`s. Trees ordered by a `Compare<K>` can be searched with any
+/// `Q` whose comparator also implements `Compare<Q, K>`, in the same order.
+pub trait Compare<L: ?Sized, R: ?Sized = L> {
+    fn compare(&self, left: &L, right: &R) -> Ordering;
+}
+
+impl<L: ?Sized, R: ?Sized, F> Compare<L, R> for F
+where
+    F: Fn(&L, &R) -> Ordering,
+{
+    #[inline]
+    fn compare(&self, left: &L, right: &R) -> Ordering {
+        self(left, right)
+    }
+}
+
+/// The order given by `Ord`, with lookups by any type the keys borrow as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Natural;
+
+impl<K, Q> Compare<Q, K> for Natural
+where
+    K: Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    #[inline]
+    fn compare(&self, left: &Q, right: &K) -> Ordering {
+        left.cmp(right.borrow())
+    }
+}
+
+/// The reverse of the order of `C`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Reverse<C>(pub C);
+
+impl<L: ?Sized, R: ?Sized, C: Compare<L, R>> Compare<L, R> for Reverse<C> {
+    #[inline]
+    fn compare(&self, left: &L, right: &R) -> Ordering {
+        self.0.compare(left, right).reverse()
+    }
+}
+
+/// A linear [`SearchStrategy`] that orders keys with the comparator `C` instead of `Ord`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct By<C>(pub C);
+
+impl<K, Q: ?Sized, C: Compare<Q, K>> SearchStrategy<K, Q> for By<C> {
+    #[inline]
+    fn search(&self, keys: &[K], key: &Q) -> Result<usize, usize> {
+        for (i, x) in keys.iter().enumerate() {
+            match self.0.compare(key, x) {
+                Ordering::Greater => {}
+                Ordering::Equal => return Ok(i),
+                Ordering::Less => return Err(i),
+            }
+        }
+        Err(keys.len())
+    }
+}
@@ -0,0 +1,40 @@
+use core::fmt::{self, Write};
+
+/// Escapes what it writes for the inside of a Graphviz record label.
+pub(crate) struct DotEscape<'a, W: ?Sized>(pub &'a mut W);
+
+impl<'a, W: Write + ?Sized> Write for DotEscape<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' | '\\' | '{' | '}' | '|' | '<' | '>' => {
+                    self.0.write_char('\\')?;
+                    self.0.write_char(c)?;
+                }
+                '\n' => self.0.write_str("\\n")?,
+                _ => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Escapes what it writes for the inside of a JSON string.
+pub(crate) struct JsonEscape<'a, W: ?Sized>(pub &'a mut W);
+
+impl<'a, W: Write + ?Sized> Write for JsonEscape<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\n")?,
+                '\r' => self.0.write_str("\\r")?,
+                '\t' => self.0.write_str("\\t")?,
+                c if c.is_control() => write!(self.0, "\\u{:04x}", c as u32)?,
+                _ => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,28 @@
 // #![no_std]
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
 
+extern crate alloc;
+
+pub mod aggregate;
+pub mod alloc_api;
+pub mod bplus_tree;
 pub mod btree;
+pub mod budget;
+pub mod compare;
+mod dump;
+pub mod magazine;
+#[cfg(feature = "fs")]
+pub mod paged;
 pub mod ref_stack;
+pub mod relocatable;
+pub mod search;
+#[cfg(feature = "serde")]
+pub mod serde_impls;
 pub mod slab;
+mod slots;
+pub mod stats;
 pub mod std_btree;
+pub mod validate;
+#[cfg(feature = "fs")]
+mod wal;
 // pub mod stack_vec;
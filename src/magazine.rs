@@ -0,0 +1,162 @@
+//! Per-CPU magazines of free slabs in front of a [`LockedSlabAllocator`]. Allocations and frees
+//! usually only touch the magazine of the CPU they run on, and the shared allocator's lock is
+//! taken once per batch of [`BATCH`] slabs, when a magazine runs empty or full.
+
+use crate::slab::{LockedSlabAllocator, SharedSlabAlloc, SlabStats};
+use core::iter;
+use core::mem::MaybeUninit;
+use core::ptr::NonNull;
+
+/// The most free slabs a magazine holds.
+pub const MAGAZINE_SIZE: usize = 32;
+
+/// The number of slabs moved between a magazine and the shared allocator at once.
+pub const BATCH: usize = MAGAZINE_SIZE / 2;
+
+/// Tells which CPU the caller runs on. The embedder provides it, e.g. from a per-CPU register or
+/// the thread's id.
+pub trait CpuId {
+    /// The current CPU. Any number will do, but one below the number of magazines spreads the
+    /// CPUs over them evenly. The caller may have moved to another CPU by the time the magazine is
+    /// used, which the magazine's lock makes safe, if slower.
+    fn current(&self) -> usize;
+}
+
+impl<F: Fn() -> usize> CpuId for F {
+    #[inline]
+    fn current(&self) -> usize {
+        self()
+    }
+}
+
+/// A stack of free slabs.
+struct Magazine<T> {
+    slabs: [MaybeUninit<NonNull<T>>; MAGAZINE_SIZE],
+    len: usize,
+}
+
+impl<T> Magazine<T> {
+    #[inline]
+    fn pop(&mut self) -> Option<NonNull<T>> {
+        self.len = self.len.checked_sub(1)?;
+        Some(unsafe { self.slabs[self.len].assume_init() })
+    }
+
+    #[inline]
+    fn push(&mut self, ptr: NonNull<T>) {
+        self.slabs[self.len].write(ptr);
+        self.len += 1;
+    }
+}
+
+impl<T> Extend<NonNull<T>> for Magazine<T> {
+    #[inline]
+    fn extend<I: IntoIterator<Item = NonNull<T>>>(&mut self, ptrs: I) {
+        for ptr in ptrs {
+            self.push(ptr);
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for Magazine<T> {}
+
+/// A [`LockedSlabAllocator`] with a magazine of free slabs for each of `CPUS` CPUs, which
+/// [`LockedSlabBox`](crate::slab::LockedSlabBox) can allocate from like from the allocator itself.
+///
+/// Each magazine has a lock of its own, which is only contended when a thread moves to another CPU
+/// while it uses the magazine. The slabs in the magazines count as in use in the allocator's stats.
+pub struct MagazineAllocator<T, C: CpuId, const CPUS: usize> {
+    magazines: [spin::Mutex<Magazine<T>>; CPUS],
+    depot: LockedSlabAllocator<T>,
+    cpu: C,
+}
+
+impl<T, C: CpuId, const CPUS: usize> MagazineAllocator<T, C, CPUS> {
+    /// Puts magazines in front of `depot`, and picks the magazine to use with `cpu`.
+    pub fn new(depot: LockedSlabAllocator<T>, cpu: C) -> Self {
+        assert!(0 < CPUS, "MagazineAllocator without magazines");
+        Self {
+            magazines: core::array::from_fn(|_| {
+                spin::Mutex::new(Magazine {
+                    slabs: [const { MaybeUninit::uninit() }; MAGAZINE_SIZE],
+                    len: 0,
+                })
+            }),
+            depot,
+            cpu,
+        }
+    }
+
+    /// The shared allocator behind the magazines.
+    #[inline]
+    pub fn depot(&self) -> &LockedSlabAllocator<T> {
+        &self.depot
+    }
+
+    #[inline]
+    pub fn add_chunk(&self, chunk: &'static mut [u8]) {
+        self.depot.add_chunk(chunk);
+    }
+
+    #[inline]
+    pub fn needs_new_chunk(&self) -> bool {
+        self.depot.needs_new_chunk()
+    }
+
+    /// The stats of the shared allocator, where the slabs in the magazines count as in use.
+    #[inline]
+    pub fn stats(&self) -> SlabStats {
+        self.depot.stats()
+    }
+
+    /// The number of free slabs in the magazines.
+    pub fn cached(&self) -> usize {
+        self.magazines
+            .iter()
+            .map(|magazine| magazine.lock().len)
+            .sum()
+    }
+
+    /// Gives the slabs in all the magazines back to the shared allocator.
+    pub fn flush(&self) {
+        for magazine in &self.magazines {
+            let mut magazine = magazine.lock();
+            let mut depot = self.depot.lock();
+            unsafe { depot.free_many(iter::from_fn(|| magazine.pop())) };
+        }
+    }
+
+    #[inline]
+    fn magazine(&self) -> &spin::Mutex<Magazine<T>> {
+        &self.magazines[self.cpu.current() % CPUS]
+    }
+}
+
+impl<T, C: CpuId, const CPUS: usize> SharedSlabAlloc<T> for MagazineAllocator<T, C, CPUS> {
+    /// Takes a slab from the current CPU's magazine, refilling it with a batch from the shared
+    /// allocator if it is empty.
+    fn malloc(&self) -> Option<NonNull<T>> {
+        let mut magazine = self.magazine().lock();
+        if magazine.len == 0 {
+            self.depot.lock().malloc_many(BATCH, &mut *magazine);
+        }
+        magazine.pop()
+    }
+
+    /// Puts a slab in the current CPU's magazine, flushing a batch to the shared allocator first
+    /// if it is full.
+    unsafe fn free(&self, ptr: NonNull<T>) {
+        let mut magazine = self.magazine().lock();
+        if magazine.len == MAGAZINE_SIZE {
+            let batch = (0..BATCH).map(|_| magazine.pop().unwrap());
+            self.depot.lock().free_many(batch);
+        }
+        magazine.push(ptr);
+    }
+}
+
+impl<T, C: CpuId, const CPUS: usize> Drop for MagazineAllocator<T, C, CPUS> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
@@ -0,0 +1,1127 @@
+//! A disk-backed [`BTree`](crate::btree::BTree), for indexes that outgrow memory. The nodes live in
+//! the fixed-size pages of a file, one node per page, laid out like the records of a
+//! [relocatable image](crate::relocatable) with the children as page numbers. A small buffer pool
+//! keeps the most recently used pages in memory, and writes dirty pages back when they are evicted
+//! or the tree is flushed. Freed pages are kept in a list and reused before the file grows.
+//!
+//! The nodes are read out of their pages into copies with the layout of a
+//! [`btree::BTree`](crate::btree::BTree) node, and go through the same insertion and removal
+//! algorithms: the same node capacity and split point, overflowing into a neighbour before
+//! splitting on insertion, and borrowing from a neighbour before merging on removal. Entries live
+//! in the internal nodes too, so it's a B-tree rather than a B+tree. Only [`Plain`] keys and values
+//! can be stored, since they are read back from raw bytes. Needs the `fs` feature.
+//!
+//! Page 0 holds a header with the root, the length and the free list. The file itself only changes
+//! at checkpoints. Every insertion and removal is appended to a write-ahead log next to it, at
+//! [`wal_path`], once it has been applied to the nodes, and so are the dirty pages the buffer pool
+//! evicts. [`PagedBTree::checkpoint`]
+//! logs the remaining dirty pages and the new header, syncs the log, copies the logged pages into
+//! the file, and empties the log. After a crash, [`PagedBTree::recover`] finishes a checkpoint
+//! whose header made it into the log, and replays the operations logged after the last one.
+
+use crate::btree::{MAX_DEPTH, MAX_NUM_ELEMENTS as CAPACITY, MIN_NUM_ELEMENTS as MIN_LEN};
+use crate::relocatable::{Plain, RecordLayout};
+use crate::search::{Linear, SearchStrategy};
+use crate::slots;
+use crate::wal::{Kind, Wal};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::iter::FusedIterator;
+use std::marker::PhantomData;
+use std::mem::{self, align_of, size_of};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+
+/// The size of a page, and of the reads and writes of the file.
+pub const PAGE_SIZE: usize = 4096;
+
+const MAGIC: [u8; 8] = *b"BTREE2PG";
+const VERSION: u32 = 1;
+
+/// The contents of page 0.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Meta {
+    magic: [u8; 8],
+    version: u32,
+    page_size: u32,
+    key_size: u32,
+    key_align: u32,
+    value_size: u32,
+    value_align: u32,
+    capacity: u32,
+    depth: u32,
+    len: u64,
+    root: u64,
+    page_count: u64,
+    free_head: u64,
+    free_count: u64,
+}
+
+// Only integers, without padding.
+unsafe impl Plain for Meta {}
+
+/// The bytes of a value, for the log.
+#[inline]
+fn bytes_of<T: Plain>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+/// Reads a value from the start of `bytes`, which don't have to be aligned.
+#[inline]
+fn read_plain<T: Plain>(bytes: &[u8]) -> T {
+    assert!(size_of::<T>() <= bytes.len());
+    unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) }
+}
+
+/// The path of the write-ahead log of the tree at `path`, with `.wal` appended.
+pub fn wal_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut wal = path.as_ref().as_os_str().to_owned();
+    wal.push(".wal");
+    PathBuf::from(wal)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The state of the buffer pool of a [`PagedBTree`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PoolStats {
+    /// The most pages the pool holds.
+    pub capacity: usize,
+    /// The pages in the pool.
+    pub cached: usize,
+    /// The pages in the pool that haven't been written back yet.
+    pub dirty: usize,
+    /// The page lookups served from the pool.
+    pub hits: u64,
+    /// The page lookups that had to read the file.
+    pub misses: u64,
+    /// The pages dropped from the pool to make room for others.
+    pub evictions: u64,
+    /// The pages in the file, including page 0 and the free ones.
+    pub page_count: u64,
+    /// The pages in the free list.
+    pub free_pages: u64,
+}
+
+struct Frame {
+    page: u64,
+    dirty: bool,
+    last_used: u64,
+    /// Words rather than bytes, so that the page is aligned for its keys and values.
+    data: Box<[u64]>,
+}
+
+impl Frame {
+    #[inline]
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.data.as_ptr() as *const u8, PAGE_SIZE) }
+    }
+
+    #[inline]
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.data.as_mut_ptr() as *mut u8, PAGE_SIZE) }
+    }
+}
+
+/// The file, its log and its buffer pool, evicting the least recently used page when it's full.
+struct Pager {
+    file: File,
+    wal: Wal,
+    /// The offsets of the latest images in the log of the pages written back since the last
+    /// checkpoint. They are read from there until the checkpoint copies them into the file.
+    logged: HashMap<u64, u64>,
+    frames: Vec<Frame>,
+    lookup: HashMap<u64, usize>,
+    clock: u64,
+    page_count: u64,
+    free_head: u64,
+    /// Counts the changes to pages and allocations, so that a failed operation can tell whether
+    /// it left any behind.
+    changes: u64,
+    stats: PoolStats,
+}
+
+impl Pager {
+    fn new(file: File, wal: Wal, capacity: usize) -> Self {
+        Self {
+            file,
+            wal,
+            logged: HashMap::new(),
+            frames: Vec::with_capacity(capacity),
+            lookup: HashMap::with_capacity(capacity),
+            clock: 0,
+            page_count: 1,
+            free_head: 0,
+            changes: 0,
+            stats: PoolStats {
+                capacity,
+                ..PoolStats::default()
+            },
+        }
+    }
+
+    /// Gets a frame for `page`, evicting another page if the pool is full. The frame's contents
+    /// are left to the caller.
+    fn claim(&mut self, page: u64) -> io::Result<usize> {
+        let index = if self.frames.len() < self.stats.capacity {
+            self.frames.push(Frame {
+                page,
+                dirty: false,
+                last_used: 0,
+                data: vec![0; PAGE_SIZE / 8].into_boxed_slice(),
+            });
+            self.frames.len() - 1
+        } else {
+            let index = (0..self.frames.len())
+                .min_by_key(|&i| self.frames[i].last_used)
+                .unwrap();
+            self.write_back(index)?;
+            self.lookup.remove(&self.frames[index].page);
+            self.stats.evictions += 1;
+            index
+        };
+        self.clock += 1;
+        let frame = &mut self.frames[index];
+        frame.page = page;
+        frame.dirty = false;
+        frame.last_used = self.clock;
+        self.lookup.insert(page, index);
+        Ok(index)
+    }
+
+    /// Gets the frame holding `page`, reading it from the file if it isn't in the pool.
+    fn frame(&mut self, page: u64) -> io::Result<usize> {
+        if page == 0 || self.page_count <= page {
+            return Err(invalid_data("page number out of range"));
+        }
+        if let Some(&index) = self.lookup.get(&page) {
+            self.stats.hits += 1;
+            self.clock += 1;
+            self.frames[index].last_used = self.clock;
+            return Ok(index);
+        }
+        self.stats.misses += 1;
+        let index = self.claim(page)?;
+        let bytes = self.frames[index].bytes_mut();
+        let read = match self.logged.get(&page) {
+            Some(&offset) => self.wal.read_at(offset, bytes),
+            None => self
+                .file
+                .seek(SeekFrom::Start(page * PAGE_SIZE as u64))
+                .and_then(|_| self.file.read_exact(bytes)),
+        };
+        if let Err(error) = read {
+            self.lookup.remove(&page);
+            self.frames.swap_remove(index);
+            if let Some(moved) = self.frames.get(index) {
+                self.lookup.insert(moved.page, index);
+            }
+            return Err(error);
+        }
+        Ok(index)
+    }
+
+    #[inline]
+    fn page(&mut self, page: u64) -> io::Result<&[u8]> {
+        let index = self.frame(page)?;
+        Ok(self.frames[index].bytes())
+    }
+
+    #[inline]
+    fn page_mut(&mut self, page: u64) -> io::Result<&mut [u8]> {
+        let index = self.frame(page)?;
+        self.changes += 1;
+        let frame = &mut self.frames[index];
+        frame.dirty = true;
+        Ok(frame.bytes_mut())
+    }
+
+    /// Takes a page from the free list, or grows the file by one.
+    fn allocate(&mut self) -> io::Result<u64> {
+        if self.free_head != 0 {
+            let page = self.free_head;
+            self.free_head = u64::from_ne_bytes(self.page(page)?[..8].try_into().unwrap());
+            self.changes += 1;
+            self.stats.free_pages -= 1;
+            return Ok(page);
+        }
+        let page = self.page_count;
+        let index = self.claim(page)?;
+        self.page_count += 1;
+        self.changes += 1;
+        let frame = &mut self.frames[index];
+        frame.data.fill(0);
+        frame.dirty = true;
+        Ok(page)
+    }
+
+    /// Pushes a page onto the free list, linked through its first 8 bytes.
+    fn free(&mut self, page: u64) -> io::Result<()> {
+        let next = self.free_head;
+        self.page_mut(page)?[..8].copy_from_slice(&next.to_ne_bytes());
+        self.free_head = page;
+        self.stats.free_pages += 1;
+        Ok(())
+    }
+
+    /// Drops the pages in the pool, dirty or not, and the pages written back to the log, so that
+    /// they are read from the file again.
+    fn discard(&mut self) {
+        self.frames.clear();
+        self.lookup.clear();
+        self.logged.clear();
+    }
+
+    /// Writes a dirty page back to the log.
+    fn write_back(&mut self, index: usize) -> io::Result<()> {
+        let frame = &mut self.frames[index];
+        if frame.dirty {
+            let offset = self
+                .wal
+                .append(Kind::Page, &[&frame.page.to_ne_bytes(), frame.bytes()])?;
+            self.logged.insert(frame.page, offset + 8);
+            frame.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn read_meta(&mut self) -> io::Result<Meta> {
+        let mut bytes = [0; size_of::<Meta>()];
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_exact(&mut bytes)?;
+        Ok(read_plain(&bytes))
+    }
+
+    /// Writes the header and syncs the file.
+    fn write_meta(&mut self, meta: &Meta) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(bytes_of(meta))?;
+        self.file.sync_data()
+    }
+
+    /// Copies the pages logged before a commit into the file, and then writes its header.
+    fn apply(&mut self, logged: &HashMap<u64, u64>, meta: &Meta) -> io::Result<()> {
+        let mut pages = logged
+            .iter()
+            .map(|(&page, &offset)| (page, offset))
+            .collect::<Vec<_>>();
+        pages.sort_unstable();
+        let mut buffer = vec![0; PAGE_SIZE];
+        for (page, offset) in pages {
+            self.wal.read_at(offset, &mut buffer)?;
+            self.file.seek(SeekFrom::Start(page * PAGE_SIZE as u64))?;
+            self.file.write_all(&buffer)?;
+        }
+        self.file.sync_data()?;
+        self.write_meta(meta)
+    }
+}
+
+/// A node read out of its page. Leaves don't use their children.
+#[derive(Clone, Copy)]
+struct PageNode<K, V> {
+    height: usize,
+    len: usize,
+    keys: [K; CAPACITY],
+    values: [V; CAPACITY],
+    children: [u64; CAPACITY + 1],
+}
+
+// The arrays are as long as a node's, and always initialized.
+unsafe impl<K, V> slots::Slots for PageNode<K, V> {
+    type Key = K;
+    type Value = V;
+    type Child = u64;
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    fn set_len(&mut self, len: usize) {
+        debug_assert!(len <= CAPACITY);
+        self.len = len;
+    }
+
+    #[inline]
+    fn key_ptr(&mut self) -> *mut K {
+        self.keys.as_mut_ptr()
+    }
+
+    #[inline]
+    fn value_ptr(&mut self) -> *mut V {
+        self.values.as_mut_ptr()
+    }
+
+    #[inline]
+    fn child_ptr(&mut self) -> *mut u64 {
+        self.children.as_mut_ptr()
+    }
+}
+
+impl<K: Plain, V: Plain> PageNode<K, V> {
+    #[inline]
+    fn empty(height: usize) -> Self {
+        // `Plain` types are valid for any bytes, zeroes included.
+        let mut node: Self = unsafe { mem::zeroed() };
+        node.height = height;
+        node
+    }
+
+    #[inline]
+    fn layout() -> RecordLayout {
+        RecordLayout::new::<K, V>(CAPACITY, true)
+    }
+
+    fn read(bytes: &[u8]) -> io::Result<Self> {
+        let field = |at: usize| u32::from_ne_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+        let mut node = Self::empty(field(4));
+        node.len = field(0);
+        if CAPACITY < node.len {
+            return Err(invalid_data("node longer than its capacity"));
+        }
+        let layout = Self::layout();
+        // The page is aligned to 8 bytes and the layout aligns each array, which fit into the page
+        // since `PagedBTree` checks that a full node does.
+        unsafe {
+            let base = bytes.as_ptr();
+            ptr::copy_nonoverlapping(
+                base.add(layout.keys) as *const K,
+                node.keys.as_mut_ptr(),
+                node.len,
+            );
+            ptr::copy_nonoverlapping(
+                base.add(layout.values) as *const V,
+                node.values.as_mut_ptr(),
+                node.len,
+            );
+            if node.height != 0 {
+                ptr::copy_nonoverlapping(
+                    base.add(layout.children) as *const u64,
+                    node.children.as_mut_ptr(),
+                    node.len + 1,
+                );
+            }
+        }
+        Ok(node)
+    }
+
+    fn write(&self, bytes: &mut [u8]) {
+        debug_assert!(self.len <= CAPACITY);
+        bytes[..4].copy_from_slice(&(self.len as u32).to_ne_bytes());
+        bytes[4..8].copy_from_slice(&(self.height as u32).to_ne_bytes());
+        let layout = Self::layout();
+        unsafe {
+            let base = bytes.as_mut_ptr();
+            ptr::copy_nonoverlapping(
+                self.keys.as_ptr(),
+                base.add(layout.keys) as *mut K,
+                self.len,
+            );
+            ptr::copy_nonoverlapping(
+                self.values.as_ptr(),
+                base.add(layout.values) as *mut V,
+                self.len,
+            );
+            if self.height != 0 {
+                ptr::copy_nonoverlapping(
+                    self.children.as_ptr(),
+                    base.add(layout.children) as *mut u64,
+                    self.len + 1,
+                );
+            }
+        }
+    }
+
+    #[inline]
+    fn keys(&self) -> &[K] {
+        &self.keys[..self.len]
+    }
+}
+
+/// How [`PagedBTree::recover`] brought a tree back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Recovery {
+    /// Whether a checkpoint had been interrupted, and was finished.
+    pub checkpoint_finished: bool,
+    /// The operations replayed from the log.
+    pub replayed: usize,
+    /// The bytes at the end of the log that didn't form a whole record, and were dropped.
+    pub discarded: u64,
+}
+
+enum LogOp<K, V> {
+    Insert(K, V),
+    Remove(K),
+}
+
+/// A B-tree stored in a file. See the [module documentation](self).
+///
+/// Reads take `&mut self` as well, since they go through the buffer pool, and every operation can
+/// fail with the I/O error of reading or writing a page or the log. An insertion or removal that
+/// fails is rolled back: it isn't logged, and the nodes it changed are dropped from the pool and
+/// rebuilt from the file and the log, as [`recover`](Self::recover) would. If that fails too, the
+/// next read, insertion or removal tries again, and checkpoints fail until one succeeds. Dropping
+/// the tree checkpoints it, ignoring errors; call [`checkpoint`](Self::checkpoint) to see them.
+pub struct PagedBTree<K, V> {
+    pager: Pager,
+    root: u64,
+    depth: usize,
+    len: usize,
+    /// Whether rolling back a failed operation failed, and the tree has to be rebuilt before it's
+    /// used again.
+    stale: bool,
+    _entries: PhantomData<(K, V)>,
+}
+
+impl<K, V> PagedBTree<K, V> {
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of levels, 0 for an empty tree and 1 when the root is a leaf.
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn pool_stats(&self) -> PoolStats {
+        PoolStats {
+            cached: self.pager.frames.len(),
+            dirty: self.pager.frames.iter().filter(|frame| frame.dirty).count(),
+            page_count: self.pager.page_count,
+            ..self.pager.stats
+        }
+    }
+
+    /// Brings the file up to date and empties the log. See the [module documentation](self).
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        if self.stale {
+            // The log still holds what a successful rebuild or the next recovery needs.
+            return Err(io::Error::other(
+                "a failed operation hasn't been rolled back",
+            ));
+        }
+        if self.pager.wal.len() == 0 {
+            return Ok(());
+        }
+        for index in 0..self.pager.frames.len() {
+            self.pager.write_back(index)?;
+        }
+        let meta = self.meta();
+        self.pager.wal.append(Kind::Commit, &[bytes_of(&meta)])?;
+        self.pager.wal.sync()?;
+        let logged = mem::take(&mut self.pager.logged);
+        let applied = self.pager.apply(&logged, &meta);
+        if applied.is_err() {
+            self.pager.logged = logged;
+            return applied;
+        }
+        self.pager.wal.truncate(0)
+    }
+
+    /// Syncs the log, making the operations so far durable without a checkpoint.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.pager.wal.sync()
+    }
+
+    fn meta(&self) -> Meta {
+        Meta {
+            magic: MAGIC,
+            version: VERSION,
+            page_size: PAGE_SIZE as u32,
+            key_size: size_of::<K>() as u32,
+            key_align: align_of::<K>() as u32,
+            value_size: size_of::<V>() as u32,
+            value_align: align_of::<V>() as u32,
+            capacity: CAPACITY as u32,
+            depth: self.depth as u32,
+            len: self.len as u64,
+            root: self.root,
+            page_count: self.pager.page_count,
+            free_head: self.pager.free_head,
+            free_count: self.pager.stats.free_pages,
+        }
+    }
+}
+
+impl<K: Plain + Ord, V: Plain> PagedBTree<K, V> {
+    /// Creates an empty tree in the file at `path`, and its log at [`wal_path`], truncating them
+    /// if they exist. The buffer pool holds up to `pool_pages` pages, at least one per level of the
+    /// tree is recommended.
+    pub fn create<P: AsRef<Path>>(path: P, pool_pages: usize) -> io::Result<Self> {
+        Self::check_types(pool_pages)?;
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let wal = Wal::open(&wal_path(path), true)?;
+        let mut tree = Self {
+            pager: Pager::new(file, wal, pool_pages),
+            root: 0,
+            depth: 0,
+            len: 0,
+            stale: false,
+            _entries: PhantomData,
+        };
+        let meta = tree.meta();
+        tree.pager.write_meta(&meta)?;
+        Ok(tree)
+    }
+
+    /// Opens a tree created by [`create`](Self::create), recovering it if it wasn't closed
+    /// cleanly.
+    #[inline]
+    pub fn open<P: AsRef<Path>>(path: P, pool_pages: usize) -> io::Result<Self> {
+        Ok(Self::recover(path, pool_pages)?.0)
+    }
+
+    /// Opens a tree created by [`create`](Self::create), and brings it back to the state after the
+    /// last operation in its log. A checkpoint that was interrupted is finished, and the
+    /// operations logged after the last checkpoint are replayed, up to a torn record a crash left
+    /// at the end. The result is checkpointed, so the log is empty again.
+    pub fn recover<P: AsRef<Path>>(path: P, pool_pages: usize) -> io::Result<(Self, Recovery)> {
+        Self::check_types(pool_pages)?;
+        let path = path.as_ref();
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let wal = Wal::open(&wal_path(path), false)?;
+        let mut tree = Self {
+            pager: Pager::new(file, wal, pool_pages),
+            root: 0,
+            depth: 0,
+            len: 0,
+            stale: false,
+            _entries: PhantomData,
+        };
+        let recovery = tree.reload()?;
+        Ok((tree, recovery))
+    }
+
+    /// Rebuilds the tree from the header in the file and the log, dropping the pool.
+    fn reload(&mut self) -> io::Result<Recovery> {
+        self.pager.discard();
+        let meta = self.pager.read_meta()?;
+        self.load_meta(&meta)?;
+        self.replay()
+    }
+
+    /// Rebuilds the tree if rolling back a failed operation failed.
+    fn refresh(&mut self) -> io::Result<()> {
+        if self.stale {
+            // The checkpoint at the end of the replay mustn't get here again.
+            self.stale = false;
+            if let Err(error) = self.reload() {
+                self.stale = true;
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Rolls back the operation that returned `result` if it failed after changing pages, since
+    /// it had counted `changes`.
+    fn roll_back<T>(&mut self, result: io::Result<T>, changes: u64) -> io::Result<T> {
+        if result.is_err() && self.pager.changes != changes {
+            self.stale = true;
+            let _ = self.refresh();
+        }
+        result
+    }
+
+    fn load_meta(&mut self, meta: &Meta) -> io::Result<()> {
+        if meta.magic != MAGIC {
+            return Err(invalid_data("not a paged tree"));
+        }
+        if meta.version != VERSION {
+            return Err(invalid_data("unsupported paged tree version"));
+        }
+        if meta.page_size as usize != PAGE_SIZE
+            || meta.key_size as usize != size_of::<K>()
+            || meta.key_align as usize != align_of::<K>()
+            || meta.value_size as usize != size_of::<V>()
+            || meta.value_align as usize != align_of::<V>()
+            || meta.capacity as usize != CAPACITY
+        {
+            return Err(invalid_data(
+                "the tree holds different key or value types, or different pages",
+            ));
+        }
+        if MAX_DEPTH < meta.depth as usize || meta.page_count == 0 {
+            return Err(invalid_data("invalid paged tree header"));
+        }
+        self.pager.page_count = meta.page_count;
+        self.pager.free_head = meta.free_head;
+        self.pager.stats.free_pages = meta.free_count;
+        self.root = meta.root;
+        self.depth = meta.depth as usize;
+        self.len = meta.len as usize;
+        Ok(())
+    }
+
+    fn replay(&mut self) -> io::Result<Recovery> {
+        let mut recovery = Recovery::default();
+        if self.pager.wal.len() == 0 {
+            return Ok(recovery);
+        }
+        let entry_size = size_of::<K>() + size_of::<V>();
+        let mut logged = HashMap::new();
+        let mut commit = None;
+        let mut ops = Vec::new();
+        let end = self.pager.wal.scan(|kind, offset, payload| {
+            match kind {
+                Kind::Insert if payload.len() == entry_size => ops.push(LogOp::Insert(
+                    read_plain(payload),
+                    read_plain(&payload[size_of::<K>()..]),
+                )),
+                Kind::Remove if payload.len() == size_of::<K>() => {
+                    ops.push(LogOp::Remove(read_plain(payload)))
+                }
+                Kind::Page if payload.len() == 8 + PAGE_SIZE => {
+                    logged.insert(read_plain::<u64>(payload), offset + 8);
+                }
+                Kind::Commit if payload.len() == size_of::<Meta>() => {
+                    commit = Some((read_plain::<Meta>(payload), mem::take(&mut logged)));
+                    ops.clear();
+                }
+                Kind::Recovered => logged.clear(),
+                _ => return Err(invalid_data("the log holds different key or value types")),
+            }
+            Ok(())
+        })?;
+        recovery.discarded = self.pager.wal.len() - end;
+        self.pager.wal.truncate(end)?;
+
+        if let Some((meta, logged)) = commit {
+            self.load_meta(&meta)?;
+            self.pager.apply(&logged, &meta)?;
+            recovery.checkpoint_finished = true;
+        }
+        if ops.is_empty() {
+            self.pager.wal.truncate(0)?;
+            return Ok(recovery);
+        }
+        // The pages the replay writes back are logged after this, and the ones before it belong
+        // to the operations that are replayed.
+        self.pager.wal.append(Kind::Recovered, &[])?;
+        for op in ops {
+            match op {
+                LogOp::Insert(key, value) => drop(self.apply_insert(key, value)?),
+                LogOp::Remove(key) => drop(self.apply_remove(&key)?),
+            }
+            recovery.replayed += 1;
+        }
+        self.checkpoint()?;
+        Ok(recovery)
+    }
+
+    fn check_types(pool_pages: usize) -> io::Result<()> {
+        if pool_pages == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the buffer pool needs at least one page",
+            ));
+        }
+        if PAGE_SIZE < PageNode::<K, V>::layout().size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a full node doesn't fit into a page",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads the node in `page`, checking that it's on the level its parent expects.
+    fn load(&mut self, page: u64, height: usize) -> io::Result<PageNode<K, V>> {
+        let node = PageNode::read(self.pager.page(page)?)?;
+        if node.height != height {
+            return Err(invalid_data("node on the wrong level"));
+        }
+        Ok(node)
+    }
+
+    #[inline]
+    fn store(&mut self, page: u64, node: &PageNode<K, V>) -> io::Result<()> {
+        node.write(self.pager.page_mut(page)?);
+        Ok(())
+    }
+
+    pub fn get_entry(&mut self, key: &K) -> io::Result<Option<(K, V)>> {
+        self.refresh()?;
+        let mut page = self.root;
+        for height in (0..self.depth).rev() {
+            let node = self.load(page, height)?;
+            match Linear.search(node.keys(), key) {
+                Ok(i) => return Ok(Some((node.keys[i], node.values[i]))),
+                Err(i) => page = node.children[i],
+            }
+        }
+        Ok(None)
+    }
+
+    #[inline]
+    pub fn get(&mut self, key: &K) -> io::Result<Option<V>> {
+        Ok(self.get_entry(key)?.map(|(_, value)| value))
+    }
+
+    #[inline]
+    pub fn contains_key(&mut self, key: &K) -> io::Result<bool> {
+        Ok(self.get_entry(key)?.is_some())
+    }
+
+    /// Inserts an entry, and logs it once it's in the tree.
+    pub fn insert(&mut self, key: K, value: V) -> io::Result<Option<(K, V)>> {
+        self.refresh()?;
+        let changes = self.pager.changes;
+        let result = self.apply_insert(key, value).and_then(|old| {
+            self.pager
+                .wal
+                .append(Kind::Insert, &[bytes_of(&key), bytes_of(&value)])?;
+            Ok(old)
+        });
+        self.roll_back(result, changes)
+    }
+
+    fn apply_insert(&mut self, key: K, value: V) -> io::Result<Option<(K, V)>> {
+        if self.depth == 0 {
+            let page = self.pager.allocate()?;
+            let mut leaf = PageNode::empty(0);
+            slots::push(&mut leaf, key, value, 0);
+            self.store(page, &leaf)?;
+            self.root = page;
+            self.depth = 1;
+            self.len = 1;
+            return Ok(None);
+        }
+
+        // The nodes from the root down to the leaf, each with the index of the child taken or,
+        // in the leaf, of the new entry.
+        let mut path = Vec::with_capacity(self.depth);
+        let mut page = self.root;
+        for height in (0..self.depth).rev() {
+            let mut node = self.load(page, height)?;
+            match Linear.search(node.keys(), &key) {
+                Ok(i) => {
+                    let old = (
+                        mem::replace(&mut node.keys[i], key),
+                        mem::replace(&mut node.values[i], value),
+                    );
+                    self.store(page, &node)?;
+                    return Ok(Some(old));
+                }
+                Err(i) => {
+                    let child = node.children[i];
+                    path.push((page, node, i));
+                    page = child;
+                }
+            }
+        }
+        self.insert_along(path, key, value)?;
+        self.len += 1;
+        Ok(None)
+    }
+
+    /// Inserts a new entry into the leaf at the end of `path`, splitting the nodes on the way up
+    /// that are full.
+    fn insert_along(
+        &mut self,
+        mut path: Vec<(u64, PageNode<K, V>, usize)>,
+        key: K,
+        value: V,
+    ) -> io::Result<()> {
+        // Each level hands the entry and the new child to go after it up to its parent, until a
+        // node has room for them.
+        let (mut key, mut value, mut rchild) = (key, value, 0);
+        while let Some((page, mut node, j)) = path.pop() {
+            if node.len < CAPACITY {
+                let overflow = slots::insert(&mut node, j, key, value, rchild);
+                debug_assert!(overflow.is_none());
+                return self.store(page, &node);
+            }
+            if let Some((parent_page, parent, i)) = path.last_mut() {
+                let i = *i;
+                if 0 < i {
+                    let left_page = parent.children[i - 1];
+                    let mut left = self.load(left_page, node.height)?;
+                    if left.len < CAPACITY {
+                        let (key, value, lchild) =
+                            slots::insert_overflow_left(&mut node, j, key, value, rchild);
+                        let key = mem::replace(&mut parent.keys[i - 1], key);
+                        let value = mem::replace(&mut parent.values[i - 1], value);
+                        slots::push(&mut left, key, value, lchild);
+                        self.store(left_page, &left)?;
+                        self.store(page, &node)?;
+                        return self.store(*parent_page, parent);
+                    }
+                }
+                if i < parent.len {
+                    let right_page = parent.children[i + 1];
+                    let mut right = self.load(right_page, node.height)?;
+                    if right.len < CAPACITY {
+                        let (key, value, rchild) =
+                            slots::insert(&mut node, j, key, value, rchild).unwrap();
+                        let key = mem::replace(&mut parent.keys[i], key);
+                        let value = mem::replace(&mut parent.values[i], value);
+                        slots::unshift(&mut right, key, value, rchild);
+                        self.store(right_page, &right)?;
+                        self.store(page, &node)?;
+                        return self.store(*parent_page, parent);
+                    }
+                }
+            }
+            let mut right = PageNode::empty(node.height);
+            (key, value) = slots::insert_split(&mut node, &mut right, j, key, value, rchild);
+            rchild = self.pager.allocate()?;
+            self.store(page, &node)?;
+            self.store(rchild, &right)?;
+        }
+
+        // The root split.
+        let mut root = PageNode::empty(self.depth);
+        root.children[0] = self.root;
+        slots::push(&mut root, key, value, rchild);
+        let page = self.pager.allocate()?;
+        self.store(page, &root)?;
+        self.root = page;
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Removes an entry, and logs it once it's out of the tree.
+    pub fn remove(&mut self, key: &K) -> io::Result<Option<(K, V)>> {
+        self.refresh()?;
+        let changes = self.pager.changes;
+        let result = self.apply_remove(key).and_then(|old| {
+            self.pager.wal.append(Kind::Remove, &[bytes_of(key)])?;
+            Ok(old)
+        });
+        self.roll_back(result, changes)
+    }
+
+    fn apply_remove(&mut self, key: &K) -> io::Result<Option<(K, V)>> {
+        if self.depth == 0 {
+            return Ok(None);
+        }
+
+        // The nodes from the root down to the leaf, each with the index of the child taken or,
+        // in the leaf, of the entry to remove. An entry found in an internal node is replaced by
+        // its predecessor, the last entry of the subtree before it.
+        let mut path = Vec::with_capacity(self.depth);
+        let mut target = None;
+        let mut page = self.root;
+        for height in (0..self.depth).rev() {
+            let node = self.load(page, height)?;
+            let i = match target {
+                Some(_) if height == 0 => match node.len.checked_sub(1) {
+                    Some(last) => last,
+                    None => return Err(invalid_data("empty leaf")),
+                },
+                Some(_) => node.len,
+                None => match Linear.search(node.keys(), key) {
+                    Ok(i) => {
+                        target = Some(path.len());
+                        i
+                    }
+                    Err(_) if height == 0 => return Ok(None),
+                    Err(i) => i,
+                },
+            };
+            let child = node.children[i];
+            path.push((page, node, i));
+            page = child;
+        }
+
+        let (mut page, mut node, mut hole) = path.pop().unwrap();
+        let mut entry = unsafe { slots::read(&mut node, hole) };
+        if let Some((_, target, i)) = target.and_then(|target| path.get_mut(target)) {
+            entry = (
+                mem::replace(&mut target.keys[*i], entry.0),
+                mem::replace(&mut target.values[*i], entry.1),
+            );
+        }
+
+        // Each level closes the hole left by the level below, until a node can spare an entry or
+        // borrow one from a neighbour.
+        loop {
+            let Some((parent_page, parent, i)) = path.last_mut() else {
+                slots::remove(&mut node, hole);
+                if node.len != 0 {
+                    self.store(page, &node)?;
+                } else {
+                    self.pager.free(page)?;
+                    self.root = if node.height == 0 {
+                        0
+                    } else {
+                        node.children[0]
+                    };
+                    self.depth -= 1;
+                }
+                break;
+            };
+            if MIN_LEN < node.len {
+                slots::remove(&mut node, hole);
+                self.store(page, &node)?;
+                break;
+            }
+            let i = *i;
+            let mut left = None;
+            if 0 < i {
+                let left_page = parent.children[i - 1];
+                let mut neighbour = self.load(left_page, node.height)?;
+                if MIN_LEN < neighbour.len {
+                    let (key, value, lchild) = slots::pop(&mut neighbour);
+                    let key = mem::replace(&mut parent.keys[i - 1], key);
+                    let value = mem::replace(&mut parent.values[i - 1], value);
+                    slots::remove_borrow_left(&mut node, hole, key, value, lchild);
+                    self.store(left_page, &neighbour)?;
+                    self.store(page, &node)?;
+                    self.store(*parent_page, parent)?;
+                    break;
+                }
+                left = Some((left_page, neighbour));
+            }
+            if i < parent.len {
+                let right_page = parent.children[i + 1];
+                let mut neighbour = self.load(right_page, node.height)?;
+                if MIN_LEN < neighbour.len {
+                    let (key, value, rchild) = slots::shift(&mut neighbour);
+                    let key = mem::replace(&mut parent.keys[i], key);
+                    let value = mem::replace(&mut parent.values[i], value);
+                    slots::remove(&mut node, hole);
+                    slots::push(&mut node, key, value, rchild);
+                    self.store(right_page, &neighbour)?;
+                    self.store(page, &node)?;
+                    self.store(*parent_page, parent)?;
+                    break;
+                }
+                if left.is_none() {
+                    let (key, value) = unsafe { slots::read(parent, i) };
+                    slots::remove(&mut node, hole);
+                    slots::merge(&mut node, key, value, &mut neighbour);
+                    self.store(page, &node)?;
+                    self.pager.free(right_page)?;
+                    hole = i;
+                }
+            }
+            if let Some((left_page, mut neighbour)) = left {
+                let (key, value) = unsafe { slots::read(parent, i - 1) };
+                slots::merge_remove(&mut neighbour, key, value, &mut node, hole);
+                self.store(left_page, &neighbour)?;
+                self.pager.free(page)?;
+                hole = i - 1;
+            }
+            (page, node, _) = path.pop().unwrap();
+        }
+
+        // The node the entry was found in, if the loop stopped below it.
+        if let Some((page, target, _)) = target.and_then(|target| path.get(target)) {
+            self.store(*page, target)?;
+        }
+        self.len -= 1;
+        Ok(Some(entry))
+    }
+
+    /// Gets an iterator over the entries of the tree, sorted by key. If reading a page fails, it
+    /// yields the error and ends.
+    pub fn iter(&mut self) -> PagedIter<'_, K, V> {
+        let error = self.refresh().err();
+        let mut iter = PagedIter {
+            remaining: self.len,
+            tree: self,
+            stack: Vec::with_capacity(MAX_DEPTH),
+            error,
+        };
+        if iter.error.is_none() && iter.tree.depth != 0 {
+            let (root, height) = (iter.tree.root, iter.tree.depth - 1);
+            iter.error = iter.descend(root, height).err();
+        }
+        iter
+    }
+}
+
+impl<K, V> Drop for PagedBTree<K, V> {
+    fn drop(&mut self) {
+        let _ = self.checkpoint();
+    }
+}
+
+/// An iterator over the entries of a [`PagedBTree`], sorted by key.
+pub struct PagedIter<'a, K, V> {
+    tree: &'a mut PagedBTree<K, V>,
+    /// The nodes on the path to the next entry, with the index of their next key.
+    stack: Vec<(PageNode<K, V>, usize)>,
+    error: Option<io::Error>,
+    remaining: usize,
+}
+
+impl<'a, K: Plain + Ord, V: Plain> PagedIter<'a, K, V> {
+    /// Pushes the path from `page` down to its first leaf.
+    fn descend(&mut self, mut page: u64, mut height: usize) -> io::Result<()> {
+        loop {
+            let node = self.tree.load(page, height)?;
+            let first = node.children[0];
+            self.stack.push((node, 0));
+            if height == 0 {
+                return Ok(());
+            }
+            page = first;
+            height -= 1;
+        }
+    }
+
+    #[inline]
+    fn fail(&mut self, error: io::Error) -> Option<io::Result<(K, V)>> {
+        self.stack.clear();
+        self.remaining = 0;
+        Some(Err(error))
+    }
+}
+
+impl<'a, K: Plain + Ord, V: Plain> Iterator for PagedIter<'a, K, V> {
+    type Item = io::Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(error) = self.error.take() {
+            return self.fail(error);
+        }
+        while let Some((node, index)) = self.stack.last_mut() {
+            let i = *index;
+            if i < node.len {
+                *index += 1;
+                let entry = (node.keys[i], node.values[i]);
+                if node.height != 0 {
+                    let (child, height) = (node.children[i + 1], node.height - 1);
+                    if let Err(error) = self.descend(child, height) {
+                        return self.fail(error);
+                    }
+                }
+                self.remaining -= 1;
+                return Some(Ok(entry));
+            }
+            self.stack.pop();
+        }
+        None
+    }
+
+    /// One more than the remaining entries at most, for an error.
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining + 1))
+    }
+}
+
+impl<'a, K: Plain + Ord, V: Plain> FusedIterator for PagedIter<'a, K, V> {}
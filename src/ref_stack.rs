@@ -1,16 +1,31 @@
+//! A stack of mutable references, each reborrowed from the one below it, such as the nodes on a
+//! path down a tree. Only the top frame can be mutated; the frames below it are frozen until it is
+//! popped, as with a chain of `&mut` reborrows, which the borrow checker can't express in a loop.
+
 use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::ptr::NonNull;
 
-pub struct RefStack<'a, T, const N: usize> {
-    stack: [*mut T; N],
+pub struct RefStack<'a, T: ?Sized, const N: usize> {
+    /// The first `len` frames are initialized. Pointers need no dropping, so the rest are simply
+    /// left uninitialized.
+    stack: [MaybeUninit<NonNull<T>>; N],
     len: usize,
     phantom: PhantomData<&'a mut T>,
 }
 
-impl<'a, T, const N: usize> RefStack<'a, T, N> {
+impl<'a, T: ?Sized, const N: usize> Default for RefStack<'a, T, N> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, T: ?Sized, const N: usize> RefStack<'a, T, N> {
     #[inline]
     pub fn new() -> Self {
         Self {
-            stack: unsafe { core::mem::MaybeUninit::uninit().assume_init() },
+            stack: [const { MaybeUninit::uninit() }; N],
             len: 0,
             phantom: PhantomData,
         }
@@ -24,18 +39,32 @@ impl<'a, T, const N: usize> RefStack<'a, T, N> {
         slf
     }
 
+    /// Replaces all the frames with `root`, and returns the previous root.
     #[inline]
     pub fn set_root(&mut self, root: &'a mut T) -> Option<&'a mut T> {
-        let prev_root = if self.is_empty() {
-            None
-        } else {
-            unsafe { Some(&mut *self.stack[0]) }
-        };
+        assert!(0 < N, "RefStack without room for a root");
+        let prev_root = self.take_root();
+        self.stack[0].write(NonNull::from(root));
         self.len = 1;
-        self.stack[0] = root as _;
         prev_root
     }
 
+    /// Drops all the frames, and returns the root.
+    #[inline]
+    pub fn into_root(mut self) -> Option<&'a mut T> {
+        self.take_root()
+    }
+
+    #[inline]
+    fn take_root(&mut self) -> Option<&'a mut T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.len = 0;
+        // The frames above the root are gone, so it is the only reference left.
+        Some(unsafe { self.stack[0].assume_init().as_mut() })
+    }
+
     #[inline]
     pub fn len(&self) -> usize {
         self.len
@@ -51,49 +80,82 @@ impl<'a, T, const N: usize> RefStack<'a, T, N> {
         self.len == N
     }
 
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Pushes the reference that `f` reborrows from the top frame. Panics if the stack is empty
+    /// or full.
     #[inline]
     pub fn push<F: FnOnce(&mut T) -> &mut T>(&mut self, f: F) {
-        assert!(self.ret_push(f).is_none());
+        if self.try_push(f).is_err() {
+            panic!("RefStack of {N} frames is empty or full");
+        }
     }
 
+    /// Pushes the reference that `f` reborrows from the top frame, or gives `f` back if the stack
+    /// is empty or full.
     #[inline]
-    pub fn ret_push<F: FnOnce(&mut T) -> &mut T>(&mut self, f: F) -> Option<F> {
+    pub fn try_push<F: FnOnce(&mut T) -> &mut T>(&mut self, f: F) -> Result<(), F> {
         if self.is_empty() || self.is_full() {
-            Some(f)
-        } else {
-            unsafe {
-                self.stack[self.len] = f(&mut *self.stack[self.len() - 1]) as _;
-                self.len += 1;
-            }
-            None
+            return Err(f);
         }
+        let top = unsafe { self.stack[self.len - 1].assume_init().as_mut() };
+        self.stack[self.len].write(NonNull::from(f(top)));
+        self.len += 1;
+        Ok(())
     }
 
+    /// Removes the top frame, and returns it for as long as the stack stays borrowed.
     #[inline]
-    pub fn pop(&mut self) -> Option<&'a mut T> {
-        self.len -= 1;
-        if self.is_empty() {
-            unsafe { Some(&mut *self.stack[0]) }
-        } else {
-            None
-        }
+    pub fn pop(&mut self) -> Option<&mut T> {
+        self.len = self.len.checked_sub(1)?;
+        Some(unsafe { self.stack[self.len].assume_init().as_mut() })
+    }
+
+    /// Drops the frames from `len` on, if there are that many.
+    #[inline]
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    /// Gets frame `i`, counting from the root.
+    ///
+    /// # Safety
+    /// Reading a frame reads through the reference that the frames above it were reborrowed from.
+    /// If those overlap the frame, they mustn't be written through again until they are popped,
+    /// as with plain reborrows. Frames that `push` reaches through pointers to separate
+    /// allocations, like the nodes on a path down a tree, never overlap. No borrow of the stack
+    /// can enforce this, since the read outlives the reference it returns: once that is gone,
+    /// `top_mut` would write through a frame the read has frozen.
+    #[inline]
+    pub unsafe fn get(&self, i: usize) -> Option<&T> {
+        self.frames().get(i).map(|frame| frame.as_ref())
+    }
+
+    /// Iterates over the frames, from the root up.
+    ///
+    /// # Safety
+    /// As for [`get`](Self::get), for every frame below the top.
+    #[inline]
+    pub unsafe fn iter(&self) -> impl DoubleEndedIterator<Item = &T> + ExactSizeIterator + '_ {
+        self.frames().iter().map(|frame| frame.as_ref())
     }
 
     #[inline]
     pub fn top(&self) -> Option<&T> {
-        if self.is_empty() {
-            None
-        } else {
-            unsafe { Some(&*self.stack[self.len - 1]) }
-        }
+        self.frames().last().map(|frame| unsafe { frame.as_ref() })
     }
 
     #[inline]
     pub fn top_mut(&mut self) -> Option<&mut T> {
-        if self.is_empty() {
-            None
-        } else {
-            unsafe { Some(&mut *self.stack[self.len - 1]) }
-        }
+        let top = self.len.checked_sub(1)?;
+        Some(unsafe { self.stack[top].assume_init().as_mut() })
+    }
+
+    #[inline]
+    fn frames(&self) -> &[NonNull<T>] {
+        unsafe { core::slice::from_raw_parts(self.stack.as_ptr().cast(), self.len) }
     }
 }
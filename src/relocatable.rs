@@ -0,0 +1,546 @@
+//! A relocatable image of a [`btree::BTree`](crate::btree::BTree): the same nodes, with the child
+//! links stored as offsets from the start of the image instead of pointers. An image can be written
+//! to a file and `mmap`ed back, or handed across an address space boundary, and read in place with
+//! a [`BTreeView`] once [`BTreeView::from_bytes`] has validated it.
+//!
+//! The image is laid out as a [`Header`] followed by one record per node, children before their
+//! parents. A record is a `len: u32` and a `height: u32`, 0 for leaves, followed by `len` keys,
+//! `len` values, and for internal nodes `len + 1` child offsets as `u64`s, each array aligned to
+//! its type. Records start at multiples of 8 bytes. Everything is in the native byte order, which
+//! the header records.
+
+use crate::btree::{MAX_DEPTH, MAX_NUM_ELEMENTS as CAPACITY, MIN_NUM_ELEMENTS as MIN_LEN};
+use crate::search::{Linear, SearchStrategy};
+use core::fmt;
+use core::iter::FusedIterator;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ptr;
+use core::slice;
+
+/// Types that can be stored in an image and read back from arbitrary bytes: they have no padding,
+/// no pointers, and every bit pattern is a valid value.
+///
+/// # Safety
+/// Implementors must uphold all of the above, and have an alignment of at most 8.
+pub unsafe trait Plain: Copy + 'static {}
+
+macro_rules! impl_plain {
+    ($($t:ty),* $(,)?) => {$(
+        unsafe impl Plain for $t {}
+    )*};
+}
+
+impl_plain!((), u8, i8, u16, i16, u32, i32, u64, i64, usize, isize);
+
+unsafe impl<T: Plain, const N: usize> Plain for [T; N] {}
+
+const MAGIC: [u8; 8] = *b"BTREE2\0\0";
+const VERSION: u32 = 1;
+const ENDIANNESS: u32 = 0x0102_0304;
+
+/// The start of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Header {
+    pub magic: [u8; 8],
+    pub version: u32,
+    /// `0x01020304` in the byte order of the writer.
+    pub endianness: u32,
+    pub key_size: u32,
+    pub key_align: u32,
+    pub value_size: u32,
+    pub value_align: u32,
+    /// The most keys a node holds.
+    pub capacity: u32,
+    /// The number of levels, 0 for an empty tree.
+    pub depth: u32,
+    pub len: u64,
+    /// The offset of the root record, 0 for an empty tree.
+    pub root: u64,
+    /// The size of the whole image.
+    pub size: u64,
+}
+
+const HEADER_SIZE: usize = size_of::<Header>();
+
+/// Why an image couldn't be written or read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatError {
+    /// The output buffer is smaller than the image, which needs `needed` bytes.
+    BufferTooSmall {
+        needed: usize,
+    },
+    /// The bytes are shorter than the image says it is.
+    Truncated,
+    /// The bytes don't start at a multiple of 8.
+    Misaligned,
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// The image was written with a different byte order.
+    WrongEndianness,
+    /// The image was written with different key or value types, or a different node capacity.
+    TypeMismatch,
+    /// The image has more levels than a tree could have.
+    TooDeep {
+        depth: usize,
+    },
+    /// A child offset points outside of the image, isn't aligned, or its record doesn't fit.
+    BadOffset {
+        offset: u64,
+    },
+    /// The record at `offset` has an invalid length, or isn't on the level its parent expects.
+    BadNode {
+        offset: u64,
+    },
+    /// The key at `index` of the record at `offset` isn't greater than the key before it.
+    OutOfOrder {
+        offset: u64,
+        index: usize,
+    },
+    /// The header's length doesn't match the number of entries in the records.
+    LengthMismatch {
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::BufferTooSmall { needed } => write!(f, "the image needs {needed} bytes"),
+            Self::Truncated => write!(f, "the image is truncated"),
+            Self::Misaligned => write!(f, "the image isn't aligned to 8 bytes"),
+            Self::BadMagic => write!(f, "not a tree image"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported image version {version}"),
+            Self::WrongEndianness => write!(f, "the image has the wrong byte order"),
+            Self::TypeMismatch => write!(f, "the image holds different key or value types"),
+            Self::TooDeep { depth } => write!(f, "the image has {depth} levels"),
+            Self::BadOffset { offset } => write!(f, "invalid record offset {offset}"),
+            Self::BadNode { offset } => write!(f, "invalid record at offset {offset}"),
+            Self::OutOfOrder { offset, index } => {
+                write!(
+                    f,
+                    "key {index} of the record at offset {offset} is out of order"
+                )
+            }
+            Self::LengthMismatch { expected, actual } => write!(
+                f,
+                "the image has a length of {expected} but holds {actual} entries"
+            ),
+        }
+    }
+}
+
+#[inline]
+const fn align_up(offset: usize, align: usize) -> usize {
+    (offset + align - 1) & !(align - 1)
+}
+
+/// Where the arrays of a record with `len` keys are, relative to its start.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RecordLayout {
+    pub keys: usize,
+    pub values: usize,
+    pub children: usize,
+    pub size: usize,
+}
+
+impl RecordLayout {
+    #[inline]
+    pub(crate) fn new<K, V>(len: usize, internal: bool) -> Self {
+        let keys = align_up(8, align_of::<K>());
+        let values = align_up(keys + len * size_of::<K>(), align_of::<V>());
+        let children = align_up(values + len * size_of::<V>(), 8);
+        let end = if internal {
+            children + (len + 1) * size_of::<u64>()
+        } else {
+            children
+        };
+        Self {
+            keys,
+            values,
+            children,
+            size: align_up(end, 8),
+        }
+    }
+}
+
+impl Header {
+    pub(crate) fn new<K, V>(depth: usize, len: usize, root: usize, size: usize) -> Self {
+        Self {
+            magic: MAGIC,
+            version: VERSION,
+            endianness: ENDIANNESS,
+            key_size: size_of::<K>() as u32,
+            key_align: align_of::<K>() as u32,
+            value_size: size_of::<V>() as u32,
+            value_align: align_of::<V>() as u32,
+            capacity: CAPACITY as u32,
+            depth: depth as u32,
+            len: len as u64,
+            root: root as u64,
+            size: size as u64,
+        }
+    }
+}
+
+/// Writes images. The records are appended by `BTree::write_relocatable`, from the leaves up.
+pub(crate) struct Writer<'a> {
+    out: &'a mut [u8],
+    cursor: usize,
+}
+
+impl<'a> Writer<'a> {
+    #[inline]
+    pub(crate) fn new(out: &'a mut [u8]) -> Self {
+        Self {
+            out,
+            cursor: HEADER_SIZE,
+        }
+    }
+
+    /// The size of the header, where the first record goes.
+    #[inline]
+    pub(crate) fn header_size() -> usize {
+        HEADER_SIZE
+    }
+
+    /// Appends a record and returns its offset. The caller has checked that the image fits.
+    pub(crate) fn write_record<K: Plain, V: Plain>(
+        &mut self,
+        height: usize,
+        keys: &[K],
+        values: &[V],
+        children: &[u64],
+    ) -> u64 {
+        let offset = self.cursor;
+        let layout = RecordLayout::new::<K, V>(keys.len(), height != 0);
+        let record = &mut self.out[offset..offset + layout.size];
+        record.fill(0);
+        record[..4].copy_from_slice(&(keys.len() as u32).to_ne_bytes());
+        record[4..8].copy_from_slice(&(height as u32).to_ne_bytes());
+        write_slice(&mut record[layout.keys..], keys);
+        write_slice(&mut record[layout.values..], values);
+        write_slice(&mut record[layout.children..], children);
+        self.cursor += layout.size;
+        offset as u64
+    }
+
+    /// Writes the header, and returns the size of the image.
+    pub(crate) fn finish<K, V>(self, depth: usize, len: usize, root: u64) -> usize {
+        let header = Header::new::<K, V>(depth, len, root as usize, self.cursor);
+        write_slice(self.out, slice::from_ref(&header));
+        self.cursor
+    }
+}
+
+#[inline]
+fn write_slice<T: Copy>(out: &mut [u8], items: &[T]) {
+    let size = core::mem::size_of_val(items);
+    assert!(size <= out.len());
+    unsafe { ptr::copy_nonoverlapping(items.as_ptr() as *const u8, out.as_mut_ptr(), size) };
+}
+
+/// A record of an image, read in place.
+struct Record<'a, K, V> {
+    height: usize,
+    keys: &'a [K],
+    values: &'a [V],
+    children: &'a [u64],
+}
+
+/// A read-only tree backed by an image, which it reads in place without copying. Searches use the
+/// keys' `Ord`, so images of trees ordered by a comparator fail validation.
+pub struct BTreeView<'a, K, V> {
+    bytes: &'a [u8],
+    depth: usize,
+    len: usize,
+    root: u64,
+    _entries: PhantomData<(&'a K, &'a V)>,
+}
+
+impl<'a, K, V> Clone for BTreeView<'a, K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, K, V> Copy for BTreeView<'a, K, V> {}
+
+impl<'a, K: Plain + Ord, V: Plain> BTreeView<'a, K, V> {
+    /// Validates an image and returns a view of it. Checks the header against `K` and `V`, that
+    /// every record is in bounds and on the right level, the node lengths, the key order and the
+    /// number of entries, so the view can't read out of bounds whatever the bytes are. `bytes` has
+    /// to start at a multiple of 8 bytes, which `mmap`ed files do. Takes linear time.
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, FormatError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(FormatError::Truncated);
+        }
+        if !(bytes.as_ptr() as usize).is_multiple_of(8) {
+            return Err(FormatError::Misaligned);
+        }
+        let header = unsafe { ptr::read(bytes.as_ptr() as *const Header) };
+        if header.magic != MAGIC {
+            return Err(FormatError::BadMagic);
+        }
+        if header.endianness != ENDIANNESS {
+            return Err(if header.endianness.swap_bytes() == ENDIANNESS {
+                FormatError::WrongEndianness
+            } else {
+                FormatError::BadMagic
+            });
+        }
+        if header.version != VERSION {
+            return Err(FormatError::UnsupportedVersion(header.version));
+        }
+        if header.key_size as usize != size_of::<K>()
+            || header.key_align as usize != align_of::<K>()
+            || header.value_size as usize != size_of::<V>()
+            || header.value_align as usize != align_of::<V>()
+            || header.capacity as usize != CAPACITY
+        {
+            return Err(FormatError::TypeMismatch);
+        }
+        if (bytes.len() as u64) < header.size {
+            return Err(FormatError::Truncated);
+        }
+        let depth = header.depth as usize;
+        if MAX_DEPTH < depth {
+            return Err(FormatError::TooDeep { depth });
+        }
+
+        let view = Self {
+            bytes: &bytes[..header.size as usize],
+            depth,
+            len: header.len as usize,
+            root: header.root,
+            _entries: PhantomData,
+        };
+        let mut count = 0;
+        if depth != 0 {
+            let mut last = None;
+            view.validate_record(view.root, depth - 1, true, &mut last, &mut count)?;
+        }
+        if count != view.len {
+            return Err(FormatError::LengthMismatch {
+                expected: view.len,
+                actual: count,
+            });
+        }
+        Ok(view)
+    }
+
+    fn validate_record(
+        &self,
+        offset: u64,
+        height: usize,
+        is_root: bool,
+        last: &mut Option<&'a K>,
+        count: &mut usize,
+    ) -> Result<(), FormatError> {
+        let record = self.record(offset)?;
+        let min = if is_root { 1 } else { MIN_LEN };
+        if record.height != height || !(min..=CAPACITY).contains(&record.keys.len()) {
+            return Err(FormatError::BadNode { offset });
+        }
+        *count += record.keys.len();
+
+        if height == 0 {
+            for (index, key) in record.keys.iter().enumerate() {
+                Self::validate_order(last, key, offset, index)?;
+            }
+        } else {
+            for (index, &child) in record.children.iter().enumerate() {
+                self.validate_record(child, height - 1, false, last, count)?;
+                if let Some(key) = record.keys.get(index) {
+                    Self::validate_order(last, key, offset, index)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn validate_order(
+        last: &mut Option<&'a K>,
+        key: &'a K,
+        offset: u64,
+        index: usize,
+    ) -> Result<(), FormatError> {
+        if last.is_some_and(|last| key <= last) {
+            return Err(FormatError::OutOfOrder { offset, index });
+        }
+        *last = Some(key);
+        Ok(())
+    }
+
+    /// Reads the record at `offset`, checking that it lies within the image.
+    fn record(&self, offset: u64) -> Result<Record<'a, K, V>, FormatError> {
+        let bad_offset = FormatError::BadOffset { offset };
+        let start = usize::try_from(offset).map_err(|_| bad_offset)?;
+        if start < HEADER_SIZE
+            || start % 8 != 0
+            || start
+                .checked_add(8)
+                .is_none_or(|end| end > self.bytes.len())
+        {
+            return Err(bad_offset);
+        }
+        let field = |at: usize| {
+            u32::from_ne_bytes(self.bytes[start + at..start + at + 4].try_into().unwrap()) as usize
+        };
+        let (len, height) = (field(0), field(4));
+        if CAPACITY < len {
+            return Err(FormatError::BadNode { offset });
+        }
+        let layout = RecordLayout::new::<K, V>(len, height != 0);
+        if self.bytes.len() - start < layout.size {
+            return Err(bad_offset);
+        }
+        let children = if height == 0 { 0 } else { len + 1 };
+        // In bounds by the check above, and aligned since the bytes and the record start at
+        // multiples of 8, and the layout aligns each array.
+        unsafe {
+            let base = self.bytes.as_ptr().add(start);
+            Ok(Record {
+                height,
+                keys: slice::from_raw_parts(base.add(layout.keys) as *const K, len),
+                values: slice::from_raw_parts(base.add(layout.values) as *const V, len),
+                children: slice::from_raw_parts(base.add(layout.children) as *const u64, children),
+            })
+        }
+    }
+
+    /// Reads a record that `from_bytes` already validated.
+    #[inline]
+    fn validated_record(&self, offset: u64) -> Record<'a, K, V> {
+        self.record(offset)
+            .expect("the record was validated by `from_bytes`")
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn get_entry(&self, key: &K) -> Option<(&'a K, &'a V)> {
+        if self.depth == 0 {
+            return None;
+        }
+        let mut offset = self.root;
+        loop {
+            let record = self.validated_record(offset);
+            match Linear.search(record.keys, key) {
+                Ok(i) => return Some((&record.keys[i], &record.values[i])),
+                Err(i) if record.height != 0 => offset = record.children[i],
+                Err(_) => return None,
+            }
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, key: &K) -> Option<&'a V> {
+        Some(self.get_entry(key)?.1)
+    }
+
+    #[inline]
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get_entry(key).is_some()
+    }
+
+    /// Gets an iterator over the entries of the image, sorted by key.
+    pub fn iter(&self) -> ViewIter<'a, K, V> {
+        let mut iter = ViewIter {
+            view: *self,
+            stack: [(0, 0); MAX_DEPTH],
+            stack_len: 0,
+            remaining: self.len,
+        };
+        if self.depth != 0 {
+            iter.descend(self.root);
+        }
+        iter
+    }
+}
+
+/// An iterator over the entries of a [`BTreeView`], sorted by key.
+pub struct ViewIter<'a, K, V> {
+    view: BTreeView<'a, K, V>,
+    /// The records on the path to the next entry, with the index of their next key.
+    stack: [(u64, usize); MAX_DEPTH],
+    stack_len: usize,
+    remaining: usize,
+}
+
+impl<'a, K: Plain + Ord, V: Plain> ViewIter<'a, K, V> {
+    /// Pushes the path from `offset` down to its first leaf.
+    fn descend(&mut self, mut offset: u64) {
+        loop {
+            self.stack[self.stack_len] = (offset, 0);
+            self.stack_len += 1;
+            let record = self.view.validated_record(offset);
+            if record.height == 0 {
+                break;
+            }
+            offset = record.children[0];
+        }
+    }
+}
+
+impl<'a, K: Plain + Ord, V: Plain> Iterator for ViewIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while 0 < self.stack_len {
+            let (offset, index) = &mut self.stack[self.stack_len - 1];
+            let record = self.view.validated_record(*offset);
+            let i = *index;
+            if i < record.keys.len() {
+                *index += 1;
+                if record.height != 0 {
+                    self.descend(record.children[i + 1]);
+                }
+                self.remaining -= 1;
+                return Some((&record.keys[i], &record.values[i]));
+            }
+            self.stack_len -= 1;
+        }
+        None
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K: Plain + Ord, V: Plain> ExactSizeIterator for ViewIter<'a, K, V> {}
+
+impl<'a, K: Plain + Ord, V: Plain> FusedIterator for ViewIter<'a, K, V> {}
+
+impl<'a, K: Plain + Ord, V: Plain> IntoIterator for BTreeView<'a, K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = ViewIter<'a, K, V>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, K: Plain + Ord + fmt::Debug, V: Plain + fmt::Debug> fmt::Debug for BTreeView<'a, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
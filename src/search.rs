@@ -0,0 +1,219 @@
+use core::borrow::Borrow;
+use core::cmp::Ordering;
+#[cfg(target_arch = "x86_64")]
+use core::mem;
+
+/// A way of searching the sorted keys of a node. Trees store their strategy, so it can carry
+/// state, like [`By`](crate::compare::By) does with its comparator.
+///
+/// `search` returns `Ok(i)` if `keys[i]` equals `key`, and otherwise `Err(i)` where `i` is the
+/// index `key` would be inserted at, just like [`slice::binary_search`].
+pub trait SearchStrategy<K, Q: ?Sized> {
+    fn search(&self, keys: &[K], key: &Q) -> Result<usize, usize>;
+}
+
+/// Scans the keys from the start. The fastest for short nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Linear;
+
+impl<K, Q> SearchStrategy<K, Q> for Linear
+where
+    K: Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    #[inline]
+    fn search(&self, keys: &[K], key: &Q) -> Result<usize, usize> {
+        for (i, x) in keys.iter().enumerate() {
+            match key.cmp(x.borrow()) {
+                Ordering::Greater => {}
+                Ordering::Equal => return Ok(i),
+                Ordering::Less => return Err(i),
+            }
+        }
+        Err(keys.len())
+    }
+}
+
+/// A binary search whose loop doesn't branch on the comparisons, so it doesn't suffer from
+/// mispredictions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Binary;
+
+impl<K, Q> SearchStrategy<K, Q> for Binary
+where
+    K: Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    #[inline]
+    fn search(&self, keys: &[K], key: &Q) -> Result<usize, usize> {
+        if keys.is_empty() {
+            return Err(0);
+        }
+        let mut base = 0;
+        let mut size = keys.len();
+        while 1 < size {
+            let half = size / 2;
+            let mid = base + half;
+            // `mid < keys.len()` since `base + size <= keys.len()` and `half < size`.
+            let x = unsafe { keys.get_unchecked(mid) };
+            base = if x.borrow() <= key { mid } else { base };
+            size -= half;
+        }
+        match unsafe { keys.get_unchecked(base) }.borrow().cmp(key) {
+            Ordering::Less => Err(base + 1),
+            Ordering::Equal => Ok(base),
+            Ordering::Greater => Err(base),
+        }
+    }
+}
+
+/// Compares many keys at once with SIMD instructions. Only works for primitive integer keys.
+///
+/// On x86_64 it uses AVX2 when the CPU supports it, and SSE2 otherwise (SSE4.2 for 64 bit keys).
+/// Elsewhere it falls back to a scalar search. With the `std` feature the CPU is checked once, at
+/// the first search, and without it only the instructions enabled at compile time are used, like
+/// with `-C target-cpu=native`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Simd;
+
+/// Keys that [`Simd`] can search.
+pub trait SimdKey: Copy + Ord {
+    /// Counts the keys that are less than `key`. `keys` must be sorted.
+    fn count_less(keys: &[Self], key: Self) -> usize;
+}
+
+impl<K: SimdKey> SearchStrategy<K, K> for Simd {
+    #[inline]
+    fn search(&self, keys: &[K], key: &K) -> Result<usize, usize> {
+        let i = K::count_less(keys, *key);
+        match keys.get(i) {
+            Some(x) if x == key => Ok(i),
+            _ => Err(i),
+        }
+    }
+}
+
+#[inline]
+fn count_less_scalar<K: Ord>(keys: &[K], key: &K) -> usize {
+    keys.iter().take_while(|x| *x < key).count()
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+macro_rules! impl_simd_key {
+    ($($t:ty),* $(,)?) => {$(
+        impl SimdKey for $t {
+            #[inline]
+            fn count_less(keys: &[Self], key: Self) -> usize {
+                count_less_scalar(keys, &key)
+            }
+        }
+    )*};
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+impl_simd_key!(i8, u8, i16, u16, i32, u32, i64, u64, isize, usize);
+
+/// The instructions [`Simd`] can use, detected at runtime with the `std` feature.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Clone, Copy)]
+struct Features {
+    avx2: bool,
+    sse2: bool,
+    sse4_2: bool,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Features {
+    #[cfg(feature = "std")]
+    #[inline]
+    fn get() -> Self {
+        static FEATURES: std::sync::OnceLock<Features> = std::sync::OnceLock::new();
+        *FEATURES.get_or_init(|| Features {
+            avx2: is_x86_feature_detected!("avx2"),
+            sse2: is_x86_feature_detected!("sse2"),
+            sse4_2: is_x86_feature_detected!("sse4.2"),
+        })
+    }
+
+    #[cfg(not(feature = "std"))]
+    #[inline]
+    fn get() -> Self {
+        Features {
+            avx2: cfg!(target_feature = "avx2"),
+            sse2: cfg!(target_feature = "sse2"),
+            sse4_2: cfg!(target_feature = "sse4.2"),
+        }
+    }
+}
+
+/// Implements [`SimdKey`] for `$t` by comparing its keys as `$s`, a signed integer of the same
+/// size. Unsigned keys are biased by `$bias` first, which maps their order onto the signed one.
+/// `$has_128` is the field of [`Features`] for `$feature_128`, the 128 bit instructions.
+#[cfg(target_arch = "x86_64")]
+macro_rules! impl_simd_key {
+    ($(
+        $t:ty as $s:ty, bias $bias:expr,
+        $feature_128:tt $has_128:ident ($set1_128:ident, $cmpgt_128:ident),
+        avx2 ($set1_256:ident, $cmpgt_256:ident);
+    )*) => {$(
+        impl SimdKey for $t {
+            #[inline]
+            fn count_less(keys: &[Self], key: Self) -> usize {
+                use core::arch::x86_64::*;
+
+                #[target_feature(enable = "avx2")]
+                unsafe fn count_less_256(keys: &[$t], key: $t) -> usize {
+                    const LANES: usize = 32 / mem::size_of::<$t>();
+                    let bias = $set1_256($bias);
+                    let needle = _mm256_xor_si256($set1_256(key as $s), bias);
+                    let mut count = 0;
+                    let mut chunks = keys.chunks_exact(LANES);
+                    for chunk in &mut chunks {
+                        let chunk = _mm256_loadu_si256(chunk.as_ptr() as *const __m256i);
+                        let less = $cmpgt_256(needle, _mm256_xor_si256(chunk, bias));
+                        count += _mm256_movemask_epi8(less).count_ones() as usize;
+                    }
+                    count / mem::size_of::<$t>() + count_less_scalar(chunks.remainder(), &key)
+                }
+
+                #[target_feature(enable = $feature_128)]
+                unsafe fn count_less_128(keys: &[$t], key: $t) -> usize {
+                    const LANES: usize = 16 / mem::size_of::<$t>();
+                    let bias = $set1_128($bias);
+                    let needle = _mm_xor_si128($set1_128(key as $s), bias);
+                    let mut count = 0;
+                    let mut chunks = keys.chunks_exact(LANES);
+                    for chunk in &mut chunks {
+                        let chunk = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+                        let less = $cmpgt_128(needle, _mm_xor_si128(chunk, bias));
+                        count += _mm_movemask_epi8(less).count_ones() as usize;
+                    }
+                    count / mem::size_of::<$t>() + count_less_scalar(chunks.remainder(), &key)
+                }
+
+                let features = Features::get();
+                if features.avx2 {
+                    unsafe { count_less_256(keys, key) }
+                } else if features.$has_128 {
+                    unsafe { count_less_128(keys, key) }
+                } else {
+                    count_less_scalar(keys, &key)
+                }
+            }
+        }
+    )*};
+}
+
+#[cfg(target_arch = "x86_64")]
+impl_simd_key! {
+    i8 as i8, bias 0, "sse2" sse2 (_mm_set1_epi8, _mm_cmpgt_epi8), avx2 (_mm256_set1_epi8, _mm256_cmpgt_epi8);
+    u8 as i8, bias i8::MIN, "sse2" sse2 (_mm_set1_epi8, _mm_cmpgt_epi8), avx2 (_mm256_set1_epi8, _mm256_cmpgt_epi8);
+    i16 as i16, bias 0, "sse2" sse2 (_mm_set1_epi16, _mm_cmpgt_epi16), avx2 (_mm256_set1_epi16, _mm256_cmpgt_epi16);
+    u16 as i16, bias i16::MIN, "sse2" sse2 (_mm_set1_epi16, _mm_cmpgt_epi16), avx2 (_mm256_set1_epi16, _mm256_cmpgt_epi16);
+    i32 as i32, bias 0, "sse2" sse2 (_mm_set1_epi32, _mm_cmpgt_epi32), avx2 (_mm256_set1_epi32, _mm256_cmpgt_epi32);
+    u32 as i32, bias i32::MIN, "sse2" sse2 (_mm_set1_epi32, _mm_cmpgt_epi32), avx2 (_mm256_set1_epi32, _mm256_cmpgt_epi32);
+    i64 as i64, bias 0, "sse4.2" sse4_2 (_mm_set1_epi64x, _mm_cmpgt_epi64), avx2 (_mm256_set1_epi64x, _mm256_cmpgt_epi64);
+    u64 as i64, bias i64::MIN, "sse4.2" sse4_2 (_mm_set1_epi64x, _mm_cmpgt_epi64), avx2 (_mm256_set1_epi64x, _mm256_cmpgt_epi64);
+    isize as i64, bias 0, "sse4.2" sse4_2 (_mm_set1_epi64x, _mm_cmpgt_epi64), avx2 (_mm256_set1_epi64x, _mm256_cmpgt_epi64);
+    usize as i64, bias i64::MIN, "sse4.2" sse4_2 (_mm_set1_epi64x, _mm_cmpgt_epi64), avx2 (_mm256_set1_epi64x, _mm256_cmpgt_epi64);
+}
@@ -0,0 +1,343 @@
+//! `serde` support, behind the `serde` feature. All the trees serialize as maps.
+//!
+//! Deserialization appends the entries with the sorted bulk build for as long as they come in
+//! increasing key order, which is how the trees serialize, and falls back to inserting them one by
+//! one from the first entry that doesn't. [`BPlusTree`](bplus_tree::BPlusTree) has no bulk build
+//! and inserts them all. Keys that are already in the tree are handled according
+//! to a [`DuplicatePolicy`].
+
+use crate::aggregate::{Aggregate, Counted, SubtreeSize};
+use crate::search::SearchStrategy;
+use crate::{bplus_tree, btree, std_btree};
+use core::fmt;
+use core::marker::PhantomData;
+use serde::de::{Error, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// What deserialization does with a key that is already in the tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// Fails the deserialization.
+    #[default]
+    Reject,
+    /// Keeps the entry that came first, and drops the later ones.
+    KeepFirst,
+    /// Keeps the entry that came last, like `BTreeMap` does.
+    KeepLast,
+}
+
+/// The operations deserialization needs from a tree.
+trait Build<K, V> {
+    fn push_sorted(&mut self, key: K, value: V) -> Result<(), (K, V)>;
+
+    fn finish_sorted(&mut self);
+
+    fn contains(&self, key: &K) -> bool;
+
+    fn insert(&mut self, key: K, value: V);
+
+    /// Whether the tree is sure to have room for one more entry.
+    fn has_room(&self) -> bool;
+}
+
+impl<K, V, const COUNTED: bool, A, S> Build<K, V> for btree::BTree<K, V, COUNTED, A, S>
+where
+    A: Aggregate<K, V>,
+    S: SearchStrategy<K, K>,
+    Counted<COUNTED>: SubtreeSize,
+{
+    #[inline]
+    fn push_sorted(&mut self, key: K, value: V) -> Result<(), (K, V)> {
+        self.push_sorted(key, value)
+    }
+
+    #[inline]
+    fn finish_sorted(&mut self) {
+        self.finish_sorted()
+    }
+
+    #[inline]
+    fn contains(&self, key: &K) -> bool {
+        self.get_entry(key).is_some()
+    }
+
+    #[inline]
+    fn insert(&mut self, key: K, value: V) {
+        self.insert(key, value);
+    }
+
+    #[inline]
+    fn has_room(&self) -> bool {
+        self.has_room()
+    }
+}
+
+impl<K, V, const COUNTED: bool, A, S> Build<K, V> for std_btree::BTree<K, V, COUNTED, A, S>
+where
+    A: Aggregate<K, V>,
+    S: SearchStrategy<K, K>,
+    Counted<COUNTED>: SubtreeSize,
+{
+    #[inline]
+    fn push_sorted(&mut self, key: K, value: V) -> Result<(), (K, V)> {
+        self.push_sorted(key, value)
+    }
+
+    #[inline]
+    fn finish_sorted(&mut self) {
+        self.finish_sorted()
+    }
+
+    #[inline]
+    fn contains(&self, key: &K) -> bool {
+        self.get_entry(key).is_some()
+    }
+
+    #[inline]
+    fn insert(&mut self, key: K, value: V) {
+        self.insert(key, value);
+    }
+
+    #[inline]
+    fn has_room(&self) -> bool {
+        true
+    }
+}
+
+impl<K, V, const COUNTED: bool, A, S> Build<K, V> for bplus_tree::BPlusTree<K, V, COUNTED, A, S>
+where
+    K: Clone,
+    A: Aggregate<K, V>,
+    S: SearchStrategy<K, K>,
+    Counted<COUNTED>: SubtreeSize,
+{
+    #[inline]
+    fn push_sorted(&mut self, key: K, value: V) -> Result<(), (K, V)> {
+        self.push_sorted(key, value)
+    }
+
+    #[inline]
+    fn finish_sorted(&mut self) {}
+
+    #[inline]
+    fn contains(&self, key: &K) -> bool {
+        self.get_entry(key).is_some()
+    }
+
+    #[inline]
+    fn insert(&mut self, key: K, value: V) {
+        self.insert(key, value);
+    }
+
+    #[inline]
+    fn has_room(&self) -> bool {
+        self.has_room()
+    }
+}
+
+/// Fills `tree`, which has to be empty, from a map.
+struct TreeVisitor<T, K, V> {
+    tree: T,
+    policy: DuplicatePolicy,
+    _entries: PhantomData<(K, V)>,
+}
+
+impl<'de, T, K, V> Visitor<'de> for TreeVisitor<T, K, V>
+where
+    T: Build<K, V>,
+    K: Deserialize<'de>,
+    V: Deserialize<'de>,
+{
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a map")
+    }
+
+    fn visit_map<M: MapAccess<'de>>(mut self, mut map: M) -> Result<T, M::Error> {
+        let mut sorted = true;
+        while let Some((mut key, mut value)) = map.next_entry()? {
+            if !self.tree.has_room() {
+                return Err(M::Error::custom("the chunk is too small for the tree"));
+            }
+            if sorted {
+                match self.tree.push_sorted(key, value) {
+                    Ok(()) => continue,
+                    Err(entry) => {
+                        self.tree.finish_sorted();
+                        sorted = false;
+                        (key, value) = entry;
+                    }
+                }
+            }
+            if self.tree.contains(&key) {
+                match self.policy {
+                    DuplicatePolicy::Reject => return Err(M::Error::custom("duplicate key")),
+                    DuplicatePolicy::KeepFirst => continue,
+                    DuplicatePolicy::KeepLast => {}
+                }
+            }
+            self.tree.insert(key, value);
+        }
+        if sorted {
+            self.tree.finish_sorted();
+        }
+        Ok(self.tree)
+    }
+}
+
+impl<K, V, const COUNTED: bool, A, S> Serialize for btree::BTree<K, V, COUNTED, A, S>
+where
+    K: Serialize,
+    V: Serialize,
+    A: Aggregate<K, V>,
+    Counted<COUNTED>: SubtreeSize,
+{
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+impl<K, V, const COUNTED: bool, A, S> btree::BTree<K, V, COUNTED, A, S>
+where
+    A: Aggregate<K, V>,
+    S: SearchStrategy<K, K> + Default,
+    Counted<COUNTED>: SubtreeSize,
+{
+    /// Deserializes a tree whose nodes are allocated from `chunk`, rejecting duplicate keys.
+    /// Fails if the chunk runs out.
+    pub fn deserialize_in<'de, D>(
+        chunk: &'static mut [u8],
+        deserializer: D,
+    ) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+        K: Deserialize<'de>,
+        V: Deserialize<'de>,
+    {
+        Self::deserialize_in_with_policy(chunk, deserializer, DuplicatePolicy::default())
+    }
+
+    /// Deserializes a tree whose nodes are allocated from `chunk`, handling duplicate keys
+    /// according to `policy`. Fails if the chunk runs out.
+    pub fn deserialize_in_with_policy<'de, D>(
+        chunk: &'static mut [u8],
+        deserializer: D,
+        policy: DuplicatePolicy,
+    ) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+        K: Deserialize<'de>,
+        V: Deserialize<'de>,
+    {
+        deserializer.deserialize_map(TreeVisitor {
+            tree: Self::new(chunk),
+            policy,
+            _entries: PhantomData,
+        })
+    }
+}
+
+impl<K, V, const COUNTED: bool, A, S> Serialize for std_btree::BTree<K, V, COUNTED, A, S>
+where
+    K: Serialize,
+    V: Serialize,
+    A: Aggregate<K, V>,
+    Counted<COUNTED>: SubtreeSize,
+{
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+/// Rejects duplicate keys, see [`std_btree::BTree::deserialize_with_policy`] for the others.
+impl<'de, K, V, const COUNTED: bool, A, S> Deserialize<'de>
+    for std_btree::BTree<K, V, COUNTED, A, S>
+where
+    K: Deserialize<'de>,
+    V: Deserialize<'de>,
+    A: Aggregate<K, V>,
+    S: SearchStrategy<K, K> + Default,
+    Counted<COUNTED>: SubtreeSize,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Self::deserialize_with_policy(deserializer, DuplicatePolicy::default())
+    }
+}
+
+impl<K, V, const COUNTED: bool, A, S> std_btree::BTree<K, V, COUNTED, A, S>
+where
+    A: Aggregate<K, V>,
+    S: SearchStrategy<K, K> + Default,
+    Counted<COUNTED>: SubtreeSize,
+{
+    /// Deserializes a tree, handling duplicate keys according to `policy`.
+    pub fn deserialize_with_policy<'de, D>(
+        deserializer: D,
+        policy: DuplicatePolicy,
+    ) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+        K: Deserialize<'de>,
+        V: Deserialize<'de>,
+    {
+        deserializer.deserialize_map(TreeVisitor {
+            tree: Self::new(),
+            policy,
+            _entries: PhantomData,
+        })
+    }
+}
+
+impl<K, V, const COUNTED: bool, A, S> Serialize for bplus_tree::BPlusTree<K, V, COUNTED, A, S>
+where
+    K: Serialize,
+    V: Serialize,
+    A: Aggregate<K, V>,
+    Counted<COUNTED>: SubtreeSize,
+{
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+impl<K, V, const COUNTED: bool, A, S> bplus_tree::BPlusTree<K, V, COUNTED, A, S>
+where
+    K: Clone,
+    A: Aggregate<K, V>,
+    S: SearchStrategy<K, K> + Default,
+    Counted<COUNTED>: SubtreeSize,
+{
+    /// Deserializes a tree whose nodes are allocated from `chunk`, rejecting duplicate keys.
+    /// Fails if the chunk runs out.
+    pub fn deserialize_in<'de, D>(
+        chunk: &'static mut [u8],
+        deserializer: D,
+    ) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+        K: Deserialize<'de>,
+        V: Deserialize<'de>,
+    {
+        Self::deserialize_in_with_policy(chunk, deserializer, DuplicatePolicy::default())
+    }
+
+    /// Deserializes a tree whose nodes are allocated from `chunk`, handling duplicate keys
+    /// according to `policy`. Fails if the chunk runs out.
+    pub fn deserialize_in_with_policy<'de, D>(
+        chunk: &'static mut [u8],
+        deserializer: D,
+        policy: DuplicatePolicy,
+    ) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+        K: Deserialize<'de>,
+        V: Deserialize<'de>,
+    {
+        deserializer.deserialize_map(TreeVisitor {
+            tree: Self::new(chunk),
+            policy,
+            _entries: PhantomData,
+        })
+    }
+}
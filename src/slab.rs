@@ -1,125 +1,828 @@
+use core::alloc::Layout;
 use core::marker::PhantomData;
 use core::mem::{self, size_of};
-use core::{fmt, ops, ptr};
+use core::sync::atomic::{self, AtomicUsize};
+use core::{fmt, iter, ops, ptr, slice};
 
-/// A slab allocator, that allocates only type T. It needs a page allocator, but it never
-/// deallocates.
+/// An allocator of slabs for `T`, which [`SlabBox`] allocates from and frees to.
+pub trait SlabAlloc<T> {
+    /// Allocates a pointer to `T`, or returns `None` if the allocator needs a new chunk.
+    fn malloc(&mut self) -> Option<ptr::NonNull<T>>;
+
+    /// Deallocates a pointer to `T`.
+    ///
+    /// # Safety
+    /// `ptr` must point to a value allocated by this allocator.
+    unsafe fn free(&mut self, ptr: ptr::NonNull<T>);
+}
+
+/// A slab allocator, that allocates only type T. It is a [`SlabCache`] with a single size class,
+/// so it lays its chunks out in pages, and gives a page back to its pool as soon as all of its
+/// slabs are freed. It never deallocates its chunks, but remembers them, so it can be reset to
+/// them in one go.
 #[derive(Debug)]
 pub struct SlabAllocator<T> {
-    free_size: usize,
-    free_list: ptr::NonNull<SlabFreeList>,
+    cache: SlabCache<1>,
     _phantom: PhantomData<T>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(C, align(8))]
-struct SlabFreeList {
-    size: usize,
-    next: Option<ptr::NonNull<SlabFreeList>>,
-}
-
 impl<T: Sized> SlabAllocator<T> {
-    const SLAB_SIZE: usize = size_of::<T>();
+    /// Creates a slab allocator with the pages of `chunk`. Pages are aligned to their size, at
+    /// least 4 KiB, so a chunk should be many pages long, like the 2 MiB chunks of a page
+    /// allocator.
+    pub fn new(chunk: &'static mut [u8]) -> Self {
+        Self {
+            cache: SlabCache::new([Layout::new::<T>()], chunk),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Adds the pages of `chunk` to the allocator.
+    pub fn add_chunk(&mut self, chunk: &'static mut [u8]) {
+        self.cache.add_chunk(chunk);
+    }
+
+    /// Returns true if the allocator needs a new chunk. To add the new chunk call `add_chunk`.
+    pub fn needs_new_chunk(&self) -> bool {
+        self.cache.needs_new_chunk()
+    }
+
+    /// The number of slabs that can still be allocated without a new chunk.
+    pub fn free_slabs(&self) -> usize {
+        self.cache.free_slabs(0)
+    }
+
+    /// Gathers the allocator's statistics, counting the unused pages as free runs too. Takes
+    /// linear time in the number of partly used pages.
+    pub fn stats(&self) -> SlabStats {
+        let mut stats = self.cache.stats(0);
+        let page_free = self.cache.slabs_per_page(0) * stats.slab_size;
+        stats.reserved += self.cache.pool_size();
+        stats.free = self.free_slabs() * stats.slab_size;
+        stats.free_runs += self.cache.free_pages();
+        if 0 < self.cache.free_pages() {
+            stats.largest_free_run = page_free;
+        }
+        stats
+    }
+
+    /// Takes a page that was never used out of the allocator, so it can be given as a chunk to
+    /// another one, see [`SlabCache::release_page`].
+    #[inline]
+    pub fn release_page(&mut self) -> Option<&'static mut [u8]> {
+        self.cache.release_page()
+    }
 
-    /// Creates a new slab allocator from a page allocator.
+    /// Frees every slab at once, in time linear in the number of chunks, see
+    /// [`SlabCache::reset`].
     ///
     /// # Safety
-    /// `chunk_addr` has to be a pointer to a chunk of 2 MiB.
-    pub fn new(chunk: &'static mut [u8]) -> Self {
+    /// None of the pointers the allocator handed out may be used or freed afterwards.
+    #[inline]
+    pub unsafe fn reset(&mut self) {
+        self.cache.reset();
+    }
+
+    /// Whether `ptr` was allocated by this allocator. Takes constant time.
+    ///
+    /// # Safety
+    /// `ptr` must point to a value allocated by a slab allocator or slab cache.
+    #[inline]
+    pub unsafe fn owns(&self, ptr: ptr::NonNull<T>) -> bool {
+        self.cache.owns(ptr.cast())
+    }
+
+    /// Allocates a pointer to `T`. Make sure to not leak this memory.
+    /// Using this function directly is not recommended, please use `SlabBox::<T>::new(slf, data)` instead.
+    #[inline]
+    pub fn malloc(&mut self) -> Option<ptr::NonNull<T>> {
+        self.cache.malloc(0).map(ptr::NonNull::cast)
+    }
+
+    /// Deallocates a pointer to `T`. Debug builds, and the `debug-alloc` feature, check that the
+    /// allocator owns it, and that it isn't free already.
+    ///
+    /// # Safety
+    /// `ptr` must point to a value allocated by this allocator.
+    #[inline]
+    pub unsafe fn free(&mut self, ptr: ptr::NonNull<T>) {
+        self.cache.free(ptr.cast(), 0);
+    }
+
+    /// Allocates up to `n` pointers to `T` into `out`, and returns how many it allocated, which
+    /// is fewer than `n` only if the allocator needs a new chunk. Takes the free slabs of a page
+    /// together, see [`SlabCache::malloc_many`].
+    #[inline]
+    pub fn malloc_many(&mut self, n: usize, out: &mut impl Extend<ptr::NonNull<T>>) -> usize {
+        self.cache
+            .malloc_many(0, n, &mut CastExtend(out, PhantomData))
+    }
+
+    /// Deallocates the pointers in `ptrs`, settling those in the same page together, see
+    /// [`SlabCache::free_many`].
+    ///
+    /// # Safety
+    /// Every pointer must point to a value allocated by this allocator.
+    #[inline]
+    pub unsafe fn free_many(&mut self, ptrs: impl IntoIterator<Item = ptr::NonNull<T>>) {
+        self.cache
+            .free_many(0, ptrs.into_iter().map(ptr::NonNull::cast));
+    }
+}
+
+/// Extends a collection of pointers to `T` with the slabs of a [`SlabCache`].
+struct CastExtend<'a, E, T>(&'a mut E, PhantomData<T>);
+
+impl<E: Extend<ptr::NonNull<T>>, T> Extend<ptr::NonNull<u8>> for CastExtend<'_, E, T> {
+    #[inline]
+    fn extend<I: IntoIterator<Item = ptr::NonNull<u8>>>(&mut self, slabs: I) {
+        self.0.extend(slabs.into_iter().map(ptr::NonNull::cast));
+    }
+}
+
+impl<T> SlabAlloc<T> for SlabAllocator<T> {
+    #[inline]
+    fn malloc(&mut self) -> Option<ptr::NonNull<T>> {
+        SlabAllocator::malloc(self)
+    }
+
+    #[inline]
+    unsafe fn free(&mut self, ptr: ptr::NonNull<T>) {
+        SlabAllocator::free(self, ptr)
+    }
+}
+
+/// The state of a [`SlabAllocator`], or of one size class of a [`SlabCache`]. Sizes are in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SlabStats {
+    /// The size of one slab, the size of `T` rounded up to its alignment.
+    pub slab_size: usize,
+    /// The bytes of the pages the allocator holds, headers included.
+    pub reserved: usize,
+    /// The bytes handed out.
+    pub in_use: usize,
+    /// The bytes of the slabs that can be handed out without a new chunk.
+    pub free: usize,
+    /// The number of pages with free slabs.
+    pub free_runs: usize,
+    /// The most free bytes in a single page.
+    pub largest_free_run: usize,
+}
+
+/// The smallest page of a [`SlabCache`].
+const MIN_PAGE_SIZE: usize = 4096;
+
+/// The fewest slabs of any size class that fit in a page of a [`SlabCache`].
+const MIN_SLABS_PER_PAGE: usize = 8;
+
+/// The words of the free bitmap in a page header, which bounds the slabs in a page. Enough for a
+/// page of the smallest size to be filled with the smallest slabs of 8 or 16 bytes.
+const BITMAP_WORDS: usize = 8;
+const MAX_SLABS_PER_PAGE: usize = BITMAP_WORDS * u64::BITS as usize;
+
+/// Whether frees are checked against the page headers. Debug builds check them too, but only
+/// `debug-alloc` poisons the slabs.
+const CHECKED: bool = cfg!(any(debug_assertions, feature = "debug-alloc"));
+
+/// With `debug-alloc`, slabs are filled with this when they are handed out, so that reads of
+/// fields that were never written stand out.
+#[cfg(feature = "debug-alloc")]
+const ALLOC_POISON: u8 = 0xa5;
+
+/// With `debug-alloc`, slabs are filled with this while they are free, and checked for it when
+/// they are handed out again, which catches writes after free.
+#[cfg(feature = "debug-alloc")]
+const FREE_POISON: u8 = 0xdd;
+
+/// Hands out the ids that pages record their owner with.
+static NEXT_OWNER: AtomicUsize = AtomicUsize::new(1);
+
+/// The header at the start of every page of a [`SlabCache`].
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Page {
+    /// The id of the cache that the page belongs to.
+    owner: usize,
+    /// The size class of the slabs in the page.
+    class: usize,
+    /// The number of slabs handed out.
+    in_use: usize,
+    /// A set bit for every free slab.
+    free: [u64; BITMAP_WORDS],
+    /// The neighbours in the list of partly used pages of the class, or in the pool.
+    prev: Option<ptr::NonNull<Page>>,
+    next: Option<ptr::NonNull<Page>>,
+    /// The record of the chunk that the page starts, which only the first page of a chunk keeps,
+    /// while the other fields change as the page is used.
+    chunk: ChunkRecord,
+}
+
+/// The pages of a chunk of a [`SlabCache`], in the header of its first page.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ChunkRecord {
+    pages: usize,
+    /// The number of pages at the end of the chunk that were never taken from the pool.
+    fresh: usize,
+    /// The first page of the chunk added before this one.
+    next: Option<ptr::NonNull<Page>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SizeClass {
+    /// The size of the class's layout, rounded up to its alignment.
+    slab_size: usize,
+    /// Where the first slab of a page starts, past the header.
+    offset: usize,
+    slabs_per_page: usize,
+    /// The free bitmap of a page whose slabs are all free.
+    all_free: [u64; BITMAP_WORDS],
+    /// The number of pages taken from the pool.
+    pages: usize,
+    /// The number of slabs handed out.
+    in_use: usize,
+    /// The pages with both used and free slabs. Full pages aren't on any list, and empty ones go
+    /// back to the pool.
+    partial: Option<ptr::NonNull<Page>>,
+}
+
+impl SizeClass {
+    #[inline]
+    fn free_slabs(&self) -> usize {
+        self.pages * self.slabs_per_page - self.in_use
+    }
+}
+
+/// A slab allocator with `N` size classes, which share a pool of pages. A class takes a page from
+/// the pool when its pages are full, and gives it back as soon as all of its slabs are freed, so
+/// every class can grow into the room the others leave, whatever the mix of allocations is.
+///
+/// Pages are aligned to their size, which is a power of two, so the header of the page a slab is
+/// in can be found from the slab's address. The header records the cache that owns the page and
+/// which of its slabs are free, which debug builds, and the `debug-alloc` feature, check every
+/// freed slab against.
+///
+/// It never deallocates its chunks, but keeps a record of each in the header of its first page.
+/// The pool hands out the pages that were given back to it first, and then carves the chunks
+/// front to back, so adding a chunk, and [`reset`](Self::reset) which makes all of its pages
+/// fresh again, take time linear in the number of chunks, not pages.
+#[derive(Debug)]
+pub struct SlabCache<const N: usize> {
+    /// The id that the pages of the cache record as their owner.
+    id: usize,
+    page_size: usize,
+    classes: [SizeClass; N],
+    /// The pages that classes gave back.
+    pool: Option<ptr::NonNull<Page>>,
+    /// The pages in the pool, and those never taken from the chunks.
+    pool_pages: usize,
+    /// The first page of the last chunk added.
+    chunks: Option<ptr::NonNull<Page>>,
+    /// The first chunk that may still have fresh pages. The ones added after it have none.
+    carving: Option<ptr::NonNull<Page>>,
+}
+
+impl<const N: usize> SlabCache<N> {
+    /// Creates a cache with a size class for each of `classes`, and the pages of `chunk`. The
+    /// slabs of a class are aligned to its layout's alignment, however large: pages are aligned to
+    /// their size, and the first slab starts at a multiple of the alignment past the header.
+    pub fn new(classes: [Layout; N], chunk: &'static mut [u8]) -> Self {
+        let header = size_of::<Page>();
+        let classes = classes.map(|layout| {
+            let slab_size = layout.size().max(1).next_multiple_of(layout.align());
+            (header.next_multiple_of(layout.align()), slab_size)
+        });
+        let page_size = classes
+            .iter()
+            .map(|&(offset, slab_size)| offset + MIN_SLABS_PER_PAGE * slab_size)
+            .fold(MIN_PAGE_SIZE, usize::max)
+            .next_power_of_two();
+
+        let mut slf = Self {
+            id: NEXT_OWNER.fetch_add(1, atomic::Ordering::Relaxed),
+            page_size,
+            classes: classes.map(|(offset, slab_size)| {
+                let slabs_per_page = ((page_size - offset) / slab_size).min(MAX_SLABS_PER_PAGE);
+                SizeClass {
+                    slab_size,
+                    offset,
+                    slabs_per_page,
+                    all_free: core::array::from_fn(|word| {
+                        let bits = slabs_per_page.saturating_sub(word * 64).min(64);
+                        u64::MAX.checked_shr(64 - bits as u32).unwrap_or(0)
+                    }),
+                    pages: 0,
+                    in_use: 0,
+                    partial: None,
+                }
+            }),
+            pool: None,
+            pool_pages: 0,
+            chunks: None,
+            carving: None,
+        };
+        slf.add_chunk(chunk);
+        slf
+    }
+
+    /// Adds the pages of `chunk` to the pool. The part of the chunk before its first aligned page
+    /// and after its last full page is left unused. Takes constant time: the pages are only
+    /// written to when they are taken from the pool.
+    pub fn add_chunk(&mut self, chunk: &'static mut [u8]) {
+        let skip = chunk.as_ptr().align_offset(self.page_size).min(chunk.len());
+        let pages = (chunk.len() - skip) / self.page_size;
+        if pages == 0 {
+            return;
+        }
+        let start = unsafe { chunk.as_mut_ptr().add(skip) };
+        start.expose_provenance();
+        let first = unsafe { ptr::NonNull::new_unchecked(start.cast::<Page>()) };
         unsafe {
-            assert_eq!(size_of::<SlabFreeList>(), 16);
+            ptr::addr_of_mut!((*first.as_ptr()).chunk).write(ChunkRecord {
+                pages,
+                fresh: pages,
+                next: self.chunks,
+            })
+        };
+        self.chunks = Some(first);
+        self.carving = Some(first);
+        self.pool_pages += pages;
+    }
 
-            assert!(
-                16 <= Self::SLAB_SIZE,
-                "Slab allocator's type T size, {} bytes, is smaller than 16 bytes",
-                Self::SLAB_SIZE,
-            );
-            assert_eq!(Self::SLAB_SIZE & 7, 0);
+    /// The size of the pages, a power of two.
+    #[inline]
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
 
-            let free_size = chunk.len() - chunk.len() % Self::SLAB_SIZE;
-            Self {
-                free_size,
-                free_list: {
-                    let mut free_list = ptr::NonNull::new(chunk.as_mut_ptr() as _).unwrap();
-                    *free_list.as_mut() = SlabFreeList {
-                        size: free_size,
-                        next: None,
-                    };
-                    free_list
-                },
-                _phantom: PhantomData,
+    /// The number of slabs of `class` in a page.
+    #[inline]
+    pub fn slabs_per_page(&self, class: usize) -> usize {
+        self.classes[class].slabs_per_page
+    }
+
+    /// The number of pages in the pool, which any class can take.
+    #[inline]
+    pub fn free_pages(&self) -> usize {
+        self.pool_pages
+    }
+
+    /// The number of slabs of `class` that can be allocated without a new chunk, if no other
+    /// class takes pages from the pool in the meantime.
+    #[inline]
+    pub fn free_slabs(&self, class: usize) -> usize {
+        let class = &self.classes[class];
+        class.free_slabs() + self.pool_pages * class.slabs_per_page
+    }
+
+    /// Whether `slabs[class]` slabs of every class can be allocated together without a new
+    /// chunk.
+    pub fn has_room(&self, slabs: [usize; N]) -> bool {
+        let pages = self.classes.iter().zip(slabs).map(|(class, slabs)| {
+            slabs
+                .saturating_sub(class.free_slabs())
+                .div_ceil(class.slabs_per_page)
+        });
+        pages.sum::<usize>() <= self.pool_pages
+    }
+
+    /// Returns true if any class can allocate fewer than 64 slabs without a new chunk. To add the
+    /// new chunk call `add_chunk`.
+    #[inline]
+    pub fn needs_new_chunk(&self) -> bool {
+        !self.has_room([64; N])
+    }
+
+    /// Walks the partly used pages of `class` to gather its statistics. Its reserved bytes are
+    /// those of the pages it took from the pool. Takes linear time in the number of partly used
+    /// pages.
+    pub fn stats(&self, class: usize) -> SlabStats {
+        let class = &self.classes[class];
+        let mut stats = SlabStats {
+            slab_size: class.slab_size,
+            reserved: class.pages * self.page_size,
+            in_use: class.in_use * class.slab_size,
+            free: class.free_slabs() * class.slab_size,
+            free_runs: 0,
+            largest_free_run: 0,
+        };
+        let mut page = class.partial;
+        while let Some(partial) = page {
+            let Page { in_use, next, .. } = unsafe { *partial.as_ptr() };
+            stats.free_runs += 1;
+            stats.largest_free_run = stats
+                .largest_free_run
+                .max((class.slabs_per_page - in_use) * class.slab_size);
+            page = next;
+        }
+        stats
+    }
+
+    /// The bytes of the pages in the pool.
+    #[inline]
+    pub fn pool_size(&self) -> usize {
+        self.pool_pages * self.page_size
+    }
+
+    /// Takes a page that was never used off the end of a chunk, so it can be given as a chunk to
+    /// another allocator. Pages that were used are kept, as is the first page of every chunk,
+    /// which records it.
+    pub fn release_page(&mut self) -> Option<&'static mut [u8]> {
+        let mut chunk = self.carving;
+        while let Some(first) = chunk {
+            unsafe {
+                let record = &mut (*first.as_ptr()).chunk;
+                if record.fresh != 0 && 1 < record.pages {
+                    record.fresh -= 1;
+                    record.pages -= 1;
+                    self.pool_pages -= 1;
+                    let page = first.byte_add(record.pages * self.page_size);
+                    return Some(slice::from_raw_parts_mut(
+                        page.as_ptr().cast(),
+                        self.page_size,
+                    ));
+                }
+                chunk = record.next;
             }
         }
+        None
     }
 
-    /// Allocates a pointer to `T`.
-    pub fn add_chunk(&mut self, chunk: &'static mut [u8]) {
+    /// Frees every slab of every class at once, by making all the pages of the chunks fresh
+    /// again, in time linear in the number of chunks. With `debug-alloc`, the pages are poisoned
+    /// again as they are taken from the pool.
+    ///
+    /// # Safety
+    /// None of the slabs the cache handed out may be used or freed afterwards.
+    pub unsafe fn reset(&mut self) {
+        self.pool = None;
+        self.pool_pages = 0;
+        let mut chunk = self.chunks;
+        while let Some(first) = chunk {
+            let record = &mut (*first.as_ptr()).chunk;
+            record.fresh = record.pages;
+            self.pool_pages += record.pages;
+            chunk = record.next;
+        }
+        self.carving = self.chunks;
+        for class in &mut self.classes {
+            class.pages = 0;
+            class.in_use = 0;
+            class.partial = None;
+        }
+    }
+
+    /// Whether `ptr` was allocated by this cache. Takes constant time.
+    ///
+    /// # Safety
+    /// `ptr` must point to a slab allocated by a slab allocator or slab cache.
+    #[inline]
+    pub unsafe fn owns(&self, ptr: ptr::NonNull<u8>) -> bool {
+        (*self.page_of(ptr).as_ptr()).owner == self.id
+    }
+
+    /// The size class of the slab `ptr` points to, as its page header records it. Takes constant
+    /// time.
+    ///
+    /// # Safety
+    /// `ptr` must point to a slab allocated by this cache.
+    #[inline]
+    pub unsafe fn class_of(&self, ptr: ptr::NonNull<u8>) -> usize {
+        (*self.page_of(ptr).as_ptr()).class
+    }
+
+    /// Whether `ptr` points into the pages of one of the cache's chunks. Unlike
+    /// [`owns`](Self::owns) it takes any pointer, but takes time linear in the number of chunks.
+    pub fn contains(&self, ptr: ptr::NonNull<u8>) -> bool {
+        let addr = ptr.as_ptr().addr();
+        let mut chunk = self.chunks;
+        while let Some(first) = chunk {
+            let record = unsafe { &(*first.as_ptr()).chunk };
+            let start = first.as_ptr().addr();
+            if (start..start + record.pages * self.page_size).contains(&addr) {
+                return true;
+            }
+            chunk = record.next;
+        }
+        false
+    }
+
+    /// Allocates a slab of `class`, taking a page from the pool if the class has no free slabs.
+    pub fn malloc(&mut self, class: usize) -> Option<ptr::NonNull<u8>> {
         unsafe {
-            let alloc_size = chunk.len() - chunk.len() % Self::SLAB_SIZE;
+            let page = self.partial_page(class)?;
+            let header = page.as_ptr();
+            let (word, bits) = (*header)
+                .free
+                .iter_mut()
+                .enumerate()
+                .find(|(_, bits)| **bits != 0)
+                .unwrap();
+            let bit = bits.trailing_zeros() as usize;
+            *bits &= *bits - 1;
+            let slab = self.hand_out(page, class, word * 64 + bit);
+            self.taken(class, page, 1);
+            Some(slab)
+        }
+    }
 
-            self.free_size += alloc_size;
-            let mut free_list = ptr::NonNull::new(chunk.as_mut_ptr() as _).unwrap();
-            *free_list.as_mut() = SlabFreeList {
-                size: alloc_size,
-                next: Some(self.free_list),
+    /// Allocates up to `n` slabs of `class` into `out`, taking pages from the pool as needed, and
+    /// returns how many it allocated, which is fewer than `n` only if the cache needs a new chunk.
+    /// The free slabs of a page are taken a bitmap word at a time, and the counters and lists are
+    /// updated once per page instead of once per slab.
+    pub fn malloc_many(
+        &mut self,
+        class: usize,
+        n: usize,
+        out: &mut impl Extend<ptr::NonNull<u8>>,
+    ) -> usize {
+        let mut allocated = 0;
+        while allocated < n {
+            let Some(page) = self.partial_page(class) else {
+                break;
             };
-            self.free_list = free_list;
+            let header = page.as_ptr();
+            let mut taken = 0;
+            for word in 0..BITMAP_WORDS {
+                let wanted = n - allocated - taken;
+                if wanted == 0 {
+                    break;
+                }
+                let bits = unsafe { (*header).free[word] };
+                let mut rest = 0;
+                if wanted < bits.count_ones() as usize {
+                    rest = bits;
+                    for _ in 0..wanted {
+                        rest &= rest - 1;
+                    }
+                }
+                unsafe { (*header).free[word] = rest };
+                let mut take = bits ^ rest;
+                taken += take.count_ones() as usize;
+                out.extend(iter::from_fn(|| {
+                    let bit = take.trailing_zeros() as usize;
+                    take &= take.checked_sub(1)?;
+                    Some(unsafe { self.hand_out(page, class, word * 64 + bit) })
+                }));
+            }
+            unsafe { self.taken(class, page, taken) };
+            allocated += taken;
         }
+        allocated
     }
 
-    /// Returns true if the allocator needs a new chunk. To add the new chunk call `add_chunk`.
-    pub fn needs_new_chunk(&self) -> bool {
-        self.free_size < 64 * Self::SLAB_SIZE
+    /// Deallocates a slab of `class`, and gives its page back to the pool if it was the last one
+    /// used. Debug builds, and the `debug-alloc` feature, check that the cache owns the slab, and
+    /// that it isn't free already.
+    ///
+    /// # Safety
+    /// `ptr` must point to a slab of `class` allocated by this cache.
+    pub unsafe fn free(&mut self, ptr: ptr::NonNull<u8>, class: usize) {
+        let page = self.mark_free(ptr, class);
+        self.returned(class, page, 1);
     }
 
-    /// Allocates a pointer to `T`. Make sure to not leak this memory.
-    /// Using this function directly is not recommended, please use `SlabBox::<T>::new(slf, data)` instead.
-    pub fn malloc(&mut self) -> Option<ptr::NonNull<T>> {
+    /// Deallocates the slabs of `class` in `slabs`, with the same checks as `free`. Slabs that
+    /// follow each other in the same page are settled together, so the counters and lists are
+    /// updated once per run of them instead of once per slab.
+    ///
+    /// # Safety
+    /// Every slab must be a slab of `class` allocated by this cache.
+    pub unsafe fn free_many(
+        &mut self,
+        class: usize,
+        slabs: impl IntoIterator<Item = ptr::NonNull<u8>>,
+    ) {
+        let mut run: Option<(ptr::NonNull<Page>, usize)> = None;
+        for ptr in slabs {
+            let page = self.mark_free(ptr, class);
+            match &mut run {
+                Some((run_page, freed)) if *run_page == page => *freed += 1,
+                _ => {
+                    if let Some((run_page, freed)) = run.replace((page, 1)) {
+                        self.returned(class, run_page, freed);
+                    }
+                }
+            }
+        }
+        if let Some((page, freed)) = run {
+            self.returned(class, page, freed);
+        }
+    }
+
+    /// A page of `class` with free slabs, taken from the pool if the class has none.
+    fn partial_page(&mut self, class: usize) -> Option<ptr::NonNull<Page>> {
+        if let Some(page) = self.classes[class].partial {
+            return Some(page);
+        }
+        let page = self.take_page()?;
         unsafe {
-            let SlabFreeList { size, next } = *self.free_list.as_mut();
-            if Self::SLAB_SIZE < size {
-                let ptr = ptr::NonNull::new(self.free_list.as_ptr() as _)?;
-                self.free_list =
-                    ptr::NonNull::new((self.free_list.as_ptr() as usize + Self::SLAB_SIZE) as _)
-                        .unwrap();
-                *self.free_list.as_mut() = SlabFreeList {
-                    size: size - Self::SLAB_SIZE,
-                    next,
-                };
-                self.free_size -= Self::SLAB_SIZE;
-
-                Some(ptr)
-            } else if Self::SLAB_SIZE == size {
-                let ptr = ptr::NonNull::new(self.free_list.as_ptr() as _)?;
-                self.free_list = next?;
-                self.free_size -= Self::SLAB_SIZE;
-
-                Some(ptr)
-            } else {
-                log::error!("Slab allocator free area is too small");
-                self.free_list = next?;
-                self.free_size -= size;
-
-                self.malloc()
+            let header = page.as_ptr();
+            (*header).owner = self.id;
+            (*header).class = class;
+            (*header).in_use = 0;
+            (*header).free = self.classes[class].all_free;
+            self.classes[class].pages += 1;
+            self.link(class, page);
+            #[cfg(feature = "debug-alloc")]
+            {
+                let SizeClass {
+                    offset,
+                    slab_size,
+                    slabs_per_page,
+                    ..
+                } = self.classes[class];
+                let slabs = page.byte_add(offset).cast::<u8>();
+                slabs.write_bytes(FREE_POISON, slabs_per_page * slab_size);
+            }
+        }
+        Some(page)
+    }
+
+    /// The slab at `index` in `page`, whose free bit was just cleared. With `debug-alloc`, checks
+    /// that it wasn't written while it was free, and poisons it.
+    #[inline]
+    unsafe fn hand_out(
+        &self,
+        page: ptr::NonNull<Page>,
+        class: usize,
+        index: usize,
+    ) -> ptr::NonNull<u8> {
+        let size_class = &self.classes[class];
+        let slab = page
+            .byte_add(size_class.offset + index * size_class.slab_size)
+            .cast();
+        #[cfg(feature = "debug-alloc")]
+        {
+            let bytes = slice::from_raw_parts_mut(slab.as_ptr(), size_class.slab_size);
+            if let Some(i) = bytes.iter().position(|&byte| byte != FREE_POISON) {
+                panic!(
+                    "slab at {slab:p} of size class {class} was written at offset {i} after it \
+                     was freed"
+                );
+            }
+            bytes.fill(ALLOC_POISON);
+        }
+        #[cfg(not(feature = "debug-alloc"))]
+        let _ = class;
+        slab
+    }
+
+    /// Counts `taken` slabs handed out of `page`, and takes it off the partly used pages if it is
+    /// full.
+    #[inline]
+    unsafe fn taken(&mut self, class: usize, page: ptr::NonNull<Page>, taken: usize) {
+        let header = page.as_ptr();
+        (*header).in_use += taken;
+        self.classes[class].in_use += taken;
+        if (*header).in_use == self.classes[class].slabs_per_page {
+            self.unlink(class, page);
+        }
+    }
+
+    /// Sets the free bit of the slab at `ptr`, after checking it, and returns its page.
+    #[inline]
+    unsafe fn mark_free(&mut self, ptr: ptr::NonNull<u8>, class: usize) -> ptr::NonNull<Page> {
+        let page = self.page_of(ptr);
+        let header = page.as_ptr();
+        let size_class = &self.classes[class];
+        let offset = (ptr.as_ptr() as usize % self.page_size).wrapping_sub(size_class.offset);
+        let slab = offset / size_class.slab_size;
+        let (word, bit) = (slab / 64, 1 << (slab % 64));
+        if CHECKED {
+            assert_eq!(
+                (*header).owner,
+                self.id,
+                "slab freed to a cache that doesn't own it"
+            );
+            assert_eq!((*header).class, class, "slab freed to the wrong size class");
+            assert!(
+                offset.is_multiple_of(size_class.slab_size) && slab < size_class.slabs_per_page,
+                "pointer to {ptr:p} isn't to the start of a slab"
+            );
+            assert_eq!((*header).free[word] & bit, 0, "slab freed twice");
+        }
+        #[cfg(feature = "debug-alloc")]
+        ptr.write_bytes(FREE_POISON, size_class.slab_size);
+        (*header).free[word] |= bit;
+        page
+    }
+
+    /// Counts `freed` slabs given back to `page`, which puts it back on the partly used pages if
+    /// it was full, or in the pool if none of its slabs are used anymore.
+    #[inline]
+    unsafe fn returned(&mut self, class: usize, page: ptr::NonNull<Page>, freed: usize) {
+        let header = page.as_ptr();
+        let was_full = (*header).in_use == self.classes[class].slabs_per_page;
+        let in_use = (*header).in_use - freed;
+        (*header).in_use = in_use;
+        self.classes[class].in_use -= freed;
+        if in_use == 0 {
+            if !was_full {
+                self.unlink(class, page);
             }
+            self.classes[class].pages -= 1;
+            self.give_page(page);
+        } else if was_full {
+            self.link(class, page);
         }
     }
 
-    /// Deallocates a pointer to `T`;
+    /// The header of the page that `ptr` is in. The header isn't reached through `ptr`, whose
+    /// provenance may be narrowed to its slab, like that of a pointer that went through a `Box`,
+    /// but through the provenance of the chunk, which `add_chunk` exposed.
+    #[inline]
+    fn page_of(&self, ptr: ptr::NonNull<u8>) -> ptr::NonNull<Page> {
+        let addr = ptr.as_ptr().addr();
+        let page = ptr::with_exposed_provenance_mut::<Page>(addr - addr % self.page_size);
+        unsafe { ptr::NonNull::new_unchecked(page) }
+    }
+
+    /// Takes a page that was given back to the pool, or else the next fresh page of a chunk,
+    /// whose header is only initialized if it is the first page.
+    #[inline]
+    fn take_page(&mut self) -> Option<ptr::NonNull<Page>> {
+        if let Some(page) = self.pool {
+            self.pool = unsafe { (*page.as_ptr()).next };
+            self.pool_pages -= 1;
+            return Some(page);
+        }
+        loop {
+            let first = self.carving?;
+            unsafe {
+                let record = &mut (*first.as_ptr()).chunk;
+                if record.fresh != 0 {
+                    let page = record.pages - record.fresh;
+                    record.fresh -= 1;
+                    self.pool_pages -= 1;
+                    return Some(first.byte_add(page * self.page_size));
+                }
+                self.carving = record.next;
+            }
+        }
+    }
+
+    /// Puts a page back in the pool. Its fields but the chunk record are overwritten.
     ///
     /// # Safety
-    /// `ptr` must point to a value allocated by a slab allocator.
-    pub unsafe fn free(&mut self, ptr: ptr::NonNull<T>) {
-        let free_list = self.free_list;
-        self.free_list = ptr::NonNull::new(ptr.as_ptr() as _).unwrap();
-        *self.free_list.as_mut() = SlabFreeList {
-            size: Self::SLAB_SIZE,
-            next: Some(free_list),
-        };
-        self.free_size += Self::SLAB_SIZE;
+    /// `page` must be a page of a chunk of this cache, with no slabs in use.
+    #[inline]
+    unsafe fn give_page(&mut self, page: ptr::NonNull<Page>) {
+        let header = page.as_ptr();
+        (*header).class = usize::MAX;
+        (*header).in_use = 0;
+        (*header).free = [0; BITMAP_WORDS];
+        (*header).prev = None;
+        (*header).next = self.pool;
+        self.pool = Some(page);
+        self.pool_pages += 1;
+    }
+
+    /// Pushes `page` onto the partly used pages of `class`.
+    #[inline]
+    unsafe fn link(&mut self, class: usize, page: ptr::NonNull<Page>) {
+        let next = self.classes[class].partial;
+        if let Some(next) = next {
+            (*next.as_ptr()).prev = Some(page);
+        }
+        (*page.as_ptr()).prev = None;
+        (*page.as_ptr()).next = next;
+        self.classes[class].partial = Some(page);
+    }
+
+    /// Removes `page` from the partly used pages of `class`.
+    #[inline]
+    unsafe fn unlink(&mut self, class: usize, page: ptr::NonNull<Page>) {
+        let Page { prev, next, .. } = *page.as_ptr();
+        match prev {
+            Some(prev) => (*prev.as_ptr()).next = next,
+            None => self.classes[class].partial = next,
+        }
+        if let Some(next) = next {
+            (*next.as_ptr()).prev = prev;
+        }
+    }
+}
+
+/// Reports the slabs that are still in use, which the allocator's users leaked.
+#[cfg(feature = "debug-alloc")]
+impl<const N: usize> Drop for SlabCache<N> {
+    fn drop(&mut self) {
+        for (i, class) in self.classes.iter().enumerate() {
+            if class.in_use != 0 {
+                log::error!(
+                    "{} slabs of {} bytes in size class {i} leaked",
+                    class.in_use,
+                    class.slab_size,
+                );
+            }
+        }
     }
 }
 
+unsafe impl<const N: usize> Send for SlabCache<N> {}
+unsafe impl<const N: usize> Sync for SlabCache<N> {}
+
 /// Represents a box allocated by a slab allocator.
 #[repr(transparent)]
 pub struct SlabBox<T> {
@@ -130,7 +833,7 @@ pub struct SlabBox<T> {
 impl<T> SlabBox<T> {
     /// Allocates the box from the given slab allocator and moves x to it.
     #[inline]
-    pub fn new(alloc: &mut SlabAllocator<T>, x: T) -> Self {
+    pub fn new(alloc: &mut impl SlabAlloc<T>, x: T) -> Self {
         unsafe {
             let ptr = alloc.malloc().expect("Failed to allocate");
             ptr.cast::<mem::MaybeUninit<T>>().as_mut().write(x);
@@ -143,7 +846,7 @@ impl<T> SlabBox<T> {
 
     /// Allocates the box from the given slab allocator and moves x to it.
     #[inline]
-    pub fn uninit(alloc: &mut SlabAllocator<T>) -> SlabBox<mem::MaybeUninit<T>> {
+    pub fn uninit(alloc: &mut impl SlabAlloc<T>) -> SlabBox<mem::MaybeUninit<T>> {
         SlabBox {
             ptr: alloc.malloc().expect("Failed to allocate").cast(),
             phantom: PhantomData,
@@ -160,11 +863,10 @@ impl<T> SlabBox<T> {
         self.ptr.as_ptr()
     }
 
-    /// Frees the allocation with the given allocator. This allocator doesn't have to be the same
-    /// allocator that was used to allocate this box, but it's not recommended to use a different
-    /// allocator or multiple slab allocators of the same type in general.
+    /// Frees the allocation with the given allocator, which has to be the one that allocated the
+    /// box. Debug builds, and the `debug-alloc` feature, check that it owns the box.
     #[inline]
-    pub fn free(self, alloc: &mut SlabAllocator<T>) {
+    pub fn free(self, alloc: &mut impl SlabAlloc<T>) {
         unsafe {
             let mut md = mem::ManuallyDrop::new(self);
             md.as_mut_ptr().drop_in_place();
@@ -174,7 +876,7 @@ impl<T> SlabBox<T> {
 
     /// Does the same thing as `free` but without dropping the data inside.
     #[inline]
-    pub fn free_forget(self, alloc: &mut SlabAllocator<T>) {
+    pub fn free_forget(self, alloc: &mut impl SlabAlloc<T>) {
         unsafe {
             let md = mem::ManuallyDrop::new(self);
             alloc.free(md.ptr);
@@ -183,7 +885,7 @@ impl<T> SlabBox<T> {
 
     /// Does the same thing as `free` but moves the data and returns it.
     #[inline]
-    pub fn free_move(self, alloc: &mut SlabAllocator<T>) -> T {
+    pub fn free_move(self, alloc: &mut impl SlabAlloc<T>) -> T {
         let x;
         unsafe {
             let md = mem::ManuallyDrop::new(self);
@@ -199,8 +901,15 @@ impl<T> SlabBox<T> {
         unsafe { &mut *md.as_mut_ptr() }
     }
 
+    /// Gives up the box without freeing it, to be freed later through the pointer, e.g. with
+    /// others by `free_many`.
+    #[inline]
+    pub fn into_raw(self) -> ptr::NonNull<T> {
+        mem::ManuallyDrop::new(self).ptr
+    }
+
     #[inline]
-    pub fn clone(&self, alloc: &mut SlabAllocator<T>) -> Self
+    pub fn clone(&self, alloc: &mut impl SlabAlloc<T>) -> Self
     where
         T: Clone,
     {
@@ -214,7 +923,7 @@ unsafe impl<T: Sync> Sync for SlabAllocator<T> {}
 impl<T> AsRef<T> for SlabBox<T> {
     #[inline]
     fn as_ref(&self) -> &T {
-        &*self
+        self
     }
 }
 
@@ -263,6 +972,8 @@ impl<T: fmt::Debug> fmt::Debug for SlabBox<T> {
 }
 
 impl<T> SlabBox<mem::MaybeUninit<T>> {
+    /// # Safety
+    /// The value inside the box must be fully initialized.
     pub unsafe fn assume_init(self) -> SlabBox<T> {
         let md = mem::ManuallyDrop::new(self);
         SlabBox {
@@ -275,19 +986,86 @@ impl<T> SlabBox<mem::MaybeUninit<T>> {
 unsafe impl<T: Send> Send for SlabBox<T> {}
 unsafe impl<T: Sync> Sync for SlabBox<T> {}
 
+/// A slab allocator that threads can share, like [`LockedSlabAllocator`], which
+/// [`LockedSlabBox`] allocates from and frees to.
+pub trait SharedSlabAlloc<T> {
+    /// Allocates a pointer to `T`, or returns `None` if the allocator needs a new chunk.
+    fn malloc(&self) -> Option<ptr::NonNull<T>>;
+
+    /// Deallocates a pointer to `T`.
+    ///
+    /// # Safety
+    /// `ptr` must point to a value allocated by this allocator.
+    unsafe fn free(&self, ptr: ptr::NonNull<T>);
+}
+
+impl<T, A: SharedSlabAlloc<T> + ?Sized> SlabAlloc<T> for &A {
+    #[inline]
+    fn malloc(&mut self) -> Option<ptr::NonNull<T>> {
+        A::malloc(self)
+    }
+
+    #[inline]
+    unsafe fn free(&mut self, ptr: ptr::NonNull<T>) {
+        A::free(self, ptr)
+    }
+}
+
+/// A [`SlabAllocator`] behind a spin lock.
 pub struct LockedSlabAllocator<T>(spin::Mutex<SlabAllocator<T>>);
 
-pub struct LockedSlabBox<'a, T> {
+impl<T> LockedSlabAllocator<T> {
+    #[inline]
+    pub fn new(chunk: &'static mut [u8]) -> Self {
+        Self(spin::Mutex::new(SlabAllocator::new(chunk)))
+    }
+
+    #[inline]
+    pub fn add_chunk(&self, chunk: &'static mut [u8]) {
+        self.0.lock().add_chunk(chunk);
+    }
+
+    #[inline]
+    pub fn needs_new_chunk(&self) -> bool {
+        self.0.lock().needs_new_chunk()
+    }
+
+    #[inline]
+    pub fn stats(&self) -> SlabStats {
+        self.0.lock().stats()
+    }
+
+    /// Locks the allocator, to allocate or free many slabs at once.
+    #[inline]
+    pub fn lock(&self) -> spin::MutexGuard<'_, SlabAllocator<T>> {
+        self.0.lock()
+    }
+}
+
+impl<T> SharedSlabAlloc<T> for LockedSlabAllocator<T> {
+    #[inline]
+    fn malloc(&self) -> Option<ptr::NonNull<T>> {
+        self.0.lock().malloc()
+    }
+
+    #[inline]
+    unsafe fn free(&self, ptr: ptr::NonNull<T>) {
+        self.0.lock().free(ptr)
+    }
+}
+
+/// A box allocated by a shared slab allocator, which it frees itself to when it is dropped.
+pub struct LockedSlabBox<'a, T, A: SharedSlabAlloc<T> = LockedSlabAllocator<T>> {
     data: Option<SlabBox<T>>,
-    alloc: &'a LockedSlabAllocator<T>,
+    alloc: &'a A,
 }
 
-impl<'a, T> LockedSlabBox<'a, T> {
+impl<'a, T, A: SharedSlabAlloc<T>> LockedSlabBox<'a, T, A> {
     /// Allocates the box from the given slab allocator and moves x to it.
     #[inline]
-    pub fn new(alloc: &'a LockedSlabAllocator<T>, x: T) -> Self {
+    pub fn new(mut alloc: &'a A, x: T) -> Self {
         Self {
-            data: Some(SlabBox::new(&mut alloc.0.lock(), x)),
+            data: Some(SlabBox::new(&mut alloc, x)),
             alloc,
         }
     }
@@ -320,30 +1098,22 @@ impl<'a, T> LockedSlabBox<'a, T> {
         self.data_mut().as_mut_ptr()
     }
 
-    /// Frees the allocation with the given allocator. This allocator doesn't have to be the same
-    /// allocator that was used to allocate this box, but it's not recommended to use a different
-    /// allocator or multiple slab allocators of the same type in general.
+    /// Frees the allocation with the allocator that allocated the box.
     #[inline]
     pub fn free(mut self) {
-        self.data.take().unwrap().free(&mut self.alloc.0.lock());
+        self.data.take().unwrap().free(&mut self.alloc);
     }
 
     /// Does the same thing as `free` but without dropping the data inside.
     #[inline]
     pub fn free_forget(mut self) {
-        self.data
-            .take()
-            .unwrap()
-            .free_forget(&mut self.alloc.0.lock());
+        self.data.take().unwrap().free_forget(&mut self.alloc);
     }
 
     /// Does the same thing as `free` but moves the data and returns it.
     #[inline]
     pub fn free_move(mut self) -> T {
-        self.data
-            .take()
-            .unwrap()
-            .free_move(&mut self.alloc.0.lock())
+        self.data.take().unwrap().free_move(&mut self.alloc)
     }
 
     #[inline]
@@ -352,10 +1122,10 @@ impl<'a, T> LockedSlabBox<'a, T> {
     }
 }
 
-impl<'a, T: Clone> Clone for LockedSlabBox<'a, T> {
+impl<'a, T: Clone, A: SharedSlabAlloc<T>> Clone for LockedSlabBox<'a, T, A> {
     fn clone(&self) -> Self {
         Self {
-            data: Some(self.data().clone(&mut self.alloc.0.lock())),
+            data: Some(self.data().clone(&mut { self.alloc })),
             alloc: self.alloc,
         }
     }
@@ -364,21 +1134,21 @@ impl<'a, T: Clone> Clone for LockedSlabBox<'a, T> {
 unsafe impl<T: Send> Send for LockedSlabAllocator<T> {}
 unsafe impl<T: Sync> Sync for LockedSlabAllocator<T> {}
 
-impl<'a, T> AsRef<T> for LockedSlabBox<'a, T> {
+impl<'a, T, A: SharedSlabAlloc<T>> AsRef<T> for LockedSlabBox<'a, T, A> {
     #[inline]
     fn as_ref(&self) -> &T {
         self.data()
     }
 }
 
-impl<'a, T> AsMut<T> for LockedSlabBox<'a, T> {
+impl<'a, T, A: SharedSlabAlloc<T>> AsMut<T> for LockedSlabBox<'a, T, A> {
     #[inline]
     fn as_mut(&mut self) -> &mut T {
         self.data_mut()
     }
 }
 
-impl<'a, T> ops::Deref for LockedSlabBox<'a, T> {
+impl<'a, T, A: SharedSlabAlloc<T>> ops::Deref for LockedSlabBox<'a, T, A> {
     type Target = T;
 
     #[inline]
@@ -387,33 +1157,35 @@ impl<'a, T> ops::Deref for LockedSlabBox<'a, T> {
     }
 }
 
-impl<'a, T> ops::DerefMut for LockedSlabBox<'a, T> {
+impl<'a, T, A: SharedSlabAlloc<T>> ops::DerefMut for LockedSlabBox<'a, T, A> {
     #[inline]
     fn deref_mut(&mut self) -> &mut T {
         self.data_mut()
     }
 }
 
-impl<'a, T> Drop for LockedSlabBox<'a, T> {
+impl<'a, T, A: SharedSlabAlloc<T>> Drop for LockedSlabBox<'a, T, A> {
     #[inline]
     fn drop(&mut self) {
-        self.data.take().unwrap().free(&mut self.alloc.0.lock());
+        if let Some(data) = self.data.take() {
+            data.free(&mut self.alloc);
+        }
     }
 }
 
-impl<'a, T: fmt::Display> fmt::Display for LockedSlabBox<'a, T> {
+impl<'a, T: fmt::Display, A: SharedSlabAlloc<T>> fmt::Display for LockedSlabBox<'a, T, A> {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         self.as_ref().fmt(f)
     }
 }
 
-impl<'a, T: fmt::Debug> fmt::Debug for LockedSlabBox<'a, T> {
+impl<'a, T: fmt::Debug, A: SharedSlabAlloc<T>> fmt::Debug for LockedSlabBox<'a, T, A> {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         self.as_ref().fmt(f)
     }
 }
 
-unsafe impl<'a, T: Send> Send for LockedSlabBox<'a, T> {}
-unsafe impl<'a, T: Sync> Sync for LockedSlabBox<'a, T> {}
+unsafe impl<'a, T: Send, A: SharedSlabAlloc<T> + Sync> Send for LockedSlabBox<'a, T, A> {}
+unsafe impl<'a, T: Sync, A: SharedSlabAlloc<T> + Sync> Sync for LockedSlabBox<'a, T, A> {}
@@ -0,0 +1,344 @@
+//! The algorithms that move entries within and between the nodes of a [`BTree`]: inserting with
+//! an overflow, splitting, shifting through a separator, removing and merging. They work on the
+//! storage of a node, described by [`Slots`], rather than on the node itself, so that the pages
+//! of a `PagedBTree` go through the same splits and merges as the
+//! slab tree's nodes.
+//!
+//! Every function moves children along with the entries. Leaves use `()` as their children, which
+//! makes those moves no-ops. Like the rest of [`BTree`], the removals leave a hole that the caller
+//! has already moved the entry out of, together with its right child in internal nodes, so they
+//! never read it.
+//!
+//! [`BTree`]: crate::btree::BTree
+
+use crate::btree::{copy_within, B, MAX_NUM_ELEMENTS, MIN_NUM_ELEMENTS};
+use core::cmp::Ordering;
+use core::ptr;
+
+/// The storage of a node: its length, arrays of `MAX_NUM_ELEMENTS` keys and values, and an array
+/// of one more children.
+///
+/// # Safety
+/// The pointers have to be valid for reads and writes of their whole arrays, and suitably aligned,
+/// or dangling for zero-sized types. `set_len` only changes the length, it doesn't initialize or
+/// drop anything. The functions of this module only hold on to a pointer until the next call on
+/// the same node.
+pub(crate) unsafe trait Slots {
+    type Key;
+    type Value;
+    type Child;
+
+    fn len(&self) -> usize;
+
+    fn set_len(&mut self, len: usize);
+
+    fn key_ptr(&mut self) -> *mut Self::Key;
+
+    fn value_ptr(&mut self) -> *mut Self::Value;
+
+    fn child_ptr(&mut self) -> *mut Self::Child;
+}
+
+type Entry<N> = (<N as Slots>::Key, <N as Slots>::Value);
+
+pub(crate) type Moved<N> = (<N as Slots>::Key, <N as Slots>::Value, <N as Slots>::Child);
+
+/// Moves the entry at `idx` out, leaving a hole for one of the removals below.
+///
+/// # Safety
+/// The entry has to be initialized, and is only valid once.
+#[inline]
+pub(crate) unsafe fn read<N: Slots>(node: &mut N, idx: usize) -> Entry<N> {
+    debug_assert!(idx < node.len());
+    (
+        node.key_ptr().add(idx).read(),
+        node.value_ptr().add(idx).read(),
+    )
+}
+
+#[inline]
+pub(crate) fn push<N: Slots>(node: &mut N, key: N::Key, value: N::Value, rchild: N::Child) {
+    let len = node.len();
+    debug_assert_ne!(len, MAX_NUM_ELEMENTS);
+
+    unsafe {
+        node.key_ptr().add(len).write(key);
+        node.value_ptr().add(len).write(value);
+        node.child_ptr().add(len + 1).write(rchild);
+    }
+    node.set_len(len + 1);
+}
+
+pub(crate) fn unshift<N: Slots>(node: &mut N, key: N::Key, value: N::Value, lchild: N::Child) {
+    let len = node.len();
+    debug_assert_ne!(len, MAX_NUM_ELEMENTS);
+
+    unsafe {
+        copy_within(node.key_ptr(), 0, 1, len);
+        copy_within(node.value_ptr(), 0, 1, len);
+        copy_within(node.child_ptr(), 0, 1, len + 1);
+        node.key_ptr().write(key);
+        node.value_ptr().write(value);
+        node.child_ptr().write(lchild);
+    }
+    node.set_len(len + 1);
+}
+
+/// Inserts an entry at `idx`, with `rchild` after it. A full node hands back its last entry and
+/// child, or the new ones if they would have gone after them.
+pub(crate) fn insert<N: Slots>(
+    node: &mut N,
+    idx: usize,
+    key: N::Key,
+    value: N::Value,
+    rchild: N::Child,
+) -> Option<Moved<N>> {
+    debug_assert!(idx <= node.len());
+    if idx == MAX_NUM_ELEMENTS {
+        return Some((key, value, rchild));
+    }
+    let mut len = node.len();
+    let overflow = if len == MAX_NUM_ELEMENTS {
+        len -= 1;
+        unsafe {
+            Some((
+                node.key_ptr().add(len).read(),
+                node.value_ptr().add(len).read(),
+                node.child_ptr().add(len + 1).read(),
+            ))
+        }
+    } else {
+        None
+    };
+    unsafe {
+        copy_within(node.key_ptr(), idx, idx + 1, len - idx);
+        copy_within(node.value_ptr(), idx, idx + 1, len - idx);
+        copy_within(node.child_ptr(), idx + 1, idx + 2, len - idx);
+        node.key_ptr().add(idx).write(key);
+        node.value_ptr().add(idx).write(value);
+        node.child_ptr().add(idx + 1).write(rchild);
+    }
+    node.set_len(len + 1);
+    overflow
+}
+
+/// Inserts an entry at `idx` into a full node, and hands back its first entry and child to make
+/// room, or the new ones if they would have gone before them.
+pub(crate) fn insert_overflow_left<N: Slots>(
+    node: &mut N,
+    mut idx: usize,
+    key: N::Key,
+    value: N::Value,
+    rchild: N::Child,
+) -> Moved<N> {
+    debug_assert_eq!(node.len(), MAX_NUM_ELEMENTS);
+    if idx == 0 {
+        (key, value, unsafe { node.child_ptr().replace(rchild) })
+    } else {
+        unsafe {
+            idx -= 1;
+            let overflow = (
+                node.key_ptr().read(),
+                node.value_ptr().read(),
+                node.child_ptr().read(),
+            );
+            copy_within(node.key_ptr(), 1, 0, idx);
+            copy_within(node.value_ptr(), 1, 0, idx);
+            copy_within(node.child_ptr(), 1, 0, idx + 1);
+            node.key_ptr().add(idx).write(key);
+            node.value_ptr().add(idx).write(value);
+            node.child_ptr().add(idx + 1).write(rchild);
+            overflow
+        }
+    }
+}
+
+/// Inserts an entry at `idx` into a full node by splitting it: the node keeps the first `B`
+/// entries, or `B - 1` and the new one, `right` gets the `B - 1` after the returned separator.
+/// `right` has to be empty, and its previous contents are overwritten.
+pub(crate) fn insert_split<N: Slots>(
+    node: &mut N,
+    right: &mut N,
+    idx: usize,
+    key: N::Key,
+    value: N::Value,
+    rchild: N::Child,
+) -> Entry<N> {
+    debug_assert_eq!(node.len(), MAX_NUM_ELEMENTS);
+    right.set_len(B - 1);
+    unsafe {
+        match idx.cmp(&B) {
+            Ordering::Less => {
+                ptr::copy_nonoverlapping(node.key_ptr().add(B), right.key_ptr(), B - 1);
+                ptr::copy_nonoverlapping(node.value_ptr().add(B), right.value_ptr(), B - 1);
+                ptr::copy_nonoverlapping(node.child_ptr().add(B), right.child_ptr(), B);
+                let sep = read(node, B - 1);
+
+                node.set_len(B - 1);
+                insert(node, idx, key, value, rchild);
+                sep
+            }
+            Ordering::Equal => {
+                ptr::copy_nonoverlapping(node.key_ptr().add(B), right.key_ptr(), B - 1);
+                ptr::copy_nonoverlapping(node.value_ptr().add(B), right.value_ptr(), B - 1);
+                ptr::copy_nonoverlapping(
+                    node.child_ptr().add(B + 1),
+                    right.child_ptr().add(1),
+                    B - 1,
+                );
+                right.child_ptr().write(rchild);
+                node.set_len(B);
+                (key, value)
+            }
+            Ordering::Greater => {
+                let sep = read(node, B);
+
+                ptr::copy_nonoverlapping(node.key_ptr().add(B + 1), right.key_ptr(), idx - B - 1);
+                ptr::copy_nonoverlapping(
+                    node.key_ptr().add(idx),
+                    right.key_ptr().add(idx - B),
+                    2 * B - 1 - idx,
+                );
+                ptr::copy_nonoverlapping(
+                    node.value_ptr().add(B + 1),
+                    right.value_ptr(),
+                    idx - B - 1,
+                );
+                ptr::copy_nonoverlapping(
+                    node.value_ptr().add(idx),
+                    right.value_ptr().add(idx - B),
+                    2 * B - 1 - idx,
+                );
+                ptr::copy_nonoverlapping(node.child_ptr().add(B + 1), right.child_ptr(), idx - B);
+                ptr::copy_nonoverlapping(
+                    node.child_ptr().add(idx + 1),
+                    right.child_ptr().add(idx - B + 1),
+                    2 * B - 1 - idx,
+                );
+                right.key_ptr().add(idx - B - 1).write(key);
+                right.value_ptr().add(idx - B - 1).write(value);
+                right.child_ptr().add(idx - B).write(rchild);
+
+                node.set_len(B);
+                sep
+            }
+        }
+    }
+}
+
+pub(crate) fn pop<N: Slots>(node: &mut N) -> Moved<N> {
+    let len = node.len();
+    debug_assert_ne!(len, 0);
+
+    unsafe {
+        let rchild = node.child_ptr().add(len).read();
+        let (key, value) = read(node, len - 1);
+        node.set_len(len - 1);
+        (key, value, rchild)
+    }
+}
+
+pub(crate) fn shift<N: Slots>(node: &mut N) -> Moved<N> {
+    let len = node.len();
+    debug_assert_ne!(len, 0);
+
+    unsafe {
+        let lchild = node.child_ptr().read();
+        copy_within(node.child_ptr(), 1, 0, len);
+        let (key, value) = read(node, 0);
+        copy_within(node.key_ptr(), 1, 0, len - 1);
+        copy_within(node.value_ptr(), 1, 0, len - 1);
+        node.set_len(len - 1);
+        (key, value, lchild)
+    }
+}
+
+/// Closes the hole at `idx`, and the one of its right child.
+pub(crate) fn remove<N: Slots>(node: &mut N, idx: usize) {
+    let len = node.len() - 1;
+    debug_assert!(idx <= len);
+
+    unsafe {
+        copy_within(node.key_ptr(), idx + 1, idx, len - idx);
+        copy_within(node.value_ptr(), idx + 1, idx, len - idx);
+        copy_within(node.child_ptr(), idx + 2, idx + 1, len - idx);
+    }
+    node.set_len(len);
+}
+
+/// Closes the hole at `idx` by moving the entries before it up, and puts an entry borrowed from
+/// the left neighbour first.
+pub(crate) fn remove_borrow_left<N: Slots>(
+    node: &mut N,
+    idx: usize,
+    key: N::Key,
+    value: N::Value,
+    lchild: N::Child,
+) {
+    debug_assert!(idx < node.len());
+
+    unsafe {
+        copy_within(node.key_ptr(), 0, 1, idx);
+        node.key_ptr().write(key);
+        copy_within(node.value_ptr(), 0, 1, idx);
+        node.value_ptr().write(value);
+        copy_within(node.child_ptr(), 0, 1, idx + 1);
+        node.child_ptr().write(lchild);
+    }
+}
+
+/// Appends the separator and all of `right` but its hole at `idx`. Both nodes have the minimum
+/// length, and `right` is left to be freed.
+pub(crate) fn merge_remove<N: Slots>(
+    node: &mut N,
+    sep_key: N::Key,
+    sep_value: N::Value,
+    right: &mut N,
+    idx: usize,
+) {
+    debug_assert_eq!(node.len(), MIN_NUM_ELEMENTS);
+    debug_assert_eq!(right.len(), MIN_NUM_ELEMENTS);
+
+    unsafe {
+        node.key_ptr().add(B - 1).write(sep_key);
+        ptr::copy(right.key_ptr(), node.key_ptr().add(B), idx);
+        ptr::copy(
+            right.key_ptr().add(idx + 1),
+            node.key_ptr().add(B + idx),
+            B - 2 - idx,
+        );
+
+        node.value_ptr().add(B - 1).write(sep_value);
+        ptr::copy(right.value_ptr(), node.value_ptr().add(B), idx);
+        ptr::copy(
+            right.value_ptr().add(idx + 1),
+            node.value_ptr().add(B + idx),
+            B - 2 - idx,
+        );
+        ptr::copy(right.child_ptr(), node.child_ptr().add(B), idx + 1);
+        ptr::copy(
+            right.child_ptr().add(idx + 2),
+            node.child_ptr().add(B + idx + 1),
+            B - 2 - idx,
+        );
+    }
+    node.set_len(2 * B - 2);
+}
+
+/// Appends the separator and all of `right`. The node is one short of the minimum length and
+/// `right` has it, and `right` is left to be freed.
+pub(crate) fn merge<N: Slots>(node: &mut N, sep_key: N::Key, sep_value: N::Value, right: &mut N) {
+    debug_assert_eq!(node.len(), MIN_NUM_ELEMENTS - 1);
+    debug_assert_eq!(right.len(), MIN_NUM_ELEMENTS);
+
+    unsafe {
+        node.key_ptr().add(B - 2).write(sep_key);
+        ptr::copy(right.key_ptr(), node.key_ptr().add(B - 1), B - 1);
+
+        node.value_ptr().add(B - 2).write(sep_value);
+        ptr::copy(right.value_ptr(), node.value_ptr().add(B - 1), B - 1);
+
+        ptr::copy(right.child_ptr(), node.child_ptr().add(B - 1), B);
+    }
+    node.set_len(2 * B - 2);
+}
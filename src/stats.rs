@@ -0,0 +1,105 @@
+use crate::slab::SlabStats;
+
+/// The most levels a tree can have when every node below the root has at least `min_children`
+/// children. Such a tree holds at least `min_children.pow(depth - 1)` entries, so any deeper tree
+/// would have more entries than its `usize` length can count. The trees size the buffers for their
+/// paths with this, at compile time.
+pub const fn max_depth(min_children: usize) -> usize {
+    assert!(2 <= min_children);
+    let mut depth = 1;
+    let mut min_len = 1usize;
+    while let Some(len) = min_len.checked_mul(min_children) {
+        min_len = len;
+        depth += 1;
+    }
+    depth
+}
+
+/// The most levels of any tree, with the fewest children per node that any branching factor
+/// allows.
+pub const MAX_LEVELS: usize = max_depth(2);
+
+/// The shape of a tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TreeStats {
+    /// The number of entries.
+    pub len: usize,
+    /// The number of levels, 0 for an empty tree and 1 when the root is a leaf.
+    pub depth: usize,
+    /// The number of internal nodes.
+    pub nodes: usize,
+    /// The number of leaves.
+    pub leaves: usize,
+    /// The nodes and keys on each level, from the root down. Only the first `depth` are used.
+    pub levels: [LevelStats; MAX_LEVELS],
+}
+
+// Arrays this long don't implement `Default`.
+impl Default for TreeStats {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            len: 0,
+            depth: 0,
+            nodes: 0,
+            leaves: 0,
+            levels: [LevelStats::default(); MAX_LEVELS],
+        }
+    }
+}
+
+impl TreeStats {
+    /// The levels of the tree, from the root down.
+    #[inline]
+    pub fn levels(&self) -> &[LevelStats] {
+        &self.levels[..self.depth]
+    }
+}
+
+/// The nodes on one level of a tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LevelStats {
+    pub nodes: usize,
+    pub keys: usize,
+    /// The most keys the nodes could hold.
+    pub capacity: usize,
+}
+
+impl LevelStats {
+    /// The average fill of the nodes, from 0 for empty nodes to 1 for full ones.
+    #[inline]
+    pub fn fill(&self) -> f64 {
+        if self.capacity == 0 {
+            0.0
+        } else {
+            self.keys as f64 / self.capacity as f64
+        }
+    }
+}
+
+/// The shape of a slab allocated tree, and the state of its two allocators, or of the two size
+/// classes of its slab cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BTreeStats {
+    pub tree: TreeStats,
+    /// The allocator of the internal nodes.
+    pub node_alloc: SlabStats,
+    /// The allocator of the leaves.
+    pub leaf_alloc: SlabStats,
+    /// The bytes of the pages that neither class of a slab cache has taken yet.
+    pub free_pages: usize,
+}
+
+impl BTreeStats {
+    /// The bytes taken by nodes in both allocators.
+    #[inline]
+    pub fn in_use(&self) -> usize {
+        self.node_alloc.in_use + self.leaf_alloc.in_use
+    }
+
+    /// The bytes of the chunks given to both allocators.
+    #[inline]
+    pub fn reserved(&self) -> usize {
+        self.node_alloc.reserved + self.leaf_alloc.reserved + self.free_pages
+    }
+}
@@ -1,12 +1,24 @@
 // use bitflags::bitflags;
+use crate::aggregate::{Aggregate, Counted, Size, SizeOf, SubtreeSize};
+use crate::budget::{Account, BudgetExceeded, MemoryBudget};
+use crate::compare::By;
+use crate::dump::{DotEscape, JsonEscape};
 use crate::ref_stack::RefStack;
+use crate::search::{Linear, SearchStrategy};
+use crate::stats::{max_depth, TreeStats};
+#[cfg(feature = "check-invariants")]
+use crate::validate::Corruption;
+use crate::validate::InvariantViolation;
 use std::any::type_name;
-use std::borrow::Borrow;
 use std::cmp::Ordering;
-use std::fmt;
-use std::mem::{self, ManuallyDrop, MaybeUninit};
+use std::fmt::{self, Write as _};
+use std::iter::FusedIterator;
+use std::marker::PhantomData;
+use std::mem::{self, size_of, ManuallyDrop, MaybeUninit};
+use std::ops::{Bound, RangeBounds};
 use std::ptr;
 use std::slice;
+use std::sync::Arc;
 
 const B: usize = 6;
 
@@ -15,6 +27,30 @@ const MAX_NUM_ELEMENTS: usize = 2 * B - 1;
 // const MIN_NUM_CHILDREN: usize = B;
 const MAX_NUM_CHILDREN: usize = 2 * B;
 
+/// The most levels the tree can have, which sizes the paths kept by insertions, removals and
+/// iterators.
+const MAX_DEPTH: usize = max_depth(B);
+
+/// Moves `count` elements within an array, from index `src` to index `dst`, like `ptr::copy`.
+/// Both pointers are derived from `ptr`, so that creating one doesn't invalidate the other.
+#[inline]
+unsafe fn copy_within<T>(ptr: *mut T, src: usize, dst: usize, count: usize) {
+    ptr::copy(ptr.add(src), ptr.add(dst), count);
+}
+
+/// Frees a boxed node without dropping it or reading it, like `SlabBox::free_forget`, and refunds
+/// its bytes to the tree's budget. Moving the node out of the box would read `size` and `agg`,
+/// which are uninitialized when unused.
+#[inline]
+fn free_forget<T>(account: &mut Account, node: Box<T>) {
+    account.refund(size_of::<T>());
+    unsafe {
+        drop(Box::from_raw(
+            Box::into_raw(node) as *mut mem::ManuallyDrop<T>
+        ))
+    }
+}
+
 trait Child<K, V> {
     fn len(&self) -> usize;
 
@@ -30,33 +66,49 @@ trait Child<K, V> {
     unsafe fn get_key_mut_unchecked(&mut self, i: usize) -> &mut K;
     unsafe fn get_value_mut_unchecked(&mut self, i: usize) -> &mut V;
 
+    /// Searches the keys with `strategy`.
     #[inline]
-    fn linsearch<Q>(&self, key: &Q) -> Result<usize, usize>
+    fn search<S, Q>(&self, strategy: &S, key: &Q) -> Result<usize, usize>
     where
-        K: Borrow<Q> + Ord,
-        Q: Ord + ?Sized,
+        S: SearchStrategy<K, Q>,
+        Q: ?Sized,
     {
-        for (i, x) in self.keys().iter().enumerate() {
-            let ordering = key.cmp(x.borrow());
-            // if ordering == Ordering::Less {
-            //     return Err(i);
-            // } else if ordering == Ordering::Equal {
-            //     return Ok(i);
-            // }
-            if ordering == Ordering::Greater {
-                continue;
-            } else if ordering == Ordering::Less {
-                return Err(i);
-            } else {
-                return Ok(i);
-            }
-        }
-        Err(self.len())
+        strategy.search(self.keys(), key)
+    }
+
+    /// Finds the indices of the keys that fall between `start` and `end`.
+    fn bound_range<S, Q>(&self, strategy: &S, start: Bound<&Q>, end: Bound<&Q>) -> (usize, usize)
+    where
+        S: SearchStrategy<K, Q>,
+        Q: ?Sized,
+    {
+        let lo = match start {
+            Bound::Included(key) => self.search(strategy, key).unwrap_or_else(|i| i),
+            Bound::Excluded(key) => self.search(strategy, key).map_or_else(|i| i, |i| i + 1),
+            Bound::Unbounded => 0,
+        };
+        let hi = match end {
+            Bound::Included(key) => self.search(strategy, key).map_or_else(|i| i, |i| i + 1),
+            Bound::Excluded(key) => self.search(strategy, key).unwrap_or_else(|i| i),
+            Bound::Unbounded => self.len(),
+        };
+        (lo, hi.max(lo))
     }
 }
 
+/// Whether a child is a leaf or an internal node. Both start with their length and their kind, so
+/// that `validate` can tell them apart even where the children aren't tagged with their kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Kind {
+    Leaf,
+    Node,
+}
+
+#[repr(C)]
 struct Leaf<K, V> {
     len: u8,
+    kind: Kind,
     keys: [MaybeUninit<K>; MAX_NUM_ELEMENTS],
     values: [MaybeUninit<V>; MAX_NUM_ELEMENTS],
 }
@@ -106,10 +158,12 @@ impl<K, V> Child<K, V> for Leaf<K, V> {
 
 impl<K, V> Leaf<K, V> {
     #[inline]
-    fn new() -> Box<Self> {
+    fn new(account: &mut Account) -> Box<Self> {
+        account.charge(size_of::<Self>());
         unsafe {
             Box::new(Self {
                 len: 0,
+                kind: Kind::Leaf,
                 keys: MaybeUninit::uninit().assume_init(),
                 values: MaybeUninit::uninit().assume_init(),
             })
@@ -140,16 +194,8 @@ impl<K, V> Leaf<K, V> {
         debug_assert_ne!(self.len(), MAX_NUM_ELEMENTS);
 
         unsafe {
-            ptr::copy(
-                self.keys.as_ptr(),
-                self.keys.as_mut_ptr().add(1),
-                self.len(),
-            );
-            ptr::copy(
-                self.values.as_ptr(),
-                self.values.as_mut_ptr().add(1),
-                self.len(),
-            );
+            copy_within(self.keys.as_mut_ptr(), 0, 1, self.len());
+            copy_within(self.values.as_mut_ptr(), 0, 1, self.len());
             self.len += 1;
             self.keys[0].write(key);
             self.values[0].write(value);
@@ -161,29 +207,20 @@ impl<K, V> Leaf<K, V> {
         if idx == MAX_NUM_ELEMENTS {
             return Some((key, value));
         }
-        let overflow;
-        if self.len() == MAX_NUM_ELEMENTS {
+        let overflow = if self.len() == MAX_NUM_ELEMENTS {
             self.len -= 1;
-            overflow = unsafe {
+            unsafe {
                 Some((
                     self.keys[self.len()].as_ptr().read(),
                     self.values[self.len()].as_ptr().read(),
                 ))
-            };
+            }
         } else {
-            overflow = None;
-        }
+            None
+        };
         unsafe {
-            ptr::copy(
-                self.keys.as_ptr().add(idx),
-                self.keys.as_mut_ptr().add(idx + 1),
-                self.len() - idx,
-            );
-            ptr::copy(
-                self.values.as_ptr().add(idx),
-                self.values.as_mut_ptr().add(idx + 1),
-                self.len() - idx,
-            );
+            copy_within(self.keys.as_mut_ptr(), idx, idx + 1, self.len() - idx);
+            copy_within(self.values.as_mut_ptr(), idx, idx + 1, self.len() - idx);
             self.keys[idx].write(key);
             self.values[idx].write(value);
         }
@@ -199,8 +236,8 @@ impl<K, V> Leaf<K, V> {
             unsafe {
                 idx -= 1;
                 let overflow = (self.keys[0].as_ptr().read(), self.values[0].as_ptr().read());
-                ptr::copy(self.keys.as_ptr().add(1), self.keys.as_mut_ptr(), idx);
-                ptr::copy(self.values.as_ptr().add(1), self.values.as_mut_ptr(), idx);
+                copy_within(self.keys.as_mut_ptr(), 1, 0, idx);
+                copy_within(self.values.as_mut_ptr(), 1, 0, idx);
                 self.keys[idx].write(key);
                 self.values[idx].write(value);
                 overflow
@@ -208,12 +245,18 @@ impl<K, V> Leaf<K, V> {
         }
     }
 
-    fn insert_split(&mut self, idx: usize, key: K, value: V) -> (K, V, Box<Self>) {
+    fn insert_split(
+        &mut self,
+        account: &mut Account,
+        idx: usize,
+        key: K,
+        value: V,
+    ) -> (K, V, Box<Self>) {
         debug_assert_eq!(self.len(), MAX_NUM_ELEMENTS);
         unsafe {
-            let mut right = Leaf::new();
+            let mut right = Leaf::new(account);
             right.len = (B - 1) as _;
-            match idx.cmp(&(B as usize)) {
+            match idx.cmp(&B) {
                 Ordering::Less => {
                     ptr::copy_nonoverlapping(
                         self.keys.as_ptr().add(B),
@@ -302,17 +345,9 @@ impl<K, V> Leaf<K, V> {
         unsafe {
             self.len -= 1;
             let key = self.keys[0].as_ptr().read();
-            ptr::copy(
-                self.keys.as_ptr().add(1),
-                self.keys.as_mut_ptr(),
-                self.len(),
-            );
+            copy_within(self.keys.as_mut_ptr(), 1, 0, self.len());
             let value = self.values[0].as_ptr().read();
-            ptr::copy(
-                self.values.as_ptr().add(1),
-                self.values.as_mut_ptr(),
-                self.len(),
-            );
+            copy_within(self.values.as_mut_ptr(), 1, 0, self.len());
             (key, value)
         }
     }
@@ -325,17 +360,9 @@ impl<K, V> Leaf<K, V> {
         unsafe {
             self.len -= 1;
             let key = self.keys[idx].as_ptr().read();
-            ptr::copy(
-                self.keys.as_ptr().add(idx + 1),
-                self.keys.as_mut_ptr().add(idx),
-                self.len() - idx,
-            );
+            copy_within(self.keys.as_mut_ptr(), idx + 1, idx, self.len() - idx);
             let value = self.values[idx].as_ptr().read();
-            ptr::copy(
-                self.values.as_ptr().add(idx + 1),
-                self.values.as_mut_ptr().add(idx),
-                self.len() - idx,
-            );
+            copy_within(self.values.as_mut_ptr(), idx + 1, idx, self.len() - idx);
             (key, value)
         }
     }
@@ -347,18 +374,26 @@ impl<K, V> Leaf<K, V> {
 
         unsafe {
             let rm_key = self.keys[idx].as_ptr().read();
-            ptr::copy(self.keys.as_ptr(), self.keys.as_mut_ptr().add(1), idx);
+            copy_within(self.keys.as_mut_ptr(), 0, 1, idx);
             self.keys[0].write(key);
 
             let rm_value = self.values[idx].as_ptr().read();
-            ptr::copy(self.values.as_ptr(), self.values.as_mut_ptr().add(1), idx);
+            copy_within(self.values.as_mut_ptr(), 0, 1, idx);
             self.values[0].write(value);
 
             (rm_key, rm_value)
         }
     }
 
-    fn merge_remove(&mut self, sep_key: K, sep_value: V, right: Box<Self>, idx: usize) -> (K, V) {
+    #[allow(clippy::boxed_local)]
+    fn merge_remove(
+        &mut self,
+        account: &mut Account,
+        sep_key: K,
+        sep_value: V,
+        right: Box<Self>,
+        idx: usize,
+    ) -> (K, V) {
         // log::info!("Leaf::merge_remove(..)");
 
         debug_assert_eq!(self.len(), MIN_NUM_ELEMENTS);
@@ -385,13 +420,14 @@ impl<K, V> Leaf<K, V> {
                 B - 2 - idx,
             );
 
-            mem::forget(*right);
+            free_forget(account, right);
 
             (rm_key, rm_value)
         }
     }
 
-    fn merge(&mut self, sep_key: K, sep_value: V, right: Box<Self>) {
+    #[allow(clippy::boxed_local)]
+    fn merge(&mut self, account: &mut Account, sep_key: K, sep_value: V, right: Box<Self>) {
         // log::info!("Leaf::merge(..)");
 
         debug_assert_eq!(self.len(), MIN_NUM_ELEMENTS - 1);
@@ -414,19 +450,25 @@ impl<K, V> Leaf<K, V> {
                 B - 1,
             );
 
-            mem::forget(*right);
+            free_forget(account, right);
         }
     }
 }
 
-struct Node<K, V> {
+#[repr(C)]
+struct Node<K, V, N, S> {
     len: u8,
+    kind: Kind,
+    /// The number of elements in this node's subtree. Only counted trees have room for it.
+    size: N,
+    /// The aggregate of this node's subtree. Only maintained by aggregated trees.
+    agg: S,
     keys: [MaybeUninit<K>; MAX_NUM_ELEMENTS],
-    children: [MaybeUninit<ChildUnion<K, V>>; MAX_NUM_CHILDREN],
+    children: [MaybeUninit<ChildUnion<K, V, N, S>>; MAX_NUM_CHILDREN],
     values: [MaybeUninit<V>; MAX_NUM_ELEMENTS],
 }
 
-impl<K, V> Child<K, V> for Node<K, V> {
+impl<K, V, N, S> Child<K, V> for Node<K, V, N, S> {
     #[inline]
     fn len(&self) -> usize {
         self.len as _
@@ -469,12 +511,21 @@ impl<K, V> Child<K, V> for Node<K, V> {
     }
 }
 
-impl<K, V> Node<K, V> {
+impl<K, V, N, S> Node<K, V, N, S> {
     #[inline]
-    fn new(key: K, value: V, lchild: ChildUnion<K, V>, rchild: ChildUnion<K, V>) -> Box<Self> {
+    fn new(
+        account: &mut Account,
+        key: K,
+        value: V,
+        lchild: ChildUnion<K, V, N, S>,
+        rchild: ChildUnion<K, V, N, S>,
+    ) -> Box<Self> {
+        account.charge(size_of::<Self>());
         unsafe {
-            let mut slf: Box<Self> = Box::new(MaybeUninit::uninit().assume_init());
-            slf.len = 1;
+            let mut slf = Box::<Self>::new_uninit();
+            ptr::addr_of_mut!((*slf.as_mut_ptr()).len).write(1);
+            ptr::addr_of_mut!((*slf.as_mut_ptr()).kind).write(Kind::Node);
+            let mut slf = slf.assume_init();
             slf.keys[0].write(key);
             slf.values[0].write(value);
             slf.children[0].write(lchild);
@@ -483,37 +534,70 @@ impl<K, V> Node<K, V> {
         }
     }
 
+    /// Creates a node without keys and with a single child. Only the right border of a tree built
+    /// with `BTree::push_sorted` has these, until `BTree::finish_sorted` fills them.
+    #[cfg_attr(not(feature = "serde"), allow(dead_code))]
+    #[inline]
+    fn with_child(account: &mut Account, child: ChildUnion<K, V, N, S>) -> Box<Self> {
+        account.charge(size_of::<Self>());
+        unsafe {
+            let mut slf = Box::<Self>::new_uninit();
+            ptr::addr_of_mut!((*slf.as_mut_ptr()).len).write(0);
+            ptr::addr_of_mut!((*slf.as_mut_ptr()).kind).write(Kind::Node);
+            let mut slf = slf.assume_init();
+            slf.children[0].write(child);
+            slf
+        }
+    }
+
+    /// Recomputes `size` from the children. `leaf_children` tells whether the children are leaves.
+    #[inline]
+    fn recount(&mut self, leaf_children: bool)
+    where
+        N: Size,
+    {
+        let size = self.len()
+            + self
+                .children()
+                .iter()
+                .map(|child| unsafe { child.count(leaf_children) })
+                .sum::<usize>();
+        self.size.set(size);
+    }
+
     #[inline]
-    fn children(&self) -> &[ChildUnion<K, V>] {
+    fn children(&self) -> &[ChildUnion<K, V, N, S>] {
         unsafe { slice::from_raw_parts(self.children.as_ptr() as _, self.len() + 1) }
     }
     // #[inline]
-    // fn children_mut(&mut self) -> &mut [ChildUnion<K, V>] {
+    // fn children_mut(&mut self) -> &mut [ChildUnion<K, V, N, S>] {
     //     unsafe { slice::from_raw_parts_mut(self.children.as_mut_ptr() as _, self.len() + 1) }
     // }
 
     #[inline]
-    unsafe fn get_child_unchecked(&self, i: usize) -> &ChildUnion<K, V> {
+    unsafe fn get_child_unchecked(&self, i: usize) -> &ChildUnion<K, V, N, S> {
         self.children.get_unchecked(i).assume_init_ref()
     }
     #[inline]
-    unsafe fn get_child_mut_unchecked(&mut self, i: usize) -> &mut ChildUnion<K, V> {
+    unsafe fn get_child_mut_unchecked(&mut self, i: usize) -> &mut ChildUnion<K, V, N, S> {
         self.children.get_unchecked_mut(i).assume_init_mut()
     }
 
     #[inline]
-    fn get_all_mut(&mut self) -> (&mut [K], &mut [V], &mut [ChildUnion<K, V>]) {
+    #[allow(clippy::type_complexity)]
+    fn get_all_mut(&mut self) -> (&mut [K], &mut [V], &mut [ChildUnion<K, V, N, S>]) {
+        let len = self.len();
         unsafe {
             (
-                slice::from_raw_parts_mut(self.keys.as_mut_ptr() as _, self.len()),
-                slice::from_raw_parts_mut(self.values.as_mut_ptr() as _, self.len()),
-                slice::from_raw_parts_mut(self.children.as_mut_ptr() as _, self.len() + 1),
+                slice::from_raw_parts_mut(self.keys.as_mut_ptr() as _, len),
+                slice::from_raw_parts_mut(self.values.as_mut_ptr() as _, len),
+                slice::from_raw_parts_mut(self.children.as_mut_ptr() as _, len + 1),
             )
         }
     }
 
     #[inline]
-    fn push(&mut self, key: K, value: V, rchild: ChildUnion<K, V>) {
+    fn push(&mut self, key: K, value: V, rchild: ChildUnion<K, V, N, S>) {
         debug_assert_ne!(self.len(), MAX_NUM_ELEMENTS);
 
         self.keys[self.len()].write(key);
@@ -522,26 +606,14 @@ impl<K, V> Node<K, V> {
         self.children[self.len()].write(rchild);
     }
 
-    fn unshift(&mut self, key: K, value: V, lchild: ChildUnion<K, V>) {
+    fn unshift(&mut self, key: K, value: V, lchild: ChildUnion<K, V, N, S>) {
         debug_assert_ne!(self.len(), MAX_NUM_ELEMENTS);
 
         unsafe {
-            ptr::copy(
-                self.keys.as_ptr(),
-                self.keys.as_mut_ptr().add(1),
-                self.len(),
-            );
-            ptr::copy(
-                self.values.as_ptr(),
-                self.values.as_mut_ptr().add(1),
-                self.len(),
-            );
+            copy_within(self.keys.as_mut_ptr(), 0, 1, self.len());
+            copy_within(self.values.as_mut_ptr(), 0, 1, self.len());
             self.len += 1;
-            ptr::copy(
-                self.children.as_ptr(),
-                self.children.as_mut_ptr().add(1),
-                self.len(),
-            );
+            copy_within(self.children.as_mut_ptr(), 0, 1, self.len());
             self.keys[0].write(key);
             self.values[0].write(value);
             self.children[0].write(lchild);
@@ -553,38 +625,30 @@ impl<K, V> Node<K, V> {
         idx: usize,
         key: K,
         value: V,
-        rchild: ChildUnion<K, V>,
-    ) -> Option<(K, V, ChildUnion<K, V>)> {
+        rchild: ChildUnion<K, V, N, S>,
+    ) -> Option<Moved<K, V, N, S>> {
         if idx == MAX_NUM_ELEMENTS {
             return Some((key, value, rchild));
         }
-        let overflow;
-        if self.len() == MAX_NUM_ELEMENTS {
+        let overflow = if self.len() == MAX_NUM_ELEMENTS {
             self.len -= 1;
-            overflow = unsafe {
+            unsafe {
                 Some((
                     self.keys[self.len()].as_ptr().read(),
                     self.values[self.len()].as_ptr().read(),
                     self.children[1 + self.len()].as_ptr().read(),
                 ))
-            };
+            }
         } else {
-            overflow = None;
-        }
+            None
+        };
         unsafe {
-            ptr::copy(
-                self.keys.as_ptr().add(idx),
-                self.keys.as_mut_ptr().add(idx + 1),
-                self.len() - idx,
-            );
-            ptr::copy(
-                self.values.as_ptr().add(idx),
-                self.values.as_mut_ptr().add(idx + 1),
-                self.len() - idx,
-            );
-            ptr::copy(
-                self.children.as_ptr().add(idx + 1),
-                self.children.as_mut_ptr().add(idx + 2),
+            copy_within(self.keys.as_mut_ptr(), idx, idx + 1, self.len() - idx);
+            copy_within(self.values.as_mut_ptr(), idx, idx + 1, self.len() - idx);
+            copy_within(
+                self.children.as_mut_ptr(),
+                idx + 1,
+                idx + 2,
                 self.len() - idx,
             );
             self.keys[idx].write(key);
@@ -600,8 +664,8 @@ impl<K, V> Node<K, V> {
         mut idx: usize,
         key: K,
         value: V,
-        rchild: ChildUnion<K, V>,
-    ) -> (K, V, ChildUnion<K, V>) {
+        rchild: ChildUnion<K, V, N, S>,
+    ) -> (K, V, ChildUnion<K, V, N, S>) {
         debug_assert_eq!(self.len(), MAX_NUM_ELEMENTS);
         if idx == 0 {
             (key, value, unsafe {
@@ -615,13 +679,9 @@ impl<K, V> Node<K, V> {
                     self.values[0].as_ptr().read(),
                     self.children[0].as_ptr().read(),
                 );
-                ptr::copy(self.keys.as_ptr().add(1), self.keys.as_mut_ptr(), idx);
-                ptr::copy(self.values.as_ptr().add(1), self.values.as_mut_ptr(), idx);
-                ptr::copy(
-                    self.children.as_ptr().add(1),
-                    self.children.as_mut_ptr(),
-                    idx + 1,
-                );
+                copy_within(self.keys.as_mut_ptr(), 1, 0, idx);
+                copy_within(self.values.as_mut_ptr(), 1, 0, idx);
+                copy_within(self.children.as_mut_ptr(), 1, 0, idx + 1);
                 self.keys[idx].write(key);
                 self.values[idx].write(value);
                 self.children[idx + 1].write(rchild);
@@ -632,16 +692,20 @@ impl<K, V> Node<K, V> {
 
     fn insert_split(
         &mut self,
+        account: &mut Account,
         idx: usize,
         key: K,
         value: V,
-        rchild: ChildUnion<K, V>,
+        rchild: ChildUnion<K, V, N, S>,
     ) -> (K, V, Box<Self>) {
         debug_assert_eq!(self.len(), MAX_NUM_ELEMENTS);
+        account.charge(size_of::<Self>());
         unsafe {
-            let mut right: Box<Self> = Box::new(MaybeUninit::uninit().assume_init());
-            right.len = (B - 1) as _;
-            match idx.cmp(&(B as usize)) {
+            let mut right = Box::<Self>::new_uninit();
+            ptr::addr_of_mut!((*right.as_mut_ptr()).len).write((B - 1) as _);
+            ptr::addr_of_mut!((*right.as_mut_ptr()).kind).write(Kind::Node);
+            let mut right = right.assume_init();
+            match idx.cmp(&B) {
                 Ordering::Less => {
                     ptr::copy_nonoverlapping(
                         self.keys.as_ptr().add(B),
@@ -739,7 +803,7 @@ impl<K, V> Node<K, V> {
         }
     }
 
-    fn pop(&mut self) -> (K, V, ChildUnion<K, V>) {
+    fn pop(&mut self) -> (K, V, ChildUnion<K, V, N, S>) {
         // log::info!("Node::pop()");
 
         debug_assert_ne!(self.len(), 0);
@@ -753,102 +817,67 @@ impl<K, V> Node<K, V> {
         }
     }
 
-    fn shift(&mut self) -> (K, V, ChildUnion<K, V>) {
+    fn shift(&mut self) -> (K, V, ChildUnion<K, V, N, S>) {
         // log::info!("Node::shift(..)");
 
         debug_assert_ne!(self.len(), 0);
 
         unsafe {
             let lchild = self.children[0].as_ptr().read();
-            ptr::copy(
-                self.children.as_ptr().add(1),
-                self.children.as_mut_ptr(),
-                self.len(),
-            );
+            copy_within(self.children.as_mut_ptr(), 1, 0, self.len());
             self.len -= 1;
             let key = self.keys[0].as_ptr().read();
-            ptr::copy(
-                self.keys.as_ptr().add(1),
-                self.keys.as_mut_ptr(),
-                self.len(),
-            );
+            copy_within(self.keys.as_mut_ptr(), 1, 0, self.len());
             let value = self.values[0].as_ptr().read();
-            ptr::copy(
-                self.values.as_ptr().add(1),
-                self.values.as_mut_ptr(),
-                self.len(),
-            );
+            copy_within(self.values.as_mut_ptr(), 1, 0, self.len());
             (key, value, lchild)
         }
     }
 
-    fn remove(&mut self, idx: usize) -> (K, V, ChildUnion<K, V>) {
+    /// Closes the gap at `idx`. The entry and its right child must have been moved out already, so
+    /// unlike `Leaf::remove` (and the same goes for `remove_borrow_left` and `merge_remove`) this
+    /// doesn't read them, which would duplicate them.
+    fn remove(&mut self, idx: usize) {
         // log::info!("Node::remove(..)");
         debug_assert!(idx < self.len());
 
         unsafe {
             self.len -= 1;
-            let key = self.keys[idx].as_ptr().read();
-            ptr::copy(
-                self.keys.as_ptr().add(idx + 1),
-                self.keys.as_mut_ptr().add(idx),
-                self.len() - idx,
-            );
-            let value = self.values[idx].as_ptr().read();
-            ptr::copy(
-                self.values.as_ptr().add(idx + 1),
-                self.values.as_mut_ptr().add(idx),
-                self.len() - idx,
-            );
-            let rchild = self.children[idx + 1].as_ptr().read();
-            ptr::copy(
-                self.children.as_ptr().add(idx + 2),
-                self.children.as_mut_ptr().add(idx + 1),
+            copy_within(self.keys.as_mut_ptr(), idx + 1, idx, self.len() - idx);
+            copy_within(self.values.as_mut_ptr(), idx + 1, idx, self.len() - idx);
+            copy_within(
+                self.children.as_mut_ptr(),
+                idx + 2,
+                idx + 1,
                 self.len() - idx,
             );
-            (key, value, rchild)
         }
     }
 
-    fn remove_borrow_left(
-        &mut self,
-        idx: usize,
-        key: K,
-        value: V,
-        lchild: ChildUnion<K, V>,
-    ) -> (K, V, ChildUnion<K, V>) {
+    fn remove_borrow_left(&mut self, idx: usize, key: K, value: V, lchild: ChildUnion<K, V, N, S>) {
         // log::info!("Node::remove_borrow_left(..)");
 
         debug_assert!(idx < self.len());
 
         unsafe {
-            let rm_key = self.keys[idx].as_ptr().read();
-            ptr::copy(self.keys.as_ptr(), self.keys.as_mut_ptr().add(1), idx);
+            copy_within(self.keys.as_mut_ptr(), 0, 1, idx);
             self.keys[0].write(key);
-
-            let rm_value = self.values[idx].as_ptr().read();
-            ptr::copy(self.values.as_ptr(), self.values.as_mut_ptr().add(1), idx);
+            copy_within(self.values.as_mut_ptr(), 0, 1, idx);
             self.values[0].write(value);
-
-            let rm_rchild = self.children[idx + 1].as_ptr().read();
-            ptr::copy(
-                self.children.as_ptr(),
-                self.children.as_mut_ptr().add(1),
-                idx + 1,
-            );
+            copy_within(self.children.as_mut_ptr(), 0, 1, idx + 1);
             self.children[0].write(lchild);
-
-            (rm_key, rm_value, rm_rchild)
         }
     }
 
+    #[allow(clippy::boxed_local)]
     fn merge_remove(
         &mut self,
+        account: &mut Account,
         sep_key: K,
         sep_value: V,
         right: Box<Self>,
         idx: usize,
-    ) -> (K, V, ChildUnion<K, V>) {
+    ) {
         // log::info!("Node::merge_remove(..)");
 
         debug_assert_eq!(self.len(), MIN_NUM_ELEMENTS);
@@ -858,7 +887,6 @@ impl<K, V> Node<K, V> {
             self.len = (2 * B - 2) as _;
 
             self.keys[B - 1].write(sep_key);
-            let rm_key = right.keys[idx].as_ptr().read();
             ptr::copy(right.keys.as_ptr(), self.keys.as_mut_ptr().add(B), idx);
             ptr::copy(
                 right.keys.as_ptr().add(idx + 1),
@@ -867,15 +895,12 @@ impl<K, V> Node<K, V> {
             );
 
             self.values[B - 1].write(sep_value);
-            let rm_value = right.values[idx].as_ptr().read();
             ptr::copy(right.values.as_ptr(), self.values.as_mut_ptr().add(B), idx);
             ptr::copy(
                 right.values.as_ptr().add(idx + 1),
                 self.values.as_mut_ptr().add(B + idx),
                 B - 2 - idx,
             );
-
-            let rm_rchild = right.children[idx + 1].as_ptr().read();
             ptr::copy(
                 right.children.as_ptr(),
                 self.children.as_mut_ptr().add(B),
@@ -887,13 +912,12 @@ impl<K, V> Node<K, V> {
                 B - 2 - idx,
             );
 
-            mem::forget(*right);
-
-            (rm_key, rm_value, rm_rchild)
+            free_forget(account, right);
         }
     }
 
-    fn merge(&mut self, sep_key: K, sep_value: V, right: Box<Self>) {
+    #[allow(clippy::boxed_local)]
+    fn merge(&mut self, account: &mut Account, sep_key: K, sep_value: V, right: Box<Self>) {
         // log::info!("Node::merge(..)");
         debug_assert_eq!(self.len(), MIN_NUM_ELEMENTS - 1);
         debug_assert_eq!(right.len(), MIN_NUM_ELEMENTS);
@@ -921,25 +945,35 @@ impl<K, V> Node<K, V> {
                 B,
             );
 
-            mem::forget(*right);
+            free_forget(account, right);
         }
     }
 }
 
+/// An entry and the child after it, moved out of a node.
+type Moved<K, V, N, S> = (K, V, ChildUnion<K, V, N, S>);
+
 #[cfg(not(debug_assertions))]
-union ChildUnion<K, V> {
-    node: ManuallyDrop<Box<Node<K, V>>>,
+union ChildUnion<K, V, N, S> {
+    node: ManuallyDrop<Box<Node<K, V, N, S>>>,
     leaf: ManuallyDrop<Box<Leaf<K, V>>>,
 }
 
 #[cfg(debug_assertions)]
-enum ChildUnion<K, V> {
-    Node(Box<Node<K, V>>),
+enum ChildUnion<K, V, N, S> {
+    Node(Box<Node<K, V, N, S>>),
     Leaf(Box<Leaf<K, V>>),
 }
 
 #[cfg(not(debug_assertions))]
-impl<K, V> ChildUnion<K, V> {
+impl<K, V, N, S> ChildUnion<K, V, N, S> {
+    /// Whether this is a leaf, read from the kind that leaves and internal nodes both keep after
+    /// their length.
+    #[inline]
+    fn is_leaf(&self) -> bool {
+        unsafe { ptr::addr_of!((*self.leaf).kind).read() == Kind::Leaf }
+    }
+
     #[inline]
     unsafe fn into_leaf(self) -> Box<Leaf<K, V>> {
         let md = ManuallyDrop::new(self);
@@ -947,7 +981,7 @@ impl<K, V> ChildUnion<K, V> {
     }
 
     #[inline]
-    unsafe fn into_node(self) -> Box<Node<K, V>> {
+    unsafe fn into_node(self) -> Box<Node<K, V, N, S>> {
         let md = ManuallyDrop::new(self);
         ptr::read(&*md.node as *const _)
     }
@@ -961,7 +995,7 @@ impl<K, V> ChildUnion<K, V> {
 
     #[cfg(not(debug_assertions))]
     #[inline]
-    fn node(node: Box<Node<K, V>>) -> Self {
+    fn node(node: Box<Node<K, V, N, S>>) -> Self {
         Self {
             node: ManuallyDrop::new(node),
         }
@@ -973,7 +1007,7 @@ impl<K, V> ChildUnion<K, V> {
     }
 
     #[inline]
-    unsafe fn as_node(&self) -> &Node<K, V> {
+    unsafe fn as_node(&self) -> &Node<K, V, N, S> {
         &*self.node
     }
 
@@ -983,13 +1017,19 @@ impl<K, V> ChildUnion<K, V> {
     }
 
     #[inline]
-    unsafe fn as_node_mut(&mut self) -> &mut Node<K, V> {
+    unsafe fn as_node_mut(&mut self) -> &mut Node<K, V, N, S> {
         &mut *self.node
     }
 }
 
 #[cfg(debug_assertions)]
-impl<K, V> ChildUnion<K, V> {
+impl<K, V, N, S> ChildUnion<K, V, N, S> {
+    /// Whether this is a leaf.
+    #[inline]
+    fn is_leaf(&self) -> bool {
+        matches!(self, Self::Leaf(_))
+    }
+
     #[inline]
     unsafe fn into_leaf(self) -> Box<Leaf<K, V>> {
         let md = ManuallyDrop::new(self);
@@ -1000,7 +1040,7 @@ impl<K, V> ChildUnion<K, V> {
     }
 
     #[inline]
-    unsafe fn into_node(self) -> Box<Node<K, V>> {
+    unsafe fn into_node(self) -> Box<Node<K, V, N, S>> {
         let md = ManuallyDrop::new(self);
         match &*md {
             Self::Leaf(_) => unreachable!(),
@@ -1014,23 +1054,23 @@ impl<K, V> ChildUnion<K, V> {
     }
 
     #[inline]
-    fn node(node: Box<Node<K, V>>) -> Self {
+    fn node(node: Box<Node<K, V, N, S>>) -> Self {
         Self::Node(node)
     }
 
     #[inline]
     unsafe fn as_leaf(&self) -> &Leaf<K, V> {
         match self {
-            Self::Leaf(leaf) => &*leaf,
+            Self::Leaf(leaf) => leaf,
             Self::Node(_node) => unreachable!(),
         }
     }
 
     #[inline]
-    unsafe fn as_node(&self) -> &Node<K, V> {
+    unsafe fn as_node(&self) -> &Node<K, V, N, S> {
         match self {
             Self::Leaf(_leaf) => unreachable!(),
-            Self::Node(node) => &*node,
+            Self::Node(node) => node,
         }
     }
 
@@ -1043,7 +1083,7 @@ impl<K, V> ChildUnion<K, V> {
     }
 
     #[inline]
-    unsafe fn as_node_mut(&mut self) -> &mut Node<K, V> {
+    unsafe fn as_node_mut(&mut self) -> &mut Node<K, V, N, S> {
         match self {
             Self::Leaf(_leaf) => unreachable!(),
             Self::Node(node) => &mut *node,
@@ -1051,13 +1091,28 @@ impl<K, V> ChildUnion<K, V> {
     }
 }
 
-impl<K, V> Drop for ChildUnion<K, V> {
+impl<K, V, N, S> ChildUnion<K, V, N, S> {
+    /// The number of elements in this child's subtree. Only valid in counted trees.
+    #[inline]
+    unsafe fn count(&self, is_leaf: bool) -> usize
+    where
+        N: Size,
+    {
+        if is_leaf {
+            self.as_leaf().len()
+        } else {
+            self.as_node().size.get()
+        }
+    }
+}
+
+impl<K, V, N, S> Drop for ChildUnion<K, V, N, S> {
     fn drop(&mut self) {
         panic!("Dropped undropable type: `{}`", type_name::<Self>(),);
     }
 }
 
-impl<K, V> Drop for Node<K, V> {
+impl<K, V, N, S> Drop for Node<K, V, N, S> {
     fn drop(&mut self) {
         panic!("Dropped undropable type: `{}`", type_name::<Self>(),);
     }
@@ -1069,18 +1124,67 @@ impl<K, V> Drop for Leaf<K, V> {
     }
 }
 
-pub struct BTree<K, V> {
-    root: MaybeUninit<ChildUnion<K, V>>,
+/// The nodes and children of a tree with the given `COUNTED` flag and aggregate `A`.
+type TreeNode<K, V, const COUNTED: bool, A> =
+    Node<K, V, SizeOf<COUNTED>, <A as Aggregate<K, V>>::Value>;
+type TreeChild<K, V, const COUNTED: bool, A> =
+    ChildUnion<K, V, SizeOf<COUNTED>, <A as Aggregate<K, V>>::Value>;
+
+/// A B-tree map that allocates its nodes on the heap.
+///
+/// When `COUNTED` is set, every node also keeps the size of its subtree, which makes positional
+/// queries like [`BTree::nth`] and [`BTree::rank`] logarithmic, at a small cost on every update.
+/// Uncounted nodes have no room for it, see [`Counted`].
+///
+/// `A` is an [`Aggregate`] cached in every node, which [`BTree::fold_range`] folds over key ranges
+/// in logarithmic time. Aggregated trees don't hand out mutable references to their values.
+///
+/// `S` is the [`SearchStrategy`] used to search the keys of each node, see [`crate::search`]. The
+/// tree owns it, so it can also be a [`By`] comparator that orders the keys instead of `Ord`, see
+/// [`BTreeBy`].
+pub struct BTree<K, V, const COUNTED: bool = false, A: Aggregate<K, V> = (), S = Linear>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    root: MaybeUninit<TreeChild<K, V, COUNTED, A>>,
     depth: u8,
     size: usize,
+    search: S,
+    _aggregate: PhantomData<A>,
+    account: Account,
 }
 
-impl<K, V> BTree<K, V> {
-    pub fn new() -> Self {
+impl<K, V, const COUNTED: bool, A: Aggregate<K, V>, S: Default> Default
+    for BTree<K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, const COUNTED: bool, A: Aggregate<K, V>, S> BTree<K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    #[inline]
+    pub fn new() -> Self
+    where
+        S: Default,
+    {
+        Self::with_search(S::default())
+    }
+
+    /// Creates a tree that searches its nodes with `search`.
+    pub fn with_search(search: S) -> Self {
         Self {
             root: MaybeUninit::uninit(),
             depth: 0,
             size: 0,
+            search,
+            _aggregate: PhantomData,
+            account: Account::default(),
         }
     }
 
@@ -1089,16 +1193,60 @@ impl<K, V> BTree<K, V> {
         self.size
     }
 
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Charges every leaf and node the tree allocates from now on to `budget`, which other trees
+    /// may share, and gives the bytes back as they are freed. Insertions that the budget can't
+    /// afford panic, while [`try_insert`](Self::try_insert) refuses them. Panics if the tree has
+    /// any nodes.
+    pub fn set_budget(&mut self, budget: Arc<dyn MemoryBudget>) {
+        assert_eq!(self.depth, 0, "a budget can only be set on an empty tree");
+        self.account.set_budget(budget);
+    }
+
+    /// The bytes of the leaves and nodes charged to the tree's budget.
+    #[inline]
+    pub fn charged(&self) -> usize {
+        self.account.charged()
+    }
+
+    /// Gathers the shape of the tree. Takes linear time in the number of nodes.
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats {
+            len: self.size,
+            depth: self.depth as usize,
+            ..TreeStats::default()
+        };
+        if self.depth != 0 {
+            Self::stats_child(
+                unsafe { self.root.assume_init_ref() },
+                0,
+                self.depth,
+                &mut stats,
+            );
+        }
+        stats
+    }
+
+    /// Gets an iterator over the entries of the tree, sorted by key.
+    #[inline]
+    pub fn iter(&self) -> Iter<'_, K, V, COUNTED, A, S> {
+        Iter::new(self, 0, self.size)
+    }
+
     pub fn get_entry<Q>(&self, key: &Q) -> Option<(&K, &V)>
     where
-        K: Borrow<Q> + Ord,
-        Q: Ord + ?Sized,
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
     {
         match self.depth {
             0 => None,
             1 => {
                 let root = unsafe { self.root.assume_init_ref().as_leaf() };
-                match root.linsearch(key) {
+                match root.search(&self.search, key) {
                     Ok(i) => unsafe {
                         Some((root.get_key_unchecked(i), root.get_value_unchecked(i)))
                     },
@@ -1108,7 +1256,7 @@ impl<K, V> BTree<K, V> {
             _ => {
                 let mut node = unsafe { self.root.assume_init_ref().as_node() };
                 for _ in 0..self.depth - 2 {
-                    match node.linsearch(key) {
+                    match node.search(&self.search, key) {
                         Ok(i) => {
                             return unsafe {
                                 Some((node.get_key_unchecked(i), node.get_value_unchecked(i)))
@@ -1119,13 +1267,13 @@ impl<K, V> BTree<K, V> {
                         }
                     }
                 }
-                match node.linsearch(key) {
+                match node.search(&self.search, key) {
                     Ok(i) => unsafe {
                         Some((node.get_key_unchecked(i), node.get_value_unchecked(i)))
                     },
                     Err(i) => {
                         let leaf = unsafe { node.get_child_unchecked(i).as_leaf() };
-                        match leaf.linsearch(key) {
+                        match leaf.search(&self.search, key) {
                             Ok(i) => unsafe {
                                 Some((leaf.get_key_unchecked(i), leaf.get_value_unchecked(i)))
                             },
@@ -1137,81 +1285,67 @@ impl<K, V> BTree<K, V> {
         }
     }
 
-    pub fn get_entry_mut<Q>(&mut self, key: &Q) -> Option<(&K, &mut V)>
+    #[inline]
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
     where
-        K: Borrow<Q> + Ord,
-        Q: Ord + ?Sized,
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
     {
-        match self.depth {
-            0 => None,
-            1 => {
-                let root = unsafe { self.root.assume_init_mut().as_leaf_mut() };
-                match root.linsearch(key) {
-                    Ok(i) => unsafe {
-                        let (keys, values) = root.get_all_mut();
-                        Some((keys.get_unchecked(i), values.get_unchecked_mut(i)))
-                    },
-                    Err(_) => None,
-                }
-            }
-            _ => {
-                let mut node = unsafe { self.root.assume_init_mut().as_node_mut() };
-                for _ in 0..self.depth - 2 {
-                    match node.linsearch(key) {
-                        Ok(i) => unsafe {
-                            let (keys, values, _) = node.get_all_mut();
-                            return Some((keys.get_unchecked(i), values.get_unchecked_mut(i)));
-                        },
-                        Err(i) => {
-                            node = unsafe { node.get_child_mut_unchecked(i).as_node_mut() };
-                        }
-                    }
-                }
-                match node.linsearch(key) {
-                    Ok(i) => unsafe {
-                        let (keys, values, _) = node.get_all_mut();
-                        Some((keys.get_unchecked(i), values.get_unchecked_mut(i)))
-                    },
-                    Err(i) => {
-                        let leaf = unsafe { node.get_child_mut_unchecked(i).as_leaf_mut() };
-                        match leaf.linsearch(key) {
-                            Ok(i) => unsafe {
-                                let (keys, values) = leaf.get_all_mut();
-                                Some((keys.get_unchecked(i), values.get_unchecked_mut(i)))
-                            },
-                            Err(_) => None,
-                        }
-                    }
-                }
-            }
-        }
+        Some(self.get_entry(key)?.1)
     }
 
-    #[inline]
-    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    /// Panics, before it changes anything, if the key is new and the tree's budget can't afford
+    /// the worst case of the insertion, like [`try_insert`](Self::try_insert) refuses it.
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)>
     where
-        K: Borrow<Q> + Ord,
-        Q: Ord + ?Sized,
+        S: SearchStrategy<K, K>,
     {
-        Some(self.get_entry(key)?.1)
+        assert!(self.prepay_insert(&key), "memory budget exceeded");
+        let replaced = self.insert_inner(key, value);
+        self.account.settle();
+        self.check_invariants();
+        replaced
+    }
+
+    /// Inserts like [`insert`](Self::insert), or hands the entry back if the key is new and the
+    /// tree's budget can't afford the worst case of the insertion, a leaf and a node for every
+    /// level. What the insertion doesn't use of it is given back afterwards. Replacing the value
+    /// of a key allocates nothing, so it is never refused.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<(K, V)>, BudgetExceeded<K, V>>
+    where
+        S: SearchStrategy<K, K>,
+    {
+        if !self.prepay_insert(&key) {
+            return Err(BudgetExceeded { key, value });
+        }
+        let replaced = self.insert_inner(key, value);
+        self.account.settle();
+        self.check_invariants();
+        Ok(replaced)
     }
 
-    #[inline]
-    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    /// Reserves the worst case of inserting `key`, a leaf and a node for every level, unless the
+    /// key is already there, so that the insertion can't run out of budget halfway through a
+    /// split. Returns false if the budget can't afford it.
+    fn prepay_insert(&mut self, key: &K) -> bool
     where
-        K: Borrow<Q> + Ord,
-        Q: Ord + ?Sized,
+        S: SearchStrategy<K, K>,
     {
-        Some(self.get_entry_mut(key)?.1)
+        if !self.account.has_budget() || self.get_entry(key).is_some() {
+            return true;
+        }
+        let worst =
+            size_of::<Leaf<K, V>>() + self.depth as usize * size_of::<TreeNode<K, V, COUNTED, A>>();
+        self.account.prepay(worst)
     }
 
-    pub fn insert(&mut self, mut key: K, mut value: V) -> Option<(K, V)>
+    fn insert_inner(&mut self, mut key: K, mut value: V) -> Option<(K, V)>
     where
-        K: Ord,
+        S: SearchStrategy<K, K>,
     {
         match self.depth {
             0 => {
-                let mut leaf = Leaf::new();
+                let mut leaf = Leaf::new(&mut self.account);
                 leaf.push(key, value);
                 self.root.write(ChildUnion::leaf(leaf));
                 self.depth = 1;
@@ -1221,7 +1355,7 @@ impl<K, V> BTree<K, V> {
             }
             1 => {
                 let root = unsafe { self.root.assume_init_mut().as_leaf_mut() };
-                match root.linsearch(&key) {
+                match root.search(&self.search, &key) {
                     Ok(i) => unsafe {
                         Some((
                             mem::replace(root.get_key_mut_unchecked(i), key),
@@ -1236,37 +1370,45 @@ impl<K, V> BTree<K, V> {
                         } else {
                             self.depth = 2;
 
-                            let (sep_key, sep_value, right) = root.insert_split(i, key, value);
+                            let (sep_key, sep_value, right) =
+                                root.insert_split(&mut self.account, i, key, value);
                             let left = unsafe { self.root.as_ptr().read() };
 
-                            self.root.write(ChildUnion::node(Node::new(
+                            let mut new_root = Node::new(
+                                &mut self.account,
                                 sep_key,
                                 sep_value,
                                 left,
                                 ChildUnion::leaf(right),
-                            )));
+                            );
+                            Self::refresh(&mut new_root, true);
+                            self.root.write(ChildUnion::node(new_root));
                         }
                         None
                     }
                 }
             }
             _ => {
-                let mut nodes_stack = RefStack::<_, 24>::with_root(unsafe {
+                let mut nodes_stack = RefStack::<_, MAX_DEPTH>::with_root(unsafe {
                     self.root.assume_init_mut().as_node_mut()
                 });
-                let mut indices = [0; 24];
+                let mut indices = [0; MAX_DEPTH];
 
-                for depth in 0..self.depth as usize - 2 {
+                for index in indices.iter_mut().take(self.depth as usize - 2) {
                     let node = nodes_stack.top_mut().unwrap();
-                    match node.linsearch(&key) {
-                        Ok(i) => unsafe {
-                            return Some((
-                                mem::replace(node.get_key_mut_unchecked(i), key),
-                                mem::replace(node.get_value_mut_unchecked(i), value),
-                            ));
-                        },
+                    match node.search(&self.search, &key) {
+                        Ok(i) => {
+                            let old = unsafe {
+                                (
+                                    mem::replace(node.get_key_mut_unchecked(i), key),
+                                    mem::replace(node.get_value_mut_unchecked(i), value),
+                                )
+                            };
+                            Self::update_path(&mut nodes_stack, self.depth, false);
+                            return Some(old);
+                        }
                         Err(i) => {
-                            indices[depth] = i;
+                            *index = i;
                             nodes_stack.push(|node| unsafe {
                                 node.get_child_mut_unchecked(i).as_node_mut()
                             });
@@ -1275,25 +1417,33 @@ impl<K, V> BTree<K, V> {
                 }
 
                 let (mut sep_key, mut sep_value, mut right);
-                let node: &mut Node<K, V> = nodes_stack.top_mut().unwrap();
-                match node.linsearch(&key) {
-                    Ok(i) => unsafe {
-                        return Some((
-                            mem::replace(node.get_key_mut_unchecked(i), key),
-                            mem::replace(node.get_value_mut_unchecked(i), value),
-                        ));
-                    },
+                let node: &mut TreeNode<K, V, COUNTED, A> = nodes_stack.top_mut().unwrap();
+                match node.search(&self.search, &key) {
+                    Ok(i) => {
+                        let old = unsafe {
+                            (
+                                mem::replace(node.get_key_mut_unchecked(i), key),
+                                mem::replace(node.get_value_mut_unchecked(i), value),
+                            )
+                        };
+                        Self::update_path(&mut nodes_stack, self.depth, false);
+                        return Some(old);
+                    }
                     Err(i) => {
                         let j;
                         indices[self.depth as usize - 2] = i;
                         let leaf = unsafe { node.get_child_mut_unchecked(i).as_leaf_mut() };
-                        match leaf.linsearch(&key) {
-                            Ok(i) => unsafe {
-                                return Some((
-                                    mem::replace(leaf.get_key_mut_unchecked(i), key),
-                                    mem::replace(leaf.get_value_mut_unchecked(i), value),
-                                ));
-                            },
+                        match leaf.search(&self.search, &key) {
+                            Ok(i) => {
+                                let old = unsafe {
+                                    (
+                                        mem::replace(leaf.get_key_mut_unchecked(i), key),
+                                        mem::replace(leaf.get_value_mut_unchecked(i), value),
+                                    )
+                                };
+                                Self::update_path(&mut nodes_stack, self.depth, false);
+                                return Some(old);
+                            }
                             Err(i) => {
                                 j = i;
                                 indices[self.depth as usize - 1] = j;
@@ -1301,6 +1451,7 @@ impl<K, V> BTree<K, V> {
                                 if leaf.len() < MAX_NUM_ELEMENTS {
                                     let overflow = leaf.insert(j, key, value);
                                     assert!(overflow.is_none());
+                                    Self::update_path(&mut nodes_stack, self.depth, true);
                                     return None;
                                 }
                             }
@@ -1322,6 +1473,7 @@ impl<K, V> BTree<K, V> {
                                 let leaf =
                                     unsafe { node.get_child_mut_unchecked(i - 1).as_leaf_mut() };
                                 leaf.push(key, value);
+                                Self::update_path(&mut nodes_stack, self.depth, true);
                                 return None;
                             }
                         }
@@ -1338,16 +1490,19 @@ impl<K, V> BTree<K, V> {
                                 let leaf =
                                     unsafe { node.get_child_mut_unchecked(i + 1).as_leaf_mut() };
                                 leaf.unshift(key, value);
+                                Self::update_path(&mut nodes_stack, self.depth, true);
                                 return None;
                             }
                         }
                         let leaf_right;
                         let child = unsafe { node.get_child_mut_unchecked(i).as_leaf_mut() };
-                        (sep_key, sep_value, leaf_right) = child.insert_split(j, key, value);
+                        (sep_key, sep_value, leaf_right) =
+                            child.insert_split(&mut self.account, j, key, value);
                         right = ChildUnion::leaf(leaf_right);
                         if node.len() < MAX_NUM_ELEMENTS {
                             let overflow = node.insert(i, sep_key, sep_value, right);
                             debug_assert!(overflow.is_none());
+                            Self::update_path(&mut nodes_stack, self.depth, true);
                             return None;
                         }
                     }
@@ -1356,9 +1511,10 @@ impl<K, V> BTree<K, V> {
                 while 1 < nodes_stack.len() {
                     nodes_stack.pop();
                     let depth = nodes_stack.len() - 1;
-                    let node: &mut Node<K, V> = nodes_stack.top_mut().unwrap();
+                    let node: &mut TreeNode<K, V, COUNTED, A> = nodes_stack.top_mut().unwrap();
                     let i = indices[depth];
                     let j = indices[depth + 1];
+                    let leaf_grandchildren = depth + 3 == self.depth as usize;
 
                     if 0 < i {
                         let left_neighbour = unsafe { node.get_child_unchecked(i - 1).as_node() };
@@ -1374,6 +1530,12 @@ impl<K, V> BTree<K, V> {
                             let neighbour =
                                 unsafe { node.get_child_mut_unchecked(i - 1).as_node_mut() };
                             neighbour.push(key, value, lchild);
+                            Self::refresh(neighbour, leaf_grandchildren);
+                            Self::refresh(
+                                unsafe { node.get_child_mut_unchecked(i).as_node_mut() },
+                                leaf_grandchildren,
+                            );
+                            Self::update_path(&mut nodes_stack, self.depth, true);
                             return None;
                         }
                     }
@@ -1385,33 +1547,48 @@ impl<K, V> BTree<K, V> {
                                 child.insert(j, sep_key, sep_value, right).unwrap();
                             key = mem::replace(unsafe { node.get_key_mut_unchecked(i) }, key);
                             value = mem::replace(unsafe { node.get_value_mut_unchecked(i) }, value);
-                            let node = unsafe { node.get_child_mut_unchecked(i + 1).as_node_mut() };
-                            node.unshift(key, value, rchild);
+                            let neighbour =
+                                unsafe { node.get_child_mut_unchecked(i + 1).as_node_mut() };
+                            neighbour.unshift(key, value, rchild);
+                            Self::refresh(neighbour, leaf_grandchildren);
+                            Self::refresh(
+                                unsafe { node.get_child_mut_unchecked(i).as_node_mut() },
+                                leaf_grandchildren,
+                            );
+                            Self::update_path(&mut nodes_stack, self.depth, true);
                             return None;
                         }
                     }
-                    let node_right;
+                    let mut node_right;
                     let child = unsafe { node.get_child_mut_unchecked(i).as_node_mut() };
                     (sep_key, sep_value, node_right) =
-                        child.insert_split(j, sep_key, sep_value, right);
+                        child.insert_split(&mut self.account, j, sep_key, sep_value, right);
+                    Self::refresh(child, leaf_grandchildren);
+                    Self::refresh(&mut node_right, leaf_grandchildren);
                     right = ChildUnion::node(node_right);
                     if node.len() < MAX_NUM_ELEMENTS {
                         let overflow = node.insert(i, sep_key, sep_value, right);
                         debug_assert!(overflow.is_none());
+                        Self::update_path(&mut nodes_stack, self.depth, true);
                         return None;
                     }
                 }
                 let root = nodes_stack.pop().unwrap();
-                let node_right;
+                let mut node_right;
                 (sep_key, sep_value, node_right) =
-                    root.insert_split(indices[0], sep_key, sep_value, right);
+                    root.insert_split(&mut self.account, indices[0], sep_key, sep_value, right);
+                let leaf_children = self.depth == 2;
+                Self::refresh(root, leaf_children);
+                Self::refresh(&mut node_right, leaf_children);
                 right = ChildUnion::node(node_right);
-                let new_root = Node::new(
+                let mut new_root = Node::new(
+                    &mut self.account,
                     sep_key,
                     sep_value,
                     unsafe { self.root.as_ptr().read() },
                     right,
                 );
+                Self::refresh(&mut new_root, false);
                 self.root.write(ChildUnion::node(new_root));
                 self.depth += 1;
                 None
@@ -1419,24 +1596,50 @@ impl<K, V> BTree<K, V> {
         }
     }
 
+    #[cfg(not(feature = "check-invariants"))]
     #[inline]
     pub fn remove<Q>(&mut self, key: &Q) -> Option<(K, V)>
     where
-        K: Borrow<Q> + Ord,
-        Q: Ord + ?Sized,
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        self.remove_inner(key)
+    }
+
+    /// With the `check-invariants` feature, `S` also has to compare keys with each other, to
+    /// check their order after the removal.
+    #[cfg(feature = "check-invariants")]
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q> + SearchStrategy<K, K>,
+    {
+        let removed = self.remove_inner(key);
+        self.check_invariants();
+        removed
+    }
+
+    #[inline]
+    fn remove_inner<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
     {
         match self.depth {
             0 => None,
             1 => {
                 let root = unsafe { self.root.assume_init_mut().as_leaf_mut() };
-                match root.linsearch(&key) {
+                match root.search(&self.search, key) {
                     Ok(i) => {
                         self.size -= 1;
                         let (key, value) = root.remove(i);
                         if root.len() == 0 {
                             unsafe {
                                 self.depth = 0;
-                                mem::forget(*self.root.as_ptr().read().into_leaf());
+                                free_forget(
+                                    &mut self.account,
+                                    self.root.as_ptr().read().into_leaf(),
+                                );
                             }
                         }
                         Some((key, value))
@@ -1445,16 +1648,16 @@ impl<K, V> BTree<K, V> {
                 }
             }
             _ => {
-                let mut node_stack = RefStack::<_, 24>::with_root(unsafe {
+                let mut node_stack = RefStack::<_, MAX_DEPTH>::with_root(unsafe {
                     self.root.assume_init_mut().as_node_mut()
                 });
-                let mut indices = [0; 24];
+                let mut indices = [0; MAX_DEPTH];
                 let mut target_depth = usize::MAX;
 
-                for depth in 0..self.depth as usize - 2 {
+                for (depth, index) in indices.iter_mut().enumerate().take(self.depth as usize - 2) {
                     let node = node_stack.top().unwrap();
                     let i = if target_depth == usize::MAX {
-                        match node.linsearch(key) {
+                        match node.search(&self.search, key) {
                             Ok(i) => {
                                 self.size -= 1;
                                 target_depth = depth;
@@ -1465,15 +1668,15 @@ impl<K, V> BTree<K, V> {
                     } else {
                         node.len()
                     };
-                    indices[depth] = i;
+                    *index = i;
                     node_stack
                         .push(|node| unsafe { node.get_child_mut_unchecked(i).as_node_mut() });
                 }
 
                 let depth = self.depth as usize - 2;
-                let node: &mut Node<K, V> = node_stack.top_mut().unwrap();
+                let node: &mut TreeNode<K, V, COUNTED, A> = node_stack.top_mut().unwrap();
                 let i = if target_depth == usize::MAX {
-                    match node.linsearch(key) {
+                    match node.search(&self.search, key) {
                         Ok(i) => {
                             self.size -= 1;
                             target_depth = depth;
@@ -1490,7 +1693,7 @@ impl<K, V> BTree<K, V> {
                 let j = {
                     let depth = self.depth as usize - 1;
                     let i = if target_depth == usize::MAX {
-                        match leaf.linsearch(key) {
+                        match leaf.search(&self.search, key) {
                             Ok(i) => {
                                 self.size -= 1;
                                 target_depth = depth;
@@ -1506,18 +1709,14 @@ impl<K, V> BTree<K, V> {
 
                     if MIN_NUM_ELEMENTS < leaf.len() {
                         let (key, value) = leaf.remove(i);
-                        if depth == target_depth {
-                            return Some((key, value));
-                        }
-                        while target_depth < node_stack.len() - 1 {
-                            let root = node_stack.pop();
-                            debug_assert!(root.is_none());
-                        }
-                        let node: &mut Node<K, V> = node_stack.top_mut().unwrap();
-                        let i = indices[target_depth];
-                        return Some((
-                            mem::replace(unsafe { node.get_key_mut_unchecked(i) }, key),
-                            mem::replace(unsafe { node.get_value_mut_unchecked(i) }, value),
+                        return Some(Self::shrink_path(
+                            &mut node_stack,
+                            self.depth,
+                            &indices,
+                            target_depth,
+                            depth,
+                            key,
+                            value,
                         ));
                     }
 
@@ -1543,20 +1742,15 @@ impl<K, V> BTree<K, V> {
                         let child = unsafe { node.get_child_mut_unchecked(i).as_leaf_mut() };
                         (key, value) = child.remove_borrow_left(j, key, value);
 
-                        if depth <= target_depth {
-                            return Some((key, value));
-                        } else {
-                            while target_depth < node_stack.len() - 1 {
-                                let root = node_stack.pop();
-                                debug_assert!(root.is_none());
-                            }
-                            let node: &mut Node<K, V> = node_stack.top_mut().unwrap();
-                            let i = indices[target_depth];
-                            return Some((
-                                mem::replace(unsafe { node.get_key_mut_unchecked(i) }, key),
-                                mem::replace(unsafe { node.get_value_mut_unchecked(i) }, value),
-                            ));
-                        }
+                        return Some(Self::shrink_path(
+                            &mut node_stack,
+                            self.depth,
+                            &indices,
+                            target_depth,
+                            depth,
+                            key,
+                            value,
+                        ));
                     }
                 }
                 if i < node.len() {
@@ -1570,20 +1764,15 @@ impl<K, V> BTree<K, V> {
                         let (rm_key, rm_value) = child.remove(j);
                         child.push(key, value);
 
-                        if depth <= target_depth {
-                            return Some((rm_key, rm_value));
-                        } else {
-                            while target_depth < node_stack.len() - 1 {
-                                let root = node_stack.pop();
-                                debug_assert!(root.is_none());
-                            }
-                            let node: &mut Node<K, V> = node_stack.top_mut().unwrap();
-                            let i = indices[target_depth];
-                            return Some((
-                                mem::replace(unsafe { node.get_key_mut_unchecked(i) }, rm_key),
-                                mem::replace(unsafe { node.get_value_mut_unchecked(i) }, rm_value),
-                            ));
-                        }
+                        return Some(Self::shrink_path(
+                            &mut node_stack,
+                            self.depth,
+                            &indices,
+                            target_depth,
+                            depth,
+                            rm_key,
+                            rm_value,
+                        ));
                     }
                 }
 
@@ -1597,7 +1786,8 @@ impl<K, V> BTree<K, V> {
                     let child = unsafe { node.children[i].as_ptr().read().into_leaf() };
 
                     let left = unsafe { node.get_child_mut_unchecked(i - 1).as_leaf_mut() };
-                    (rm_key, rm_value) = left.merge_remove(sep_key, sep_value, child, j);
+                    (rm_key, rm_value) =
+                        left.merge_remove(&mut self.account, sep_key, sep_value, child, j);
                 } else {
                     hole = 0;
                     let sep_key = unsafe { node.keys[0].as_ptr().read() };
@@ -1606,35 +1796,31 @@ impl<K, V> BTree<K, V> {
 
                     let child = unsafe { node.get_child_mut_unchecked(0).as_leaf_mut() };
                     (rm_key, rm_value) = child.remove(j);
-                    child.merge(sep_key, sep_value, right);
+                    child.merge(&mut self.account, sep_key, sep_value, right);
                 }
 
                 if MIN_NUM_ELEMENTS < node.len() {
-                    mem::forget(node.remove(hole));
-
-                    if depth <= target_depth {
-                        return Some((rm_key, rm_value));
-                    } else {
-                        while target_depth < node_stack.len() - 1 {
-                            let root = node_stack.pop();
-                            debug_assert!(root.is_none());
-                        }
-                        let node: &mut Node<K, V> = node_stack.top_mut().unwrap();
-                        let i = indices[target_depth];
-                        return Some((
-                            mem::replace(unsafe { node.get_key_mut_unchecked(i) }, rm_key),
-                            mem::replace(unsafe { node.get_value_mut_unchecked(i) }, rm_value),
-                        ));
-                    }
-                }
+                    node.remove(hole);
+
+                    return Some(Self::shrink_path(
+                        &mut node_stack,
+                        self.depth,
+                        &indices,
+                        target_depth,
+                        depth,
+                        rm_key,
+                        rm_value,
+                    ));
+                }
 
                 while 1 < node_stack.len() {
                     node_stack.pop();
 
                     let depth = node_stack.len() - 1;
                     let i = indices[depth];
+                    let leaf_grandchildren = depth + 3 == self.depth as usize;
 
-                    let node: &mut Node<K, V> = node_stack.top_mut().unwrap();
+                    let node: &mut TreeNode<K, V, COUNTED, A> = node_stack.top_mut().unwrap();
 
                     if depth == target_depth {
                         rm_key = mem::replace(unsafe { node.get_key_mut_unchecked(i) }, rm_key);
@@ -1651,25 +1837,22 @@ impl<K, V> BTree<K, V> {
                             value =
                                 mem::replace(unsafe { node.get_value_mut_unchecked(i - 1) }, value);
                             let child = unsafe { node.get_child_mut_unchecked(i).as_node_mut() };
-                            mem::forget(child.remove_borrow_left(hole, key, value, rchild));
-
-                            if depth <= target_depth {
-                                return Some((rm_key, rm_value));
-                            } else {
-                                while target_depth < node_stack.len() - 1 {
-                                    let root = node_stack.pop();
-                                    debug_assert!(root.is_none());
-                                }
-                                let node: &mut Node<K, V> = node_stack.top_mut().unwrap();
-                                let i = indices[target_depth];
-                                return Some((
-                                    mem::replace(unsafe { node.get_key_mut_unchecked(i) }, rm_key),
-                                    mem::replace(
-                                        unsafe { node.get_value_mut_unchecked(i) },
-                                        rm_value,
-                                    ),
-                                ));
-                            }
+                            child.remove_borrow_left(hole, key, value, rchild);
+                            Self::refresh(child, leaf_grandchildren);
+                            Self::refresh(
+                                unsafe { node.get_child_mut_unchecked(i - 1).as_node_mut() },
+                                leaf_grandchildren,
+                            );
+
+                            return Some(Self::shrink_path(
+                                &mut node_stack,
+                                self.depth,
+                                &indices,
+                                target_depth,
+                                depth,
+                                rm_key,
+                                rm_value,
+                            ));
                         }
                     }
                     if i < node.len() {
@@ -1680,26 +1863,23 @@ impl<K, V> BTree<K, V> {
                             key = mem::replace(unsafe { node.get_key_mut_unchecked(i) }, key);
                             value = mem::replace(unsafe { node.get_value_mut_unchecked(i) }, value);
                             let child = unsafe { node.get_child_mut_unchecked(i).as_node_mut() };
-                            mem::forget(child.remove(hole));
+                            child.remove(hole);
                             child.push(key, value, lchild);
-
-                            if depth <= target_depth {
-                                return Some((rm_key, rm_value));
-                            } else {
-                                while target_depth < node_stack.len() - 1 {
-                                    let root = node_stack.pop();
-                                    debug_assert!(root.is_none());
-                                }
-                                let node: &mut Node<K, V> = node_stack.top_mut().unwrap();
-                                let i = indices[target_depth];
-                                return Some((
-                                    mem::replace(unsafe { node.get_key_mut_unchecked(i) }, rm_key),
-                                    mem::replace(
-                                        unsafe { node.get_value_mut_unchecked(i) },
-                                        rm_value,
-                                    ),
-                                ));
-                            }
+                            Self::refresh(child, leaf_grandchildren);
+                            Self::refresh(
+                                unsafe { node.get_child_mut_unchecked(i + 1).as_node_mut() },
+                                leaf_grandchildren,
+                            );
+
+                            return Some(Self::shrink_path(
+                                &mut node_stack,
+                                self.depth,
+                                &indices,
+                                target_depth,
+                                depth,
+                                rm_key,
+                                rm_value,
+                            ));
                         }
                     }
 
@@ -1709,7 +1889,8 @@ impl<K, V> BTree<K, V> {
                         let child = unsafe { node.children[i].as_ptr().read().into_node() };
 
                         let left = unsafe { node.get_child_mut_unchecked(i - 1).as_node_mut() };
-                        mem::forget(left.merge_remove(sep_key, sep_value, child, hole));
+                        left.merge_remove(&mut self.account, sep_key, sep_value, child, hole);
+                        Self::refresh(left, leaf_grandchildren);
                         hole = i - 1;
                     } else {
                         let sep_key = unsafe { node.keys[0].as_ptr().read() };
@@ -1717,43 +1898,903 @@ impl<K, V> BTree<K, V> {
                         let right = unsafe { node.children[1].as_ptr().read().into_node() };
 
                         let child = unsafe { node.get_child_mut_unchecked(0).as_node_mut() };
-                        mem::forget(child.remove(hole));
-                        child.merge(sep_key, sep_value, right);
+                        child.remove(hole);
+                        child.merge(&mut self.account, sep_key, sep_value, right);
+                        Self::refresh(child, leaf_grandchildren);
                         hole = 0;
                     }
 
                     if MIN_NUM_ELEMENTS < node.len() {
-                        mem::forget(node.remove(hole));
-
-                        if depth <= target_depth {
-                            return Some((rm_key, rm_value));
-                        } else {
-                            while target_depth < node_stack.len() - 1 {
-                                let root = node_stack.pop();
-                                debug_assert!(root.is_none());
-                            }
-                            let node: &mut Node<K, V> = node_stack.top_mut().unwrap();
-                            let i = indices[target_depth];
-                            return Some((
-                                mem::replace(unsafe { node.get_key_mut_unchecked(i) }, rm_key),
-                                mem::replace(unsafe { node.get_value_mut_unchecked(i) }, rm_value),
-                            ));
-                        }
+                        node.remove(hole);
+
+                        return Some(Self::shrink_path(
+                            &mut node_stack,
+                            self.depth,
+                            &indices,
+                            target_depth,
+                            depth,
+                            rm_key,
+                            rm_value,
+                        ));
                     }
                 }
 
-                let root: &mut Node<K, V> = node_stack.pop().unwrap();
-                mem::forget(root.remove(hole));
+                let root: &mut TreeNode<K, V, COUNTED, A> = node_stack.pop().unwrap();
+                root.remove(hole);
                 if root.len() == 0 {
                     self.depth -= 1;
                     let root = unsafe { self.root.as_ptr().read().into_node() };
                     self.root.write(unsafe { root.children[0].as_ptr().read() });
-                    mem::forget(*root);
+                    free_forget(&mut self.account, root);
+                } else {
+                    if COUNTED {
+                        root.size.set(root.size.get() - 1);
+                    }
+                    if A::ENABLED {
+                        root.agg = Self::fold_node(root, self.depth == 2);
+                    }
                 }
                 Some((rm_key, rm_value))
             }
         }
     }
+
+    /// Gets the aggregate of all the entries in the tree.
+    #[inline]
+    pub fn fold(&self) -> A::Value {
+        match self.depth {
+            0 => A::identity(),
+            1 => Self::fold_leaf(unsafe { self.root.assume_init_ref().as_leaf() }),
+            _ => unsafe { self.root.assume_init_ref().as_node() }.agg,
+        }
+    }
+
+    /// Gets the aggregate of the entries with keys in `range`. Takes logarithmic time, since
+    /// subtrees that fall entirely inside the range use their cached aggregates.
+    pub fn fold_range<Q, R>(&self, range: R) -> A::Value
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+        R: RangeBounds<Q>,
+    {
+        match self.depth {
+            0 => A::identity(),
+            _ => self.fold_child(
+                unsafe { self.root.assume_init_ref() },
+                self.depth as usize - 1,
+                range.start_bound(),
+                range.end_bound(),
+            ),
+        }
+    }
+
+    /// Folds the entries of `child` between `start` and `end`. `height` is the number of levels
+    /// below `child`.
+    fn fold_child<Q>(
+        &self,
+        child: &TreeChild<K, V, COUNTED, A>,
+        height: usize,
+        start: Bound<&Q>,
+        end: Bound<&Q>,
+    ) -> A::Value
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        if height == 0 {
+            let leaf = unsafe { child.as_leaf() };
+            let (lo, hi) = leaf.bound_range(&self.search, start, end);
+            return Self::fold_entries(&leaf.keys()[lo..hi], &leaf.values()[lo..hi]);
+        }
+        let node = unsafe { child.as_node() };
+        if let (Bound::Unbounded, Bound::Unbounded) = (start, end) {
+            return node.agg;
+        }
+
+        let (lo, hi) = node.bound_range(&self.search, start, end);
+        let child = |i: usize| unsafe { node.get_child_unchecked(i) };
+        if lo == hi {
+            return self.fold_child(child(lo), height - 1, start, end);
+        }
+        // Only the children at the edges of the range can stick out of it.
+        let mut agg = self.fold_child(child(lo), height - 1, start, Bound::Unbounded);
+        for i in lo..hi {
+            let entry = unsafe { A::lift(node.get_key_unchecked(i), node.get_value_unchecked(i)) };
+            agg = A::combine(&agg, &entry);
+            let end = if i + 1 < hi { Bound::Unbounded } else { end };
+            agg = A::combine(
+                &agg,
+                &self.fold_child(child(i + 1), height - 1, Bound::Unbounded, end),
+            );
+        }
+        agg
+    }
+
+    /// Drops the entries of `child`, which has `height` levels below it, and frees its nodes.
+    unsafe fn drop_child(&mut self, child: TreeChild<K, V, COUNTED, A>, height: usize) {
+        if height == 0 {
+            let mut leaf = child.into_leaf();
+            ptr::drop_in_place(leaf.keys_mut());
+            ptr::drop_in_place(leaf.values_mut());
+            free_forget(&mut self.account, leaf);
+        } else {
+            let mut node = child.into_node();
+            ptr::drop_in_place(node.keys_mut());
+            ptr::drop_in_place(node.values_mut());
+            for i in 0..=node.len() {
+                self.drop_child(node.children[i].as_ptr().read(), height - 1);
+            }
+            free_forget(&mut self.account, node);
+        }
+    }
+
+    fn stats_child(
+        child: &TreeChild<K, V, COUNTED, A>,
+        level: usize,
+        depth: u8,
+        stats: &mut TreeStats,
+    ) {
+        if level + 1 == depth as usize {
+            Self::record_node(stats, level, unsafe { child.as_leaf() }.len(), true);
+        } else {
+            let node = unsafe { child.as_node() };
+            Self::record_node(stats, level, node.len(), false);
+            for child in node.children() {
+                Self::stats_child(child, level + 1, depth, stats);
+            }
+        }
+    }
+
+    #[inline]
+    fn record_node(stats: &mut TreeStats, level: usize, len: usize, is_leaf: bool) {
+        if is_leaf {
+            stats.leaves += 1;
+        } else {
+            stats.nodes += 1;
+        }
+        let level = &mut stats.levels[level];
+        level.nodes += 1;
+        level.keys += len;
+        level.capacity += MAX_NUM_ELEMENTS;
+    }
+
+    /// Recomputes the cached size and aggregate of a node whose children changed.
+    #[inline]
+    fn refresh(node: &mut TreeNode<K, V, COUNTED, A>, leaf_children: bool) {
+        if COUNTED {
+            node.recount(leaf_children);
+        }
+        if A::ENABLED {
+            node.agg = Self::fold_node(node, leaf_children);
+        }
+    }
+
+    /// Folds a node's children and entries, using the cached aggregates of child nodes.
+    fn fold_node(node: &TreeNode<K, V, COUNTED, A>, leaf_children: bool) -> A::Value {
+        let fold_child = |i: usize| unsafe {
+            let child = node.get_child_unchecked(i);
+            if leaf_children {
+                Self::fold_leaf(child.as_leaf())
+            } else {
+                child.as_node().agg
+            }
+        };
+        let mut agg = fold_child(0);
+        for (i, (key, value)) in node.keys().iter().zip(node.values()).enumerate() {
+            agg = A::combine(&agg, &A::lift(key, value));
+            agg = A::combine(&agg, &fold_child(i + 1));
+        }
+        agg
+    }
+
+    #[inline]
+    fn fold_leaf(leaf: &Leaf<K, V>) -> A::Value {
+        Self::fold_entries(leaf.keys(), leaf.values())
+    }
+
+    #[inline]
+    fn fold_entries(keys: &[K], values: &[V]) -> A::Value {
+        keys.iter()
+            .zip(values)
+            .fold(A::identity(), |agg, (key, value)| {
+                A::combine(&agg, &A::lift(key, value))
+            })
+    }
+
+    /// Unwinds the insertion path. In counted trees every node on it gains an element if `grown`
+    /// is set, and in aggregated trees every node on it is refolded.
+    #[inline]
+    fn update_path(
+        nodes_stack: &mut RefStack<'_, TreeNode<K, V, COUNTED, A>, MAX_DEPTH>,
+        tree_depth: u8,
+        grown: bool,
+    ) {
+        if COUNTED || A::ENABLED {
+            while !nodes_stack.is_empty() {
+                let depth = nodes_stack.len() - 1;
+                let node = nodes_stack.top_mut().unwrap();
+                if COUNTED && grown {
+                    node.size.set(node.size.get() + 1);
+                }
+                if A::ENABLED {
+                    node.agg = Self::fold_node(node, depth + 2 == tree_depth as usize);
+                }
+                nodes_stack.pop();
+            }
+        }
+    }
+
+    /// Unwinds the removal path once the tree is balanced from `depth` down. The removed entry is
+    /// swapped into its original place if that is above `depth`, in counted trees every node on
+    /// the path loses an element, and in aggregated trees every node on the path is refolded.
+    fn shrink_path(
+        node_stack: &mut RefStack<'_, TreeNode<K, V, COUNTED, A>, MAX_DEPTH>,
+        tree_depth: u8,
+        indices: &[usize],
+        target_depth: usize,
+        depth: usize,
+        mut key: K,
+        mut value: V,
+    ) -> (K, V) {
+        while !node_stack.is_empty() {
+            let node_depth = node_stack.len() - 1;
+            let node: &mut TreeNode<K, V, COUNTED, A> = node_stack.top_mut().unwrap();
+            if COUNTED {
+                node.size.set(node.size.get() - 1);
+            }
+            if node_depth == target_depth && target_depth < depth {
+                let i = indices[target_depth];
+                key = mem::replace(unsafe { node.get_key_mut_unchecked(i) }, key);
+                value = mem::replace(unsafe { node.get_value_mut_unchecked(i) }, value);
+            }
+            if A::ENABLED {
+                node.agg = Self::fold_node(node, node_depth + 2 == tree_depth as usize);
+            }
+            if !COUNTED && !A::ENABLED && node_depth <= target_depth {
+                break;
+            }
+            node_stack.pop();
+        }
+        (key, value)
+    }
+}
+
+/// The sorted bulk build, only used by deserialization for now.
+#[cfg_attr(not(feature = "serde"), allow(dead_code))]
+impl<K, V, const COUNTED: bool, A: Aggregate<K, V>, S: SearchStrategy<K, K>>
+    BTree<K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    /// Appends an entry whose key is greater than every key in the tree, and hands it back
+    /// otherwise. Doesn't search or split: the nodes are filled up from left to right, which
+    /// leaves the right border underfull, and the cached sizes and aggregates stale, until
+    /// [`finish_sorted`](Self::finish_sorted) is called. Nothing else may use the tree before.
+    pub(crate) fn push_sorted(&mut self, key: K, value: V) -> Result<(), (K, V)> {
+        if self.depth == 0 {
+            let mut leaf = Leaf::new(&mut self.account);
+            leaf.push(key, value);
+            self.root.write(ChildUnion::leaf(leaf));
+            self.depth = 1;
+            self.size = 1;
+            return Ok(());
+        }
+
+        // The deepest key on the right border is the last one, and new entries go into the lowest
+        // node there that has room.
+        let height = self.depth as usize - 1;
+        let mut last = None;
+        let mut open = None;
+        let mut child = unsafe { self.root.assume_init_ref() };
+        for level in 0..height {
+            let node = unsafe { child.as_node() };
+            last = node.keys().last().or(last);
+            if node.len() < MAX_NUM_ELEMENTS {
+                open = Some(level);
+            }
+            child = &node.children()[node.len()];
+        }
+        let leaf = unsafe { child.as_leaf() };
+        if let Some(last) = leaf.keys().last().or(last) {
+            if self.search.search(slice::from_ref(last), &key) != Err(1) {
+                return Err((key, value));
+            }
+        }
+        let leaf_has_room = leaf.len() < MAX_NUM_ELEMENTS;
+
+        self.size += 1;
+        if leaf_has_room {
+            unsafe { self.border_mut(height).as_leaf_mut() }.push(key, value);
+        } else if let Some(level) = open {
+            let subtree = self.empty_subtree(height - level - 1);
+            unsafe { self.border_mut(level).as_node_mut() }.push(key, value, subtree);
+        } else {
+            let subtree = self.empty_subtree(height);
+            let root = unsafe { self.root.as_ptr().read() };
+            let root = Node::new(&mut self.account, key, value, root, subtree);
+            self.root.write(ChildUnion::node(root));
+            self.depth += 1;
+        }
+        Ok(())
+    }
+
+    /// Finishes a tree built with [`push_sorted`](Self::push_sorted). Every underfull node on the
+    /// right border takes entries from its left neighbour, which is full since nodes are only left
+    /// behind once they are. Then the cached sizes and aggregates are recomputed.
+    pub(crate) fn finish_sorted(&mut self) {
+        if self.depth < 2 {
+            return;
+        }
+        let height = self.depth as usize - 1;
+        let mut child = unsafe { self.root.assume_init_mut() };
+        for level in 0..height {
+            let node = unsafe { child.as_node_mut() };
+            let len = node.len();
+            let (keys, values, children) = node.get_all_mut();
+            let (left, right) = children.split_at_mut(len);
+            let (left, right) = (&mut left[len - 1], &mut right[0]);
+            if level + 1 == height {
+                let (left, right) = unsafe { (left.as_leaf_mut(), right.as_leaf_mut()) };
+                while right.len() < MIN_NUM_ELEMENTS {
+                    let (key, value) = left.pop();
+                    let key = mem::replace(&mut keys[len - 1], key);
+                    let value = mem::replace(&mut values[len - 1], value);
+                    right.unshift(key, value);
+                }
+            } else {
+                let (left, right) = unsafe { (left.as_node_mut(), right.as_node_mut()) };
+                while right.len() < MIN_NUM_ELEMENTS {
+                    let (key, value, lchild) = left.pop();
+                    let key = mem::replace(&mut keys[len - 1], key);
+                    let value = mem::replace(&mut values[len - 1], value);
+                    right.unshift(key, value, lchild);
+                }
+            }
+            child = right;
+        }
+
+        if COUNTED || A::ENABLED {
+            Self::refresh_subtree(unsafe { self.root.assume_init_mut().as_node_mut() }, height);
+        }
+        self.check_invariants();
+    }
+
+    /// Gets the child on the right border of the tree at `level`.
+    unsafe fn border_mut(&mut self, level: usize) -> &mut TreeChild<K, V, COUNTED, A> {
+        let mut child = self.root.assume_init_mut();
+        for _ in 0..level {
+            let node = child.as_node_mut();
+            child = node.get_child_mut_unchecked(node.len());
+        }
+        child
+    }
+
+    /// Allocates a chain of `height` nodes without keys above an empty leaf.
+    fn empty_subtree(&mut self, height: usize) -> TreeChild<K, V, COUNTED, A> {
+        let mut child = ChildUnion::leaf(Leaf::new(&mut self.account));
+        for _ in 0..height {
+            child = ChildUnion::node(Node::with_child(&mut self.account, child));
+        }
+        child
+    }
+
+    /// Recomputes the cached sizes and aggregates of the subtree of `node`, which is `height`
+    /// levels above the leaves.
+    fn refresh_subtree(node: &mut TreeNode<K, V, COUNTED, A>, height: usize) {
+        if 1 < height {
+            for i in 0..=node.len() {
+                let child = unsafe { node.get_child_mut_unchecked(i).as_node_mut() };
+                Self::refresh_subtree(child, height - 1);
+            }
+        }
+        Self::refresh(node, height == 1);
+    }
+}
+
+impl<K, V, const COUNTED: bool, A: Aggregate<K, V>, S: SearchStrategy<K, K>>
+    BTree<K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    /// Checks the structural invariants of the tree: the keys are strictly ordered within and
+    /// across nodes, every node but the root holds between `MIN_NUM_ELEMENTS` and
+    /// `MAX_NUM_ELEMENTS` keys, the leaves are all on the last level, and the cached sizes match
+    /// the entries. Returns the first violation found, or the shape of the tree.
+    ///
+    /// The order is checked with the tree's own search strategy. Takes linear time.
+    pub fn validate(&self) -> Result<TreeStats, InvariantViolation> {
+        if MAX_DEPTH < self.depth as usize {
+            return Err(InvariantViolation::TooDeep {
+                depth: self.depth as usize,
+            });
+        }
+        let mut stats = TreeStats {
+            depth: self.depth as usize,
+            ..TreeStats::default()
+        };
+        if self.depth != 0 {
+            let mut last = None;
+            let root = unsafe { self.root.assume_init_ref() };
+            stats.len = self.validate_child(root, 0, &mut last, &mut stats)?;
+        }
+        if stats.len != self.size {
+            return Err(InvariantViolation::SizeMismatch {
+                expected: self.size,
+                actual: stats.len,
+            });
+        }
+        Ok(stats)
+    }
+
+    /// Validates the subtree of `child` at `level`, and returns its number of entries. `last` is
+    /// the last key visited in order.
+    fn validate_child<'a>(
+        &'a self,
+        child: &'a TreeChild<K, V, COUNTED, A>,
+        level: usize,
+        last: &mut Option<&'a K>,
+        stats: &mut TreeStats,
+    ) -> Result<usize, InvariantViolation> {
+        let is_leaf = level + 1 == self.depth as usize;
+        if child.is_leaf() != is_leaf {
+            return Err(InvariantViolation::LeafDepth { level });
+        }
+        let len = unsafe {
+            if is_leaf {
+                child.as_leaf().len()
+            } else {
+                child.as_node().len()
+            }
+        };
+        let min = if level == 0 { 1 } else { MIN_NUM_ELEMENTS };
+        if !(min..=MAX_NUM_ELEMENTS).contains(&len) {
+            return Err(InvariantViolation::BadLength { level, len });
+        }
+
+        Self::record_node(stats, level, len, is_leaf);
+        if is_leaf {
+            let leaf = unsafe { child.as_leaf() };
+            for (index, key) in leaf.keys().iter().enumerate() {
+                self.validate_order(last, key, level, index)?;
+            }
+            Ok(len)
+        } else {
+            let node = unsafe { child.as_node() };
+            let mut count = len;
+            for (index, child) in node.children().iter().enumerate() {
+                count += self.validate_child(child, level + 1, last, stats)?;
+                if let Some(key) = node.keys().get(index) {
+                    self.validate_order(last, key, level, index)?;
+                }
+            }
+            if COUNTED && node.size.get() != count {
+                return Err(InvariantViolation::CountMismatch {
+                    level,
+                    cached: node.size.get(),
+                    actual: count,
+                });
+            }
+            Ok(count)
+        }
+    }
+
+    #[inline]
+    fn validate_order<'a>(
+        &self,
+        last: &mut Option<&'a K>,
+        key: &'a K,
+        level: usize,
+        index: usize,
+    ) -> Result<(), InvariantViolation> {
+        if let Some(last) = last {
+            if self.search.search(slice::from_ref(*last), key) != Err(1) {
+                return Err(InvariantViolation::OutOfOrder { level, index });
+            }
+        }
+        *last = Some(key);
+        Ok(())
+    }
+
+    /// Panics if the tree is invalid, when the `check-invariants` feature is enabled. Called after
+    /// every mutation, so a corruption is caught by the operation that caused it.
+    #[inline]
+    fn check_invariants(&self) {
+        #[cfg(feature = "check-invariants")]
+        if let Err(violation) = self.validate() {
+            panic!("{}: {violation}", type_name::<Self>());
+        }
+    }
+
+    /// Breaks the tree with `corruption`, for the tests of [`validate`](Self::validate), and
+    /// returns the corruption that undoes it.
+    ///
+    /// # Safety
+    /// Until it is undone, the tree may only be validated and corrupted. The leaf corruptions
+    /// need a leaf with two keys.
+    #[cfg(feature = "check-invariants")]
+    #[doc(hidden)]
+    pub unsafe fn corrupt(&mut self, corruption: Corruption) -> Corruption {
+        let first_leaf = |tree: &mut Self| {
+            let mut child = tree.root.assume_init_mut();
+            for _ in 1..tree.depth {
+                child = child.as_node_mut().get_child_mut_unchecked(0);
+            }
+            child.as_leaf_mut() as *mut Leaf<K, V>
+        };
+        match corruption {
+            Corruption::Size(size) => Corruption::Size(mem::replace(&mut self.size, size)),
+            Corruption::Depth(depth) => {
+                Corruption::Depth(mem::replace(&mut self.depth, depth as u8) as usize)
+            }
+            Corruption::LeafLen(len) => {
+                let leaf = &mut *first_leaf(self);
+                Corruption::LeafLen(mem::replace(&mut leaf.len, len as u8) as usize)
+            }
+            Corruption::SwapKeys => {
+                (*first_leaf(self)).keys.swap(0, 1);
+                Corruption::SwapKeys
+            }
+            Corruption::RootCount(size) => {
+                let root = self.root.assume_init_mut().as_node_mut();
+                let cached = root.size.get();
+                root.size.set(size);
+                Corruption::RootCount(cached)
+            }
+            Corruption::SwapLinks => panic!("{corruption:?} only applies to a BPlusTree"),
+        }
+    }
+}
+
+/// Mutable access to values is only given out when nothing is aggregated over them, since the
+/// cached aggregates couldn't follow the changes.
+impl<K, V, const COUNTED: bool, S> BTree<K, V, COUNTED, (), S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    pub fn get_entry_mut<Q>(&mut self, key: &Q) -> Option<(&K, &mut V)>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        match self.depth {
+            0 => None,
+            1 => {
+                let root = unsafe { self.root.assume_init_mut().as_leaf_mut() };
+                match root.search(&self.search, key) {
+                    Ok(i) => unsafe {
+                        let (keys, values) = root.get_all_mut();
+                        Some((keys.get_unchecked(i), values.get_unchecked_mut(i)))
+                    },
+                    Err(_) => None,
+                }
+            }
+            _ => {
+                let mut node = unsafe { self.root.assume_init_mut().as_node_mut() };
+                for _ in 0..self.depth - 2 {
+                    match node.search(&self.search, key) {
+                        Ok(i) => unsafe {
+                            let (keys, values, _) = node.get_all_mut();
+                            return Some((keys.get_unchecked(i), values.get_unchecked_mut(i)));
+                        },
+                        Err(i) => {
+                            node = unsafe { node.get_child_mut_unchecked(i).as_node_mut() };
+                        }
+                    }
+                }
+                match node.search(&self.search, key) {
+                    Ok(i) => unsafe {
+                        let (keys, values, _) = node.get_all_mut();
+                        Some((keys.get_unchecked(i), values.get_unchecked_mut(i)))
+                    },
+                    Err(i) => {
+                        let leaf = unsafe { node.get_child_mut_unchecked(i).as_leaf_mut() };
+                        match leaf.search(&self.search, key) {
+                            Ok(i) => unsafe {
+                                let (keys, values) = leaf.get_all_mut();
+                                Some((keys.get_unchecked(i), values.get_unchecked_mut(i)))
+                            },
+                            Err(_) => None,
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[inline]
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        Some(self.get_entry_mut(key)?.1)
+    }
+}
+
+/// A [`BTree`] ordered by the comparator `C` instead of `Ord`.
+pub type BTreeBy<K, V, C> = BTree<K, V, false, (), By<C>>;
+
+impl<K, V, const COUNTED: bool, A: Aggregate<K, V>, C> BTree<K, V, COUNTED, A, By<C>>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    /// Creates a tree ordered by `compare`.
+    #[inline]
+    pub fn with_comparator(compare: C) -> Self {
+        Self::with_search(By(compare))
+    }
+
+    /// Gets the comparator that orders the tree.
+    #[inline]
+    pub fn comparator(&self) -> &C {
+        &self.search.0
+    }
+}
+
+impl<K, V, A: Aggregate<K, V>, S> BTree<K, V, true, A, S> {
+    /// Returns the entry at position `index` in key order.
+    pub fn nth(&self, mut index: usize) -> Option<(&K, &V)> {
+        if self.size <= index {
+            return None;
+        }
+        let mut child = unsafe { self.root.assume_init_ref() };
+        for depth in 0..self.depth as usize - 1 {
+            let node = unsafe { child.as_node() };
+            let leaf_children = depth + 2 == self.depth as usize;
+            let mut i = 0;
+            loop {
+                let count = unsafe { node.get_child_unchecked(i).count(leaf_children) };
+                match index.cmp(&count) {
+                    Ordering::Less => break,
+                    Ordering::Equal => unsafe {
+                        return Some((node.get_key_unchecked(i), node.get_value_unchecked(i)));
+                    },
+                    Ordering::Greater => {
+                        index -= count + 1;
+                        i += 1;
+                    }
+                }
+            }
+            child = unsafe { node.get_child_unchecked(i) };
+        }
+        let leaf = unsafe { child.as_leaf() };
+        unsafe {
+            Some((
+                leaf.get_key_unchecked(index),
+                leaf.get_value_unchecked(index),
+            ))
+        }
+    }
+
+    /// Searches for `key`. Returns its position in key order if it's present, or the position it
+    /// would be inserted at otherwise.
+    pub fn rank<Q>(&self, key: &Q) -> Result<usize, usize>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+    {
+        if self.depth == 0 {
+            return Err(0);
+        }
+        let mut rank = 0;
+        let mut child = unsafe { self.root.assume_init_ref() };
+        for depth in 0..self.depth as usize - 1 {
+            let node = unsafe { child.as_node() };
+            let leaf_children = depth + 2 == self.depth as usize;
+            let (i, found) = match node.search(&self.search, key) {
+                Ok(i) => (i, true),
+                Err(i) => (i, false),
+            };
+            rank += i + node.children()[..i]
+                .iter()
+                .map(|child| unsafe { child.count(leaf_children) })
+                .sum::<usize>();
+            child = unsafe { node.get_child_unchecked(i) };
+            if found {
+                return Ok(rank + unsafe { child.count(leaf_children) });
+            }
+        }
+        let leaf = unsafe { child.as_leaf() };
+        leaf.search(&self.search, key)
+            .map(|i| rank + i)
+            .map_err(|i| rank + i)
+    }
+
+    /// Gets an iterator over the entries whose keys lie in `range`. Its length is known upfront,
+    /// and skipping through it is logarithmic.
+    pub fn range<Q, R>(&self, range: R) -> Iter<'_, K, V, true, A, S>
+    where
+        Q: ?Sized,
+        S: SearchStrategy<K, Q>,
+        R: RangeBounds<Q>,
+    {
+        let start = match range.start_bound() {
+            Bound::Included(key) => self.rank(key).unwrap_or_else(|i| i),
+            Bound::Excluded(key) => self.rank(key).map_or_else(|i| i, |i| i + 1),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => self.rank(key).map_or_else(|i| i, |i| i + 1),
+            Bound::Excluded(key) => self.rank(key).unwrap_or_else(|i| i),
+            Bound::Unbounded => self.size,
+        };
+        Iter::new(self, start, end.max(start))
+    }
+}
+
+/// An iterator over the entries of a [`BTree`], sorted by key.
+pub struct Iter<'a, K, V, const COUNTED: bool = false, A: Aggregate<K, V> = (), S = Linear>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    tree: &'a BTree<K, V, COUNTED, A, S>,
+    nodes: [Option<&'a TreeNode<K, V, COUNTED, A>>; MAX_DEPTH],
+    leaf: Option<&'a Leaf<K, V>>,
+    indices: [usize; MAX_DEPTH],
+    /// The depth of the next entry.
+    level: usize,
+    /// The position of the next entry.
+    index: usize,
+    end: usize,
+}
+
+impl<'a, K, V, const COUNTED: bool, A: Aggregate<K, V>, S> Iter<'a, K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    fn new(tree: &'a BTree<K, V, COUNTED, A, S>, start: usize, end: usize) -> Self {
+        let mut iter = Self {
+            tree,
+            nodes: [None; MAX_DEPTH],
+            leaf: None,
+            indices: [0; MAX_DEPTH],
+            level: 0,
+            index: start,
+            end,
+        };
+        if start < end {
+            if COUNTED {
+                iter.seek(start);
+            } else {
+                debug_assert_eq!(start, 0);
+                iter.descend(0, unsafe { tree.root.assume_init_ref() });
+            }
+        }
+        iter
+    }
+
+    /// Moves to the first entry in the subtree of `child`, which is at depth `level`.
+    fn descend(&mut self, mut level: usize, mut child: &'a TreeChild<K, V, COUNTED, A>) {
+        let leaf_level = self.tree.depth as usize - 1;
+        while level < leaf_level {
+            let node = unsafe { child.as_node() };
+            self.nodes[level] = Some(node);
+            self.indices[level] = 0;
+            child = unsafe { node.get_child_unchecked(0) };
+            level += 1;
+        }
+        self.leaf = Some(unsafe { child.as_leaf() });
+        self.indices[level] = 0;
+        self.level = level;
+    }
+
+    /// Moves to the entry at position `index`. Only valid in counted trees.
+    fn seek(&mut self, mut index: usize) {
+        let leaf_level = self.tree.depth as usize - 1;
+        let mut child = unsafe { self.tree.root.assume_init_ref() };
+        for level in 0..leaf_level {
+            let node = unsafe { child.as_node() };
+            self.nodes[level] = Some(node);
+            let mut i = 0;
+            loop {
+                let count = unsafe { node.get_child_unchecked(i).count(level + 1 == leaf_level) };
+                match index.cmp(&count) {
+                    Ordering::Less => break,
+                    Ordering::Equal => {
+                        self.indices[level] = i;
+                        self.level = level;
+                        return;
+                    }
+                    Ordering::Greater => {
+                        index -= count + 1;
+                        i += 1;
+                    }
+                }
+            }
+            self.indices[level] = i;
+            child = unsafe { node.get_child_unchecked(i) };
+        }
+        self.leaf = Some(unsafe { child.as_leaf() });
+        self.indices[leaf_level] = index;
+        self.level = leaf_level;
+    }
+}
+
+impl<'a, K, V, const COUNTED: bool, A: Aggregate<K, V>, S> Iterator
+    for Iter<'a, K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index == self.end {
+            return None;
+        }
+        self.index += 1;
+
+        let i = self.indices[self.level];
+        self.indices[self.level] = i + 1;
+        if self.level == self.tree.depth as usize - 1 {
+            let leaf = unsafe { self.leaf.unwrap_unchecked() };
+            if i + 1 == leaf.len() {
+                while 0 < self.level {
+                    self.level -= 1;
+                    let node = unsafe { self.nodes[self.level].unwrap_unchecked() };
+                    if self.indices[self.level] < node.len() {
+                        break;
+                    }
+                }
+            }
+            unsafe { Some((leaf.get_key_unchecked(i), leaf.get_value_unchecked(i))) }
+        } else {
+            let node = unsafe { self.nodes[self.level].unwrap_unchecked() };
+            self.descend(self.level + 1, unsafe { node.get_child_unchecked(i + 1) });
+            unsafe { Some((node.get_key_unchecked(i), node.get_value_unchecked(i))) }
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.end - self.index;
+        (len, Some(len))
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        if COUNTED {
+            if self.end - self.index <= n {
+                self.index = self.end;
+                return None;
+            }
+            if 0 < n {
+                self.index += n;
+                self.seek(self.index);
+            }
+        } else {
+            for _ in 0..n {
+                self.next()?;
+            }
+        }
+        self.next()
+    }
+}
+
+impl<'a, K, V, const COUNTED: bool, A: Aggregate<K, V>, S> ExactSizeIterator
+    for Iter<'a, K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+}
+
+impl<'a, K, V, const COUNTED: bool, A: Aggregate<K, V>, S> FusedIterator
+    for Iter<'a, K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+}
+
+impl<'a, K, V, const COUNTED: bool, A: Aggregate<K, V>, S> IntoIterator
+    for &'a BTree<K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V, COUNTED, A, S>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
 }
 
 struct KVPair<K, V> {
@@ -1776,7 +2817,7 @@ impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for KVPair<K, V> {
 }
 
 #[cfg(debug_assertions)]
-impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for ChildUnion<K, V> {
+impl<K: fmt::Debug, V: fmt::Debug, N, S> fmt::Debug for ChildUnion<K, V, N, S> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ChildUnion::Node(node) => node.fmt(f),
@@ -1786,7 +2827,7 @@ impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for ChildUnion<K, V> {
 }
 
 #[cfg(debug_assertions)]
-impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for Node<K, V> {
+impl<K: fmt::Debug, V: fmt::Debug, N, S> fmt::Debug for Node<K, V, N, S> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Node(len={}, ", self.len())?;
         let mut dbg_list = f.debug_list();
@@ -1814,21 +2855,159 @@ impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for Leaf<K, V> {
     }
 }
 
-impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for BTree<K, V> {
+impl<K: fmt::Debug, V, const COUNTED: bool, A: Aggregate<K, V>, S> BTree<K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
+    /// Writes the structure of the tree as a Graphviz digraph, with one record per node showing
+    /// its fill and its keys, and an edge from the port between two keys to the child between
+    /// them. Meant for debugging, render it with `dot -Tsvg`.
+    pub fn dump_dot(&self, w: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(w, "digraph BTree {{")?;
+        writeln!(w, "    node [shape=record];")?;
+        if self.depth != 0 {
+            let mut next_id = 0;
+            Self::dump_dot_child(
+                unsafe { self.root.assume_init_ref() },
+                self.depth as usize - 1,
+                &mut next_id,
+                w,
+            )?;
+        }
+        writeln!(w, "}}")
+    }
+
+    /// Writes the node of `child`, `height` levels above the leaves, and its subtree. Returns the
+    /// id of the node.
+    fn dump_dot_child(
+        child: &TreeChild<K, V, COUNTED, A>,
+        height: usize,
+        next_id: &mut usize,
+        w: &mut impl fmt::Write,
+    ) -> Result<usize, fmt::Error> {
+        let id = *next_id;
+        *next_id += 1;
+        let keys = unsafe {
+            if height == 0 {
+                child.as_leaf().keys()
+            } else {
+                child.as_node().keys()
+            }
+        };
+
+        write!(
+            w,
+            "    n{id} [label=\"{{{}/{MAX_NUM_ELEMENTS}|{{",
+            keys.len()
+        )?;
+        for (i, key) in keys.iter().enumerate() {
+            if height != 0 {
+                write!(w, "<c{i}>|")?;
+            }
+            write!(DotEscape(w), "{key:?}")?;
+            if i + 1 < keys.len() {
+                write!(w, "|")?;
+            }
+        }
+        if height != 0 {
+            write!(w, "|<c{}>", keys.len())?;
+        }
+        writeln!(w, "}}}}\"];")?;
+
+        if height != 0 {
+            for (i, child) in unsafe { child.as_node() }.children().iter().enumerate() {
+                let child_id = Self::dump_dot_child(child, height - 1, next_id, w)?;
+                writeln!(w, "    n{id}:c{i} -> n{child_id};")?;
+            }
+        }
+        Ok(id)
+    }
+
+    /// Writes the structure of the tree as JSON, for tools. The keys are written as strings of
+    /// their `Debug` output:
+    ///
+    /// ```text
+    /// {"len": 4, "depth": 2, "root": {"kind": "node", "keys": ["2"], "children": [
+    ///     {"kind": "leaf", "keys": ["1"]}, {"kind": "leaf", "keys": ["3", "4"]}]}}
+    /// ```
+    ///
+    /// `root` is `null` for an empty tree.
+    pub fn dump_json(&self, w: &mut impl fmt::Write) -> fmt::Result {
+        write!(
+            w,
+            "{{\"len\": {}, \"depth\": {}, \"root\": ",
+            self.size, self.depth
+        )?;
+        if self.depth == 0 {
+            write!(w, "null")?;
+        } else {
+            Self::dump_json_child(
+                unsafe { self.root.assume_init_ref() },
+                self.depth as usize - 1,
+                w,
+            )?;
+        }
+        write!(w, "}}")
+    }
+
+    fn dump_json_child(
+        child: &TreeChild<K, V, COUNTED, A>,
+        height: usize,
+        w: &mut impl fmt::Write,
+    ) -> fmt::Result {
+        let keys = unsafe {
+            if height == 0 {
+                child.as_leaf().keys()
+            } else {
+                child.as_node().keys()
+            }
+        };
+        let kind = if height == 0 { "leaf" } else { "node" };
+
+        write!(w, "{{\"kind\": \"{kind}\", \"keys\": [")?;
+        for (i, key) in keys.iter().enumerate() {
+            if i != 0 {
+                write!(w, ", ")?;
+            }
+            write!(w, "\"")?;
+            write!(JsonEscape(w), "{key:?}")?;
+            write!(w, "\"")?;
+        }
+        write!(w, "]")?;
+
+        if height != 0 {
+            write!(w, ", \"children\": [")?;
+            for (i, child) in unsafe { child.as_node() }.children().iter().enumerate() {
+                if i != 0 {
+                    write!(w, ", ")?;
+                }
+                Self::dump_json_child(child, height - 1, w)?;
+            }
+            write!(w, "]")?;
+        }
+        write!(w, "}}")
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, const COUNTED: bool, A: Aggregate<K, V>, S> fmt::Debug
+    for BTree<K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        struct DebugNode<'a, K, V> {
-            node: &'a Node<K, V>,
+        struct DebugNode<'a, K, V, N, S> {
+            node: &'a Node<K, V, N, S>,
             depth: u8,
         }
 
-        impl<'a, K, V> std::ops::Deref for DebugNode<'a, K, V> {
-            type Target = Node<K, V>;
+        impl<'a, K, V, N, S> std::ops::Deref for DebugNode<'a, K, V, N, S> {
+            type Target = Node<K, V, N, S>;
             fn deref(&self) -> &Self::Target {
                 self.node
             }
         }
 
-        impl<'a, K: fmt::Debug, V: fmt::Debug> fmt::Debug for DebugNode<'a, K, V> {
+        impl<'a, K: fmt::Debug, V: fmt::Debug, N, S> fmt::Debug for DebugNode<'a, K, V, N, S> {
             fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
                 let add_child = |dbg_list: &mut fmt::DebugList, i: usize| match self.depth {
                     0 | 1 => unreachable!(),
@@ -1881,8 +3060,16 @@ impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for BTree<K, V> {
     }
 }
 
-impl<K, V> Drop for BTree<K, V> {
+impl<K, V, const COUNTED: bool, A: Aggregate<K, V>, S> Drop for BTree<K, V, COUNTED, A, S>
+where
+    Counted<COUNTED>: SubtreeSize,
+{
     fn drop(&mut self) {
-        todo!()
+        if self.depth != 0 {
+            unsafe {
+                let root = self.root.as_ptr().read();
+                self.drop_child(root, self.depth as usize - 1);
+            }
+        }
     }
 }
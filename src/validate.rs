@@ -0,0 +1,81 @@
+use core::fmt;
+
+/// The first broken invariant found by `validate`. `level` is 0 at the root and grows towards the
+/// leaves, `index` is the position of a key within its node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvariantViolation {
+    /// A key isn't greater than the key before it, in the same node or across a node boundary.
+    OutOfOrder { level: usize, index: usize },
+    /// A node has fewer than `MIN_NUM_ELEMENTS` or more than `MAX_NUM_ELEMENTS` keys. The root
+    /// may have fewer, but never none.
+    BadLength { level: usize, len: usize },
+    /// A leaf above the last level or an internal node on it.
+    LeafDepth { level: usize },
+    /// The cached size of a node's subtree in a counted tree doesn't match its entries.
+    CountMismatch {
+        level: usize,
+        cached: usize,
+        actual: usize,
+    },
+    /// The length of the tree doesn't match the number of entries in it.
+    SizeMismatch { expected: usize, actual: usize },
+    /// The tree has more levels than its branching factor allows, see
+    /// [`max_depth`](crate::stats::max_depth).
+    TooDeep { depth: usize },
+    /// A leaf of a [`BPlusTree`](crate::bplus_tree::BPlusTree) isn't linked to the leaves
+    /// before and after it. `index` counts the leaves from the left.
+    BrokenLink { index: usize },
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::OutOfOrder { level, index } => {
+                write!(f, "key {index} at level {level} is out of order")
+            }
+            Self::BadLength { level, len } => {
+                write!(f, "node at level {level} has an invalid length of {len}")
+            }
+            Self::LeafDepth { level } => write!(f, "node of the wrong kind at level {level}"),
+            Self::CountMismatch {
+                level,
+                cached,
+                actual,
+            } => write!(
+                f,
+                "node at level {level} caches a size of {cached} but holds {actual} entries"
+            ),
+            Self::SizeMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "tree has a length of {expected} but holds {actual} entries"
+                )
+            }
+            Self::TooDeep { depth } => write!(f, "tree has {depth} levels"),
+            Self::BrokenLink { index } => {
+                write!(f, "leaf {index} isn't linked to its neighbours")
+            }
+        }
+    }
+}
+
+/// A way to break a tree on purpose, to check that `validate` reports it. Only meant for the tests
+/// of the `check-invariants` feature, through the trees' `corrupt`.
+#[cfg(feature = "check-invariants")]
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    /// Sets the length of the tree.
+    Size(usize),
+    /// Sets the number of levels of the tree.
+    Depth(usize),
+    /// Sets the length of the first leaf.
+    LeafLen(usize),
+    /// Swaps the first two keys of the first leaf.
+    SwapKeys,
+    /// Sets the cached size of the root of a counted tree.
+    RootCount(usize),
+    /// Swaps the links of the first leaf of a [`BPlusTree`](crate::bplus_tree::BPlusTree) to the
+    /// leaves before and after it.
+    SwapLinks,
+}
@@ -0,0 +1,166 @@
+//! The write-ahead log of a [`PagedBTree`](crate::paged::PagedBTree). A file of records, each a
+//! kind, the length of its payload and a checksum, followed by the payload. Records are only ever
+//! appended, and the log is truncated by checkpoints. A crash can leave a torn record at the end,
+//! which fails its checksum, so the log is read up to the first record that doesn't check out.
+
+use crate::paged::PAGE_SIZE;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// The size of the kind, length and checksum in front of the payload.
+const HEADER_SIZE: usize = 16;
+
+/// The largest payload, of a page record.
+const MAX_PAYLOAD: usize = 8 + PAGE_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub(crate) enum Kind {
+    /// A key and a value.
+    Insert = 1,
+    /// A key.
+    Remove = 2,
+    /// A page number and the contents of the page, written back from the buffer pool.
+    Page = 3,
+    /// The header of the tree as of a checkpoint. The pages logged since the previous checkpoint
+    /// belong to it.
+    Commit = 4,
+    /// Recovery replays the log from here. The pages logged before it, but after the last commit,
+    /// are stale.
+    Recovered = 5,
+}
+
+impl Kind {
+    fn from_u32(kind: u32) -> Option<Self> {
+        Some(match kind {
+            1 => Self::Insert,
+            2 => Self::Remove,
+            3 => Self::Page,
+            4 => Self::Commit,
+            5 => Self::Recovered,
+            _ => return None,
+        })
+    }
+}
+
+/// FNV-1a over the kind, the length and the payload.
+fn checksum(kind: u32, parts: &[&[u8]]) -> u64 {
+    let len = parts.iter().map(|part| part.len() as u32).sum::<u32>();
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for part in [&kind.to_ne_bytes()[..], &len.to_ne_bytes()]
+        .into_iter()
+        .chain(parts.iter().copied())
+    {
+        for &byte in part {
+            hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+pub(crate) struct Wal {
+    file: File,
+    len: u64,
+}
+
+impl Wal {
+    /// Opens the log at `path`, creating it if it doesn't exist, or emptying it if `truncate`.
+    pub(crate) fn open(path: &Path, truncate: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(truncate)
+            .open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self { file, len })
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Appends a record with the concatenation of `parts` as its payload, and returns the offset
+    /// of the payload. The record reaches the OS before this returns, but isn't synced.
+    pub(crate) fn append(&mut self, kind: Kind, parts: &[&[u8]]) -> io::Result<u64> {
+        let kind = kind as u32;
+        let len = parts.iter().map(|part| part.len()).sum::<usize>();
+        debug_assert!(len <= MAX_PAYLOAD);
+        let mut record = Vec::with_capacity(HEADER_SIZE + len);
+        record.extend_from_slice(&kind.to_ne_bytes());
+        record.extend_from_slice(&(len as u32).to_ne_bytes());
+        record.extend_from_slice(&checksum(kind, parts).to_ne_bytes());
+        for part in parts {
+            record.extend_from_slice(part);
+        }
+        self.file.seek(SeekFrom::Start(self.len))?;
+        if let Err(error) = self.file.write_all(&record) {
+            // Don't leave a partial record for the next one to be appended after.
+            self.file.set_len(self.len)?;
+            return Err(error);
+        }
+        let offset = self.len + HEADER_SIZE as u64;
+        self.len += record.len() as u64;
+        Ok(offset)
+    }
+
+    /// Reads a payload, or a part of it, that starts at `offset`.
+    pub(crate) fn read_at(&mut self, offset: u64, out: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(out)
+    }
+
+    #[inline]
+    pub(crate) fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Cuts the log to `len` bytes, and syncs it.
+    pub(crate) fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.len = len;
+        self.file.sync_data()
+    }
+
+    /// Calls `visit` with the kind, the payload offset and the payload of every record up to the
+    /// first torn one, and returns where the intact records end.
+    pub(crate) fn scan(
+        &mut self,
+        mut visit: impl FnMut(Kind, u64, &[u8]) -> io::Result<()>,
+    ) -> io::Result<u64> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(&self.file);
+        let mut payload = vec![0; MAX_PAYLOAD];
+        let mut offset = 0;
+        loop {
+            let mut header = [0; HEADER_SIZE];
+            if !read_all(&mut reader, &mut header)? {
+                return Ok(offset);
+            }
+            let field = |at: usize| u32::from_ne_bytes(header[at..at + 4].try_into().unwrap());
+            let (raw_kind, len) = (field(0), field(4) as usize);
+            let stored = u64::from_ne_bytes(header[8..].try_into().unwrap());
+            let kind = match Kind::from_u32(raw_kind) {
+                Some(kind) if len <= MAX_PAYLOAD => kind,
+                _ => return Ok(offset),
+            };
+            let payload = &mut payload[..len];
+            if !read_all(&mut reader, payload)? || checksum(raw_kind, &[payload]) != stored {
+                return Ok(offset);
+            }
+            visit(kind, offset + HEADER_SIZE as u64, payload)?;
+            offset += (HEADER_SIZE + len) as u64;
+        }
+    }
+}
+
+/// Fills `out`, or returns `false` if the reader ends first.
+fn read_all(reader: &mut impl Read, out: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(out) {
+        Ok(()) => Ok(true),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(error) => Err(error),
+    }
+}
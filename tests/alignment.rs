@@ -0,0 +1,87 @@
+//! Checks that the trees and the slab allocators keep keys and values of 16, 32 and 64 byte
+//! alignment aligned, like cache-padded counters or SIMD vectors.
+
+mod common;
+
+use btree2::bplus_tree::BPlusTree;
+use btree2::slab::SlabAllocator;
+use btree2::{btree, std_btree};
+use common::Chunk;
+use std::mem::align_of;
+
+const LEN: u32 = if cfg!(miri) { 300 } else { 20_000 };
+
+macro_rules! aligned {
+    ($($name:ident($align:literal)),*) => {$(
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+        #[repr(align($align))]
+        struct $name(u32);
+
+        impl $name {
+            const ALIGN: usize = $align;
+        }
+    )*};
+}
+
+aligned!(Align16(16), Align32(32), Align64(64));
+
+fn assert_aligned<T>(value: &T) {
+    let addr = value as *const T as usize;
+    assert_eq!(addr % align_of::<T>(), 0, "{addr:#x} is misaligned");
+}
+
+/// Inserts `LEN` entries into `$tree`, removes every other one, and checks that every key and
+/// value is aligned after each phase. Runs `$refill` before every insertion.
+macro_rules! exercise {
+    ($tree:ident, $key:ident $(, $refill:expr)?) => {{
+        for i in 0..LEN {
+            $($refill;)?
+            assert!($tree.insert($key(i), $key(!i)).is_none());
+        }
+        for i in (0..LEN).step_by(2) {
+            assert_eq!($tree.remove(&$key(i)), Some(($key(i), $key(!i))));
+        }
+        $tree.validate().unwrap();
+        for (key, value) in $tree.iter() {
+            assert_aligned(key);
+            assert_aligned(value);
+            assert_eq!(*value, $key(!key.0));
+        }
+        assert_aligned($tree.get(&$key(1)).unwrap());
+        assert_eq!($tree.iter().count(), LEN as usize / 2);
+    }};
+}
+
+macro_rules! tree_tests {
+    ($($test:ident: $key:ident),*) => {$(
+        #[test]
+        fn $test() {
+            let mut tree = btree::BTree::<$key, $key>::with_buffer(vec![0; 1 << 20]);
+            exercise!(tree, $key, if tree.needs_new_chunk() {
+                tree.add_buffer(vec![0; 1 << 20]);
+            });
+
+            let mut tree = std_btree::BTree::<$key, $key>::new();
+            exercise!(tree, $key);
+
+            let mut chunks = vec![Chunk::new(1 << 21)];
+            let mut tree = BPlusTree::<$key, $key>::new(unsafe { chunks[0].bytes() });
+            exercise!(tree, $key, if tree.needs_new_chunk() {
+                chunks.push(Chunk::new(1 << 21));
+                tree.add_chunk(unsafe { chunks.last_mut().unwrap().bytes() });
+            });
+            drop(tree);
+
+            let mut chunk = Chunk::new(1 << 16);
+            let mut alloc = SlabAllocator::<$key>::new(unsafe { chunk.bytes() });
+            let mut ptrs = Vec::new();
+            alloc.malloc_many(alloc.free_slabs(), &mut ptrs);
+            for ptr in &ptrs {
+                assert_eq!(ptr.as_ptr() as usize % $key::ALIGN, 0);
+            }
+            unsafe { alloc.free_many(ptrs) };
+        }
+    )*};
+}
+
+tree_tests!(align_16: Align16, align_32: Align32, align_64: Align64);
@@ -0,0 +1,190 @@
+//! Allocates `Box`es and `Vec`s from the slab allocators through the allocator-api2 `Allocator`
+//! trait, and through the nightly one with the `nightly` feature.
+
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
+
+mod common;
+
+use btree2::alloc_api::{SegregatedAlloc, SlabHandle, SEGREGATED_SIZES};
+use btree2::slab::LockedSlabAllocator;
+use common::Chunk;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[test]
+fn slab_handle_serves_only_layouts_that_fit() {
+    let mut chunk = Chunk::new(1 << 15);
+    let alloc = LockedSlabAllocator::<[u64; 4]>::new(unsafe { chunk.bytes() });
+    let handle = SlabHandle::new(&alloc);
+    assert!(handle
+        .allocate(Layout::new::<[u64; 4]>())
+        .is_some_and(|slab| {
+            unsafe { handle.deallocate(slab.cast(), Layout::new::<[u64; 4]>()) };
+            slab.len() == 32
+        }));
+    assert!(handle.allocate(Layout::new::<[u64; 5]>()).is_none());
+    assert!(handle
+        .allocate(Layout::from_size_align(8, 64).unwrap())
+        .is_none());
+    assert_eq!(alloc.stats().in_use, 0);
+}
+
+#[test]
+fn segregated_alloc_picks_the_smallest_class() {
+    let mut chunk = Chunk::new(1 << 17);
+    let alloc = SegregatedAlloc::new();
+    assert!(alloc.needs_new_chunk());
+    alloc.add_chunk(unsafe { chunk.bytes() });
+
+    for (size, align) in [
+        (1, 1),
+        (16, 8),
+        (17, 4),
+        (24, 32),
+        (100, 1),
+        (512, 512),
+        (8, 256),
+    ] {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let class = SegregatedAlloc::class(layout).unwrap();
+        assert!(size.max(align) <= SEGREGATED_SIZES[class]);
+        assert!(class == 0 || SEGREGATED_SIZES[class - 1] < size.max(align));
+        unsafe {
+            let ptr = alloc.alloc(layout);
+            assert!(alloc.is_slab(NonNull::new(ptr).unwrap()));
+            assert_eq!(ptr as usize % align, 0);
+            ptr.write_bytes(0xab, size);
+            alloc.dealloc(ptr, layout);
+        }
+    }
+    assert_eq!(SegregatedAlloc::class(Layout::new::<[u8; 513]>()), None);
+    assert!(alloc.allocate_slab(Layout::new::<[u8; 513]>()).is_none());
+
+    unsafe {
+        let layout = Layout::new::<[u8; 20]>();
+        let ptr = alloc.alloc(layout);
+        ptr.write_bytes(7, 20);
+        assert_eq!(alloc.realloc(ptr, layout, 30), ptr);
+        let grown = alloc.realloc(ptr, layout, 200);
+        assert_ne!(grown, ptr);
+        assert_eq!(*grown.add(19), 7);
+        alloc.dealloc(grown, Layout::new::<[u8; 200]>());
+    }
+}
+
+/// Wraps `System` and counts the bytes it has handed out.
+#[derive(Default)]
+struct Counting {
+    live: AtomicUsize,
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.live.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.live.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// The bytes that `alloc` has taken from its fallback.
+fn fallback_live(alloc: &SegregatedAlloc<Counting>) -> usize {
+    alloc.fallback().live.load(Ordering::Relaxed)
+}
+
+#[test]
+fn segregated_alloc_falls_back_for_large_layouts_and_without_a_chunk() {
+    let alloc = SegregatedAlloc::<Counting>::default();
+    let small = Layout::new::<u64>();
+    let large = Layout::new::<[u8; 2000]>();
+    unsafe {
+        // Without a chunk even small layouts go to the fallback.
+        let early = alloc.alloc(small);
+        assert!(!early.is_null());
+        assert_eq!(fallback_live(&alloc), 8);
+
+        let mut chunk = Chunk::new(1 << 17);
+        alloc.add_chunk(chunk.bytes());
+        let slab = alloc.alloc(small);
+        assert!(alloc.is_slab(NonNull::new(slab).unwrap()));
+        let big = alloc.alloc(large);
+        assert!(!big.is_null() && !alloc.is_slab(NonNull::new(big).unwrap()));
+        big.write_bytes(0xcd, 2000);
+        assert_eq!(fallback_live(&alloc), 2008);
+
+        // Growing past the largest class moves the memory to the fallback, and back again when
+        // it shrinks.
+        slab.write_bytes(5, 8);
+        let grown = alloc.realloc(slab, small, 1000);
+        assert!(!alloc.is_slab(NonNull::new(grown).unwrap()));
+        assert_eq!(*grown.add(7), 5);
+        assert_eq!(fallback_live(&alloc), 3008);
+        let shrunk = alloc.realloc(grown, Layout::from_size_align(1000, 8).unwrap(), 8);
+        assert!(alloc.is_slab(NonNull::new(shrunk).unwrap()));
+        assert_eq!(*shrunk.add(7), 5);
+        assert_eq!(fallback_live(&alloc), 2008);
+
+        // Frees go back to wherever the memory came from.
+        alloc.dealloc(early, small);
+        alloc.dealloc(big, large);
+        alloc.dealloc(shrunk, small);
+        assert_eq!(fallback_live(&alloc), 0);
+    }
+}
+
+#[cfg(feature = "allocator-api2")]
+#[test]
+fn api2_collections_draw_from_the_slabs() {
+    use allocator_api2::boxed::Box;
+    use allocator_api2::vec::Vec;
+
+    let mut chunk = Chunk::new(1 << 17);
+    let slabs = LockedSlabAllocator::<[u64; 4]>::new(unsafe { chunk.bytes() });
+    let boxes = (0..100)
+        .map(|i| Box::new_in([i; 4], SlabHandle::new(&slabs)))
+        .collect::<std::vec::Vec<_>>();
+    assert!(boxes
+        .iter()
+        .enumerate()
+        .all(|(i, boxed)| **boxed == [i as u64; 4]));
+    assert_eq!(slabs.stats().in_use, 100 * 32);
+    drop(boxes);
+    assert_eq!(slabs.stats().in_use, 0);
+
+    let mut chunk = Chunk::new(1 << 17);
+    let alloc = SegregatedAlloc::new();
+    alloc.add_chunk(unsafe { chunk.bytes() });
+    let mut vec = Vec::new_in(&alloc);
+    vec.extend(0..128u32);
+    assert_eq!(vec.iter().sum::<u32>(), 127 * 64);
+    assert!(alloc.is_slab(NonNull::from(&vec[0]).cast()));
+    // Past the largest class the vector moves to the fallback.
+    vec.try_reserve_exact(1).unwrap();
+    assert!(!alloc.is_slab(NonNull::from(&vec[0]).cast()));
+}
+
+#[cfg(feature = "nightly")]
+#[test]
+fn nightly_collections_draw_from_the_slabs() {
+    let mut chunk = Chunk::new(1 << 17);
+    let slabs = LockedSlabAllocator::<[u64; 4]>::new(unsafe { chunk.bytes() });
+    let boxed = Box::new_in([3; 4], SlabHandle::new(&slabs));
+    assert_eq!(slabs.stats().in_use, 32);
+    assert_eq!(*boxed, [3; 4]);
+    drop(boxed);
+
+    let mut chunk = Chunk::new(1 << 17);
+    let alloc = SegregatedAlloc::new();
+    alloc.add_chunk(unsafe { chunk.bytes() });
+    let mut vec = Vec::new_in(&alloc);
+    vec.extend(0..100u16);
+    assert_eq!(vec.len(), 100);
+    assert!(alloc.is_slab(NonNull::from(&vec[0]).cast()));
+    // Past the largest class the vector moves to the fallback.
+    vec.try_reserve_exact(200).unwrap();
+    assert!(!alloc.is_slab(NonNull::from(&vec[0]).cast()));
+}
@@ -0,0 +1,184 @@
+//! Checks that the trees charge their budgets for every leaf and node, refuse insertions that
+//! their budget can't afford, and give everything back when they are cleared or dropped.
+
+use btree2::budget::{BudgetExceeded, SharedBudget};
+use btree2::{bplus_tree, btree, std_btree};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+
+const LEN: u32 = if cfg!(miri) { 2_000 } else { 50_000 };
+
+/// The budget of one tree, and of the trees of all the threads.
+const QUOTA: usize = if cfg!(miri) { 16 << 10 } else { 64 << 10 };
+const SHARED_QUOTA: usize = 4 * QUOTA;
+
+macro_rules! fill_until_refused {
+    ($tree:ident, $budget:ident) => {{
+        let mut len = 0;
+        loop {
+            match $tree.try_insert(len, len) {
+                Ok(replaced) => assert!(replaced.is_none()),
+                Err(BudgetExceeded { key, value }) => {
+                    assert_eq!((key, value), (len, len));
+                    break;
+                }
+            }
+            len += 1;
+        }
+        assert!(0 < len);
+        $tree.validate().unwrap();
+        assert_eq!($tree.len(), len as usize);
+        assert!($tree.charged() <= $budget.limit());
+        // Replacing needs no new node, so it goes through even at the quota.
+        assert_eq!($tree.try_insert(0, 1), Ok(Some((0, 0))));
+        assert!($tree.try_insert(len, len).is_err());
+
+        // Emptying the tree frees its nodes, which makes room again.
+        assert_eq!($tree.remove(&0), Some((0, 1)));
+        for key in 1..len {
+            assert_eq!($tree.remove(&key), Some((key, key)));
+        }
+        assert_eq!($tree.charged(), 0);
+        assert_eq!($budget.used(), 0);
+        assert_eq!($tree.try_insert(0, 0), Ok(None));
+        len
+    }};
+}
+
+#[test]
+fn trees_are_charged_for_their_nodes() {
+    let budget = Arc::new(SharedBudget::new(usize::MAX));
+    let mut tree = btree::BTree::<u32, u32>::with_buffer(vec![0; 1 << 22]);
+    tree.set_budget(budget.clone());
+    let mut std_tree = std_btree::BTree::<u32, u32>::new();
+    std_tree.set_budget(budget.clone());
+    let mut bplus_tree = bplus_tree::BPlusTree::<u32, u32>::with_buffer(vec![0; 1 << 21]);
+    bplus_tree.add_buffer(vec![0; 1 << 21]);
+    bplus_tree.set_budget(budget.clone());
+    let charged = |tree: &btree::BTree<u32, u32>,
+                   std_tree: &std_btree::BTree<u32, u32>,
+                   bplus_tree: &bplus_tree::BPlusTree<u32, u32>| {
+        tree.charged() + std_tree.charged() + bplus_tree.charged()
+    };
+
+    for key in 0..LEN {
+        tree.insert(key, key);
+        std_tree.insert(key, key);
+        bplus_tree.insert(key, key);
+        assert_eq!(budget.used(), charged(&tree, &std_tree, &bplus_tree));
+    }
+    assert!(0 < tree.charged() && 0 < std_tree.charged() && 0 < bplus_tree.charged());
+    for key in (0..LEN).step_by(2) {
+        tree.remove(&key);
+        std_tree.remove(&key);
+        bplus_tree.remove(&key);
+    }
+    assert_eq!(budget.used(), charged(&tree, &std_tree, &bplus_tree));
+
+    // Clearing without drops resets the cache, and gives back all the bytes at once.
+    tree.clear();
+    bplus_tree.clear();
+    assert_eq!(budget.used(), std_tree.charged());
+    assert!(bplus_tree.is_empty() && bplus_tree.iter().next().is_none());
+    drop(std_tree);
+    assert_eq!(budget.used(), 0);
+}
+
+#[test]
+fn inserts_beyond_the_quota_are_refused() {
+    let budget = Arc::new(SharedBudget::new(QUOTA));
+    let mut tree = btree::BTree::<u32, u32>::with_buffer(vec![0; 1 << 20]);
+    tree.set_budget(budget.clone());
+    let slab_len = fill_until_refused!(tree, budget);
+    drop(tree);
+    assert_eq!(budget.used(), 0);
+
+    let mut std_tree = std_btree::BTree::<u32, u32>::new();
+    std_tree.set_budget(budget.clone());
+    let boxed_len = fill_until_refused!(std_tree, budget);
+    assert!(slab_len.abs_diff(boxed_len) < slab_len / 10);
+    drop(std_tree);
+    assert_eq!(budget.used(), 0);
+
+    let mut bplus_tree = bplus_tree::BPlusTree::<u32, u32>::with_buffer(vec![0; 1 << 20]);
+    bplus_tree.set_budget(budget.clone());
+    fill_until_refused!(bplus_tree, budget);
+    drop(bplus_tree);
+    assert_eq!(budget.used(), 0);
+}
+
+macro_rules! insert_until_panic {
+    ($tree:ident, $budget:ident) => {{
+        let mut len = 0;
+        let panic = loop {
+            match panic::catch_unwind(AssertUnwindSafe(|| $tree.insert(len, len))) {
+                Ok(replaced) => assert!(replaced.is_none()),
+                Err(panic) => break panic,
+            }
+            len += 1;
+        };
+        assert_eq!(
+            panic.downcast_ref::<&str>(),
+            Some(&"memory budget exceeded")
+        );
+        assert!(0 < len);
+        // The insertion panicked before it touched the tree.
+        $tree.validate().unwrap();
+        assert_eq!($tree.len(), len as usize);
+        assert_eq!($budget.used(), $tree.charged());
+        assert_eq!($tree.insert(0, 1), Some((0, 0)));
+        len
+    }};
+}
+
+#[test]
+fn plain_inserts_beyond_the_quota_panic() {
+    let budget = Arc::new(SharedBudget::new(QUOTA));
+    let mut tree = btree::BTree::<u32, u32>::with_buffer(vec![0; 1 << 20]);
+    tree.set_budget(budget.clone());
+    let slab_len = insert_until_panic!(tree, budget);
+    drop(tree);
+    assert_eq!(budget.used(), 0);
+
+    let mut std_tree = std_btree::BTree::<u32, u32>::new();
+    std_tree.set_budget(budget.clone());
+    let boxed_len = insert_until_panic!(std_tree, budget);
+    assert!(slab_len.abs_diff(boxed_len) < slab_len / 10);
+    drop(std_tree);
+    assert_eq!(budget.used(), 0);
+
+    let mut bplus_tree = bplus_tree::BPlusTree::<u32, u32>::with_buffer(vec![0; 1 << 20]);
+    bplus_tree.set_budget(budget.clone());
+    insert_until_panic!(bplus_tree, budget);
+    drop(bplus_tree);
+    assert_eq!(budget.used(), 0);
+}
+
+#[test]
+fn threads_share_a_budget() {
+    let budget = Arc::new(SharedBudget::new(SHARED_QUOTA));
+    let lens = (0..4)
+        .map(|_| {
+            let budget = budget.clone();
+            thread::spawn(move || {
+                let mut tree = std_btree::BTree::<u32, u32>::new();
+                tree.set_budget(budget);
+                let mut len = 0;
+                while tree.try_insert(len, len).is_ok() {
+                    len += 1;
+                }
+                (len, tree)
+            })
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .collect::<Vec<_>>();
+
+    let charged = lens.iter().map(|(_, tree)| tree.charged()).sum::<usize>();
+    assert_eq!(budget.used(), charged);
+    assert!(lens.iter().all(|&(len, _)| 0 < len));
+    drop(lens);
+    assert_eq!(budget.used(), 0);
+}
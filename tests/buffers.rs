@@ -0,0 +1,41 @@
+//! Checks that a `btree::BTree` can live in buffers it owns instead of leaked chunks, and frees
+//! them when it is dropped. Under Miri, a buffer that isn't freed is reported as a leak.
+
+use btree2::btree::BTree;
+use std::rc::Rc;
+
+const LEN: u32 = if cfg!(miri) { 2_000 } else { 100_000 };
+
+#[test]
+fn trees_own_their_buffers() {
+    let mut tree = BTree::<u32, u32>::with_buffer(vec![0; 1 << 15]);
+    for key in 0..LEN {
+        if tree.needs_new_chunk() {
+            tree.add_buffer(vec![0; 1 << 15].into_boxed_slice());
+        }
+        tree.insert(key, key);
+    }
+    tree.validate().unwrap();
+    assert!(2 * (1 << 15) < tree.stats().reserved());
+    assert!((0..LEN).all(|key| tree.get(&key) == Some(&key)));
+}
+
+#[test]
+fn entries_are_dropped_before_their_buffers() {
+    let value = Rc::new(());
+    let mut tree = BTree::<u32, Rc<()>>::with_buffer(vec![0; 1 << 16]);
+    for key in 0..LEN {
+        if tree.needs_new_chunk() {
+            tree.add_buffer(vec![0; 1 << 16]);
+        }
+        tree.insert(key, value.clone());
+    }
+    drop(tree);
+    assert_eq!(Rc::strong_count(&value), 1);
+}
+
+#[test]
+#[should_panic = "a chunk needs room for two pages"]
+fn small_buffers_are_refused() {
+    BTree::<u32, u32>::with_buffer(vec![0; 4096]);
+}
@@ -0,0 +1,54 @@
+//! Checks that clearing a `btree::BTree` gives all of its pages back, both when the cache is reset
+//! without visiting the nodes and when the entries have to be dropped one by one.
+
+mod common;
+
+use btree2::btree::BTree;
+use common::Chunk;
+use std::rc::Rc;
+
+const LEN: u32 = if cfg!(miri) { 3_000 } else { 100_000 };
+
+#[test]
+fn clearing_plain_entries_resets_the_cache() {
+    let mut chunk = Chunk::new(1 << 22);
+    let mut tree = BTree::<u32, u64>::new(unsafe { chunk.bytes() });
+    let free_pages = tree.stats().free_pages;
+
+    for round in 0..2 {
+        for key in 0..LEN {
+            assert!(tree.insert(key, key as u64 + round).is_none());
+        }
+        assert!(tree.stats().free_pages < free_pages);
+        tree.clear();
+        assert!(tree.is_empty());
+        assert_eq!(tree.get(&0), None);
+        let stats = tree.stats();
+        assert_eq!(stats.free_pages, free_pages);
+        assert_eq!(stats.leaf_alloc.in_use + stats.node_alloc.in_use, 0);
+        tree.validate().unwrap();
+    }
+
+    tree.insert(7, 7);
+    assert_eq!(tree.get(&7), Some(&7));
+}
+
+#[test]
+fn clearing_drops_every_entry() {
+    let mut chunk = Chunk::new(1 << 22);
+    let mut tree = BTree::<u32, Rc<u32>>::new(unsafe { chunk.bytes() });
+    let free_pages = tree.stats().free_pages;
+    let value = Rc::new(0);
+
+    for key in 0..LEN {
+        tree.insert(key, value.clone());
+    }
+    assert_eq!(Rc::strong_count(&value), LEN as usize + 1);
+    tree.clear();
+    assert_eq!(Rc::strong_count(&value), 1);
+    assert_eq!(tree.stats().free_pages, free_pages);
+
+    tree.insert(1, value.clone());
+    drop(tree);
+    assert_eq!(Rc::strong_count(&value), 1);
+}
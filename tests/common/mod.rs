@@ -0,0 +1,83 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs, process};
+
+/// A small deterministic random number generator, so failures can be reproduced from the seed.
+#[derive(Debug, Clone)]
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Returns a number in `0..n`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// A heap buffer that can be handed to the slab trees as a `&'static mut [u8]` chunk, and is
+/// freed again when the `Chunk` is dropped. The tree using it has to be dropped first.
+pub struct Chunk {
+    words: *mut [u64],
+}
+
+impl Chunk {
+    pub fn new(bytes: usize) -> Self {
+        Self {
+            words: Box::into_raw(vec![0u64; bytes.div_ceil(8)].into_boxed_slice()),
+        }
+    }
+
+    /// Gets the chunk's bytes.
+    ///
+    /// # Safety
+    /// The bytes mustn't be used after the `Chunk` is dropped, and the function mustn't be called
+    /// again while they are still used.
+    pub unsafe fn bytes(&mut self) -> &'static mut [u8] {
+        slice::from_raw_parts_mut(self.words as *mut u8, self.words.len() * 8)
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(self.words)) };
+    }
+}
+
+/// A directory in the temporary directory, unique to the process and the call, which is removed
+/// with its contents when the `TempDir` is dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("btree2-{}-{n}", process::id()));
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
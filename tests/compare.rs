@@ -0,0 +1,86 @@
+//! Checks trees ordered by comparators against a `BTreeMap` of `std::cmp::Reverse` keys, for the
+//! `Reverse` comparator and for a custom closure that orders keys the same way.
+
+mod common;
+
+use btree2::compare::{Compare, Natural, Reverse};
+use btree2::{btree, std_btree};
+use common::{Chunk, XorShift};
+use std::cmp;
+use std::collections::BTreeMap;
+
+const LEN: u32 = if cfg!(miri) { 400 } else { 20_000 };
+
+type Model = BTreeMap<cmp::Reverse<u32>, u32>;
+
+fn model_entries(model: &Model) -> Vec<(u32, u32)> {
+    model.iter().map(|(key, value)| (key.0, *value)).collect()
+}
+
+fn entries<'a>(iter: impl Iterator<Item = (&'a u32, &'a u32)>) -> Vec<(u32, u32)> {
+    iter.map(|(key, value)| (*key, *value)).collect()
+}
+
+/// Inserts and removes random keys in both trees and the model, and compares `iter`, `get`,
+/// `range` and `remove` as it goes. The keys are kept below `LEN`, so that some of them repeat.
+fn check<C: Compare<u32> + Clone>(compare: C) {
+    let mut chunk = Chunk::new(1 << 22);
+    let mut slab = btree::BTree::<u32, u32, true, (), _>::with_comparator(
+        unsafe { chunk.bytes() },
+        compare.clone(),
+    );
+    let mut heap = std_btree::BTree::<u32, u32, true, (), _>::with_comparator(compare);
+    let mut model = Model::new();
+    let mut rng = XorShift::new(LEN as u64);
+
+    for i in 0..LEN {
+        let key = rng.below(LEN as u64) as u32;
+        let expected = model.insert(cmp::Reverse(key), i).map(|old| (key, old));
+        assert_eq!(slab.insert(key, i), expected);
+        assert_eq!(heap.insert(key, i), expected);
+    }
+    let expected = model_entries(&model);
+    assert!(expected.windows(2).all(|pair| pair[1].0 < pair[0].0));
+    assert_eq!(entries(slab.iter()), expected);
+    assert_eq!(entries(heap.iter()), expected);
+
+    for _ in 0..LEN / 4 {
+        let key = rng.below(LEN as u64) as u32;
+        let expected = model.get(&cmp::Reverse(key));
+        assert_eq!(slab.get(&key), expected);
+        assert_eq!(heap.get(&key), expected);
+
+        // In descending order, the range starts at the larger key.
+        let end = rng.below(LEN as u64) as u32;
+        let start = key.max(end);
+        let end = key.min(end);
+        let expected: Vec<_> = model
+            .range(cmp::Reverse(start)..=cmp::Reverse(end))
+            .map(|(key, value)| (key.0, *value))
+            .collect();
+        assert_eq!(entries(slab.range(start..=end)), expected);
+        assert_eq!(entries(heap.range(start..=end)), expected);
+
+        let expected = model
+            .remove_entry(&cmp::Reverse(key))
+            .map(|(key, value)| (key.0, value));
+        assert_eq!(slab.remove(&key), expected);
+        assert_eq!(heap.remove(&key), expected);
+    }
+    let expected = model_entries(&model);
+    assert_eq!(entries(slab.iter()), expected);
+    assert_eq!(entries(heap.iter()), expected);
+    assert_eq!(slab.len(), model.len());
+    assert_eq!(heap.len(), model.len());
+}
+
+#[test]
+fn reverse_comparator_orders_keys_descending() {
+    check(Reverse(Natural));
+}
+
+#[test]
+fn closure_comparator_orders_keys_descending() {
+    // Orders the keys by their distance from the top, which is descending order again.
+    check(|left: &u32, right: &u32| (u32::MAX - left).cmp(&(u32::MAX - right)));
+}
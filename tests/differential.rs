@@ -0,0 +1,590 @@
+//! Runs random operation sequences against `btree::BTree`, `std_btree::BTree`,
+//! `bplus_tree::BPlusTree` and `BTreeMap`, and compares their results. Failing sequences are
+//! shrunk before being reported.
+//!
+//! The values are boxed, so running this under Miri (`cargo +nightly miri test --test
+//! differential`) also checks that every value is moved and dropped exactly once.
+
+mod common;
+
+use btree2::aggregate::{Aggregate, Counted, SubtreeSize};
+use btree2::budget::SharedBudget;
+use btree2::{bplus_tree, btree, std_btree};
+use common::{Chunk, XorShift};
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::mem::ManuallyDrop;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Insert(u16),
+    Remove(u16),
+    Get(u16),
+    /// Gets the entry at this position, modulo the length plus one, and the length the iterator
+    /// reports after it.
+    Nth(u16),
+    /// Gets the position of this key.
+    Rank(u16),
+    /// Gets the entries with keys in this inclusive range, and the length the iterator reports.
+    Range(u16, u16),
+    /// Folds the entries with keys in this inclusive range.
+    Fold(u16, u16),
+    /// Validates the trees, and compares the lengths and all the entries. The B+tree's budget
+    /// also has to match what it was charged.
+    Check,
+}
+
+struct Config {
+    cases: u64,
+    ops: usize,
+    /// The largest key space. Large enough to grow the trees to a depth of 3, or 4 without Miri.
+    keys: u16,
+    chunk_size: usize,
+}
+
+const CONFIG: Config = if cfg!(miri) {
+    Config {
+        cases: 3,
+        ops: 800,
+        keys: 600,
+        chunk_size: 1 << 18,
+    }
+} else {
+    Config {
+        cases: 150,
+        ops: 4000,
+        keys: 12000,
+        chunk_size: 1 << 22,
+    }
+};
+
+/// Generates a sequence that alternates between growing and shrinking phases. Some sequences
+/// use ascending or descending keys, which hit the edges of the nodes instead of the middle.
+fn generate(rng: &mut XorShift) -> Vec<Op> {
+    let keys = 1 + rng.below(CONFIG.keys as u64) as u16;
+    let mode = rng.below(3);
+    let phase = 1 + rng.below(CONFIG.ops as u64 / 2) as usize;
+    let mut cursor = rng.below(keys as u64) as u16;
+
+    (0..CONFIG.ops)
+        .map(|i| {
+            let key = match mode {
+                0 => rng.below(keys as u64) as u16,
+                1 => {
+                    cursor = (cursor + 1 + rng.below(2) as u16) % keys;
+                    cursor
+                }
+                _ => {
+                    cursor = (cursor + keys - 1 - rng.below(2) as u16) % keys;
+                    cursor
+                }
+            };
+            let growing = (i / phase).is_multiple_of(2);
+            match rng.below(16) {
+                0 => Op::Check,
+                1 => Op::Get(key),
+                2 => Op::Nth(rng.next() as u16),
+                3 => Op::Range(key, key.saturating_add(rng.below(256) as u16)),
+                4 => Op::Rank(key),
+                5 => Op::Fold(key, key.saturating_add(rng.below(1024) as u16)),
+                n if (n < 12) == growing => Op::Insert(key),
+                _ => Op::Remove(key),
+            }
+        })
+        .collect()
+}
+
+type Entry<'a> = (&'a u16, &'a Box<u32>);
+
+/// A polynomial hash of the entries in key order. It is associative but not commutative, so it
+/// also tells when the trees combine their cached aggregates out of order.
+struct Digest;
+
+impl Aggregate<u16, Box<u32>> for Digest {
+    /// The hash, and the multiplier that shifts it past the entries combined after it.
+    type Value = (u64, u64);
+
+    fn identity() -> (u64, u64) {
+        (0, 1)
+    }
+
+    fn lift(key: &u16, value: &Box<u32>) -> (u64, u64) {
+        ((*key as u64) << 32 | **value as u64, 0x0100_0000_01b3)
+    }
+
+    fn combine(&(left, left_mul): &(u64, u64), &(right, right_mul): &(u64, u64)) -> (u64, u64) {
+        (
+            left.wrapping_mul(right_mul).wrapping_add(right),
+            left_mul.wrapping_mul(right_mul),
+        )
+    }
+}
+
+/// Folds entries one by one, as the cached aggregates should sum up to.
+fn digest<'a>(entries: impl Iterator<Item = Entry<'a>>) -> (u64, u64) {
+    entries.fold(Digest::identity(), |acc, (key, value)| {
+        Digest::combine(&acc, &Digest::lift(key, value))
+    })
+}
+
+type SlabTree<const COUNTED: bool> = btree::BTree<u16, Box<u32>, COUNTED, Digest>;
+type HeapTree<const COUNTED: bool> = std_btree::BTree<u16, Box<u32>, COUNTED, Digest>;
+type PlusTree<const COUNTED: bool> = bplus_tree::BPlusTree<u16, Box<u32>, COUNTED, Digest>;
+
+/// The queries that not all the trees have. `rank` and `range` are only on counted trees, so
+/// uncounted ones answer by scanning their entries instead.
+trait Queries {
+    fn rank_of(&self, key: &u16) -> Result<usize, usize>;
+
+    fn entries_in(&self, start: u16, end: u16) -> (usize, Vec<Entry<'_>>);
+
+    /// Folds the entries with keys in `start..=end`.
+    fn fold_in(&self, start: u16, end: u16) -> (u64, u64);
+}
+
+/// Finds `key` in sorted entries, like `rank`.
+fn scan_rank<'a>(entries: impl Iterator<Item = Entry<'a>>, key: &u16) -> Result<usize, usize> {
+    let mut rank = 0;
+    for (k, _) in entries {
+        if k == key {
+            return Ok(rank);
+        }
+        if key < k {
+            break;
+        }
+        rank += 1;
+    }
+    Err(rank)
+}
+
+fn scan_range<'a>(
+    entries: impl Iterator<Item = Entry<'a>>,
+    start: u16,
+    end: u16,
+) -> (usize, Vec<Entry<'a>>) {
+    let entries: Vec<_> = entries
+        .filter(|(key, _)| (start..=end).contains(*key))
+        .collect();
+    (entries.len(), entries)
+}
+
+impl Queries for SlabTree<false> {
+    fn rank_of(&self, key: &u16) -> Result<usize, usize> {
+        scan_rank(self.iter(), key)
+    }
+
+    fn entries_in(&self, start: u16, end: u16) -> (usize, Vec<Entry<'_>>) {
+        scan_range(self.iter(), start, end)
+    }
+
+    fn fold_in(&self, start: u16, end: u16) -> (u64, u64) {
+        self.fold_range(start..=end)
+    }
+}
+
+impl Queries for SlabTree<true> {
+    fn rank_of(&self, key: &u16) -> Result<usize, usize> {
+        self.rank(key)
+    }
+
+    fn entries_in(&self, start: u16, end: u16) -> (usize, Vec<Entry<'_>>) {
+        let range = self.range(start..=end);
+        (range.len(), range.collect())
+    }
+
+    fn fold_in(&self, start: u16, end: u16) -> (u64, u64) {
+        self.fold_range(start..=end)
+    }
+}
+
+impl Queries for HeapTree<false> {
+    fn rank_of(&self, key: &u16) -> Result<usize, usize> {
+        scan_rank(self.iter(), key)
+    }
+
+    fn entries_in(&self, start: u16, end: u16) -> (usize, Vec<Entry<'_>>) {
+        scan_range(self.iter(), start, end)
+    }
+
+    fn fold_in(&self, start: u16, end: u16) -> (u64, u64) {
+        self.fold_range(start..=end)
+    }
+}
+
+impl Queries for HeapTree<true> {
+    fn rank_of(&self, key: &u16) -> Result<usize, usize> {
+        self.rank(key)
+    }
+
+    fn entries_in(&self, start: u16, end: u16) -> (usize, Vec<Entry<'_>>) {
+        let range = self.range(start..=end);
+        (range.len(), range.collect())
+    }
+
+    fn fold_in(&self, start: u16, end: u16) -> (u64, u64) {
+        self.fold_range(start..=end)
+    }
+}
+
+impl Queries for PlusTree<false> {
+    fn rank_of(&self, key: &u16) -> Result<usize, usize> {
+        scan_rank(self.iter(), key)
+    }
+
+    fn entries_in(&self, start: u16, end: u16) -> (usize, Vec<Entry<'_>>) {
+        let range = self.range(start..=end);
+        (range.len(), range.collect())
+    }
+
+    fn fold_in(&self, start: u16, end: u16) -> (u64, u64) {
+        self.fold_range(start..=end)
+    }
+}
+
+impl Queries for PlusTree<true> {
+    fn rank_of(&self, key: &u16) -> Result<usize, usize> {
+        self.rank(key)
+    }
+
+    fn entries_in(&self, start: u16, end: u16) -> (usize, Vec<Entry<'_>>) {
+        let range = self.range(start..=end);
+        (range.len(), range.collect())
+    }
+
+    fn fold_in(&self, start: u16, end: u16) -> (u64, u64) {
+        self.fold_range(start..=end)
+    }
+}
+
+fn compare<T: PartialEq + Debug>(
+    step: usize,
+    op: Op,
+    expected: T,
+    slab: T,
+    heap: T,
+    bplus: T,
+) -> Result<(), String> {
+    if slab == expected && heap == expected && bplus == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "step {step}, {op:?}: expected {expected:?}, btree gave {slab:?}, std_btree gave \
+             {heap:?}, bplus_tree gave {bplus:?}"
+        ))
+    }
+}
+
+/// Finds the entry at `index` of `iter`, and how many entries the iterator reports after it.
+fn nth_and_len<I: ExactSizeIterator>(mut iter: I, index: usize) -> (Option<I::Item>, usize) {
+    (iter.nth(index), iter.len())
+}
+
+/// Runs `ops` on all four maps. Returns a description of the first difference or panic. The
+/// B+tree owns its buffers and has a budget, so it inserts with `try_insert`, and is cleared
+/// before it is dropped.
+fn run<const COUNTED: bool>(ops: &[Op]) -> Result<(), String>
+where
+    Counted<COUNTED>: SubtreeSize,
+    SlabTree<COUNTED>: Queries,
+    HeapTree<COUNTED>: Queries,
+    PlusTree<COUNTED>: Queries,
+{
+    let mut chunk = Chunk::new(CONFIG.chunk_size);
+    let budget = Arc::new(SharedBudget::new(usize::MAX));
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        // A tree that failed may be corrupted, so it's only dropped if everything matched.
+        let mut slab = ManuallyDrop::new(SlabTree::<COUNTED>::new(unsafe { chunk.bytes() }));
+        let mut heap = ManuallyDrop::new(HeapTree::<COUNTED>::new());
+        let mut bplus = ManuallyDrop::new(PlusTree::<COUNTED>::with_buffer(vec![
+            0;
+            CONFIG.chunk_size
+                / 2
+        ]));
+        bplus.add_buffer(vec![0; CONFIG.chunk_size / 2]);
+        bplus.set_budget(budget.clone());
+        let mut model = BTreeMap::new();
+
+        for (step, &op) in ops.iter().enumerate() {
+            let value = step as u32;
+            match op {
+                Op::Insert(key) => compare(
+                    step,
+                    op,
+                    model.insert(key, Box::new(value)).map(|old| (key, old)),
+                    slab.insert(key, Box::new(value)),
+                    heap.insert(key, Box::new(value)),
+                    bplus.try_insert(key, Box::new(value)).unwrap(),
+                )?,
+                Op::Remove(key) => compare(
+                    step,
+                    op,
+                    model.remove_entry(&key),
+                    slab.remove(&key),
+                    heap.remove(&key),
+                    bplus.remove(&key),
+                )?,
+                Op::Get(key) => compare(
+                    step,
+                    op,
+                    model.get_key_value(&key),
+                    slab.get_entry(&key),
+                    heap.get_entry(&key),
+                    bplus.get_entry(&key),
+                )?,
+                Op::Nth(index) => {
+                    let index = index as usize % (model.len() + 1);
+                    compare(
+                        step,
+                        op,
+                        nth_and_len(model.iter(), index),
+                        nth_and_len(slab.iter(), index),
+                        nth_and_len(heap.iter(), index),
+                        nth_and_len(bplus.iter(), index),
+                    )?
+                }
+                Op::Rank(key) => {
+                    let rank = model.range(..key).count();
+                    compare(
+                        step,
+                        op,
+                        if model.contains_key(&key) {
+                            Ok(rank)
+                        } else {
+                            Err(rank)
+                        },
+                        slab.rank_of(&key),
+                        heap.rank_of(&key),
+                        bplus.rank_of(&key),
+                    )?
+                }
+                Op::Range(start, end) => {
+                    let entries: Vec<_> = model.range(start..=end).collect();
+                    compare(
+                        step,
+                        op,
+                        (entries.len(), entries),
+                        slab.entries_in(start, end),
+                        heap.entries_in(start, end),
+                        bplus.entries_in(start, end),
+                    )?
+                }
+                Op::Fold(start, end) => compare(
+                    step,
+                    op,
+                    digest(model.range(start..=end)),
+                    slab.fold_in(start, end),
+                    heap.fold_in(start, end),
+                    bplus.fold_in(start, end),
+                )?,
+                Op::Check => {
+                    compare(step, op, model.len(), slab.len(), heap.len(), bplus.len())?;
+                    compare(
+                        step,
+                        op,
+                        model.len(),
+                        slab.iter().len(),
+                        heap.iter().len(),
+                        bplus.iter().len(),
+                    )?;
+                    if budget.used() != bplus.charged() || bplus.is_empty() != (budget.used() == 0)
+                    {
+                        return Err(format!(
+                            "step {step}, {op:?}: bplus_tree charged {} of {} entries, but the \
+                             budget has {} used",
+                            bplus.charged(),
+                            bplus.len(),
+                            budget.used()
+                        ));
+                    }
+                    compare(
+                        step,
+                        op,
+                        digest(model.iter()),
+                        slab.fold(),
+                        heap.fold(),
+                        bplus.fold(),
+                    )?;
+                    compare(
+                        step,
+                        op,
+                        Ok(model.len()),
+                        slab.validate().map(|stats| stats.len),
+                        heap.validate().map(|stats| stats.len),
+                        bplus.validate().map(|stats| stats.len),
+                    )?;
+                    compare(
+                        step,
+                        op,
+                        model.iter().collect::<Vec<_>>(),
+                        slab.iter().collect(),
+                        heap.iter().collect(),
+                        bplus.iter().collect(),
+                    )?;
+                    // Only the B+tree iterates backwards, so the others are reversed after.
+                    let reversed = |mut entries: Vec<_>| {
+                        entries.reverse();
+                        entries
+                    };
+                    compare(
+                        step,
+                        op,
+                        model.iter().rev().collect::<Vec<_>>(),
+                        reversed(slab.iter().collect()),
+                        reversed(heap.iter().collect()),
+                        bplus.iter().rev().collect(),
+                    )?;
+                }
+            }
+        }
+        compare(
+            ops.len(),
+            Op::Check,
+            model.iter().collect::<Vec<_>>(),
+            slab.iter().collect(),
+            heap.iter().collect(),
+            bplus.iter().collect(),
+        )?;
+
+        bplus.clear();
+        if !bplus.is_empty() || bplus.iter().next().is_some() || budget.used() != 0 {
+            return Err(format!(
+                "cleared bplus_tree has {} entries, and {} bytes of budget used",
+                bplus.len(),
+                budget.used()
+            ));
+        }
+
+        unsafe {
+            ManuallyDrop::drop(&mut slab);
+            ManuallyDrop::drop(&mut heap);
+            ManuallyDrop::drop(&mut bplus);
+        }
+        Ok(())
+    }));
+    outcome.unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(format!("panicked: {message}"))
+    })
+}
+
+/// Returns simpler versions of an operation to try while shrinking.
+fn simplify(op: Op) -> Vec<Op> {
+    let smaller = |key: u16| {
+        [0, key / 2, key.saturating_sub(1)]
+            .into_iter()
+            .filter(move |k| *k < key)
+    };
+    match op {
+        Op::Insert(key) => smaller(key).map(Op::Insert).collect(),
+        Op::Remove(key) => smaller(key).map(Op::Remove).collect(),
+        Op::Get(key) => smaller(key).map(Op::Get).chain([Op::Check]).collect(),
+        Op::Nth(index) => smaller(index).map(Op::Nth).chain([Op::Check]).collect(),
+        Op::Rank(key) => smaller(key).map(Op::Rank).chain([Op::Check]).collect(),
+        Op::Range(start, end) => smaller(end)
+            .filter(|&end| start <= end)
+            .map(|end| Op::Range(start, end))
+            .chain([Op::Check])
+            .collect(),
+        Op::Fold(start, end) => smaller(end)
+            .filter(|&end| start <= end)
+            .map(|end| Op::Fold(start, end))
+            .chain([Op::Check])
+            .collect(),
+        Op::Check => Vec::new(),
+    }
+}
+
+/// Shrinks a failing sequence: first removes runs of operations of halving lengths, then
+/// simplifies the remaining operations one at a time, as long as the sequence keeps failing.
+fn shrink(mut ops: Vec<Op>, fails: impl Fn(&[Op]) -> bool) -> Vec<Op> {
+    let mut run = ops.len() / 2;
+    while 0 < run {
+        let mut start = 0;
+        while start < ops.len() {
+            let mut candidate = ops.clone();
+            candidate.drain(start..ops.len().min(start + run));
+            if fails(&candidate) {
+                ops = candidate;
+            } else {
+                start += run;
+            }
+        }
+        run /= 2;
+    }
+
+    let mut progress = true;
+    while progress {
+        progress = false;
+        for i in 0..ops.len() {
+            for simpler in simplify(ops[i]) {
+                let mut candidate = ops.clone();
+                candidate[i] = simpler;
+                if fails(&candidate) {
+                    ops = candidate;
+                    progress = true;
+                    break;
+                }
+            }
+        }
+    }
+    ops
+}
+
+fn check<const COUNTED: bool>()
+where
+    Counted<COUNTED>: SubtreeSize,
+    SlabTree<COUNTED>: Queries,
+    HeapTree<COUNTED>: Queries,
+    PlusTree<COUNTED>: Queries,
+{
+    for case in 0..CONFIG.cases {
+        let ops = generate(&mut XorShift::new(case));
+        if let Err(error) = run::<COUNTED>(&ops) {
+            let shrunk = shrink(ops, |ops| run::<COUNTED>(ops).is_err());
+            let shrunk_error = run::<COUNTED>(&shrunk).unwrap_err();
+            panic!(
+                "case {case} failed: {error}\nshrunk to {} operations: {shrunk:?}\nwhich fail with: {shrunk_error}",
+                shrunk.len()
+            );
+        }
+    }
+}
+
+#[test]
+fn trees_match_btreemap() {
+    check::<false>();
+}
+
+#[test]
+fn counted_trees_match_btreemap() {
+    check::<true>();
+}
+
+#[test]
+fn shrinking_finds_minimal_sequence() {
+    // Pretend that removing a key above 100 after inserting it is a bug.
+    let fails = |ops: &[Op]| {
+        ops.iter().enumerate().any(|(i, op)| match *op {
+            Op::Insert(key) => 100 < key && ops[i + 1..].contains(&Op::Remove(key)),
+            _ => false,
+        })
+    };
+    let mut ops = generate(&mut XorShift::new(7));
+    ops.insert(ops.len() / 3, Op::Insert(4321));
+    ops.push(Op::Remove(4321));
+    assert!(fails(&ops));
+
+    let shrunk = shrink(ops, fails);
+    assert_eq!(shrunk.len(), 2);
+    assert!(fails(&shrunk));
+    let [Op::Insert(key), Op::Remove(removed)] = shrunk[..] else {
+        panic!("unexpected shrunk sequence {shrunk:?}");
+    };
+    assert_eq!(key, removed);
+}
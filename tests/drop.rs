@@ -0,0 +1,64 @@
+//! Checks that both trees drop every entry exactly once: the entries they hand back from
+//! `insert` and `remove` as well as the ones still in the tree when it is dropped.
+
+mod common;
+
+use btree2::{btree, std_btree};
+use common::Chunk;
+use std::rc::Rc;
+
+const LEN: u32 = if cfg!(miri) { 1_500 } else { 50_000 };
+
+/// Removes every third key, which goes through the merges and rotations of the internal nodes.
+fn remove_some(mut remove: impl FnMut(&String) -> Option<(String, Rc<()>)>) {
+    for i in (0..LEN).step_by(3) {
+        let (key, _value) = remove(&i.to_string()).unwrap();
+        assert_eq!(key, i.to_string());
+    }
+}
+
+#[test]
+fn btree_drops_every_entry() {
+    let rc = Rc::new(());
+    let mut chunk = Chunk::new(1 << 23);
+    let mut tree = btree::BTree::<String, Rc<()>>::new(unsafe { chunk.bytes() });
+    for i in 0..LEN {
+        assert!(tree.insert(i.to_string(), rc.clone()).is_none());
+    }
+    let (_, old) = tree.insert(0.to_string(), rc.clone()).unwrap();
+    drop(old);
+    assert_eq!(Rc::strong_count(&rc), 1 + LEN as usize);
+
+    remove_some(|key| tree.remove(key));
+    assert_eq!(Rc::strong_count(&rc), 1 + tree.len());
+
+    drop(tree);
+    assert_eq!(Rc::strong_count(&rc), 1);
+}
+
+#[test]
+fn std_btree_drops_every_entry() {
+    let rc = Rc::new(());
+    let mut tree = std_btree::BTree::<String, Rc<()>>::new();
+    for i in 0..LEN {
+        assert!(tree.insert(i.to_string(), rc.clone()).is_none());
+    }
+    let (_, old) = tree.insert(0.to_string(), rc.clone()).unwrap();
+    drop(old);
+    assert_eq!(Rc::strong_count(&rc), 1 + LEN as usize);
+
+    remove_some(|key| tree.remove(key));
+    assert_eq!(Rc::strong_count(&rc), 1 + tree.len());
+
+    drop(tree);
+    assert_eq!(Rc::strong_count(&rc), 1);
+}
+
+#[test]
+fn empty_trees_drop() {
+    let mut chunk = Chunk::new(1 << 16);
+    let tree = btree::BTree::<String, Rc<()>>::new(unsafe { chunk.bytes() });
+    drop(tree);
+    let tree = std_btree::BTree::<String, Rc<()>>::new();
+    drop(tree);
+}
@@ -0,0 +1,189 @@
+//! Checks the DOT and JSON dumps of multi-level trees against the shape `tree_stats` and `stats`
+//! report, and the keys they list against the tree's own iteration.
+
+mod common;
+
+use btree2::stats::TreeStats;
+use btree2::{bplus_tree, btree, std_btree};
+use common::Chunk;
+use serde_json::Value;
+
+const LEN: usize = if cfg!(miri) { 1_000 } else { 5_000 };
+
+/// Keys with the characters both formats have to escape.
+fn key(i: usize) -> String {
+    format!("k\"{i:05}\\|{{<>}}")
+}
+
+/// The shape and the keys found by walking the JSON dump.
+#[derive(Default)]
+struct Walk {
+    stats: TreeStats,
+    keys: Vec<String>,
+    /// Whether the keys of the internal nodes are separators rather than entries, as in a B+ tree.
+    separators: bool,
+}
+
+impl Walk {
+    /// Walks `node`, `level` levels below the root, and collects its keys in order.
+    fn child(&mut self, node: &Value, level: usize) {
+        let keys = node["keys"].as_array().expect("keys");
+        self.stats.levels[level].nodes += 1;
+        self.stats.levels[level].keys += keys.len();
+        match node["kind"].as_str() {
+            Some("leaf") => {
+                assert!(node.get("children").is_none());
+                self.stats.leaves += 1;
+                self.keys
+                    .extend(keys.iter().map(|key| key.as_str().unwrap().to_owned()));
+            }
+            Some("node") => {
+                let children = node["children"].as_array().expect("children");
+                assert_eq!(children.len(), keys.len() + 1);
+                self.stats.nodes += 1;
+                for (i, child) in children.iter().enumerate() {
+                    self.child(child, level + 1);
+                    if let Some(key) = keys.get(i).filter(|_| !self.separators) {
+                        self.keys.push(key.as_str().unwrap().to_owned());
+                    }
+                }
+            }
+            kind => panic!("unexpected kind {kind:?}"),
+        }
+    }
+}
+
+/// Parses `json` and checks it against the shape in `stats` and the keys in `expected`.
+/// `separators` says whether the keys of the internal nodes are entries.
+fn check_json(json: &str, stats: &TreeStats, expected: &[String], separators: bool) {
+    let dump: Value = serde_json::from_str(json).expect("the JSON dump parses");
+    let mut walk = Walk {
+        separators,
+        ..Walk::default()
+    };
+    walk.stats.len = dump["len"].as_u64().unwrap() as usize;
+    walk.stats.depth = dump["depth"].as_u64().unwrap() as usize;
+    if stats.depth == 0 {
+        assert!(dump["root"].is_null());
+    } else {
+        walk.child(&dump["root"], 0);
+    }
+
+    assert_eq!(walk.stats.len, stats.len);
+    assert_eq!(walk.stats.depth, stats.depth);
+    assert_eq!(walk.stats.nodes, stats.nodes);
+    assert_eq!(walk.stats.leaves, stats.leaves);
+    for (found, level) in walk.stats.levels().iter().zip(stats.levels()) {
+        assert_eq!(found.nodes, level.nodes);
+        assert_eq!(found.keys, level.keys);
+    }
+    assert_eq!(walk.keys, expected);
+}
+
+/// Checks that `dot` has one record per node, one edge to every record but the root's, and
+/// fills that add up to the keys on all the levels.
+fn check_dot(dot: &str, stats: &TreeStats) {
+    let mut lines = dot.lines();
+    assert_eq!(lines.next(), Some("digraph BTree {"));
+    assert_eq!(lines.next(), Some("    node [shape=record];"));
+    assert_eq!(dot.lines().last(), Some("}"));
+
+    let mut records = Vec::new();
+    let mut targets = Vec::new();
+    let mut fill = 0;
+    for line in lines.filter(|line| *line != "}") {
+        let line = line.trim_start();
+        if let Some((_, target)) = line.split_once(" -> ") {
+            targets.push(target.strip_suffix(';').unwrap().to_owned());
+        } else {
+            let (id, label) = line.split_once(" [label=\"{").expect("a record");
+            let (count, _) = label.split_once('/').unwrap();
+            fill += count.parse::<usize>().unwrap();
+            records.push(id.to_owned());
+        }
+    }
+    assert_eq!(records.len(), stats.nodes + stats.leaves);
+    assert_eq!(
+        fill,
+        stats.levels().iter().map(|level| level.keys).sum::<usize>()
+    );
+
+    targets.sort_unstable();
+    let mut children = records.split_off(records.len().min(1));
+    children.sort_unstable();
+    assert_eq!(targets, children);
+}
+
+#[test]
+fn slab_tree_dumps_match_stats() {
+    let mut chunk = Chunk::new(1 << 23);
+    let mut tree = btree::BTree::<String, usize>::new(unsafe { chunk.bytes() });
+    let (mut json, mut dot) = (String::new(), String::new());
+    tree.dump_json(&mut json).unwrap();
+    tree.dump_dot(&mut dot).unwrap();
+    check_json(&json, &tree.tree_stats(), &[], false);
+    check_dot(&dot, &tree.tree_stats());
+
+    for i in 0..LEN {
+        tree.insert(key(i * 7 % LEN), i);
+    }
+    let stats = tree.tree_stats();
+    assert!(stats.depth >= 3);
+    let expected: Vec<_> = tree.iter().map(|(key, _)| format!("{key:?}")).collect();
+
+    let (mut json, mut dot) = (String::new(), String::new());
+    tree.dump_json(&mut json).unwrap();
+    tree.dump_dot(&mut dot).unwrap();
+    check_json(&json, &stats, &expected, false);
+    check_dot(&dot, &stats);
+    drop(tree);
+}
+
+#[test]
+fn heap_tree_dumps_match_stats() {
+    let mut tree = std_btree::BTree::<String, usize>::new();
+    let (mut json, mut dot) = (String::new(), String::new());
+    tree.dump_json(&mut json).unwrap();
+    tree.dump_dot(&mut dot).unwrap();
+    check_json(&json, &tree.stats(), &[], false);
+    check_dot(&dot, &tree.stats());
+
+    for i in 0..LEN {
+        tree.insert(key(i * 7 % LEN), i);
+    }
+    let stats = tree.stats();
+    assert!(stats.depth >= 3);
+    let expected: Vec<_> = tree.iter().map(|(key, _)| format!("{key:?}")).collect();
+
+    let (mut json, mut dot) = (String::new(), String::new());
+    tree.dump_json(&mut json).unwrap();
+    tree.dump_dot(&mut dot).unwrap();
+    check_json(&json, &stats, &expected, false);
+    check_dot(&dot, &stats);
+}
+
+#[test]
+fn bplus_tree_dumps_match_stats() {
+    let mut chunk = Chunk::new(1 << 23);
+    let mut tree = bplus_tree::BPlusTree::<String, usize>::new(unsafe { chunk.bytes() });
+    let (mut json, mut dot) = (String::new(), String::new());
+    tree.dump_json(&mut json).unwrap();
+    tree.dump_dot(&mut dot).unwrap();
+    check_json(&json, &tree.tree_stats(), &[], true);
+    check_dot(&dot, &tree.tree_stats());
+
+    for i in 0..LEN {
+        tree.insert(key(i * 7 % LEN), i);
+    }
+    let stats = tree.tree_stats();
+    assert!(stats.depth >= 3);
+    assert_eq!(tree.validate(), Ok(stats));
+    let expected: Vec<_> = tree.iter().map(|(key, _)| format!("{key:?}")).collect();
+
+    let (mut json, mut dot) = (String::new(), String::new());
+    tree.dump_json(&mut json).unwrap();
+    tree.dump_dot(&mut dot).unwrap();
+    check_json(&json, &stats, &expected, true);
+    check_dot(&dot, &stats);
+    drop(tree);
+}
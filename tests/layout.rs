@@ -0,0 +1,97 @@
+//! Checks that only counted trees pay for the subtree sizes in their nodes: every tree is built
+//! counted and uncounted with the same entries, and the nodes of the uncounted one are exactly one
+//! `usize` smaller, while their leaves are the same.
+
+use btree2::budget::SharedBudget;
+use btree2::stats::TreeStats;
+use btree2::{bplus_tree, btree, std_btree};
+use std::mem::size_of;
+use std::sync::Arc;
+
+const LEN: u64 = if cfg!(miri) { 2_000 } else { 20_000 };
+
+/// Fills a counted and an uncounted tree made by `$new`, each charging a budget of its own, and
+/// returns their shapes and the bytes they were charged.
+macro_rules! fill_both {
+    ($new:expr, $stats:ident) => {{
+        let uncounted_budget = Arc::new(SharedBudget::new(usize::MAX));
+        let counted_budget = Arc::new(SharedBudget::new(usize::MAX));
+        let (mut uncounted, mut counted) = $new;
+        uncounted.set_budget(uncounted_budget.clone());
+        counted.set_budget(counted_budget.clone());
+        for key in 0..LEN {
+            uncounted.insert(key, key);
+            counted.insert(key, key);
+        }
+        (
+            (uncounted.$stats(), uncounted.charged()),
+            (counted.$stats(), counted.charged()),
+        )
+    }};
+}
+
+/// Checks that both trees have the same shape, and that the counted one was charged one `usize`
+/// more for each of its internal nodes.
+fn check_nodes(
+    (uncounted, uncounted_bytes): (TreeStats, usize),
+    (counted, counted_bytes): (TreeStats, usize),
+) {
+    assert_eq!(uncounted, counted);
+    assert!(3 <= counted.depth);
+    assert_eq!(
+        counted_bytes - uncounted_bytes,
+        counted.nodes * size_of::<usize>()
+    );
+}
+
+#[test]
+fn slab_tree_nodes_only_count_when_counted() {
+    let (uncounted, counted) = fill_both!(
+        (
+            btree::BTree::<u64, u64, false>::with_buffer(vec![0; 1 << 22]),
+            btree::BTree::<u64, u64, true>::with_buffer(vec![0; 1 << 22]),
+        ),
+        tree_stats
+    );
+    check_nodes(uncounted, counted);
+
+    let uncounted = btree::BTree::<u64, u64, false>::with_buffer(vec![0; 1 << 16]).stats();
+    let counted = btree::BTree::<u64, u64, true>::with_buffer(vec![0; 1 << 16]).stats();
+    assert_eq!(
+        counted.node_alloc.slab_size - uncounted.node_alloc.slab_size,
+        size_of::<usize>()
+    );
+    assert_eq!(counted.leaf_alloc.slab_size, uncounted.leaf_alloc.slab_size);
+}
+
+#[test]
+fn boxed_tree_nodes_only_count_when_counted() {
+    let (uncounted, counted) = fill_both!(
+        (
+            std_btree::BTree::<u64, u64, false>::new(),
+            std_btree::BTree::<u64, u64, true>::new(),
+        ),
+        stats
+    );
+    check_nodes(uncounted, counted);
+}
+
+#[test]
+fn bplus_tree_nodes_only_count_when_counted() {
+    let (uncounted, counted) = fill_both!(
+        (
+            bplus_tree::BPlusTree::<u64, u64, false>::with_buffer(vec![0; 1 << 22]),
+            bplus_tree::BPlusTree::<u64, u64, true>::with_buffer(vec![0; 1 << 22]),
+        ),
+        tree_stats
+    );
+    check_nodes(uncounted, counted);
+
+    let uncounted = bplus_tree::BPlusTree::<u64, u64, false>::with_buffer(vec![0; 1 << 16]).stats();
+    let counted = bplus_tree::BPlusTree::<u64, u64, true>::with_buffer(vec![0; 1 << 16]).stats();
+    assert_eq!(
+        counted.node_alloc.slab_size - uncounted.node_alloc.slab_size,
+        size_of::<usize>()
+    );
+    assert_eq!(counted.leaf_alloc.slab_size, uncounted.leaf_alloc.slab_size);
+}
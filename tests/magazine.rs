@@ -0,0 +1,83 @@
+//! Hammers a `MagazineAllocator` from several threads, each of which claims a CPU id of its own,
+//! and hands boxes over to other threads to free, so that slabs move between magazines.
+
+mod common;
+
+use btree2::magazine::MagazineAllocator;
+use btree2::slab::{LockedSlabAllocator, LockedSlabBox};
+use common::{Chunk, XorShift};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+const THREADS: usize = 4;
+
+static NEXT_CPU: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static CPU: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+fn current_cpu() -> usize {
+    CPU.with(|cpu| {
+        *cpu.get()
+            .get_or_insert_with(|| NEXT_CPU.fetch_add(1, Ordering::Relaxed))
+    })
+}
+
+type Allocator = MagazineAllocator<[u64; 4], fn() -> usize, THREADS>;
+
+#[test]
+fn threads_share_the_slabs() {
+    let mut chunk = Chunk::new(1 << 20);
+    let alloc: Allocator = MagazineAllocator::new(
+        LockedSlabAllocator::new(unsafe { chunk.bytes() }),
+        current_cpu,
+    );
+    let slabs = alloc.depot().stats().free / alloc.depot().stats().slab_size;
+    let ops = if cfg!(miri) { 300 } else { 50_000 };
+    let handoff = Mutex::new(Vec::<LockedSlabBox<[u64; 4], Allocator>>::new());
+
+    thread::scope(|scope| {
+        for seed in 0..THREADS as u64 {
+            let (alloc, handoff) = (&alloc, &handoff);
+            scope.spawn(move || {
+                let mut rng = XorShift::new(seed);
+                let mut own = Vec::new();
+                for op in 0..ops {
+                    let value = (seed << 32) | op;
+                    match rng.below(8) {
+                        0..=3 => own.push((LockedSlabBox::new(alloc, [value; 4]), value)),
+                        4 if !own.is_empty() => {
+                            let (boxed, _) = own.swap_remove(rng.below(own.len() as u64) as usize);
+                            handoff.lock().unwrap().push(boxed);
+                        }
+                        5 => {
+                            let theirs = handoff.lock().unwrap().pop();
+                            if let Some(boxed) = theirs {
+                                let [value, ..] = *boxed;
+                                assert_eq!(*boxed, [value; 4]);
+                                boxed.free();
+                            }
+                        }
+                        _ if !own.is_empty() => {
+                            let (boxed, value) =
+                                own.swap_remove(rng.below(own.len() as u64) as usize);
+                            assert_eq!(*boxed, [value; 4], "slab shared by two boxes");
+                            drop(boxed);
+                        }
+                        _ => {}
+                    }
+                }
+            });
+        }
+    });
+    handoff.into_inner().unwrap().clear();
+
+    alloc.flush();
+    assert_eq!(alloc.cached(), 0);
+    let stats = alloc.depot().stats();
+    assert_eq!(stats.in_use, 0);
+    assert_eq!(stats.free / stats.slab_size, slabs);
+}
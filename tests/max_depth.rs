@@ -0,0 +1,118 @@
+//! Checks the depth bound that sizes the paths through the trees, and grows each tree as deep as
+//! a test can afford, so that insertions and removals go through every level of their paths on
+//! the way up and down again.
+
+mod common;
+
+use btree2::bplus_tree::BPlusTree;
+use btree2::stats::{max_depth, MAX_LEVELS};
+use btree2::{btree, std_btree};
+use common::Chunk;
+
+/// The fewest entries in a tree of `depth` levels, whose root has two children and whose other
+/// nodes have `min_children` children, or as many entries less one in the leaves. Saturates.
+fn min_len(depth: usize, min_children: u128) -> u128 {
+    let (mut len, mut nodes) = (0u128, 1u128);
+    for level in 0..depth {
+        let (keys, children) = if level == 0 {
+            (1, 2)
+        } else {
+            (min_children - 1, min_children)
+        };
+        len = len.saturating_add(nodes.saturating_mul(keys));
+        nodes = nodes.saturating_mul(children);
+    }
+    len
+}
+
+#[test]
+fn deeper_trees_overflow_the_length() {
+    for min_children in 2..=64 {
+        let depth = max_depth(min_children);
+        assert!(depth <= MAX_LEVELS);
+        assert!((usize::MAX as u128) < min_len(depth + 1, min_children as u128));
+        assert!(min_len(depth - 1, min_children as u128) <= usize::MAX as u128);
+    }
+    assert_eq!(MAX_LEVELS, usize::BITS as usize);
+}
+
+/// Inserts ascending keys into `$tree` until it is `$depth` levels deep, then removes them in
+/// descending order until it is empty. Validates the tree
+/// after every thousand operations, and runs `$refill`, if given, after every thousand insertions.
+macro_rules! deepen {
+    ($tree:ident, $depth:expr $(, $refill:expr)?) => {{
+        let mut len = 0u32;
+        while $tree.validate().unwrap().depth < $depth {
+            for _ in 0..1000 {
+                assert!($tree.insert(len, len).is_none());
+                len += 1;
+            }
+            $($refill;)?
+        }
+        assert_eq!($tree.validate().unwrap().depth, $depth);
+
+        let mut depth = $depth;
+        while let Some(key) = len.checked_sub(1) {
+            assert_eq!($tree.remove(&key), Some((key, key)));
+            len = key;
+            if len % 1000 == 0 {
+                let stats = $tree.validate().unwrap();
+                assert!(stats.depth <= depth);
+                depth = stats.depth;
+            }
+        }
+        assert_eq!(depth, 0);
+    }};
+}
+
+trait Chunked {
+    fn needs_new_chunk(&self) -> bool;
+    fn add_chunk(&mut self, chunk: &'static mut [u8]);
+}
+
+impl Chunked for btree::BTree<u32, u32> {
+    fn needs_new_chunk(&self) -> bool {
+        self.needs_new_chunk()
+    }
+    fn add_chunk(&mut self, chunk: &'static mut [u8]) {
+        self.add_chunk(chunk)
+    }
+}
+
+impl Chunked for BPlusTree<u32, u32> {
+    fn needs_new_chunk(&self) -> bool {
+        self.needs_new_chunk()
+    }
+    fn add_chunk(&mut self, chunk: &'static mut [u8]) {
+        self.add_chunk(chunk)
+    }
+}
+
+const CHUNK_SIZE: usize = 1 << 20;
+
+fn add_chunks(tree: &mut impl Chunked, chunks: &mut Vec<Chunk>) {
+    while tree.needs_new_chunk() {
+        chunks.push(Chunk::new(CHUNK_SIZE));
+        tree.add_chunk(unsafe { chunks.last_mut().unwrap().bytes() });
+    }
+}
+
+#[test]
+fn btree_grows_deep() {
+    let mut chunks = vec![Chunk::new(CHUNK_SIZE)];
+    let mut tree = btree::BTree::<u32, u32>::new(unsafe { chunks[0].bytes() });
+    deepen!(tree, 5, add_chunks(&mut tree, &mut chunks));
+}
+
+#[test]
+fn std_btree_grows_deep() {
+    let mut tree = std_btree::BTree::<u32, u32>::new();
+    deepen!(tree, 5);
+}
+
+#[test]
+fn bplus_tree_grows_deep() {
+    let mut chunks = vec![Chunk::new(CHUNK_SIZE)];
+    let mut tree = BPlusTree::<u32, u32>::new(unsafe { chunks[0].bytes() });
+    deepen!(tree, 5, add_chunks(&mut tree, &mut chunks));
+}
@@ -0,0 +1,130 @@
+//! Runs random operations against `PagedBTree` and `BTreeMap` in temporary files, with buffer
+//! pools small enough that most operations evict pages, and reopens the files after checkpoints.
+
+#![cfg(feature = "fs")]
+
+mod common;
+
+use btree2::paged::PagedBTree;
+use common::{TempDir, XorShift};
+use std::collections::BTreeMap;
+use std::io;
+
+fn check_entries(tree: &mut PagedBTree<u32, u64>, model: &BTreeMap<u32, u64>) -> io::Result<()> {
+    assert_eq!(tree.len(), model.len());
+    let entries = tree.iter().collect::<io::Result<Vec<_>>>()?;
+    assert!(entries.into_iter().eq(model.iter().map(|(&k, &v)| (k, v))));
+    Ok(())
+}
+
+#[test]
+fn matches_btreemap() -> io::Result<()> {
+    for (seed, pool_pages) in [(1, 1), (2, 4), (3, 64)] {
+        let dir = TempDir::new();
+        let path = dir.join("random.db");
+        let mut tree = PagedBTree::<u32, u64>::create(&path, pool_pages)?;
+        let mut model = BTreeMap::new();
+        let mut rng = XorShift::new(seed);
+
+        for round in 0..6 {
+            let keys = 1 + rng.below(20_000) as u32;
+            for step in 0..6_000u64 {
+                let key = rng.below(keys as u64) as u32;
+                // Grow in the even rounds and shrink in the odd ones.
+                match (rng.below(4), round % 2) {
+                    (0, _) => assert_eq!(tree.get(&key)?, model.get(&key).copied()),
+                    (1, 0) | (2, 1) | (3, 1) => assert_eq!(
+                        tree.remove(&key)?,
+                        model.remove_entry(&key),
+                        "seed {seed}, round {round}, step {step}"
+                    ),
+                    _ => assert_eq!(
+                        tree.insert(key, step)?,
+                        model.insert(key, step).map(|old| (key, old)),
+                        "seed {seed}, round {round}, step {step}"
+                    ),
+                }
+            }
+            check_entries(&mut tree, &model)?;
+
+            tree.checkpoint()?;
+            drop(tree);
+            tree = PagedBTree::open(&path, pool_pages)?;
+            check_entries(&mut tree, &model)?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn reuses_freed_pages() -> io::Result<()> {
+    let dir = TempDir::new();
+    let path = dir.join("reuse.db");
+    let mut tree = PagedBTree::<u64, u64>::create(&path, 8)?;
+    for key in 0..10_000 {
+        tree.insert(key, key)?;
+    }
+    let grown = tree.pool_stats().page_count;
+    for key in 0..10_000 {
+        assert_eq!(tree.remove(&key)?, Some((key, key)));
+    }
+    assert!(tree.is_empty());
+    assert_eq!(tree.depth(), 0);
+    let stats = tree.pool_stats();
+    assert_eq!(stats.free_pages + 1, stats.page_count);
+
+    for key in 0..10_000 {
+        tree.insert(key, !key)?;
+    }
+    let stats = tree.pool_stats();
+    assert_eq!(stats.page_count, grown);
+    assert!(0 < stats.evictions);
+    assert_eq!(tree.get(&1234)?, Some(!1234));
+    Ok(())
+}
+
+#[test]
+fn rejects_other_files() -> io::Result<()> {
+    let dir = TempDir::new();
+    let path = dir.join("types.db");
+    PagedBTree::<u32, u64>::create(&path, 4)?.insert(1, 2)?;
+    let error = PagedBTree::<u64, u64>::open(&path, 4).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+    std::fs::write(&path, [0u8; 4096])?;
+    let error = PagedBTree::<u32, u64>::open(&path, 4).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    Ok(())
+}
+
+#[test]
+fn rejects_empty_leaves() -> io::Result<()> {
+    let dir = TempDir::new();
+    let path = dir.join("empty.db");
+    let mut tree = PagedBTree::<u32, u64>::create(&path, 4)?;
+    for key in 0..2_000 {
+        tree.insert(key, key as u64)?;
+    }
+    tree.checkpoint()?;
+    drop(tree);
+
+    // Every page but the header starts with the length and the height of its node.
+    let mut bytes = std::fs::read(&path)?;
+    for page in bytes.chunks_mut(4096).skip(1) {
+        if page[4..8] == [0; 4] {
+            page[..4].fill(0);
+        }
+    }
+    std::fs::write(&path, bytes)?;
+
+    // Removing a key of an internal node takes its predecessor from an empty leaf.
+    let mut tree = PagedBTree::<u32, u64>::open(&path, 4)?;
+    let errors = (0..2_000)
+        .filter_map(|key| tree.remove(&key).err())
+        .collect::<Vec<_>>();
+    assert!(!errors.is_empty());
+    assert!(errors
+        .iter()
+        .all(|error| error.kind() == io::ErrorKind::InvalidData));
+    Ok(())
+}
@@ -0,0 +1,239 @@
+//! Simulates crashes of a `PagedBTree` by copying its file and its log while it's open, cutting the
+//! log at arbitrary byte offsets, and recovering from the copies. The recovered tree has to equal
+//! the tree after some prefix of the operations, and a longer one the more of the log is kept.
+
+#![cfg(feature = "fs")]
+
+mod common;
+
+use btree2::paged::{wal_path, PagedBTree};
+use common::{TempDir, XorShift};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+type Map = BTreeMap<u32, u64>;
+
+const POOL_PAGES: usize = 4;
+
+/// Runs `count` random insertions and removals on the tree and the model, and returns the model
+/// after each of them, starting with the model before the first.
+fn run(
+    tree: &mut PagedBTree<u32, u64>,
+    model: &mut Map,
+    rng: &mut XorShift,
+    count: usize,
+) -> io::Result<Vec<Map>> {
+    let mut snapshots = vec![model.clone()];
+    for step in 0..count {
+        let key = rng.below(600) as u32;
+        if rng.below(3) == 0 {
+            assert_eq!(tree.remove(&key)?, model.remove_entry(&key));
+        } else {
+            let value = rng.next();
+            assert_eq!(
+                tree.insert(key, value)?,
+                model.insert(key, value).map(|old| (key, old)),
+                "step {step}"
+            );
+        }
+        snapshots.push(model.clone());
+    }
+    Ok(snapshots)
+}
+
+fn contents(tree: &mut PagedBTree<u32, u64>) -> io::Result<Map> {
+    let entries = tree.iter().collect::<io::Result<Map>>()?;
+    assert_eq!(tree.len(), entries.len());
+    Ok(entries)
+}
+
+/// The file and the log of an open tree, as a crash would leave them.
+fn crash_image(path: &Path) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    Ok((fs::read(path)?, fs::read(wal_path(path))?))
+}
+
+/// Writes the image with the log cut to `cut` bytes next to `path`, and recovers it.
+fn recover_cut(
+    dir: &TempDir,
+    (file, log): &(Vec<u8>, Vec<u8>),
+    cut: usize,
+) -> io::Result<PagedBTree<u32, u64>> {
+    let path = dir.join("crashed.db");
+    fs::write(&path, file)?;
+    fs::write(wal_path(&path), &log[..cut])?;
+    let (tree, recovery) = PagedBTree::recover(&path, POOL_PAGES)?;
+    assert!(recovery.discarded <= cut as u64);
+    assert_eq!(fs::metadata(wal_path(&path))?.len(), 0);
+    Ok(tree)
+}
+
+/// Some offsets spread over the whole log, including both ends, in order.
+fn cuts(rng: &mut XorShift, len: usize, count: usize) -> Vec<usize> {
+    let mut cuts = (0..count)
+        .map(|_| rng.below(len as u64 + 1) as usize)
+        .chain((0..=len).step_by(len / count + 1))
+        .chain([len])
+        .collect::<Vec<_>>();
+    cuts.sort_unstable();
+    cuts.dedup();
+    cuts
+}
+
+/// Recovers the image at every cut, and checks that the recovered trees equal non-shrinking
+/// prefixes of `snapshots`, ending with all of them.
+fn check_cuts(
+    dir: &TempDir,
+    image: &(Vec<u8>, Vec<u8>),
+    snapshots: &[Map],
+    cuts: &[usize],
+) -> io::Result<()> {
+    let mut prefix = 0;
+    for &cut in cuts {
+        let entries = contents(&mut recover_cut(dir, image, cut)?)?;
+        prefix = (prefix..snapshots.len())
+            .find(|&i| snapshots[i] == entries)
+            .unwrap_or_else(|| panic!("the log cut at {cut} recovered no prefix from {prefix} on"));
+    }
+    assert_eq!(prefix, snapshots.len() - 1, "the whole log lost operations");
+    Ok(())
+}
+
+#[test]
+fn recovers_prefixes() -> io::Result<()> {
+    for seed in 1..=2 {
+        let dir = TempDir::new();
+        let path = dir.join("tree.db");
+        let mut rng = XorShift::new(seed);
+        let mut tree = PagedBTree::create(&path, POOL_PAGES)?;
+        let mut model = Map::new();
+
+        // Operations before a checkpoint survive any cut.
+        run(&mut tree, &mut model, &mut rng, 300 * seed as usize)?;
+        tree.checkpoint()?;
+        let snapshots = run(&mut tree, &mut model, &mut rng, 400)?;
+        let image = crash_image(&path)?;
+        assert_eq!(contents(&mut tree)?, model);
+
+        let cuts = cuts(&mut rng, image.1.len(), 120);
+        check_cuts(&dir, &image, &snapshots, &cuts)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn recovers_after_recovery() -> io::Result<()> {
+    let dir = TempDir::new();
+    let path = dir.join("tree.db");
+    let mut rng = XorShift::new(7);
+    let mut tree = PagedBTree::create(&path, POOL_PAGES)?;
+    let mut model = Map::new();
+    let snapshots = run(&mut tree, &mut model, &mut rng, 500)?;
+    let image = crash_image(&path)?;
+    drop(tree);
+
+    // Crash again after recovering from a torn log, and working on the recovered tree.
+    let cut = image.1.len() / 2 + 3;
+    let mut tree = recover_cut(&dir, &image, cut)?;
+    let mut model = contents(&mut tree)?;
+    assert!(snapshots.contains(&model));
+    let snapshots = run(&mut tree, &mut model, &mut rng, 300)?;
+    let image = crash_image(&dir.join("crashed.db"))?;
+    drop(tree);
+
+    let cuts = cuts(&mut rng, image.1.len(), 100);
+    check_cuts(&dir, &image, &snapshots, &cuts)
+}
+
+#[test]
+fn clean_shutdown_empties_log() -> io::Result<()> {
+    let dir = TempDir::new();
+    let path = dir.join("tree.db");
+    let mut rng = XorShift::new(11);
+    let mut model = Map::new();
+    {
+        let mut tree = PagedBTree::create(&path, POOL_PAGES)?;
+        run(&mut tree, &mut model, &mut rng, 1000)?;
+    }
+    assert_eq!(fs::metadata(wal_path(&path))?.len(), 0);
+
+    let (mut tree, recovery) = PagedBTree::recover(&path, POOL_PAGES)?;
+    assert_eq!(recovery, Default::default());
+    assert_eq!(contents(&mut tree)?, model);
+    Ok(())
+}
+
+#[test]
+fn rolls_back_failed_operations() -> io::Result<()> {
+    let dir = TempDir::new();
+    let path = dir.join("tree.db");
+    let mut rng = XorShift::new(13);
+    let mut model = Map::new();
+    {
+        // Only insertions, so that no page is on the free list.
+        let mut tree = PagedBTree::create(&path, POOL_PAGES)?;
+        for key in 0..2_000 {
+            tree.insert(key, key as u64)?;
+            model.insert(key, key as u64);
+        }
+    }
+
+    // Every page but the header starts with the length and the height of its node. Make the nodes
+    // above the leaves too long to be read, but for the last one, which was split off last. A leaf
+    // split under it then fails when it overflows into its left neighbour.
+    let mut bytes = fs::read(&path)?;
+    let mut damaged = bytes
+        .chunks(4096)
+        .enumerate()
+        .filter(|(_, page)| page[4..8] == 1u32.to_ne_bytes())
+        .map(|(page, bytes)| (page, bytes.to_vec()))
+        .collect::<Vec<_>>();
+    damaged.pop();
+    assert!(!damaged.is_empty());
+    for (page, _) in &damaged {
+        bytes[page * 4096..][..4].fill(0xff);
+    }
+    fs::write(&path, &bytes)?;
+
+    // Append new keys, mixed with operations on random keys, many of which fail.
+    let mut tree = PagedBTree::open(&path, POOL_PAGES)?;
+    let mut failures = 0;
+    for step in 0..2_000 {
+        let (key, value) = (rng.below(2_000) as u32, rng.next());
+        let result = match step % 3 {
+            0 => tree
+                .remove(&key)
+                .map(|old| assert_eq!(old, model.remove_entry(&key), "step {step}")),
+            1 => tree.insert(key, value).map(|old| {
+                let expected = model.insert(key, value).map(|old| (key, old));
+                assert_eq!(old, expected, "step {step}");
+            }),
+            _ => {
+                let key = 2_000 + step;
+                tree.insert(key, value).map(|old| {
+                    assert_eq!(old, None, "step {step}");
+                    model.insert(key, value);
+                })
+            }
+        };
+        if let Err(error) = result {
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            failures += 1;
+        }
+        assert_eq!(tree.len(), model.len(), "step {step}");
+    }
+    assert!(0 < failures);
+    drop(tree);
+
+    // No operation could read the damaged nodes, so none of them changed, and they can be repaired.
+    let mut bytes = fs::read(&path)?;
+    for (page, original) in damaged {
+        bytes[page * 4096..][..4096].copy_from_slice(&original);
+    }
+    fs::write(&path, &bytes)?;
+    let (mut tree, recovery) = PagedBTree::recover(&path, POOL_PAGES)?;
+    assert_eq!(recovery, Default::default());
+    assert_eq!(contents(&mut tree)?, model);
+    Ok(())
+}
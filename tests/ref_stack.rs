@@ -0,0 +1,112 @@
+//! Exercises `RefStack` on paths down a linked list and on overlapping subslices. Miri checks
+//! them against both aliasing models: `cargo +nightly miri test --test ref_stack`, with and
+//! without `MIRIFLAGS=-Zmiri-tree-borrows`.
+
+use btree2::ref_stack::RefStack;
+
+struct List {
+    value: u32,
+    next: Option<Box<List>>,
+}
+
+fn list(len: u32) -> List {
+    (1..len).rev().fold(
+        List {
+            value: len - 1,
+            next: None,
+        },
+        |next, value| List {
+            value: value - 1,
+            next: Some(Box::new(next)),
+        },
+    )
+}
+
+fn next(node: &mut List) -> &mut List {
+    node.next.as_deref_mut().unwrap()
+}
+
+#[test]
+fn walks_down_and_back_up() {
+    let mut root = list(6);
+    let mut stack = RefStack::<List, 8>::with_root(&mut root);
+    while stack.top().unwrap().next.is_some() {
+        stack.push(next);
+        stack.top_mut().unwrap().value += 10;
+        // Every node is a separate allocation, so the frames don't overlap.
+        assert_eq!(unsafe { stack.get(0) }.unwrap().value, 0);
+    }
+    assert_eq!(stack.len(), 6);
+    let values = unsafe { stack.iter() }
+        .map(|node| node.value)
+        .collect::<Vec<_>>();
+    assert_eq!(values, [0, 11, 12, 13, 14, 15]);
+    assert_eq!(unsafe { stack.get(2) }.unwrap().value, 12);
+    assert!(unsafe { stack.get(6) }.is_none());
+
+    let top = stack.pop().unwrap();
+    top.value += 100;
+    assert_eq!(stack.top().unwrap().value, 14);
+    stack.truncate(2);
+    assert_eq!(stack.top().unwrap().value, 11);
+    stack.truncate(4);
+    assert_eq!(stack.len(), 2);
+
+    let root = stack.into_root().unwrap();
+    root.value = 7;
+    assert_eq!(root.value, 7);
+    assert_eq!(next(next(next(next(next(root))))).value, 115);
+}
+
+#[test]
+fn overflow_gives_the_closure_back() {
+    let mut root = list(4);
+    let mut stack = RefStack::<List, 2>::default();
+    assert!(stack.try_push(next).is_err());
+    assert!(stack.pop().is_none());
+
+    stack.set_root(&mut root);
+    stack.push(next);
+    assert!(stack.is_full());
+    let Err(f) = stack.try_push(next) else {
+        panic!("pushed beyond the capacity");
+    };
+    assert_eq!(f(stack.top_mut().unwrap()).value, 2);
+    assert_eq!(stack.len(), stack.capacity());
+
+    let mut other = list(1);
+    let previous = stack.set_root(&mut other).unwrap();
+    assert_eq!(previous.value, 0);
+    assert_eq!(stack.len(), 1);
+}
+
+#[test]
+fn reborrows_overlapping_frames() {
+    let mut values = [0u32; 8];
+    let mut stack = RefStack::<[u32], 8>::with_root(&mut values);
+    while 1 < stack.top().unwrap().len() {
+        stack.push(|slice| &mut slice[1..]);
+        for value in stack.top_mut().unwrap() {
+            *value += 1;
+        }
+    }
+    while 4 < stack.len() {
+        stack.pop().unwrap()[0] += 100;
+    }
+    assert_eq!(stack.top().unwrap(), [3, 104, 105, 106, 107]);
+
+    // Nothing is written through the frames above once the ones below are read.
+    let lens = unsafe { stack.iter() }
+        .map(|slice| slice.len())
+        .collect::<Vec<_>>();
+    assert_eq!(lens, [8, 7, 6, 5]);
+    assert_eq!(unsafe { stack.get(1) }.unwrap()[..2], [1, 2]);
+    assert_eq!(stack.top().unwrap()[0], 3);
+
+    // Once the frames above it are popped, the root that was read can be written again.
+    stack.truncate(1);
+    stack.top_mut().unwrap()[1] += 10;
+    assert_eq!(unsafe { stack.get(0) }.unwrap()[1], 11);
+    assert_eq!(stack.into_root().unwrap()[0], 0);
+    assert_eq!(values, [0, 11, 2, 3, 104, 105, 106, 107]);
+}
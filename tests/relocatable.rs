@@ -0,0 +1,212 @@
+//! Writes images of slab trees with `write_relocatable`, reads them back with `BTreeView`, and
+//! checks that truncated, misaligned and corrupted images are rejected.
+
+mod common;
+
+use btree2::btree::BTree;
+use btree2::relocatable::{BTreeView, FormatError, Header};
+use common::{Chunk, XorShift};
+use std::collections::BTreeMap;
+use std::mem::{offset_of, size_of};
+use std::slice;
+
+const LEN: usize = if cfg!(miri) { 1_000 } else { 5_000 };
+
+/// A buffer whose bytes start at a multiple of 8, like an `mmap`ed file.
+struct Image {
+    words: Vec<u64>,
+}
+
+impl Image {
+    fn new(size: usize) -> Self {
+        Self {
+            words: vec![0; size.div_ceil(8) + 1],
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.words.as_ptr() as *const u8, self.words.len() * 8) }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self.words.as_mut_ptr() as *mut u8, self.words.len() * 8)
+        }
+    }
+
+    fn read_u64(&self, at: usize) -> u64 {
+        u64::from_ne_bytes(self.bytes()[at..at + 8].try_into().unwrap())
+    }
+
+    fn write_u64(&mut self, at: usize, value: u64) {
+        self.bytes_mut()[at..at + 8].copy_from_slice(&value.to_ne_bytes());
+    }
+}
+
+/// Fills a tree with random entries, and returns the model of them and the image of the tree.
+fn image() -> (BTreeMap<u32, u64>, Image, usize) {
+    let mut chunk = Chunk::new(1 << 22);
+    let mut tree = BTree::<u32, u64>::new(unsafe { chunk.bytes() });
+    let mut model = BTreeMap::new();
+    let mut rng = XorShift::new(LEN as u64);
+    for _ in 0..LEN {
+        let (key, value) = (rng.next() as u32, rng.next());
+        tree.insert(key, value);
+        model.insert(key, value);
+    }
+    assert!(tree.tree_stats().depth >= 3);
+
+    let size = tree.relocatable_size();
+    let mut image = Image::new(size);
+    assert_eq!(
+        tree.write_relocatable(&mut image.bytes_mut()[..size - 1]),
+        Err(FormatError::BufferTooSmall { needed: size })
+    );
+    assert_eq!(tree.write_relocatable(image.bytes_mut()), Ok(size));
+    (model, image, size)
+}
+
+#[test]
+fn images_round_trip() {
+    let (model, image, size) = image();
+    let view = BTreeView::<u32, u64>::from_bytes(&image.bytes()[..size]).unwrap();
+    assert_eq!(view.len(), model.len());
+    assert!(view.depth() >= 3);
+    assert!(view.iter().eq(model.iter()));
+    assert_eq!(view.iter().len(), model.len());
+
+    let mut rng = XorShift::new(1);
+    for (key, value) in &model {
+        assert_eq!(view.get_entry(key), Some((key, value)));
+        let absent = rng.next() as u32;
+        assert_eq!(view.get(&absent), model.get(&absent));
+    }
+
+    // The image can be moved, and the view doesn't care about trailing bytes.
+    let mut copy = Image::new(size + 64);
+    copy.bytes_mut()[..size].copy_from_slice(&image.bytes()[..size]);
+    let view = BTreeView::<u32, u64>::from_bytes(copy.bytes()).unwrap();
+    assert!(view.iter().eq(model.iter()));
+}
+
+#[test]
+fn empty_images_round_trip() {
+    let mut chunk = Chunk::new(1 << 16);
+    let tree = BTree::<u32, u64>::new(unsafe { chunk.bytes() });
+    let size = tree.relocatable_size();
+    assert_eq!(size, size_of::<Header>());
+    let mut image = Image::new(size);
+    assert_eq!(tree.write_relocatable(image.bytes_mut()), Ok(size));
+
+    let view = BTreeView::<u32, u64>::from_bytes(&image.bytes()[..size]).unwrap();
+    assert!(view.is_empty());
+    assert_eq!(view.depth(), 0);
+    assert_eq!(view.get(&1), None);
+    assert_eq!(view.iter().next(), None);
+}
+
+#[test]
+fn truncated_images_are_rejected() {
+    let (_, image, size) = image();
+    for len in [0, 7, size_of::<Header>() - 1, size_of::<Header>(), size - 8] {
+        assert_eq!(
+            BTreeView::<u32, u64>::from_bytes(&image.bytes()[..len]).err(),
+            Some(FormatError::Truncated),
+            "{len} bytes"
+        );
+    }
+}
+
+#[test]
+fn misaligned_images_are_rejected() {
+    let (_, image, size) = image();
+    let mut moved = Image::new(size + 8);
+    for shift in 1..8 {
+        moved.bytes_mut()[shift..shift + size].copy_from_slice(&image.bytes()[..size]);
+        assert_eq!(
+            BTreeView::<u32, u64>::from_bytes(&moved.bytes()[shift..shift + size]).err(),
+            Some(FormatError::Misaligned),
+            "shifted by {shift}"
+        );
+    }
+}
+
+#[test]
+fn out_of_range_offsets_are_rejected() {
+    let (_, mut image, size) = image();
+    let root_at = offset_of!(Header, root);
+    let root = image.read_u64(root_at);
+
+    // Offsets into the header, unaligned, past the end, and close enough to `u64::MAX` that the
+    // end of the record overflows.
+    for offset in [
+        0,
+        8,
+        root + 4,
+        size as u64,
+        size as u64 + 8,
+        u64::MAX - 7,
+        u64::MAX,
+    ] {
+        image.write_u64(root_at, offset);
+        assert_eq!(
+            BTreeView::<u32, u64>::from_bytes(&image.bytes()[..size]).err(),
+            Some(FormatError::BadOffset { offset }),
+            "root at {offset}"
+        );
+    }
+    image.write_u64(root_at, root);
+
+    // The same offsets as the first child of the root. Its children follow its keys and values,
+    // each array aligned to 8 bytes.
+    let len = u32::from_ne_bytes(image.bytes()[root as usize..][..4].try_into().unwrap()) as usize;
+    let values = (8 + len * size_of::<u32>()).next_multiple_of(8);
+    let child_at = root as usize + values + len * size_of::<u64>();
+    let child = image.read_u64(child_at);
+    for offset in [0, child + 4, size as u64, u64::MAX - 7] {
+        image.write_u64(child_at, offset);
+        assert_eq!(
+            BTreeView::<u32, u64>::from_bytes(&image.bytes()[..size]).err(),
+            Some(FormatError::BadOffset { offset }),
+            "child at {offset}"
+        );
+    }
+
+    // A record that is in range but on the wrong level.
+    image.write_u64(child_at, size_of::<Header>() as u64);
+    assert_eq!(
+        BTreeView::<u32, u64>::from_bytes(&image.bytes()[..size]).err(),
+        Some(FormatError::BadNode {
+            offset: size_of::<Header>() as u64
+        })
+    );
+    image.write_u64(child_at, child);
+    assert!(BTreeView::<u32, u64>::from_bytes(&image.bytes()[..size]).is_ok());
+}
+
+#[test]
+fn mismatched_headers_are_rejected() {
+    let (_, mut image, size) = image();
+    assert_eq!(
+        BTreeView::<u64, u64>::from_bytes(&image.bytes()[..size]).err(),
+        Some(FormatError::TypeMismatch)
+    );
+
+    let len_at = offset_of!(Header, len);
+    let len = image.read_u64(len_at);
+    image.write_u64(len_at, len + 1);
+    assert_eq!(
+        BTreeView::<u32, u64>::from_bytes(&image.bytes()[..size]).err(),
+        Some(FormatError::LengthMismatch {
+            expected: len as usize + 1,
+            actual: len as usize
+        })
+    );
+    image.write_u64(len_at, len);
+
+    image.bytes_mut()[0] ^= 1;
+    assert_eq!(
+        BTreeView::<u32, u64>::from_bytes(&image.bytes()[..size]).err(),
+        Some(FormatError::BadMagic)
+    );
+}
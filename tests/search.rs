@@ -0,0 +1,124 @@
+//! Checks the `Binary` and `Simd` search strategies against `Linear`, on bare sorted slices of
+//! every key type `Simd` supports and inside trees.
+
+mod common;
+
+use btree2::search::{Binary, Linear, SearchStrategy, Simd};
+use btree2::{btree, std_btree};
+use common::{Chunk, XorShift};
+
+const LEN: usize = if cfg!(miri) { 300 } else { 20_000 };
+
+/// Checks the strategies on sorted slices of up to 73 keys, which covers the remainders of both
+/// the SSE and the AVX2 loops for every key size. The keys are drawn from the whole range of the
+/// type, with its minimum, maximum and zero always included, so that negative keys and unsigned
+/// keys on both sides of the bias are compared.
+macro_rules! check_slices {
+    ($($t:ty),* $(,)?) => {$({
+        let mut rng = XorShift::new(<$t>::MAX as u64);
+        for len in 0..=70 {
+            let mut keys: Vec<$t> = (0..len).map(|_| rng.next() as $t).collect();
+            keys.extend([<$t>::MIN, <$t>::MAX, 0]);
+            keys.sort_unstable();
+            keys.dedup();
+
+            let needles = keys
+                .iter()
+                .flat_map(|&key| [key, key.wrapping_sub(1), key.wrapping_add(1)])
+                .chain([<$t>::MIN, <$t>::MAX, 0, rng.next() as $t]);
+            for needle in needles {
+                let expected = Linear.search(&keys, &needle);
+                assert_eq!(
+                    Binary.search(&keys, &needle),
+                    expected,
+                    "binary search for {needle} in {keys:?}"
+                );
+                assert_eq!(
+                    Simd.search(&keys, &needle),
+                    expected,
+                    "SIMD search for {needle} in {keys:?}"
+                );
+            }
+        }
+    })*};
+}
+
+#[test]
+fn strategies_agree_on_slices() {
+    check_slices!(i8, u8, i16, u16, i32, u32, i64, u64, isize, usize);
+}
+
+/// Builds trees of the same keys with each strategy, removes every third, and checks that they
+/// find the same entries, including keys that are absent.
+macro_rules! check_trees {
+    ($t:ty, $key:expr) => {{
+        let key: fn(u64) -> $t = $key;
+        let mut chunks = [(); 3].map(|_| Chunk::new(1 << 22));
+        let [linear_chunk, binary_chunk, simd_chunk] = &mut chunks;
+        let mut slab_linear = btree::BTree::<$t, u64, false, (), Linear>::with_search(
+            unsafe { linear_chunk.bytes() },
+            Linear,
+        );
+        let mut slab_binary = btree::BTree::<$t, u64, false, (), Binary>::with_search(
+            unsafe { binary_chunk.bytes() },
+            Binary,
+        );
+        let mut slab_simd = btree::BTree::<$t, u64, false, (), Simd>::with_search(
+            unsafe { simd_chunk.bytes() },
+            Simd,
+        );
+        let mut heap_linear = std_btree::BTree::<$t, u64, false, (), Linear>::with_search(Linear);
+        let mut heap_binary = std_btree::BTree::<$t, u64, false, (), Binary>::with_search(Binary);
+        let mut heap_simd = std_btree::BTree::<$t, u64, false, (), Simd>::with_search(Simd);
+
+        let mut rng = XorShift::new(LEN as u64);
+        let keys: Vec<$t> = (0..LEN).map(|_| key(rng.next())).collect();
+        for (i, &k) in keys.iter().enumerate() {
+            let i = i as u64;
+            let expected = slab_linear.insert(k, i);
+            assert_eq!(slab_binary.insert(k, i), expected);
+            assert_eq!(slab_simd.insert(k, i), expected);
+            assert_eq!(heap_linear.insert(k, i), expected);
+            assert_eq!(heap_binary.insert(k, i), expected);
+            assert_eq!(heap_simd.insert(k, i), expected);
+        }
+        for &k in keys.iter().step_by(3) {
+            let expected = slab_linear.remove(&k);
+            assert_eq!(slab_binary.remove(&k), expected);
+            assert_eq!(slab_simd.remove(&k), expected);
+            assert_eq!(heap_linear.remove(&k), expected);
+            assert_eq!(heap_binary.remove(&k), expected);
+            assert_eq!(heap_simd.remove(&k), expected);
+        }
+
+        for k in keys
+            .iter()
+            .copied()
+            .chain((0..LEN).map(|_| key(rng.next())))
+        {
+            let expected = slab_linear.get(&k);
+            assert_eq!(slab_binary.get(&k), expected, "binary get({k})");
+            assert_eq!(slab_simd.get(&k), expected, "SIMD get({k})");
+            assert_eq!(heap_linear.get(&k), expected, "linear get({k})");
+            assert_eq!(heap_binary.get(&k), expected, "binary get({k})");
+            assert_eq!(heap_simd.get(&k), expected, "SIMD get({k})");
+        }
+        let expected: Vec<_> = slab_linear.iter().collect();
+        assert!(expected.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(slab_binary.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(slab_simd.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(heap_linear.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(heap_binary.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(heap_simd.iter().collect::<Vec<_>>(), expected);
+    }};
+}
+
+#[test]
+fn strategies_agree_in_trees() {
+    // Keys of both signs, from a small range so that some of them repeat.
+    check_trees!(i32, |x| (x % 30_011) as i32 - 15_000);
+    // Keys on both sides of the bias that maps unsigned keys onto signed ones.
+    check_trees!(u64, |x| ((x % 30_011) << 49) | (x % 3));
+    check_trees!(i8, |x| x as i8);
+    check_trees!(u16, |x| x as u16);
+}
@@ -0,0 +1,224 @@
+//! Round-trips the trees through JSON, and checks how deserialization handles keys that come
+//! out of order or more than once.
+
+#![cfg(feature = "serde")]
+
+mod common;
+
+use btree2::serde_impls::DuplicatePolicy;
+use btree2::{bplus_tree, btree, std_btree};
+use common::{Chunk, XorShift};
+use std::collections::BTreeMap;
+
+const LEN: usize = if cfg!(miri) { 300 } else { 5_000 };
+
+type SlabTree = btree::BTree<u32, u64, true>;
+type HeapTree = std_btree::BTree<u32, u64, true>;
+type PlusTree = bplus_tree::BPlusTree<u32, u64, true>;
+
+fn model() -> BTreeMap<u32, u64> {
+    let mut rng = XorShift::new(LEN as u64);
+    (0..LEN).map(|_| (rng.next() as u32, rng.next())).collect()
+}
+
+fn entries<'a>(iter: impl Iterator<Item = (&'a u32, &'a u64)>) -> Vec<(u32, u64)> {
+    iter.map(|(key, value)| (*key, *value)).collect()
+}
+
+#[test]
+fn slab_tree_round_trips() {
+    let model = model();
+    let mut chunk = Chunk::new(1 << 22);
+    let mut tree = SlabTree::new(unsafe { chunk.bytes() });
+    for (&key, &value) in &model {
+        tree.insert(key, value);
+    }
+    let json = serde_json::to_string(&tree).unwrap();
+    assert_eq!(json, serde_json::to_string(&model).unwrap());
+    drop(tree);
+
+    let mut chunk = Chunk::new(1 << 22);
+    let tree = SlabTree::deserialize_in(
+        unsafe { chunk.bytes() },
+        &mut serde_json::Deserializer::from_str(&json),
+    )
+    .unwrap();
+    // The sorted bulk build has to leave the counts right for `nth`.
+    tree.validate().unwrap();
+    assert_eq!(tree.len(), model.len());
+    assert_eq!(entries(tree.iter()), entries(model.iter()));
+    for (i, entry) in model.iter().enumerate().step_by(7) {
+        assert_eq!(tree.nth(i), Some(entry));
+    }
+    assert_eq!(serde_json::to_string(&tree).unwrap(), json);
+}
+
+#[test]
+fn heap_tree_round_trips() {
+    let model = model();
+    let mut tree = HeapTree::new();
+    for (&key, &value) in &model {
+        tree.insert(key, value);
+    }
+    let json = serde_json::to_string(&tree).unwrap();
+    assert_eq!(json, serde_json::to_string(&model).unwrap());
+
+    let tree: HeapTree = serde_json::from_str(&json).unwrap();
+    tree.validate().unwrap();
+    assert_eq!(tree.len(), model.len());
+    assert_eq!(entries(tree.iter()), entries(model.iter()));
+    for (i, entry) in model.iter().enumerate().step_by(7) {
+        assert_eq!(tree.nth(i), Some(entry));
+    }
+    assert_eq!(serde_json::to_string(&tree).unwrap(), json);
+}
+
+#[test]
+fn bplus_tree_round_trips() {
+    let model = model();
+    let mut chunk = Chunk::new(1 << 22);
+    let mut tree = PlusTree::new(unsafe { chunk.bytes() });
+    for (&key, &value) in &model {
+        tree.insert(key, value);
+    }
+    let json = serde_json::to_string(&tree).unwrap();
+    assert_eq!(json, serde_json::to_string(&model).unwrap());
+    drop(tree);
+
+    let mut chunk = Chunk::new(1 << 22);
+    let tree = PlusTree::deserialize_in(
+        unsafe { chunk.bytes() },
+        &mut serde_json::Deserializer::from_str(&json),
+    )
+    .unwrap();
+    tree.validate().unwrap();
+    assert_eq!(tree.len(), model.len());
+    assert_eq!(entries(tree.iter()), entries(model.iter()));
+    for (i, entry) in model.iter().enumerate().step_by(7) {
+        assert_eq!(tree.nth(i), Some(entry));
+    }
+    assert_eq!(serde_json::to_string(&tree).unwrap(), json);
+}
+
+#[test]
+fn unsorted_maps_deserialize() {
+    let model = model();
+    // Every other entry in order, then the rest backwards.
+    let json = format!(
+        "{{{}}}",
+        model
+            .iter()
+            .step_by(2)
+            .chain(model.iter().skip(1).step_by(2).rev())
+            .map(|(key, value)| format!("\"{key}\":{value}"))
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+
+    let mut chunk = Chunk::new(1 << 22);
+    let slab = SlabTree::deserialize_in(
+        unsafe { chunk.bytes() },
+        &mut serde_json::Deserializer::from_str(&json),
+    )
+    .unwrap();
+    slab.validate().unwrap();
+    assert_eq!(entries(slab.iter()), entries(model.iter()));
+    drop(slab);
+
+    let heap: HeapTree = serde_json::from_str(&json).unwrap();
+    heap.validate().unwrap();
+    assert_eq!(entries(heap.iter()), entries(model.iter()));
+}
+
+/// Deserializes `json` into all the trees with `policy`, and returns their entries, or `None` if
+/// they all fail.
+fn with_policy(json: &str, policy: DuplicatePolicy) -> Option<Vec<(u32, u64)>> {
+    let mut chunk = Chunk::new(1 << 16);
+    let slab = SlabTree::deserialize_in_with_policy(
+        unsafe { chunk.bytes() },
+        &mut serde_json::Deserializer::from_str(json),
+        policy,
+    );
+    let heap =
+        HeapTree::deserialize_with_policy(&mut serde_json::Deserializer::from_str(json), policy);
+    // The B+ tree sets aside a small share of its chunk for the nodes, so it needs a larger one.
+    let mut plus_chunk = Chunk::new(1 << 20);
+    let plus = PlusTree::deserialize_in_with_policy(
+        unsafe { plus_chunk.bytes() },
+        &mut serde_json::Deserializer::from_str(json),
+        policy,
+    );
+    match (slab, heap, plus) {
+        (Ok(slab), Ok(heap), Ok(plus)) => {
+            slab.validate().unwrap();
+            heap.validate().unwrap();
+            plus.validate().unwrap();
+            let found = entries(slab.iter());
+            assert_eq!(entries(heap.iter()), found);
+            assert_eq!(entries(plus.iter()), found);
+            Some(found)
+        }
+        (Err(slab), Err(heap), Err(plus)) => {
+            assert!(slab.to_string().starts_with("duplicate key"), "{slab}");
+            assert!(heap.to_string().starts_with("duplicate key"), "{heap}");
+            assert!(plus.to_string().starts_with("duplicate key"), "{plus}");
+            None
+        }
+        (slab, heap, plus) => panic!(
+            "the trees disagree: {:?}, {:?} and {:?}",
+            slab.is_ok(),
+            heap.is_ok(),
+            plus.is_ok()
+        ),
+    }
+}
+
+#[test]
+fn duplicate_keys_follow_the_policy() {
+    // One duplicate right after its first entry, while the bulk build still runs, and one after
+    // it has fallen back to inserting.
+    let json = r#"{"1": 10, "1": 11, "3": 30, "2": 20, "3": 31}"#;
+
+    assert_eq!(with_policy(json, DuplicatePolicy::Reject), None);
+    assert_eq!(with_policy(json, DuplicatePolicy::default()), None);
+    assert_eq!(
+        with_policy(json, DuplicatePolicy::KeepFirst),
+        Some(vec![(1, 10), (2, 20), (3, 30)])
+    );
+    assert_eq!(
+        with_policy(json, DuplicatePolicy::KeepLast),
+        Some(vec![(1, 11), (2, 20), (3, 31)])
+    );
+    // `KeepLast` agrees with `BTreeMap`.
+    let model: BTreeMap<u32, u64> = serde_json::from_str(json).unwrap();
+    assert_eq!(
+        Some(entries(model.iter())),
+        with_policy(json, DuplicatePolicy::KeepLast)
+    );
+
+    // Without duplicates every policy gives the same tree.
+    let json = r#"{"1": 10, "3": 30, "2": 20}"#;
+    for policy in [
+        DuplicatePolicy::Reject,
+        DuplicatePolicy::KeepFirst,
+        DuplicatePolicy::KeepLast,
+    ] {
+        assert_eq!(
+            with_policy(json, policy),
+            Some(vec![(1, 10), (2, 20), (3, 30)])
+        );
+    }
+}
+
+#[test]
+fn small_chunk_fails_the_deserialization() {
+    let model: BTreeMap<u32, u64> = (0..5_000).map(|key| (key, key.into())).collect();
+    let json = serde_json::to_string(&model).unwrap();
+    let mut chunk = Chunk::new(1 << 15);
+    let error = SlabTree::deserialize_in(
+        unsafe { chunk.bytes() },
+        &mut serde_json::Deserializer::from_str(&json),
+    )
+    .expect_err("the chunk runs out");
+    assert!(error.to_string().starts_with("the chunk is too small"));
+}
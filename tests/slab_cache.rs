@@ -0,0 +1,288 @@
+//! Checks that the size classes of a `SlabCache` share its pages, that the slabs it hands out are
+//! aligned and never overlap, also when they are allocated and freed in batches, that a reset
+//! frees all of them, and that the page headers tell which allocator owns a slab. With
+//! `debug-alloc`, also checks the poisoning and the leak reports.
+
+mod common;
+
+use btree2::slab::{SlabAllocator, SlabCache};
+use common::{Chunk, XorShift};
+use std::alloc::Layout;
+use std::ptr::NonNull;
+
+const CLASSES: [Layout; 3] = [
+    Layout::new::<[u64; 3]>(),
+    Layout::new::<[u8; 200]>(),
+    match Layout::from_size_align(40, 64) {
+        Ok(layout) => layout,
+        Err(_) => panic!(),
+    },
+];
+
+#[test]
+fn classes_take_turns_with_the_pages() {
+    let mut chunk = Chunk::new(1 << 16);
+    let mut cache = SlabCache::new(CLASSES, unsafe { chunk.bytes() });
+    let pages = cache.free_pages();
+    assert!(8 <= pages);
+
+    for class in 0..CLASSES.len() {
+        let slabs = cache.free_slabs(class);
+        let ptrs = (0..slabs)
+            .map(|_| cache.malloc(class).unwrap())
+            .collect::<Vec<_>>();
+        assert!(cache.malloc(class).is_none());
+        assert_eq!(cache.free_pages(), 0);
+        assert!(cache.needs_new_chunk());
+        let stats = cache.stats(class);
+        assert_eq!(stats.reserved, pages * cache.page_size());
+        assert_eq!(stats.free, 0);
+
+        for ptr in ptrs {
+            unsafe { cache.free(ptr, class) };
+        }
+        assert_eq!(cache.free_pages(), pages);
+        assert_eq!(cache.stats(class).reserved, 0);
+    }
+
+    let mut slabs = [0; 3];
+    slabs[1] = cache.free_slabs(1);
+    assert!(cache.has_room(slabs));
+    slabs[0] = 1;
+    assert!(!cache.has_room(slabs));
+}
+
+#[test]
+fn slabs_are_aligned_and_disjoint() {
+    let mut chunks = vec![Chunk::new(1 << 15)];
+    let mut cache = SlabCache::new(CLASSES, unsafe { chunks[0].bytes() });
+    let mut rng = XorShift::new(7);
+    let mut live: Vec<(NonNull<u8>, usize, u8)> = Vec::new();
+    let ops = if cfg!(miri) { 2_000 } else { 100_000 };
+
+    for op in 0..ops {
+        if live.is_empty() || rng.below(5) < 3 {
+            if cache.needs_new_chunk() {
+                chunks.push(Chunk::new(1 << 15));
+                cache.add_chunk(unsafe { chunks.last_mut().unwrap().bytes() });
+            }
+            let class = rng.below(3) as usize;
+            let ptr = cache.malloc(class).unwrap();
+            assert_eq!(ptr.as_ptr() as usize % CLASSES[class].align(), 0);
+            let fill = op as u8;
+            unsafe { ptr.as_ptr().write_bytes(fill, CLASSES[class].size()) };
+            live.push((ptr, class, fill));
+        } else {
+            let (ptr, class, fill) = live.swap_remove(rng.below(live.len() as u64) as usize);
+            let bytes = unsafe { std::slice::from_raw_parts(ptr.as_ptr(), CLASSES[class].size()) };
+            assert!(bytes.iter().all(|&byte| byte == fill), "slabs overlap");
+            unsafe { cache.free(ptr, class) };
+        }
+    }
+
+    for class in 0..CLASSES.len() {
+        let stats = cache.stats(class);
+        let live = live.iter().filter(|&&(_, c, _)| c == class).count();
+        assert_eq!(stats.in_use, live * stats.slab_size);
+    }
+    for (ptr, class, _) in live {
+        unsafe { cache.free(ptr, class) };
+    }
+    let pages = chunks.len() * ((1 << 15) / cache.page_size() - 1);
+    assert!(pages <= cache.free_pages());
+}
+
+#[test]
+fn pages_know_their_owner() {
+    let (mut a_chunk, mut b_chunk) = (Chunk::new(1 << 15), Chunk::new(1 << 15));
+    let mut a = SlabAllocator::<[u32; 3]>::new(unsafe { a_chunk.bytes() });
+    let mut b = SlabAllocator::<[u32; 3]>::new(unsafe { b_chunk.bytes() });
+    let (x, y) = (a.malloc().unwrap(), b.malloc().unwrap());
+    unsafe {
+        assert!(a.owns(x) && !a.owns(y));
+        assert!(b.owns(y) && !b.owns(x));
+        a.free(x);
+        b.free(y);
+    }
+
+    // A page given from one allocator to another changes hands.
+    let pages = a.stats().reserved / 4096;
+    let page = a.release_page().unwrap();
+    assert_eq!(page.len(), 4096);
+    b.add_chunk(page);
+    assert_eq!(a.stats().reserved, (pages - 1) * 4096);
+    let slabs = b.free_slabs();
+    let ptrs = (0..slabs).map(|_| b.malloc().unwrap()).collect::<Vec<_>>();
+    assert!(b.malloc().is_none());
+    for ptr in ptrs {
+        unsafe {
+            assert!(b.owns(ptr) && !a.owns(ptr));
+            b.free(ptr);
+        }
+    }
+}
+
+#[test]
+fn batches_take_and_give_whole_pages() {
+    let mut chunk = Chunk::new(1 << 16);
+    let mut cache = SlabCache::new(CLASSES, unsafe { chunk.bytes() });
+    let pages = cache.free_pages();
+    let per_page = cache.slabs_per_page(0);
+
+    let mut ptrs = Vec::new();
+    assert_eq!(cache.malloc_many(0, per_page + 3, &mut ptrs), per_page + 3);
+    assert_eq!(cache.free_pages(), pages - 2);
+    let stats = cache.stats(0);
+    assert_eq!(stats.in_use, (per_page + 3) * stats.slab_size);
+    assert_eq!(
+        (stats.free_runs, stats.free),
+        (1, (per_page - 3) * stats.slab_size)
+    );
+    let single = cache.malloc(0).unwrap();
+    assert!(!ptrs.contains(&single));
+    ptrs.push(single);
+
+    // Asking for more than there is takes every slab the pool has room for.
+    let slabs = cache.free_slabs(1);
+    let mut rest = Vec::new();
+    assert_eq!(cache.malloc_many(1, usize::MAX, &mut rest), slabs);
+    assert_eq!(cache.free_pages(), 0);
+    assert!(cache.malloc(1).is_none());
+
+    let mut sorted = ptrs.iter().chain(&rest).collect::<Vec<_>>();
+    sorted.sort();
+    sorted.dedup();
+    assert_eq!(sorted.len(), ptrs.len() + rest.len());
+
+    unsafe {
+        cache.free_many(1, rest);
+        cache.free_many(0, ptrs.into_iter().rev());
+    }
+    assert_eq!(cache.free_pages(), pages);
+    assert_eq!(cache.stats(0).reserved + cache.stats(1).reserved, 0);
+
+    let mut alloc_chunk = Chunk::new(1 << 15);
+    let mut alloc = SlabAllocator::<[u32; 3]>::new(unsafe { alloc_chunk.bytes() });
+    let free = alloc.stats().free;
+    let mut ptrs = Vec::new();
+    assert_eq!(alloc.malloc_many(100, &mut ptrs), 100);
+    assert_eq!(alloc.stats().in_use, 100 * 12);
+    unsafe { alloc.free_many(ptrs) };
+    assert_eq!(alloc.stats().free, free);
+}
+
+#[test]
+fn reset_makes_every_page_fresh() {
+    let mut chunks = [Chunk::new(1 << 15), Chunk::new(1 << 16)];
+    let mut cache = SlabCache::new(CLASSES, unsafe { chunks[0].bytes() });
+    cache.add_chunk(unsafe { chunks[1].bytes() });
+    let pages = cache.free_pages();
+
+    let mut ptrs = Vec::new();
+    for class in 0..CLASSES.len() {
+        cache.malloc_many(class, 50, &mut ptrs);
+    }
+    unsafe { cache.free(ptrs[0], 0) };
+    assert!(cache.free_pages() < pages);
+
+    // Only pages that were never used are released, which leaves out the first one of a chunk.
+    let mut released = 0;
+    while cache.release_page().is_some() {
+        released += 1;
+    }
+    assert_eq!(cache.free_pages(), 1);
+
+    unsafe { cache.reset() };
+    assert_eq!(cache.free_pages(), pages - released);
+    for class in 0..CLASSES.len() {
+        assert_eq!(cache.stats(class).in_use, 0);
+    }
+    let slabs = cache.free_slabs(2);
+    let mut ptrs = Vec::new();
+    assert_eq!(cache.malloc_many(2, usize::MAX, &mut ptrs), slabs);
+    unsafe { cache.free_many(2, ptrs) };
+}
+
+#[cfg(any(debug_assertions, feature = "debug-alloc"))]
+#[test]
+#[should_panic = "doesn't own it"]
+fn freeing_to_another_allocator_panics() {
+    let (mut a_chunk, mut b_chunk) = (Chunk::new(1 << 15), Chunk::new(1 << 15));
+    let mut a = SlabAllocator::<u64>::new(unsafe { a_chunk.bytes() });
+    let mut b = SlabAllocator::<u64>::new(unsafe { b_chunk.bytes() });
+    let x = a.malloc().unwrap();
+    unsafe { b.free(x) };
+}
+
+#[cfg(any(debug_assertions, feature = "debug-alloc"))]
+#[test]
+#[should_panic = "slab freed twice"]
+fn freeing_twice_panics() {
+    let mut chunk = Chunk::new(1 << 15);
+    let mut alloc = SlabAllocator::<u64>::new(unsafe { chunk.bytes() });
+    let (x, _y) = (alloc.malloc().unwrap(), alloc.malloc().unwrap());
+    unsafe {
+        alloc.free(x);
+        alloc.free(x);
+    }
+}
+
+#[cfg(feature = "debug-alloc")]
+#[test]
+fn slabs_are_poisoned() {
+    let mut chunk = Chunk::new(1 << 15);
+    let mut alloc = SlabAllocator::<[u8; 24]>::new(unsafe { chunk.bytes() });
+    let x = alloc.malloc().unwrap();
+    assert_eq!(unsafe { x.read() }, [0xa5; 24]);
+    unsafe {
+        x.write([1; 24]);
+        alloc.free(x);
+        assert_eq!(x.read(), [0xdd; 24]);
+        assert_eq!(alloc.malloc().unwrap().read(), [0xa5; 24]);
+    }
+}
+
+#[cfg(feature = "debug-alloc")]
+#[test]
+#[should_panic = "after it was freed"]
+fn writing_after_free_panics() {
+    let mut chunk = Chunk::new(1 << 15);
+    let mut alloc = SlabAllocator::<u64>::new(unsafe { chunk.bytes() });
+    let (x, _y) = (alloc.malloc().unwrap(), alloc.malloc().unwrap());
+    unsafe {
+        alloc.free(x);
+        x.write(7);
+    }
+    alloc.malloc();
+}
+
+#[cfg(feature = "debug-alloc")]
+#[test]
+fn leaks_are_reported() {
+    use std::sync::Mutex;
+
+    struct Logger(Mutex<Vec<String>>);
+
+    impl log::Log for Logger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() == log::Level::Error
+        }
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+        }
+        fn flush(&self) {}
+    }
+
+    static LOGGER: Logger = Logger(Mutex::new(Vec::new()));
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(log::LevelFilter::Error);
+
+    let mut chunk = Chunk::new(1 << 15);
+    let mut alloc = SlabAllocator::<[u64; 4]>::new(unsafe { chunk.bytes() });
+    let _leaked = (alloc.malloc(), alloc.malloc());
+    drop(alloc);
+    let messages = LOGGER.0.lock().unwrap();
+    assert!(messages.contains(&"2 slabs of 32 bytes in size class 0 leaked".to_string()));
+}
@@ -0,0 +1,162 @@
+//! Checks the statistics of the trees against trees of a known shape, and the free runs of the
+//! slab allocators against the slabs freed.
+
+mod common;
+
+use btree2::slab::SlabAllocator;
+use btree2::stats::{BTreeStats, TreeStats};
+use btree2::{btree, std_btree};
+use common::Chunk;
+
+const LEN: u32 = if cfg!(miri) { 1_000 } else { 20_000 };
+
+/// Checks that the levels of `stats` add up: every node has one more child than keys, the keys
+/// of all the levels are the entries, and the fill of a level is its keys over its capacity.
+fn check_levels(stats: &TreeStats) {
+    let levels = stats.levels();
+    assert_eq!(levels.len(), stats.depth);
+    assert_eq!(
+        levels.iter().map(|level| level.keys).sum::<usize>(),
+        stats.len
+    );
+    let (leaves, nodes) = levels.split_last().unwrap();
+    assert_eq!(leaves.nodes, stats.leaves);
+    assert_eq!(
+        nodes.iter().map(|level| level.nodes).sum::<usize>(),
+        stats.nodes
+    );
+    assert_eq!(levels[0].nodes, 1);
+    for pair in levels.windows(2) {
+        assert_eq!(pair[1].nodes, pair[0].nodes + pair[0].keys);
+    }
+    let capacity = levels[0].capacity;
+    for level in levels {
+        assert_eq!(level.capacity, level.nodes * capacity);
+        assert_eq!(level.fill(), level.keys as f64 / level.capacity as f64);
+    }
+}
+
+/// Inserts ascending keys until the root leaf splits, and checks the shape before and after. The
+/// tree holds one more entry than a leaf can then, and its root the one separator.
+macro_rules! check_first_split {
+    ($tree:ident, $stats:ident) => {{
+        assert_eq!($tree.$stats(), TreeStats::default());
+        let mut len = 0;
+        while $tree.$stats().depth < 2 {
+            $tree.insert(len, len);
+            len += 1;
+        }
+        let capacity = len as usize - 1;
+        let stats = $tree.$stats();
+        assert_eq!((stats.len, stats.nodes, stats.leaves), (len as usize, 1, 2));
+        let [root, leaves] = stats.levels() else {
+            panic!("{stats:?}");
+        };
+        assert_eq!((root.nodes, root.keys, root.capacity), (1, 1, capacity));
+        assert_eq!((leaves.nodes, leaves.keys), (2, capacity));
+        assert_eq!(leaves.fill(), 0.5);
+        check_levels(&stats);
+
+        // Removing entries merges the leaves back into the root.
+        let mut removed = 0;
+        while 1 < $tree.$stats().depth {
+            $tree.remove(&removed);
+            removed += 1;
+        }
+        let stats = $tree.$stats();
+        assert_eq!((stats.leaves, stats.nodes), (1, 0));
+        assert_eq!(stats.levels()[0].keys, (len - removed) as usize);
+        check_levels(&stats);
+    }};
+}
+
+#[test]
+fn first_split() {
+    let mut tree = btree::BTree::<u32, u32>::with_buffer(vec![0; 1 << 16]);
+    check_first_split!(tree, tree_stats);
+    let mut std_tree = std_btree::BTree::<u32, u32>::new();
+    check_first_split!(std_tree, stats);
+}
+
+#[test]
+fn levels_and_slabs_add_up() {
+    let mut tree = btree::BTree::<u32, u32>::with_buffer(vec![0; 1 << 22]);
+    let mut std_tree = std_btree::BTree::<u32, u32>::new();
+    for key in 0..LEN {
+        tree.insert(key, key);
+        std_tree.insert(key, key);
+    }
+    let stats = tree.stats();
+    assert!(3 <= stats.tree.depth);
+    check_levels(&stats.tree);
+    assert_eq!(stats.tree, tree.validate().unwrap());
+    let std_stats = std_tree.stats();
+    assert!(3 <= std_stats.depth);
+    check_levels(&std_stats);
+    let in_use = |stats: &BTreeStats| {
+        stats.tree.nodes * stats.node_alloc.slab_size
+            + stats.tree.leaves * stats.leaf_alloc.slab_size
+    };
+    assert_eq!(stats.in_use(), in_use(&stats));
+    assert!(stats.in_use() <= stats.reserved());
+
+    // Removing most of the entries merges leaves all over the pages, which leaves free slabs
+    // behind in the pages that keep some.
+    for key in (0..LEN).filter(|key| key % 4 != 0) {
+        tree.remove(&key);
+    }
+    let stats = tree.stats();
+    check_levels(&stats.tree);
+    assert_eq!(stats.tree.len, LEN as usize / 4);
+    assert_eq!(stats.in_use(), in_use(&stats));
+    let leaf_alloc = stats.leaf_alloc;
+    assert!(0 < leaf_alloc.free_runs);
+    assert!(leaf_alloc.slab_size <= leaf_alloc.largest_free_run);
+    assert!(leaf_alloc.largest_free_run <= leaf_alloc.free);
+    assert!(leaf_alloc.free <= leaf_alloc.free_runs * leaf_alloc.largest_free_run);
+    assert_eq!(leaf_alloc.free % leaf_alloc.slab_size, 0);
+}
+
+#[test]
+fn free_runs_follow_the_frees() {
+    let mut chunk = Chunk::new(1 << 16);
+    let mut alloc = SlabAllocator::<[u64; 4]>::new(unsafe { chunk.bytes() });
+    let slab_size = 32;
+
+    // Every page is free, and counts as a run.
+    let stats = alloc.stats();
+    let slabs = alloc.free_slabs();
+    assert_eq!(stats.slab_size, slab_size);
+    assert_eq!((stats.in_use, stats.free), (0, slabs * slab_size));
+    assert!(1 < stats.free_runs);
+    assert_eq!(stats.largest_free_run * stats.free_runs, stats.free);
+
+    let mut ptrs = (0..slabs)
+        .map(|_| alloc.malloc().unwrap())
+        .collect::<Vec<_>>();
+    let stats = alloc.stats();
+    assert_eq!((stats.in_use, stats.free), (slabs * slab_size, 0));
+    assert_eq!((stats.free_runs, stats.largest_free_run), (0, 0));
+    assert!(stats.in_use <= stats.reserved);
+
+    unsafe { alloc.free(ptrs.pop().unwrap()) };
+    let stats = alloc.stats();
+    assert_eq!((stats.free_runs, stats.largest_free_run), (1, slab_size));
+    assert_eq!(stats.free, slab_size);
+
+    // The second slab freed makes a run of its own if it is in another page than the first.
+    unsafe { alloc.free(ptrs.swap_remove(0)) };
+    let stats = alloc.stats();
+    assert_eq!(stats.free, 2 * slab_size);
+    match stats.free_runs {
+        1 => assert_eq!(stats.largest_free_run, 2 * slab_size),
+        2 => assert_eq!(stats.largest_free_run, slab_size),
+        runs => panic!("{runs} free runs"),
+    }
+
+    for ptr in ptrs {
+        unsafe { alloc.free(ptr) };
+    }
+    assert_eq!(alloc.stats().in_use, 0);
+    assert_eq!(alloc.free_slabs(), slabs);
+}
@@ -0,0 +1,126 @@
+//! Breaks trees on purpose through their `corrupt` hooks, and checks that `validate` reports every
+//! kind of violation, also in release builds, and accepts the trees again once they are repaired.
+
+#![cfg(feature = "check-invariants")]
+
+mod common;
+
+use btree2::validate::{Corruption, InvariantViolation};
+use btree2::{bplus_tree, btree, std_btree};
+use common::Chunk;
+
+const LEN: usize = 2_000;
+
+/// Applies each corruption to `$tree`, checks that `validate` reports its violation, and undoes
+/// it again.
+macro_rules! check_corruptions {
+    ($tree:ident, $cases:expr) => {{
+        let stats = $tree.validate().unwrap();
+        for (corruption, violation) in $cases {
+            let undo = unsafe { $tree.corrupt(corruption) };
+            assert_eq!($tree.validate(), Err(violation), "{corruption:?}");
+            assert_eq!(unsafe { $tree.corrupt(undo) }, corruption);
+            assert_eq!($tree.validate(), Ok(stats));
+        }
+    }};
+}
+
+/// The corruptions that every tree reports, with the violations of a tree of `depth` levels.
+fn common_cases(depth: usize) -> [(Corruption, InvariantViolation); 4] {
+    let leaves = depth - 1;
+    [
+        (
+            Corruption::SwapKeys,
+            InvariantViolation::OutOfOrder {
+                level: leaves,
+                index: 1,
+            },
+        ),
+        (
+            Corruption::LeafLen(1),
+            InvariantViolation::BadLength {
+                level: leaves,
+                len: 1,
+            },
+        ),
+        (
+            Corruption::Size(LEN + 1),
+            InvariantViolation::SizeMismatch {
+                expected: LEN + 1,
+                actual: LEN,
+            },
+        ),
+        (
+            Corruption::Depth(u8::MAX as usize),
+            InvariantViolation::TooDeep {
+                depth: u8::MAX as usize,
+            },
+        ),
+    ]
+}
+
+/// The corruptions that the B-trees, which tell their leaves from their internal nodes and count
+/// their subtrees, report on top of the common ones.
+fn counted_cases(depth: usize) -> [(Corruption, InvariantViolation); 3] {
+    [
+        (
+            Corruption::Depth(depth + 1),
+            InvariantViolation::LeafDepth { level: depth - 1 },
+        ),
+        (
+            Corruption::Depth(depth - 1),
+            InvariantViolation::LeafDepth { level: depth - 2 },
+        ),
+        (
+            Corruption::RootCount(LEN + 1),
+            InvariantViolation::CountMismatch {
+                level: 0,
+                cached: LEN + 1,
+                actual: LEN,
+            },
+        ),
+    ]
+}
+
+#[test]
+fn slab_tree_reports_every_violation() {
+    let mut tree = btree::BTree::<u32, u32, true>::with_buffer(vec![0; 1 << 20]);
+    for key in 0..LEN as u32 {
+        tree.insert(key, key);
+    }
+    let depth = tree.validate().unwrap().depth;
+    assert!(3 <= depth);
+    check_corruptions!(tree, common_cases(depth));
+    check_corruptions!(tree, counted_cases(depth));
+}
+
+#[test]
+fn boxed_tree_reports_every_violation() {
+    let mut tree = std_btree::BTree::<u32, u32, true>::new();
+    for key in 0..LEN as u32 {
+        tree.insert(key, key);
+    }
+    let depth = tree.validate().unwrap().depth;
+    assert!(3 <= depth);
+    check_corruptions!(tree, common_cases(depth));
+    check_corruptions!(tree, counted_cases(depth));
+}
+
+#[test]
+fn bplus_tree_reports_every_violation() {
+    let mut chunk = Chunk::new(1 << 20);
+    let mut tree = bplus_tree::BPlusTree::<u32, u32>::new(unsafe { chunk.bytes() });
+    for key in 0..LEN as u32 {
+        tree.insert(key, key);
+    }
+    let depth = tree.validate().unwrap().depth;
+    assert!(3 <= depth);
+    check_corruptions!(tree, common_cases(depth));
+    check_corruptions!(
+        tree,
+        [(
+            Corruption::SwapLinks,
+            InvariantViolation::BrokenLink { index: 0 }
+        )]
+    );
+}